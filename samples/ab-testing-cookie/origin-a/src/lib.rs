@@ -12,6 +12,6 @@ fn handle_origin_a(req: Request) -> anyhow::Result<impl IntoResponse> {
 fn get_cookie_response(_req: Request, _: Params) -> Result<impl IntoResponse> {
     Ok(ResponseBuilder::new(200)
         .header("content-type", "text/html")
-        .body("<html><head><title>Variant A</title></head><body><h1>Variant A</h1><h2>Request has been routed here because desired cookie does not exist (yet)</h2></body></html>")
+        .body("<html><head><title>Variant A</title></head><body><h1>Variant A</h1><h2>The request has been routed here because of the variant stored in your cookie</h2></body></html>")
         .build())
 }
@@ -0,0 +1,123 @@
+//! The cookie remembering the variant of a visitor. It is signed, so visitors can't pick a
+//! variant on their own.
+
+use anyhow::{bail, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Shorter secrets are too easy to guess
+const MIN_SECRET_LEN: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Signs and verifies assignment cookies with the `cookie_secret` variable
+pub struct CookieSigner {
+    secret: Vec<u8>,
+}
+
+impl CookieSigner {
+    /// Fails if `secret` is empty, i.e. the `cookie_secret` variable is not set. The app starts
+    /// without it, so that the routes not using cookies work with a plain `spin up`.
+    pub fn new(secret: &str) -> Result<CookieSigner> {
+        if secret.trim().is_empty() {
+            bail!(
+                "cookie_secret is not set, start the app with \
+                 --variable cookie_secret=\"$(openssl rand -base64 32)\""
+            );
+        }
+        if secret.trim().len() < MIN_SECRET_LEN {
+            bail!(
+                "cookie_secret must have at least {} characters",
+                MIN_SECRET_LEN
+            );
+        }
+        Ok(CookieSigner {
+            secret: secret.trim().as_bytes().to_vec(),
+        })
+    }
+
+    /// Cookie value assigning the visitor to `variant` of `experiment`
+    pub fn sign(&self, experiment: &str, variant: &str) -> String {
+        let signature = self.mac(experiment, variant).finalize().into_bytes();
+        format!("{}.{}", variant, URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    /// The variant of `experiment` assigned by `cookie`. Returns `None` for cookies of other
+    /// experiments and cookies not signed by this signer.
+    pub fn verify<'a>(&self, experiment: &str, cookie: &'a str) -> Option<&'a str> {
+        let (variant, signature) = cookie.split_once('.')?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        self.mac(experiment, variant)
+            .verify_slice(&signature)
+            .ok()
+            .map(|_| variant)
+    }
+
+    fn mac(&self, experiment: &str, variant: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts any key");
+        mac.update(experiment.as_bytes());
+        mac.update(b".");
+        mac.update(variant.as_bytes());
+        mac
+    }
+}
+
+/// Name of the cookie holding the variant of `experiment`
+pub fn cookie_name(experiment: &str) -> String {
+    format!("fwf-ab-testing-{}", experiment)
+}
+
+/// Name of the cookie marking that the visitor reached `goal` of `experiment`
+pub fn converted_cookie_name(experiment: &str, goal: &str) -> String {
+    format!("fwf-ab-converted-{}.{}", experiment, goal)
+}
+
+/// `Set-Cookie` value storing the cookie `name` for `max_age` seconds
+pub fn set_cookie(name: &str, value: &str, max_age: u64) -> String {
+    format!(
+        "{}={};Path=/;SameSite=Lax;HttpOnly;Max-Age={}",
+        name, value, max_age
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "a secret of at least thirty-two characters";
+
+    #[test]
+    fn test_signed_cookie_round_trip() {
+        let signer = CookieSigner::new(SECRET).unwrap();
+        let cookie = signer.sign("by-cookie", "b");
+
+        assert!(cookie.starts_with("b."));
+        assert_eq!(signer.verify("by-cookie", &cookie), Some("b"));
+        assert_eq!(signer.verify("other", &cookie), None);
+    }
+
+    #[test]
+    fn test_forged_cookies_are_rejected() {
+        let signer = CookieSigner::new(SECRET).unwrap();
+        let cookie = signer.sign("by-cookie", "a");
+        let forged = cookie.replace("a.", "b.");
+        assert_eq!(signer.verify("by-cookie", &forged), None);
+
+        let other = CookieSigner::new(&format!("{} but other", SECRET)).unwrap();
+        assert_eq!(other.verify("by-cookie", &cookie), None);
+
+        assert_eq!(signer.verify("by-cookie", "b"), None);
+        assert_eq!(signer.verify("by-cookie", "yes"), None);
+        assert_eq!(signer.verify("by-cookie", "b.!!!"), None);
+    }
+
+    #[test]
+    fn test_short_secrets_are_rejected() {
+        assert!(CookieSigner::new("secret").is_err());
+
+        let missing = CookieSigner::new(" ").err().unwrap();
+        assert!(missing.to_string().starts_with("cookie_secret is not set"));
+    }
+}
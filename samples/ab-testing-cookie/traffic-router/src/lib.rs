@@ -1,19 +1,48 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use spin_sdk::http::{
-    send, IntoResponse, Method, Params, Request, RequestBuilder, Response, ResponseBuilder, Router,
+    IntoResponse, Params, Request, Response, ResponseBuilder, ResponseOutparam, Router,
 };
-use spin_sdk::http_component;
+use spin_sdk::key_value::Store;
+use spin_sdk::{http_component, variables};
 
-const ORIGIN_A: &str = "origin-a";
-const ORIGIN_B: &str = "origin-b";
+use cookie::{cookie_name, set_cookie, CookieSigner};
+use experiment_config::proxy::{Forward, ForwardedResponse};
+use experiment_config::{cookie_value, results, Decision, Experiment, ExperimentConfig, Variant};
+
+mod cookie;
+mod tracking;
+
+/// Experiments used if the `experiments` variable is empty
+const DEFAULT_EXPERIMENTS: &str = include_str!("../experiments.json");
 
 #[http_component]
-fn handle_ab_testing(req: Request) -> anyhow::Result<impl IntoResponse> {
+async fn handle_ab_testing(req: Request, response_out: ResponseOutparam) {
+    let response = match req.path() {
+        path if path == "/" || path == "/index.html" || path.starts_with("/experiments/") => {
+            app_router().handle(req).into()
+        }
+        // the router keeps a single value per header, the responses of experiments may set
+        // several cookies
+        _ => match route_by_cookie(req).await {
+            Ok(response) => response,
+            Err(e) => {
+                println!("routing request failed: {:#}", e);
+                Response::new(500, e.to_string()).into()
+            }
+        },
+    };
+    if let Err(e) = response.write(response_out).await {
+        println!("sending response failed: {:#}", e);
+    }
+}
+
+fn app_router() -> Router {
     let mut router = Router::default();
     router.get("/", redirect_to_index);
     router.get("/index.html", route_index);
-    router.get_async("/by-cookie", route_by_cookie);
-    Ok(router.handle(req))
+    router.post("/experiments/:id/conversions", tracking::handle_conversion);
+    router.get("/experiments/:id/results", tracking::handle_results);
+    router
 }
 
 fn redirect_to_index(_req: Request, _: Params) -> Result<impl IntoResponse> {
@@ -35,7 +64,7 @@ fn route_index(_req: Request, _: Params) -> Result<impl IntoResponse> {
 <body>
     <h1>A/B Testing Sample</h1>
     <ul>
-        <li><a href="/by-cookie">Route Requests by a Cookie</a> - The first request to this sample will set a cookie. For all subsequent requests hitting that sample - within the next hour - will be routed differently</li>
+        <li><a href="/by-cookie.html">Route Requests by a Cookie</a> - The first request to this sample assigns you to a variant and stores it in a signed cookie. All subsequent requests are routed to the same variant</li>
     </ul>
 </body>
 
@@ -47,35 +76,64 @@ fn route_index(_req: Request, _: Params) -> Result<impl IntoResponse> {
         .build())
 }
 
-fn has_desired_cookie(req: &Request) -> bool {
-    match req.header("cookie") {
-        Some(header) => match header.as_str() {
-            Some(header_value) => header_value.contains("fwf-ab-testing-cookie=yes"),
-            None => false,
-        },
-        None => false,
+/// The variant of the visitor of `req` and, if the visitor has just been assigned to it, the
+/// cookie to store the assignment in
+fn assign_variant<'a>(
+    req: &Request,
+    experiment: &'a Experiment,
+    signer: &CookieSigner,
+) -> Result<(&'a Variant, Option<String>)> {
+    match experiment.decide(req) {
+        Decision::Excluded(variant) | Decision::Forced(variant) => return Ok((variant, None)),
+        Decision::Assign => {}
     }
-}
-async fn route_by_cookie(req: Request, _: Params) -> Result<impl IntoResponse> {
-    const ORIGIN_REQUEST_PATH: &str = "by-cookie.html";
-    let mut origin_route = build_request_url(ORIGIN_A, ORIGIN_REQUEST_PATH);
-    if has_desired_cookie(&req) {
-        origin_route = build_request_url(ORIGIN_B, ORIGIN_REQUEST_PATH);
+
+    let assigned = cookie_value(req, &cookie_name(&experiment.id))
+        .and_then(|cookie| signer.verify(&experiment.id, cookie))
+        .and_then(|variant| experiment.variant(variant));
+    if let Some(variant) = assigned {
+        return Ok((variant, None));
     }
 
-    let origin_req = RequestBuilder::new(Method::Get, origin_route).build();
-    let origin_response: Response = send(origin_req).await?;
+    let variant = experiment.pick(experiment.bucket(req)?);
+    Ok((variant, Some(signer.sign(&experiment.id, &variant.name))))
+}
 
-    Ok(ResponseBuilder::new(200)
-        .header("content-type", "text/html")
-        .header(
-            "set-cookie",
-            "fwf-ab-testing-cookie=yes;Path=/;SameSite=Lax;Max-Age=3600",
-        )
-        .body(origin_response.body().to_vec())
-        .build())
+async fn route_by_cookie(req: Request) -> Result<ForwardedResponse> {
+    let config = ExperimentConfig::load(DEFAULT_EXPERIMENTS)?;
+    let Some(experiment) = config.find(req.path()) else {
+        return Ok(Response::new(404, ()).into());
+    };
+    let signer = CookieSigner::new(&variables::get("cookie_secret")?)?;
+    let max_age = variables::get("cookie_max_age")?
+        .parse::<u64>()
+        .context("invalid cookie_max_age variable")?;
+
+    let (variant, cookie) = assign_variant(&req, experiment, &signer)?;
+    let mut cookies = Vec::new();
+    if let Some(cookie) = cookie {
+        record_exposure(experiment, variant);
+        cookies.push(set_cookie(&cookie_name(&experiment.id), &cookie, max_age));
+    }
+
+    Forward {
+        experiment,
+        variant,
+        cookies,
+        // the variant depends on the assignment cookie
+        vary: vec!["Cookie"],
+    }
+    .send(&req)
+    .await
 }
 
-fn build_request_url(origin: &str, path: &str) -> String {
-    format!("/{}/{}", origin, path)
+/// Count that a visitor was assigned to `variant`. Failures are logged, the request is served
+/// anyway.
+fn record_exposure(experiment: &Experiment, variant: &Variant) {
+    let recorded = Store::open_default()
+        .map_err(anyhow::Error::from)
+        .and_then(|store| results::record_exposure(&store, experiment, &variant.name));
+    if let Err(e) = recorded {
+        println!("failed to record exposure of {}: {:#}", experiment.id, e);
+    }
 }
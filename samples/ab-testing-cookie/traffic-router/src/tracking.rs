@@ -0,0 +1,105 @@
+//! Conversion beacons attributing goals to the variant in the visitor's cookie, and the
+//! results of the experiments.
+
+use anyhow::{Context, Result};
+use spin_sdk::http::{Params, Request, Response, ResponseBuilder};
+use spin_sdk::key_value::Store;
+use spin_sdk::variables;
+
+use crate::cookie::{converted_cookie_name, cookie_name, set_cookie, CookieSigner};
+use crate::DEFAULT_EXPERIMENTS;
+use experiment_config::{cookie_value, is_valid_name, query_value, results, ExperimentConfig};
+
+/// Goal of conversions sent without `goal` parameter
+const DEFAULT_GOAL: &str = "conversion";
+
+/// `POST /experiments/:id/conversions?goal=<goal>`, counts a conversion of the variant the
+/// visitor is assigned to. Every visitor is counted once per goal, conversions of visitors
+/// without assignment are ignored.
+pub fn handle_conversion(req: Request, params: Params) -> Result<Response> {
+    let config = ExperimentConfig::load(DEFAULT_EXPERIMENTS)?;
+    let Some(experiment) = params.get("id").and_then(|id| config.experiment(id)) else {
+        return Ok(Response::new(404, ()));
+    };
+    let Some(goal) = goal(&req) else {
+        return Ok(Response::new(400, "invalid goal"));
+    };
+
+    let signer = CookieSigner::new(&variables::get("cookie_secret")?)?;
+    let variant = cookie_value(&req, &cookie_name(&experiment.id))
+        .and_then(|cookie| signer.verify(&experiment.id, cookie))
+        .and_then(|name| experiment.variants.iter().find(|v| v.name == name));
+    let Some(variant) = variant else {
+        return Ok(Response::new(204, ()));
+    };
+
+    // the conversion cookie holds the variant the visitor converted with
+    let converted_cookie = converted_cookie_name(&experiment.id, &goal);
+    if cookie_value(&req, &converted_cookie) == Some(variant.name.as_str()) {
+        return Ok(Response::new(204, ()));
+    }
+
+    let store = Store::open_default()?;
+    results::record_conversion(&store, experiment, &goal, &variant.name)?;
+    let max_age = variables::get("cookie_max_age")?
+        .parse::<u64>()
+        .context("invalid cookie_max_age variable")?;
+    Ok(ResponseBuilder::new(204)
+        .header(
+            "set-cookie",
+            set_cookie(&converted_cookie, &variant.name, max_age),
+        )
+        .build())
+}
+
+/// `GET /experiments/:id/results?goal=<goal>`, the exposures and conversions of every
+/// variant, compared to the control variant
+pub fn handle_results(req: Request, params: Params) -> Result<Response> {
+    if let Err(response) = results::authorize(&req) {
+        return Ok(response);
+    }
+    let config = ExperimentConfig::load(DEFAULT_EXPERIMENTS)?;
+    let Some(experiment) = params.get("id").and_then(|id| config.experiment(id)) else {
+        return Ok(Response::new(404, ()));
+    };
+    let Some(goal) = goal(&req) else {
+        return Ok(Response::new(400, "invalid goal"));
+    };
+
+    let results = results::load(&Store::open_default()?, experiment, &goal)?;
+    Ok(ResponseBuilder::new(200)
+        .header("content-type", "application/json")
+        .header("cache-control", "no-store")
+        .body(serde_json::to_vec(&results)?)
+        .build())
+}
+
+/// The goal of `req`, `None` if it is invalid
+fn goal(req: &Request) -> Option<String> {
+    let goal = query_value(req, "goal").unwrap_or_else(|| String::from(DEFAULT_GOAL));
+    is_valid_name(&goal).then_some(goal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spin_sdk::http::Method;
+
+    #[test]
+    fn test_goal() {
+        let goal_of = |uri| goal(&Request::new(Method::Post, uri));
+        assert_eq!(
+            goal_of("/experiments/by-cookie/conversions").as_deref(),
+            Some("conversion")
+        );
+        assert_eq!(
+            goal_of("/experiments/by-cookie/conversions?goal=sign-up").as_deref(),
+            Some("sign-up")
+        );
+        assert_eq!(
+            goal_of("/experiments/by-cookie/conversions?goal=a%2Fb"),
+            None
+        );
+        assert_eq!(goal_of("/experiments/by-cookie/conversions?goal="), None);
+    }
+}
@@ -1,20 +1,52 @@
 use anyhow::Result;
 use spin_sdk::http::{
-    send, IntoResponse, Method, Params, Request, RequestBuilder, Response, ResponseBuilder, Router,
+    IntoResponse, Params, Request, Response, ResponseBuilder, ResponseOutparam, Router,
 };
 use spin_sdk::http_component;
 use spin_sdk::key_value::Store;
 
-const ORIGIN_A: &str = "origin-a";
-const ORIGIN_B: &str = "origin-b";
+use experiment_config::proxy::{Forward, ForwardedResponse};
+use experiment_config::{
+    cookie_value, random_bucket, results, Assignment, Decision, Experiment, ExperimentConfig,
+    Variant,
+};
+
+/// Experiments used if the `experiments` variable is empty
+const DEFAULT_EXPERIMENTS: &str = include_str!("../experiments.json");
+/// Cookie identifying a visitor across requests
+const VISITOR_COOKIE: &str = "fwf-ab-visitor";
+const VISITOR_COOKIE_MAX_AGE: u64 = 365 * 24 * 3600;
+/// Goal the results are reported for. The router doesn't track conversions, so the results
+/// only hold the exposures of every variant.
+const RESULTS_GOAL: &str = "conversion";
 
 #[http_component]
-fn handle_ab_testing(req: Request) -> anyhow::Result<impl IntoResponse> {
+async fn handle_ab_testing(req: Request, response_out: ResponseOutparam) {
+    let response = match req.path() {
+        path if path == "/" || path == "/index.html" || path.starts_with("/experiments/") => {
+            app_router().handle(req).into()
+        }
+        // the router keeps a single value per header, the responses of experiments may set
+        // several cookies
+        _ => match route_by_kv(req).await {
+            Ok(response) => response,
+            Err(e) => {
+                println!("routing request failed: {:#}", e);
+                Response::new(500, e.to_string()).into()
+            }
+        },
+    };
+    if let Err(e) = response.write(response_out).await {
+        println!("sending response failed: {:#}", e);
+    }
+}
+
+fn app_router() -> Router {
     let mut router = Router::default();
     router.get("/", redirect_to_index);
     router.get("/index.html", route_index);
-    router.get_async("/by-kv", route_by_kv);
-    Ok(router.handle(req))
+    router.get("/experiments/:id/results", handle_results);
+    router
 }
 
 fn redirect_to_index(_req: Request, _: Params) -> Result<impl IntoResponse> {
@@ -36,7 +68,7 @@ fn route_index(_req: Request, _: Params) -> Result<impl IntoResponse> {
 <body>
     <h1>A/B Testing Sample</h1>
     <ul>
-        <li><a href="/by-kv">Route Requests by a value in Key Value Store</a> - For every request hitting this route, a value in Key Value Store will be incremented by 1. Even and odd counts will be routed differently</li>
+        <li><a href="/by-key-value.html">Route Requests by a value in Key Value Store</a> - Every visitor is assigned to a variant by their visitor id, and the number of requests served by each variant is counted in Key Value Store</li>
     </ul>
 </body>
 
@@ -48,26 +80,187 @@ fn route_index(_req: Request, _: Params) -> Result<impl IntoResponse> {
         .build())
 }
 
-async fn route_by_kv(_req: Request, _: Params) -> Result<impl IntoResponse> {
-    const ORIGIN_REQUEST_PATH: &str = "by-key-value.html";
-    let store = Store::open_default()?;
-    let mut count = match store.get("counter")? {
-        Some(v) => u32::from_le_bytes(v.try_into().unwrap()),
-        None => 0,
+/// `GET /experiments/:id/results`, the exposures of every variant
+fn handle_results(req: Request, params: Params) -> Result<Response> {
+    if let Err(response) = results::authorize(&req) {
+        return Ok(response);
+    }
+    let config = ExperimentConfig::load(DEFAULT_EXPERIMENTS)?;
+    let Some(experiment) = params.get("id").and_then(|id| config.experiment(id)) else {
+        return Ok(Response::new(404, ()));
+    };
+
+    let results = results::load(&Store::open_default()?, experiment, RESULTS_GOAL)?;
+    Ok(ResponseBuilder::new(200)
+        .header("content-type", "application/json")
+        .header("cache-control", "no-store")
+        .body(serde_json::to_vec(&results)?)
+        .build())
+}
+
+async fn route_by_kv(req: Request) -> Result<ForwardedResponse> {
+    let config = ExperimentConfig::load(DEFAULT_EXPERIMENTS)?;
+    let Some(experiment) = config.find(req.path()) else {
+        return Ok(Response::new(404, ()).into());
     };
 
-    count += 1;
-    store.set("counter", count.to_le_bytes().as_slice())?;
+    let mut new_visitor_id = None;
+    let variant = match experiment.decide(&req) {
+        Decision::Excluded(variant) | Decision::Forced(variant) => variant,
+        Decision::Assign => {
+            // visitors without a visitor cookie are new, they get one and are counted once
+            if visitor_cookie(&req).is_none() {
+                new_visitor_id = Some(generate_visitor_id()?);
+            }
+            let visitor_id = visitor_id(&req, experiment)
+                .or(new_visitor_id.as_deref())
+                .unwrap_or_default();
+            // the same visitor always gets the same variant, no matter how many requests
+            // are served concurrently
+            let variant = experiment.pick(experiment.hash_bucket(visitor_id));
+            if new_visitor_id.is_some() {
+                record_exposure(experiment, variant);
+            }
+            variant
+        }
+    };
 
-    let mut origin_route = build_request_url(ORIGIN_A, ORIGIN_REQUEST_PATH);
-    if count % 2 == 0 {
-        origin_route = build_request_url(ORIGIN_B, ORIGIN_REQUEST_PATH);
+    let cookies = new_visitor_id
+        .map(|visitor_id| {
+            format!(
+                "{}={};Path=/;SameSite=Lax;HttpOnly;Max-Age={}",
+                VISITOR_COOKIE, visitor_id, VISITOR_COOKIE_MAX_AGE
+            )
+        })
+        .into_iter()
+        .collect();
+    Forward {
+        experiment,
+        variant,
+        cookies,
+        // the variant depends on the visitor cookie
+        vary: vec!["Cookie"],
     }
-    let origin_req = RequestBuilder::new(Method::Get, origin_route).build();
-    let response: Response = send(origin_req).await?;
-    Ok(response)
+    .send(&req)
+    .await
+}
+
+/// The id of the visitor of `req`, taken from the header of a hash assignment or the visitor
+/// cookie
+fn visitor_id<'a>(req: &'a Request, experiment: &Experiment) -> Option<&'a str> {
+    let from_header = match &experiment.assignment {
+        Assignment::Hash { header } => req
+            .header(header)
+            .and_then(|value| value.as_str())
+            .filter(|value| !value.trim().is_empty()),
+        Assignment::Random => None,
+    };
+    from_header.or_else(|| visitor_cookie(req))
 }
 
-fn build_request_url(origin: &str, path: &str) -> String {
-    format!("/{}/{}", origin, path)
+/// The visitor id in the visitor cookie of `req`
+fn visitor_cookie(req: &Request) -> Option<&str> {
+    cookie_value(req, VISITOR_COOKIE).filter(|id| is_visitor_id(id))
+}
+
+/// 128 random bits, hex encoded
+fn generate_visitor_id() -> Result<String> {
+    Ok(format!(
+        "{:016x}{:016x}",
+        random_bucket()?,
+        random_bucket()?
+    ))
+}
+
+fn is_visitor_id(id: &str) -> bool {
+    id.len() == 32 && id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Count that a new visitor was assigned to `variant`. Failures are logged, the request is
+/// served anyway.
+fn record_exposure(experiment: &Experiment, variant: &Variant) {
+    let recorded = Store::open_default()
+        .map_err(anyhow::Error::from)
+        .and_then(|store| results::record_exposure(&store, experiment, &variant.name));
+    if let Err(e) = recorded {
+        println!("failed to record exposure of {}: {:#}", experiment.id, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spin_sdk::http::Method;
+
+    fn experiment(config: &str) -> Experiment {
+        ExperimentConfig::parse(config)
+            .unwrap()
+            .experiments
+            .remove(0)
+    }
+
+    fn request(headers: &[(&str, &str)]) -> Request {
+        let mut req = Request::new(Method::Get, "/by-key-value.html");
+        for (name, value) in headers {
+            req.set_header(*name, *value);
+        }
+        req
+    }
+
+    #[test]
+    fn test_visitor_id() {
+        let experiment = experiment(DEFAULT_EXPERIMENTS);
+        let id = generate_visitor_id().unwrap();
+        assert!(is_visitor_id(&id));
+        assert_ne!(generate_visitor_id().unwrap(), id);
+
+        let cookie = format!("theme=dark; {}={}", VISITOR_COOKIE, id);
+        assert_eq!(
+            visitor_id(&request(&[("cookie", &cookie)]), &experiment),
+            Some(id.as_str())
+        );
+        let forged = format!("{}=../../counter", VISITOR_COOKIE);
+        assert_eq!(
+            visitor_id(&request(&[("cookie", &forged)]), &experiment),
+            None
+        );
+        assert_eq!(visitor_id(&request(&[]), &experiment), None);
+    }
+
+    #[test]
+    fn test_bots_are_not_counted() {
+        let experiment = experiment(DEFAULT_EXPERIMENTS);
+        let googlebot = request(&[(
+            "user-agent",
+            "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+        )]);
+        assert!(matches!(
+            experiment.decide(&googlebot),
+            Decision::Excluded(_)
+        ));
+        assert!(matches!(experiment.decide(&request(&[])), Decision::Assign));
+    }
+
+    #[test]
+    fn test_hash_assignment_prefers_header() {
+        let experiment = experiment(
+            r#"{"experiments": [{
+                "id": "by-kv",
+                "paths": ["/by-key-value.html"],
+                "variants": [{ "name": "a", "origin": "origin-a", "weight": 1 }],
+                "assignment": { "type": "hash", "header": "x-visitor-id" }
+            }]}"#,
+        );
+        let cookie = format!("{}={}", VISITOR_COOKIE, "0".repeat(32));
+        let req = request(&[("x-visitor-id", "user-42"), ("cookie", &cookie)]);
+        assert_eq!(visitor_id(&req, &experiment), Some("user-42"));
+
+        let req = request(&[("cookie", &cookie)]);
+        assert_eq!(visitor_id(&req, &experiment), Some("0".repeat(32).as_str()));
+
+        // visitors identified by the header alone are new until they have a visitor cookie
+        let req = request(&[("x-visitor-id", "user-42")]);
+        assert_eq!(visitor_id(&req, &experiment), Some("user-42"));
+        assert_eq!(visitor_cookie(&req), None);
+    }
 }
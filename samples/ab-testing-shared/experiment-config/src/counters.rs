@@ -0,0 +1,83 @@
+//! Counters of experiment events in the key-value store, e.g. how often each variant was
+//! served. The key-value store has no atomic increment, so concurrent increments of the same
+//! key can overwrite each other. Each counter is therefore spread across shards, and every
+//! increment picks one at random: concurrent requests rarely collide, and a collision loses
+//! a single count instead of corrupting anything.
+
+use anyhow::Result;
+use spin_sdk::key_value::Store;
+
+use crate::random_bucket;
+
+/// Number of keys a counter is spread across
+pub const SHARDS: u64 = 16;
+
+pub struct Counter<'a> {
+    store: &'a Store,
+    key: String,
+}
+
+impl<'a> Counter<'a> {
+    /// The counter of `event` (e.g. `exposures`) of `variant` of `experiment`
+    pub fn new(store: &'a Store, experiment: &str, event: &str, variant: &str) -> Self {
+        Counter {
+            store,
+            key: format!("experiments/{}/{}/{}", experiment, event, variant),
+        }
+    }
+
+    pub fn increment(&self) -> Result<()> {
+        let key = self.shard_key(random_bucket()? % SHARDS);
+        let count = match self.store.get(&key)? {
+            Some(value) => decode(&value).unwrap_or_else(|| {
+                println!("resetting corrupted counter {}", key);
+                0
+            }),
+            None => 0,
+        };
+        self.store
+            .set(&key, &count.saturating_add(1).to_le_bytes())?;
+        Ok(())
+    }
+
+    /// The sum of all shards. Corrupted shards are skipped.
+    pub fn total(&self) -> Result<u64> {
+        let mut total = 0u64;
+        for shard in 0..SHARDS {
+            let key = self.shard_key(shard);
+            if let Some(value) = self.store.get(&key)? {
+                match decode(&value) {
+                    Some(count) => total = total.saturating_add(count),
+                    None => println!("skipping corrupted counter {}", key),
+                }
+            }
+        }
+        Ok(total)
+    }
+
+    fn shard_key(&self, shard: u64) -> String {
+        format!("{}/{}", self.key, shard)
+    }
+}
+
+/// A count stored as little endian `u64`, or as `u32` by earlier versions
+fn decode(value: &[u8]) -> Option<u64> {
+    match value.len() {
+        8 => Some(u64::from_le_bytes(value.try_into().ok()?)),
+        4 => Some(u64::from(u32::from_le_bytes(value.try_into().ok()?))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode(&42u64.to_le_bytes()), Some(42));
+        assert_eq!(decode(&7u32.to_le_bytes()), Some(7));
+        assert_eq!(decode(b""), None);
+        assert_eq!(decode(b"not a number"), None);
+    }
+}
@@ -0,0 +1,516 @@
+//! Experiments shared by the A/B testing traffic routers. An experiment applies to the
+//! requests of some paths, splits its visitors across variants served by different origins,
+//! and may be limited to some visitors by targeting conditions.
+//!
+//! The experiments are configured with the `experiments` variable:
+//!
+//! ```jsonc
+//! {
+//!   // header holding the country code of the client, required by country conditions
+//!   "countryHeader": "x-client-country",
+//!   "experiments": [
+//!     {
+//!       "id": "checkout",
+//!       // exact paths, or all paths below a prefix with "/..."
+//!       "paths": ["/checkout/..."],
+//!       "variants": [
+//!         { "name": "a", "origin": "origin-a", "weight": 90 },
+//!         // visitors matching `when` always get this variant
+//!         { "name": "b", "origin": "origin-b", "weight": 10, "when": [] }
+//!       ],
+//!       // visitors not matching all conditions get the control variant
+//!       "targeting": [{ "type": "country", "value": { "oneOf": ["DE", "AT"] } }],
+//!       // defaults to the first variant
+//!       "control": "a",
+//!       // or { "type": "hash", "header": "x-visitor-id" }
+//!       "assignment": { "type": "random" }
+//!     }
+//!   ]
+//! }
+//! ```
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use spin_sdk::http::Request;
+use spin_sdk::variables;
+
+pub use targeting::{cookie_value, query_value, Condition, Target, ValueMatch};
+
+pub mod counters;
+pub mod proxy;
+pub mod results;
+mod targeting;
+pub mod user_agent;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ExperimentConfig {
+    pub country_header: Option<String>,
+    pub experiments: Vec<Experiment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Experiment {
+    /// Used in cookies and keys, so assignments of other experiments are not reused
+    pub id: String,
+    pub paths: Vec<String>,
+    pub variants: Vec<Variant>,
+    #[serde(default)]
+    pub targeting: Vec<Condition>,
+    control: Option<String>,
+    #[serde(default)]
+    pub assignment: Assignment,
+    #[serde(skip)]
+    country_header: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Variant {
+    pub name: String,
+    /// Route prefix of an origin of this app, e.g. `origin-a`, or the URL of an origin
+    pub origin: String,
+    /// Share of the assigned visitors, relative to the other weights
+    pub weight: u32,
+    /// Visitors matching all conditions get this variant without being assigned
+    pub when: Option<Vec<Condition>>,
+}
+
+/// How visitors are assigned to a variant
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase",
+    deny_unknown_fields
+)]
+pub enum Assignment {
+    /// A random variant
+    #[default]
+    Random,
+    /// A variant derived from the value of a header identifying the visitor, so the same
+    /// visitor always gets the same variant. Visitors without the header are assigned randomly.
+    Hash { header: String },
+}
+
+/// The variant of a request, before any assignment
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decision<'a> {
+    /// The visitor doesn't take part in the experiment and gets the control variant
+    Excluded(&'a Variant),
+    /// The visitor matches the `when` conditions of the variant
+    Forced(&'a Variant),
+    /// The visitor is to be assigned to one of the variants with weight
+    Assign,
+}
+
+impl ExperimentConfig {
+    /// Load the `experiments` variable, using `default` if it is empty
+    pub fn load(default: &str) -> Result<ExperimentConfig> {
+        let config = variables::get("experiments").unwrap_or_default();
+        let config = match config.trim() {
+            "" => default,
+            config => config,
+        };
+        ExperimentConfig::parse(config).context("invalid experiments variable")
+    }
+
+    pub fn parse(config: &str) -> Result<ExperimentConfig> {
+        let mut config: ExperimentConfig = serde_json::from_str(config)?;
+        let has_country_header = config
+            .country_header
+            .as_deref()
+            .is_some_and(|header| !header.trim().is_empty());
+
+        for (index, experiment) in config.experiments.iter().enumerate() {
+            experiment
+                .validate(has_country_header)
+                .with_context(|| format!("invalid experiment '{}'", experiment.id))?;
+            if config.experiments[..index]
+                .iter()
+                .any(|other| other.id == experiment.id)
+            {
+                bail!("experiment '{}' is listed twice", experiment.id);
+            }
+        }
+        for experiment in config.experiments.iter_mut() {
+            experiment.country_header = config.country_header.clone();
+        }
+        Ok(config)
+    }
+
+    /// The experiment with `id`
+    pub fn experiment(&self, id: &str) -> Option<&Experiment> {
+        self.experiments
+            .iter()
+            .find(|experiment| experiment.id == id)
+    }
+
+    /// The first experiment applying to `path`
+    pub fn find(&self, path: &str) -> Option<&Experiment> {
+        self.experiments
+            .iter()
+            .find(|experiment| experiment.paths.iter().any(|p| matches_path(p, path)))
+    }
+}
+
+impl Experiment {
+    fn validate(&self, has_country_header: bool) -> Result<()> {
+        if !is_valid_name(&self.id) {
+            bail!("invalid id");
+        }
+        if self.paths.is_empty() {
+            bail!("no paths");
+        }
+        if let Some(path) = self.paths.iter().find(|path| !path.starts_with('/')) {
+            bail!("path '{}' doesn't start with /", path);
+        }
+
+        for (index, variant) in self.variants.iter().enumerate() {
+            if !is_valid_name(&variant.name) {
+                bail!("invalid variant name '{}'", variant.name);
+            }
+            if self.variants[..index]
+                .iter()
+                .any(|v| v.name == variant.name)
+            {
+                bail!("variant '{}' is listed twice", variant.name);
+            }
+            if variant.origin.trim().is_empty() {
+                bail!("variant '{}' has no origin", variant.name);
+            }
+            variant
+                .when
+                .iter()
+                .flatten()
+                .try_for_each(|c| c.validate(has_country_header))?;
+        }
+        if self.total_weight() == 0 {
+            bail!("at least one variant must have a weight greater than zero");
+        }
+        if let Some(control) = self.control.as_deref() {
+            if self.variants.iter().all(|v| v.name != control) {
+                bail!("unknown control variant '{}'", control);
+            }
+        }
+        if let Assignment::Hash { header } = &self.assignment {
+            if header.trim().is_empty() {
+                bail!("hash assignment requires a header");
+            }
+        }
+        self.targeting
+            .iter()
+            .try_for_each(|c| c.validate(has_country_header))
+    }
+
+    /// The variant served to visitors not taking part in the experiment
+    pub fn control(&self) -> &Variant {
+        self.control
+            .as_deref()
+            .and_then(|control| self.variants.iter().find(|v| v.name == control))
+            .unwrap_or(&self.variants[0])
+    }
+
+    /// The variant named `name`, if visitors can still be assigned to it
+    pub fn variant(&self, name: &str) -> Option<&Variant> {
+        self.variants
+            .iter()
+            .find(|variant| variant.name == name && variant.weight > 0)
+    }
+
+    /// Whether `req` takes part in the experiment, and with which variant if the conditions
+    /// decide it
+    pub fn decide(&self, req: &Request) -> Decision<'_> {
+        let target = Target {
+            req,
+            country_header: self.country_header.as_deref(),
+        };
+        if !self.targeting.iter().all(|c| c.matches(&target)) {
+            return Decision::Excluded(self.control());
+        }
+        let forced = self.variants.iter().find(|variant| {
+            variant
+                .when
+                .as_ref()
+                .is_some_and(|when| when.iter().all(|c| c.matches(&target)))
+        });
+        match forced {
+            Some(variant) => Decision::Forced(variant),
+            None => Decision::Assign,
+        }
+    }
+
+    /// Names of the request headers deciding the variant, e.g. for the `Vary` header
+    pub fn request_headers(&self) -> Vec<String> {
+        let mut headers = Vec::new();
+        let conditions = self
+            .targeting
+            .iter()
+            .chain(self.variants.iter().flat_map(|v| v.when.iter().flatten()));
+        for condition in conditions {
+            condition.headers(self.country_header.as_deref(), &mut headers);
+        }
+        if let Assignment::Hash { header } = &self.assignment {
+            if !headers.iter().any(|h| h.eq_ignore_ascii_case(header)) {
+                headers.push(header.clone());
+            }
+        }
+        headers
+    }
+
+    /// The variant of the `bucket`, any number spread evenly over the range of `u64`
+    pub fn pick(&self, bucket: u64) -> &Variant {
+        let mut point = bucket % self.total_weight();
+        for variant in &self.variants {
+            let weight = u64::from(variant.weight);
+            if point < weight {
+                return variant;
+            }
+            point -= weight;
+        }
+        unreachable!("the weights add up to the total")
+    }
+
+    fn total_weight(&self) -> u64 {
+        self.variants.iter().map(|v| u64::from(v.weight)).sum()
+    }
+
+    /// The bucket of the visitor of `req`, according to the assignment of the experiment
+    pub fn bucket(&self, req: &Request) -> Result<u64> {
+        let visitor_id = match &self.assignment {
+            Assignment::Random => None,
+            Assignment::Hash { header } => req
+                .header(header)
+                .and_then(|value| value.as_str())
+                .filter(|value| !value.trim().is_empty()),
+        };
+        match visitor_id {
+            Some(visitor_id) => Ok(self.hash_bucket(visitor_id)),
+            None => random_bucket(),
+        }
+    }
+
+    /// The bucket of the visitor identified by `visitor_id`, the same for every request
+    pub fn hash_bucket(&self, visitor_id: &str) -> u64 {
+        let digest = Sha256::new()
+            .chain_update(self.id.as_bytes())
+            .chain_update(b":")
+            .chain_update(visitor_id.as_bytes())
+            .finalize();
+        u64::from_be_bytes(digest[..8].try_into().expect("digest has 32 bytes"))
+    }
+}
+
+impl Variant {
+    /// URL of `path` at the origin of the variant
+    pub fn origin_url(&self, path: &str) -> String {
+        let origin = self.origin.trim_end_matches('/');
+        let path = path.trim_start_matches('/');
+        if origin.contains("://") {
+            format!("{}/{}", origin, path)
+        } else {
+            format!("/{}/{}", origin.trim_start_matches('/'), path)
+        }
+    }
+}
+
+/// A random bucket
+pub fn random_bucket() -> Result<u64> {
+    let mut bytes = [0u8; 8];
+    getrandom::getrandom(&mut bytes).map_err(|_| anyhow!("failed to generate random bucket"))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+/// Whether `path` matches `pattern`, an exact path or a prefix followed by `/...`
+pub fn matches_path(pattern: &str, path: &str) -> bool {
+    match pattern.strip_suffix("/...") {
+        Some(prefix) => {
+            path == prefix
+                || path
+                    .strip_prefix(prefix)
+                    .is_some_and(|rest| rest.starts_with('/'))
+        }
+        None => pattern == path,
+    }
+}
+
+/// Names end up in cookies, keys and origin paths
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spin_sdk::http::Method;
+
+    const CONFIG: &str = r#"{
+        "countryHeader": "x-country",
+        "experiments": [
+            {
+                "id": "checkout",
+                "paths": ["/checkout/...", "/cart"],
+                "variants": [
+                    { "name": "a", "origin": "origin-a", "weight": 1 },
+                    { "name": "b", "origin": "origin-b", "weight": 0 },
+                    { "name": "c", "origin": "https://c.example.com/", "weight": 3,
+                      "when": [{ "type": "cookie", "name": "beta" }] }
+                ],
+                "targeting": [{ "type": "country", "value": { "oneOf": ["DE", "AT"] } }],
+                "control": "b",
+                "assignment": { "type": "hash", "header": "x-visitor-id" }
+            },
+            {
+                "id": "everything",
+                "paths": ["/..."],
+                "variants": [{ "name": "a", "origin": "origin-a", "weight": 1 }]
+            }
+        ]
+    }"#;
+
+    fn request(headers: &[(&str, &str)]) -> Request {
+        let mut req = Request::new(Method::Get, "/checkout");
+        for (name, value) in headers {
+            req.set_header(*name, *value);
+        }
+        req
+    }
+
+    #[test]
+    fn test_find_experiment_by_path() {
+        let config = ExperimentConfig::parse(CONFIG).unwrap();
+        let id = |path| config.find(path).map(|e| e.id.as_str());
+
+        assert_eq!(id("/checkout"), Some("checkout"));
+        assert_eq!(id("/checkout/pay"), Some("checkout"));
+        assert_eq!(id("/cart"), Some("checkout"));
+        assert_eq!(id("/checkouts"), Some("everything"));
+        assert_eq!(id("/cart/items"), Some("everything"));
+        assert_eq!(id("/"), Some("everything"));
+    }
+
+    #[test]
+    fn test_decide() {
+        let config = ExperimentConfig::parse(CONFIG).unwrap();
+        let experiment = config.find("/checkout").unwrap();
+        let name = |decision: Decision| match decision {
+            Decision::Excluded(v) => format!("excluded {}", v.name),
+            Decision::Forced(v) => format!("forced {}", v.name),
+            Decision::Assign => String::from("assign"),
+        };
+
+        assert_eq!(name(experiment.decide(&request(&[]))), "excluded b");
+        assert_eq!(
+            name(experiment.decide(&request(&[("x-country", "FR")]))),
+            "excluded b"
+        );
+        assert_eq!(
+            name(experiment.decide(&request(&[("x-country", "DE")]))),
+            "assign"
+        );
+        assert_eq!(
+            name(experiment.decide(&request(&[("x-country", "at"), ("cookie", "beta=1")]))),
+            "forced c"
+        );
+    }
+
+    #[test]
+    fn test_pick_follows_weights() {
+        let config = ExperimentConfig::parse(CONFIG).unwrap();
+        let experiment = config.find("/checkout").unwrap();
+        let picks: Vec<&str> = (0..8)
+            .map(|bucket| experiment.pick(bucket).name.as_str())
+            .collect();
+
+        assert_eq!(picks, vec!["a", "c", "c", "c", "a", "c", "c", "c"]);
+        assert_eq!(experiment.pick(u64::MAX).name, "c");
+        assert!(experiment.variant("a").is_some());
+        assert!(experiment.variant("b").is_none());
+        assert!(experiment.variant("d").is_none());
+    }
+
+    #[test]
+    fn test_hash_assignment_is_stable() {
+        let config = ExperimentConfig::parse(CONFIG).unwrap();
+        let experiment = config.find("/checkout").unwrap();
+        let req = request(&[("x-visitor-id", "visitor")]);
+
+        assert_eq!(
+            experiment.bucket(&req).unwrap(),
+            experiment.hash_bucket("visitor")
+        );
+        assert_ne!(
+            experiment.hash_bucket("visitor"),
+            experiment.hash_bucket("other")
+        );
+        let other = config.find("/other").unwrap();
+        assert_ne!(
+            experiment.hash_bucket("visitor"),
+            other.hash_bucket("visitor")
+        );
+    }
+
+    #[test]
+    fn test_origin_url() {
+        let config = ExperimentConfig::parse(CONFIG).unwrap();
+        let variants = &config.find("/checkout").unwrap().variants;
+
+        assert_eq!(
+            variants[0].origin_url("by-cookie.html"),
+            "/origin-a/by-cookie.html"
+        );
+        assert_eq!(
+            variants[2].origin_url("/by-cookie.html"),
+            "https://c.example.com/by-cookie.html"
+        );
+    }
+
+    #[test]
+    fn test_invalid_configs_are_rejected() {
+        let experiment = |fields: &str| {
+            format!(
+                r#"{{"experiments": [{{"id": "x", "paths": ["/x"], {}}}]}}"#,
+                fields
+            )
+        };
+        let variants = r#""variants": [{"name": "a", "origin": "origin-a", "weight": 1}]"#;
+        assert!(ExperimentConfig::parse(&experiment(variants)).is_ok());
+
+        let invalid = [
+            experiment(r#""variants": []"#),
+            experiment(r#""variants": [{"name": "a", "origin": "origin-a", "weight": 0}]"#),
+            experiment(r#""variants": [{"name": "a.b", "origin": "origin-a", "weight": 1}]"#),
+            experiment(r#""variants": [{"name": "a", "origin": " ", "weight": 1}]"#),
+            experiment(&format!(r#"{}, "control": "b""#, variants)),
+            experiment(&format!(
+                r#"{}, "assignment": {{"type": "hash", "header": ""}}"#,
+                variants
+            )),
+            experiment(&format!(
+                r#"{}, "targeting": [{{"type": "country", "value": {{"equals": "DE"}}}}]"#,
+                variants
+            )),
+            experiment(&format!(r#"{}, "weights": [1]"#, variants)),
+            format!(
+                r#"{{"experiments": [{{"id": "x", "paths": [], {}}}]}}"#,
+                variants
+            ),
+            format!(
+                r#"{{"experiments": [{{"id": "x", "paths": ["x"], {}}}]}}"#,
+                variants
+            ),
+            format!(
+                r#"{{"experiments": [{{"id": "x", "paths": ["/x"], {0}}}, {{"id": "x", "paths": ["/y"], {0}}}]}}"#,
+                variants
+            ),
+        ];
+        for config in invalid {
+            assert!(ExperimentConfig::parse(&config).is_err(), "{}", config);
+        }
+    }
+}
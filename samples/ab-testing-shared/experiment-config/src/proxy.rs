@@ -0,0 +1,302 @@
+//! Forwarding requests to the origin of the variant they were routed to.
+
+use anyhow::{anyhow, Result};
+use spin_sdk::http::{
+    send, Headers, IncomingResponse, OutgoingResponse, Request, RequestBuilder, Response,
+    ResponseOutparam,
+};
+
+use crate::{Experiment, Variant};
+
+/// Headers that only apply to a single connection and must not be forwarded
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "host",
+    "content-length",
+];
+/// Tells the origin and the client which experiment served the request
+const EXPERIMENT_HEADER: &str = "x-ab-experiment";
+/// Tells the origin and the client which variant served the request
+const VARIANT_HEADER: &str = "x-ab-variant";
+
+/// A response whose headers may repeat. `Response` holds a single value per header, which
+/// can't carry the `Set-Cookie` headers of both the origin and the router.
+#[derive(Debug)]
+pub struct ForwardedResponse {
+    pub status: u16,
+    /// Names and values in order, a name may occur several times
+    pub headers: Vec<(String, Vec<u8>)>,
+    pub body: Vec<u8>,
+}
+
+impl ForwardedResponse {
+    /// The values of the header `name`
+    pub fn header<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.headers
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .filter_map(|(_, value)| std::str::from_utf8(value).ok())
+    }
+
+    /// Send the response to the client
+    pub async fn write(self, response_out: ResponseOutparam) -> Result<()> {
+        let headers = Headers::from_list(&self.headers)
+            .map_err(|e| anyhow!("invalid response headers: {:?}", e))?;
+        let response = OutgoingResponse::new(headers);
+        response
+            .set_status_code(self.status)
+            .map_err(|()| anyhow!("invalid status code {}", self.status))?;
+        response_out
+            .set_with_body(response, self.body)
+            .await
+            .map_err(|e| anyhow!("error sending the response body: {:?}", e))
+    }
+}
+
+impl From<Response> for ForwardedResponse {
+    fn from(response: Response) -> Self {
+        let headers = response
+            .headers()
+            .map(|(name, value)| (String::from(name), value.as_bytes().to_vec()))
+            .collect();
+        ForwardedResponse {
+            status: *response.status(),
+            headers,
+            body: response.into_body(),
+        }
+    }
+}
+
+/// The response of `variant` to `req`
+pub struct Forward<'a> {
+    pub experiment: &'a Experiment,
+    pub variant: &'a Variant,
+    /// `Set-Cookie` values to add to the response
+    pub cookies: Vec<String>,
+    /// Request headers the variant depends on besides the ones of the experiment, e.g. the
+    /// header of an assignment cookie
+    pub vary: Vec<&'static str>,
+}
+
+impl Forward<'_> {
+    /// Send `req` to the origin of the variant, and pass its response through
+    pub async fn send(self, req: &Request) -> Result<ForwardedResponse> {
+        let upstream = self.upstream_request(req);
+        let response = match send::<_, IncomingResponse>(upstream).await {
+            Ok(response) => response,
+            Err(e) => {
+                println!(
+                    "forwarding request to {} failed: {}",
+                    self.variant.origin, e
+                );
+                return Ok(Response::new(502, ()).into());
+            }
+        };
+        let status = response.status();
+        let headers = response.headers().entries();
+        match response.into_body().await {
+            Ok(body) => Ok(self.response(ForwardedResponse {
+                status,
+                headers,
+                body,
+            })),
+            Err(e) => {
+                println!(
+                    "reading the response of {} failed: {:?}",
+                    self.variant.origin, e
+                );
+                Ok(Response::new(502, ()).into())
+            }
+        }
+    }
+
+    /// The request to send to the origin for `req`, with method, path, query, headers and
+    /// body of `req`
+    pub fn upstream_request(&self, req: &Request) -> Request {
+        let mut url = self.variant.origin_url(req.path());
+        if !req.query().is_empty() {
+            url.push('?');
+            url.push_str(req.query());
+        }
+
+        let mut builder = RequestBuilder::new(req.method().clone(), url);
+        for (name, value) in req.headers() {
+            if forwards_header(name) {
+                builder.header(name, String::from_utf8_lossy(value.as_bytes()).into_owned());
+            }
+        }
+        if let Some(host) = req.header("host").and_then(|value| value.as_str()) {
+            builder.header("x-forwarded-host", host);
+        }
+        builder.header(EXPERIMENT_HEADER, self.experiment.id.as_str());
+        builder.header(VARIANT_HEADER, self.variant.name.as_str());
+        builder.body(req.body().to_vec());
+        builder.build()
+    }
+
+    /// The response to send to the client for the `origin` response, with its status,
+    /// headers and body. The cookies are added to the ones set by the origin.
+    pub fn response(&self, origin: ForwardedResponse) -> ForwardedResponse {
+        let mut vary = self.vary_headers();
+        let mut headers = Vec::new();
+        for (name, value) in origin.headers {
+            if name.eq_ignore_ascii_case("vary") {
+                // merged with the headers the variant depends on
+                let value = String::from_utf8_lossy(&value);
+                for header in value.split(',').map(str::trim) {
+                    if !vary.iter().any(|v| v.eq_ignore_ascii_case(header)) {
+                        vary.push(String::from(header));
+                    }
+                }
+            } else if forwards_header(&name) {
+                headers.push((name, value));
+            }
+        }
+        let mut add = |name: &str, value: &str| {
+            headers.push((String::from(name), value.as_bytes().to_vec()));
+        };
+        if !vary.is_empty() {
+            add("vary", &vary.join(", "));
+        }
+        add(EXPERIMENT_HEADER, &self.experiment.id);
+        add(VARIANT_HEADER, &self.variant.name);
+        for cookie in &self.cookies {
+            add("set-cookie", cookie);
+        }
+        ForwardedResponse {
+            status: origin.status,
+            headers,
+            body: origin.body,
+        }
+    }
+
+    fn vary_headers(&self) -> Vec<String> {
+        let mut vary: Vec<String> = Vec::new();
+        let headers = self
+            .experiment
+            .request_headers()
+            .into_iter()
+            .chain(self.vary.iter().map(|header| String::from(*header)));
+        for header in headers {
+            if !vary.iter().any(|v| v.eq_ignore_ascii_case(&header)) {
+                vary.push(header);
+            }
+        }
+        vary
+    }
+}
+
+/// Headers set by the router are dropped, so clients can't spoof them
+fn forwards_header(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    !HOP_BY_HOP_HEADERS.contains(&name.as_str())
+        && name != EXPERIMENT_HEADER
+        && name != VARIANT_HEADER
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ExperimentConfig;
+    use spin_sdk::http::Method;
+
+    const CONFIG: &str = r#"{
+        "experiments": [{
+            "id": "checkout",
+            "paths": ["/checkout/..."],
+            "variants": [
+                { "name": "a", "origin": "origin-a", "weight": 1 },
+                { "name": "b", "origin": "https://b.example.com", "weight": 1,
+                  "when": [{ "type": "userAgent", "value": { "contains": "Chrome" } }] }
+            ],
+            "targeting": [{ "type": "header", "name": "X-Plan" }]
+        }]
+    }"#;
+
+    fn forward<'a>(config: &'a ExperimentConfig, variant: usize) -> Forward<'a> {
+        let experiment = &config.experiments[0];
+        Forward {
+            experiment,
+            variant: &experiment.variants[variant],
+            cookies: vec![String::from("fwf-ab-visitor=1; Path=/")],
+            vary: vec!["Cookie"],
+        }
+    }
+
+    #[test]
+    fn test_upstream_request() {
+        let config = ExperimentConfig::parse(CONFIG).unwrap();
+        let mut req = Request::new(Method::Post, "/checkout/pay?step=2");
+        req.set_header("host", "shop.example.com");
+        req.set_header("content-type", "application/json");
+        req.set_header("connection", "keep-alive");
+        req.set_header("x-ab-variant", "spoofed");
+        *req.body_mut() = b"{}".to_vec();
+
+        let upstream = forward(&config, 1).upstream_request(&req);
+        assert_eq!(*upstream.method(), Method::Post);
+        assert_eq!(upstream.uri(), "https://b.example.com/checkout/pay?step=2");
+        assert_eq!(upstream.body(), b"{}");
+        let header = |name| upstream.header(name).and_then(|v| v.as_str());
+        assert_eq!(header("content-type"), Some("application/json"));
+        assert_eq!(header("x-forwarded-host"), Some("shop.example.com"));
+        assert_eq!(header("x-ab-variant"), Some("b"));
+        assert_eq!(header("x-ab-experiment"), Some("checkout"));
+        assert_eq!(header("connection"), None);
+        assert_eq!(header("host"), None);
+
+        let upstream = forward(&config, 0).upstream_request(&req);
+        assert_eq!(upstream.uri(), "/origin-a/checkout/pay?step=2");
+    }
+
+    #[test]
+    fn test_response() {
+        let config = ExperimentConfig::parse(CONFIG).unwrap();
+        let headers = [
+            ("content-type", "application/json"),
+            ("vary", "Accept-Encoding, cookie"),
+            ("transfer-encoding", "chunked"),
+            ("set-cookie", "cart=42; Path=/"),
+            ("x-ab-variant", "spoofed"),
+        ];
+        let origin = ForwardedResponse {
+            status: 201,
+            headers: headers
+                .iter()
+                .map(|(name, value)| (String::from(*name), value.as_bytes().to_vec()))
+                .collect(),
+            body: b"created".to_vec(),
+        };
+
+        let response = forward(&config, 0).response(origin);
+        assert_eq!(response.status, 201);
+        assert_eq!(response.body, b"created");
+        let header = |name| response.header(name).collect::<Vec<_>>();
+        assert_eq!(header("content-type"), ["application/json"]);
+        assert_eq!(
+            header("vary"),
+            ["X-Plan, User-Agent, Cookie, Accept-Encoding"]
+        );
+        assert_eq!(header("x-ab-variant"), ["a"]);
+        assert_eq!(
+            header("set-cookie"),
+            ["cart=42; Path=/", "fwf-ab-visitor=1; Path=/"]
+        );
+        assert!(header("transfer-encoding").is_empty());
+    }
+
+    #[test]
+    fn test_response_from_router() {
+        let response = ForwardedResponse::from(Response::new(404, "not found"));
+        assert_eq!(response.status, 404);
+        assert_eq!(response.body, b"not found");
+        assert!(response.headers.is_empty());
+    }
+}
@@ -0,0 +1,262 @@
+//! Results of an experiment: how many visitors were exposed to each variant, how many of them
+//! converted, and whether the difference to the control variant is significant.
+
+use anyhow::Result;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use spin_sdk::http::{Request, Response};
+use spin_sdk::key_value::Store;
+use spin_sdk::variables;
+
+use crate::counters::Counter;
+use crate::Experiment;
+
+/// Differences with a lower p-value are reported as significant
+const SIGNIFICANCE_LEVEL: f64 = 0.05;
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Results {
+    pub experiment: String,
+    pub goal: String,
+    pub control: String,
+    pub variants: Vec<VariantResult>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VariantResult {
+    pub name: String,
+    pub exposures: u64,
+    pub conversions: u64,
+    /// `None` without exposures
+    pub conversion_rate: Option<f64>,
+    /// Relative change of the conversion rate compared to the control variant
+    pub uplift: Option<f64>,
+    /// Two-proportion z-test against the control variant, `None` for the control variant
+    /// and if there is not enough data
+    pub z_score: Option<f64>,
+    pub p_value: Option<f64>,
+    pub significant: bool,
+}
+
+/// Record that a visitor was exposed to `variant`
+pub fn record_exposure(store: &Store, experiment: &Experiment, variant: &str) -> Result<()> {
+    Counter::new(store, &experiment.id, "exposures", variant).increment()
+}
+
+/// Record that a visitor of `variant` reached `goal`
+pub fn record_conversion(
+    store: &Store,
+    experiment: &Experiment,
+    goal: &str,
+    variant: &str,
+) -> Result<()> {
+    conversions(store, experiment, goal, variant).increment()
+}
+
+/// The results of `goal` of `experiment`, from the counts in `store`
+pub fn load(store: &Store, experiment: &Experiment, goal: &str) -> Result<Results> {
+    let mut counts = Vec::new();
+    for variant in &experiment.variants {
+        counts.push((
+            variant.name.as_str(),
+            Counter::new(store, &experiment.id, "exposures", &variant.name).total()?,
+            conversions(store, experiment, goal, &variant.name).total()?,
+        ));
+    }
+    Ok(evaluate(experiment, goal, &counts))
+}
+
+/// The results require the `results_token` variable as bearer token, and are disabled if it
+/// is empty
+pub fn authorize(req: &Request) -> Result<(), Response> {
+    let results_token = variables::get("results_token").unwrap_or_default();
+    if results_token.trim().is_empty() {
+        return Err(Response::new(404, ()));
+    }
+
+    let presented = req
+        .header("Authorization")
+        .and_then(|value| value.as_str())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    // comparing hashes doesn't reveal how much of the token matches
+    if Sha256::digest(presented.as_bytes()) != Sha256::digest(results_token.trim().as_bytes()) {
+        return Err(Response::new(401, ()));
+    }
+    Ok(())
+}
+
+fn conversions<'a>(
+    store: &'a Store,
+    experiment: &Experiment,
+    goal: &str,
+    variant: &str,
+) -> Counter<'a> {
+    Counter::new(
+        store,
+        &experiment.id,
+        &format!("conversions/{}", goal),
+        variant,
+    )
+}
+
+/// Compare the `(variant, exposures, conversions)` counts of every variant to the control
+pub fn evaluate(experiment: &Experiment, goal: &str, counts: &[(&str, u64, u64)]) -> Results {
+    let control = experiment.control().name.as_str();
+    let (control_exposures, control_conversions) = counts
+        .iter()
+        .find(|(name, _, _)| *name == control)
+        .map(|(_, exposures, conversions)| (*exposures, *conversions))
+        .unwrap_or_default();
+    let control_rate = rate(control_conversions, control_exposures);
+
+    let variants = counts
+        .iter()
+        .map(|&(name, exposures, conversions)| {
+            let conversion_rate = rate(conversions, exposures);
+            let test = if name == control {
+                None
+            } else {
+                two_proportion_test(
+                    control_conversions,
+                    control_exposures,
+                    conversions,
+                    exposures,
+                )
+            };
+            let uplift = match (name == control, conversion_rate, control_rate) {
+                (false, Some(rate), Some(control_rate)) if control_rate > 0.0 => {
+                    Some((rate - control_rate) / control_rate)
+                }
+                _ => None,
+            };
+            VariantResult {
+                name: String::from(name),
+                exposures,
+                conversions,
+                conversion_rate,
+                uplift,
+                z_score: test.map(|(z, _)| z),
+                p_value: test.map(|(_, p)| p),
+                significant: test.is_some_and(|(_, p)| p < SIGNIFICANCE_LEVEL),
+            }
+        })
+        .collect();
+
+    Results {
+        experiment: experiment.id.clone(),
+        goal: String::from(goal),
+        control: String::from(control),
+        variants,
+    }
+}
+
+fn rate(conversions: u64, exposures: u64) -> Option<f64> {
+    // lost exposure counts can leave more conversions than exposures
+    (exposures > 0).then(|| conversions.min(exposures) as f64 / exposures as f64)
+}
+
+/// Pooled two-proportion z-test of `c1` conversions in `n1` exposures against `c2` in `n2`.
+/// Returns the z-score and the two-sided p-value, or `None` if the test is undefined.
+pub fn two_proportion_test(c1: u64, n1: u64, c2: u64, n2: u64) -> Option<(f64, f64)> {
+    if n1 == 0 || n2 == 0 {
+        return None;
+    }
+    let (c1, c2) = (c1.min(n1) as f64, c2.min(n2) as f64);
+    let (n1, n2) = (n1 as f64, n2 as f64);
+    let pooled = (c1 + c2) / (n1 + n2);
+    let standard_error = (pooled * (1.0 - pooled) * (1.0 / n1 + 1.0 / n2)).sqrt();
+    if standard_error == 0.0 {
+        return None;
+    }
+    let z = (c2 / n2 - c1 / n1) / standard_error;
+    Some((z, 2.0 * (1.0 - normal_cdf(z.abs()))))
+}
+
+/// Cumulative distribution function of the standard normal distribution
+fn normal_cdf(x: f64) -> f64 {
+    0.5 * (1.0 + erf(x / std::f64::consts::SQRT_2))
+}
+
+/// Error function, approximated by Abramowitz and Stegun formula 7.1.26 (error < 1.5e-7)
+fn erf(x: f64) -> f64 {
+    let sign = x.signum();
+    let x = x.abs();
+    let t = 1.0 / (1.0 + 0.3275911 * x);
+    let polynomial = t
+        * (0.254829592
+            + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    sign * (1.0 - polynomial * (-x * x).exp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ExperimentConfig;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-3,
+            "{} is not close to {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_normal_cdf() {
+        assert_close(normal_cdf(0.0), 0.5);
+        assert_close(normal_cdf(1.959964), 0.975);
+        assert_close(normal_cdf(-1.0), 0.158655);
+    }
+
+    #[test]
+    fn test_two_proportion_test() {
+        let (z, p) = two_proportion_test(100, 1000, 130, 1000).unwrap();
+        assert_close(z, 2.1027);
+        assert_close(p, 0.0355);
+
+        let (z, p) = two_proportion_test(100, 1000, 100, 1000).unwrap();
+        assert_close(z, 0.0);
+        assert_close(p, 1.0);
+
+        assert_eq!(two_proportion_test(0, 1000, 0, 1000), None);
+        assert_eq!(two_proportion_test(10, 100, 0, 0), None);
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let config = ExperimentConfig::parse(
+            r#"{"experiments": [{
+                "id": "checkout",
+                "paths": ["/checkout"],
+                "variants": [
+                    { "name": "a", "origin": "origin-a", "weight": 1 },
+                    { "name": "b", "origin": "origin-b", "weight": 1 },
+                    { "name": "c", "origin": "origin-c", "weight": 1 }
+                ]
+            }]}"#,
+        )
+        .unwrap();
+        let results = evaluate(
+            &config.experiments[0],
+            "purchase",
+            &[("a", 1000, 100), ("b", 1000, 130), ("c", 0, 0)],
+        );
+
+        assert_eq!(results.control, "a");
+        assert_eq!(results.goal, "purchase");
+        let [a, b, c] = &results.variants[..] else {
+            panic!("expected three variants");
+        };
+        assert_eq!(a.conversion_rate, Some(0.1));
+        assert_eq!((a.uplift, a.p_value, a.significant), (None, None, false));
+        assert_close(b.uplift.unwrap(), 0.3);
+        assert_close(b.p_value.unwrap(), 0.0355);
+        assert!(b.significant);
+        assert_eq!(c.conversion_rate, None);
+        assert_eq!((c.z_score, c.significant), (None, false));
+    }
+}
@@ -0,0 +1,429 @@
+//! Conditions selecting the requests an experiment or a variant applies to.
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use spin_sdk::http::Request;
+
+use crate::user_agent::UserAgent;
+
+/// A condition on the request. The conditions of a list must all match.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase",
+    deny_unknown_fields
+)]
+pub enum Condition {
+    /// A cookie, matching any value if `value` is omitted
+    Cookie {
+        name: String,
+        value: Option<ValueMatch>,
+    },
+    /// A header, matching any value if `value` is omitted
+    Header {
+        name: String,
+        value: Option<ValueMatch>,
+    },
+    /// A query parameter, matching any value if `value` is omitted
+    Query {
+        name: String,
+        value: Option<ValueMatch>,
+    },
+    /// The `User-Agent` header
+    UserAgent {
+        value: ValueMatch,
+    },
+    /// The browser family parsed from the `User-Agent` header, e.g. `Chrome` or `Edge`, with an
+    /// optional range of major versions. Compared ignoring case.
+    Browser {
+        value: ValueMatch,
+        min_version: Option<u32>,
+        max_version: Option<u32>,
+    },
+    /// The operating system parsed from the `User-Agent` header, e.g. `iOS` or `Windows`.
+    /// Compared ignoring case.
+    Os {
+        value: ValueMatch,
+    },
+    /// The device type parsed from the `User-Agent` header: `desktop`, `mobile`, `tablet` or
+    /// `other`. Compared ignoring case.
+    Device {
+        value: ValueMatch,
+    },
+    /// Known crawlers and HTTP clients, optionally only the bots with a matching name, e.g.
+    /// `Googlebot`. Compared ignoring case.
+    Bot {
+        value: Option<ValueMatch>,
+    },
+    /// The country code of the client, taken from the `countryHeader`. Compared ignoring case.
+    Country {
+        value: ValueMatch,
+    },
+    All {
+        conditions: Vec<Condition>,
+    },
+    Any {
+        conditions: Vec<Condition>,
+    },
+    Not {
+        condition: Box<Condition>,
+    },
+}
+
+/// How a value is compared
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ValueMatch {
+    Equals(String),
+    OneOf(Vec<String>),
+    Contains(String),
+    Prefix(String),
+}
+
+/// What conditions are evaluated against
+pub struct Target<'a> {
+    pub req: &'a Request,
+    /// Header holding the country code of the client
+    pub country_header: Option<&'a str>,
+}
+
+impl Condition {
+    pub fn matches(&self, target: &Target) -> bool {
+        match self {
+            Condition::Cookie { name, value } => {
+                matches_optional(cookie_value(target.req, name), value.as_ref())
+            }
+            Condition::Header { name, value } => {
+                matches_optional(header_value(target.req, name), value.as_ref())
+            }
+            Condition::Query { name, value } => {
+                let query = query_value(target.req, name);
+                matches_optional(query.as_deref(), value.as_ref())
+            }
+            Condition::UserAgent { value } => {
+                header_value(target.req, "user-agent").is_some_and(|ua| value.matches(ua))
+            }
+            Condition::Browser {
+                value,
+                min_version,
+                max_version,
+            } => user_agent(target.req).is_some_and(|ua| {
+                value.matches_ignore_case(ua.browser)
+                    && min_version.map_or(true, |min| ua.version.is_some_and(|v| v >= min))
+                    && max_version.map_or(true, |max| ua.version.is_some_and(|v| v <= max))
+            }),
+            Condition::Os { value } => {
+                user_agent(target.req).is_some_and(|ua| value.matches_ignore_case(ua.os))
+            }
+            Condition::Device { value } => user_agent(target.req)
+                .is_some_and(|ua| value.matches_ignore_case(ua.device.as_str())),
+            Condition::Bot { value } => user_agent(target.req)
+                .and_then(|ua| ua.bot)
+                .is_some_and(|bot| value.as_ref().map_or(true, |v| v.matches_ignore_case(bot))),
+            Condition::Country { value } => target
+                .country_header
+                .and_then(|header| header_value(target.req, header))
+                .is_some_and(|country| value.matches_ignore_case(country.trim())),
+            Condition::All { conditions } => conditions.iter().all(|c| c.matches(target)),
+            Condition::Any { conditions } => conditions.iter().any(|c| c.matches(target)),
+            Condition::Not { condition } => !condition.matches(target),
+        }
+    }
+
+    /// Names of the request headers the condition depends on
+    pub fn headers(&self, country_header: Option<&str>, headers: &mut Vec<String>) {
+        let header = match self {
+            Condition::Cookie { .. } => "Cookie",
+            Condition::Header { name, .. } => name,
+            Condition::UserAgent { .. }
+            | Condition::Browser { .. }
+            | Condition::Os { .. }
+            | Condition::Device { .. }
+            | Condition::Bot { .. } => "User-Agent",
+            Condition::Country { .. } => match country_header {
+                Some(header) => header,
+                None => return,
+            },
+            Condition::Query { .. } => return,
+            Condition::All { conditions } | Condition::Any { conditions } => {
+                for condition in conditions {
+                    condition.headers(country_header, headers);
+                }
+                return;
+            }
+            Condition::Not { condition } => {
+                return condition.headers(country_header, headers);
+            }
+        };
+        if !headers.iter().any(|h| h.eq_ignore_ascii_case(header)) {
+            headers.push(String::from(header));
+        }
+    }
+
+    /// Check the condition, `has_country_header` tells whether country conditions can match
+    pub(crate) fn validate(&self, has_country_header: bool) -> Result<()> {
+        match self {
+            Condition::Cookie { name, .. }
+            | Condition::Header { name, .. }
+            | Condition::Query { name, .. }
+                if name.trim().is_empty() =>
+            {
+                bail!("conditions require a name")
+            }
+            Condition::Browser {
+                min_version: Some(min),
+                max_version: Some(max),
+                ..
+            } if min > max => {
+                bail!("minVersion {} is greater than maxVersion {}", min, max)
+            }
+            Condition::Country { .. } if !has_country_header => {
+                bail!("country conditions require the countryHeader setting")
+            }
+            Condition::All { conditions } | Condition::Any { conditions } => conditions
+                .iter()
+                .try_for_each(|c| c.validate(has_country_header)),
+            Condition::Not { condition } => condition.validate(has_country_header),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl ValueMatch {
+    pub fn matches(&self, value: &str) -> bool {
+        match self {
+            ValueMatch::Equals(expected) => value == expected,
+            ValueMatch::OneOf(expected) => expected.iter().any(|e| e == value),
+            ValueMatch::Contains(part) => value.contains(part.as_str()),
+            ValueMatch::Prefix(prefix) => value.starts_with(prefix.as_str()),
+        }
+    }
+
+    fn matches_ignore_case(&self, value: &str) -> bool {
+        let value = value.to_ascii_lowercase();
+        match self {
+            ValueMatch::Equals(expected) => value == expected.to_ascii_lowercase(),
+            ValueMatch::OneOf(expected) => expected.iter().any(|e| e.to_ascii_lowercase() == value),
+            ValueMatch::Contains(part) => value.contains(&part.to_ascii_lowercase()),
+            ValueMatch::Prefix(prefix) => value.starts_with(&prefix.to_ascii_lowercase()),
+        }
+    }
+}
+
+fn matches_optional(value: Option<&str>, expected: Option<&ValueMatch>) -> bool {
+    match (value, expected) {
+        (None, _) => false,
+        (Some(_), None) => true,
+        (Some(value), Some(expected)) => expected.matches(value),
+    }
+}
+
+/// The parsed `User-Agent` header of `req`
+fn user_agent(req: &Request) -> Option<UserAgent> {
+    header_value(req, "user-agent").map(UserAgent::parse)
+}
+
+fn header_value<'a>(req: &'a Request, name: &str) -> Option<&'a str> {
+    req.header(name).and_then(|value| value.as_str())
+}
+
+/// Value of the cookie `name` sent with `req`
+pub fn cookie_value<'a>(req: &'a Request, name: &str) -> Option<&'a str> {
+    req.headers()
+        .filter(|(header, _)| header.eq_ignore_ascii_case("cookie"))
+        .filter_map(|(_, value)| value.as_str())
+        .flat_map(|cookies| cookies.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(cookie, _)| *cookie == name)
+        .map(|(_, value)| value.trim_matches('"'))
+}
+
+/// Value of the query parameter `name` of `req`
+pub fn query_value(req: &Request, name: &str) -> Option<String> {
+    form_urlencoded::parse(req.query().as_bytes())
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spin_sdk::http::Method;
+
+    fn request(headers: &[(&str, &str)], uri: &str) -> Request {
+        let mut req = Request::new(Method::Get, uri);
+        for (name, value) in headers {
+            req.set_header(*name, *value);
+        }
+        req
+    }
+
+    fn condition(json: &str) -> Condition {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn matches(condition: &Condition, req: &Request) -> bool {
+        condition.matches(&Target {
+            req,
+            country_header: Some("x-country"),
+        })
+    }
+
+    #[test]
+    fn test_cookie_header_and_query_conditions() {
+        let req = request(
+            &[("cookie", "beta=yes; theme=dark"), ("x-plan", "pro")],
+            "/checkout?preview=on&ref=mail%20campaign",
+        );
+
+        assert!(matches(
+            &condition(r#"{"type": "cookie", "name": "beta"}"#),
+            &req
+        ));
+        assert!(!matches(
+            &condition(r#"{"type": "cookie", "name": "beta", "value": {"equals": "no"}}"#),
+            &req
+        ));
+        assert!(matches(
+            &condition(
+                r#"{"type": "header", "name": "X-Plan", "value": {"oneOf": ["pro", "team"]}}"#
+            ),
+            &req
+        ));
+        assert!(!matches(
+            &condition(r#"{"type": "header", "name": "x-other"}"#),
+            &req
+        ));
+        assert!(matches(
+            &condition(r#"{"type": "query", "name": "preview"}"#),
+            &req
+        ));
+        assert!(matches(
+            &condition(r#"{"type": "query", "name": "ref", "value": {"prefix": "mail "}}"#),
+            &req
+        ));
+        assert!(!matches(
+            &condition(r#"{"type": "query", "name": "beta"}"#),
+            &req
+        ));
+    }
+
+    #[test]
+    fn test_user_agent_and_country_conditions() {
+        let req = request(
+            &[
+                ("user-agent", "Mozilla/5.0 (iPhone) Mobile Safari"),
+                ("x-country", "de"),
+            ],
+            "/",
+        );
+
+        assert!(matches(
+            &condition(r#"{"type": "userAgent", "value": {"contains": "Mobile"}}"#),
+            &req
+        ));
+        assert!(matches(
+            &condition(r#"{"type": "country", "value": {"oneOf": ["AT", "DE"]}}"#),
+            &req
+        ));
+        // without the country header, no country is known
+        let country = condition(r#"{"type": "country", "value": {"equals": "DE"}}"#);
+        assert!(!country.matches(&Target {
+            req: &req,
+            country_header: None
+        }));
+        assert!(country.validate(false).is_err());
+        assert!(country.validate(true).is_ok());
+    }
+
+    #[test]
+    fn test_parsed_user_agent_conditions() {
+        let edge = request(
+            &[("user-agent", "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36 Edg/124.0.2478.51")],
+            "/",
+        );
+        let googlebot = request(
+            &[(
+                "user-agent",
+                "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+            )],
+            "/",
+        );
+
+        let chrome = condition(r#"{"type": "browser", "value": {"equals": "chrome"}}"#);
+        assert!(!matches(&chrome, &edge));
+        assert!(matches(
+            &condition(r#"{"type": "browser", "value": {"equals": "Edge"}, "minVersion": 120}"#),
+            &edge
+        ));
+        assert!(!matches(
+            &condition(r#"{"type": "browser", "value": {"equals": "Edge"}, "maxVersion": 110}"#),
+            &edge
+        ));
+        assert!(matches(
+            &condition(r#"{"type": "os", "value": {"equals": "windows"}}"#),
+            &edge
+        ));
+        assert!(matches(
+            &condition(r#"{"type": "device", "value": {"oneOf": ["desktop", "tablet"]}}"#),
+            &edge
+        ));
+
+        let bot = condition(r#"{"type": "bot"}"#);
+        assert!(!matches(&bot, &edge));
+        assert!(matches(&bot, &googlebot));
+        assert!(!matches(
+            &condition(r#"{"type": "bot", "value": {"equals": "Bingbot"}}"#),
+            &googlebot
+        ));
+        // without user agent, nothing is known about the client
+        assert!(!matches(&bot, &request(&[], "/")));
+        assert!(!matches(&chrome, &request(&[], "/")));
+
+        assert!(condition(
+            r#"{"type": "browser", "value": {"equals": "Edge"}, "minVersion": 2, "maxVersion": 1}"#
+        )
+        .validate(true)
+        .is_err());
+    }
+
+    #[test]
+    fn test_combined_conditions() {
+        let req = request(&[("user-agent", "Firefox"), ("cookie", "beta=yes")], "/");
+        let combined = condition(
+            r#"{"type": "all", "conditions": [
+                {"type": "cookie", "name": "beta"},
+                {"type": "any", "conditions": [
+                    {"type": "userAgent", "value": {"contains": "Chrome"}},
+                    {"type": "userAgent", "value": {"contains": "Firefox"}}
+                ]},
+                {"type": "not", "condition": {"type": "query", "name": "optout"}}
+            ]}"#,
+        );
+        assert!(matches(&combined, &req));
+        assert!(!matches(
+            &combined,
+            &request(&[("user-agent", "Firefox")], "/")
+        ));
+        assert!(!matches(
+            &combined,
+            &request(
+                &[("user-agent", "Firefox"), ("cookie", "beta=yes")],
+                "/?optout"
+            )
+        ));
+    }
+
+    #[test]
+    fn test_invalid_conditions_are_rejected() {
+        assert!(serde_json::from_str::<Condition>(r#"{"type": "ip"}"#).is_err());
+        assert!(serde_json::from_str::<Condition>(
+            r#"{"type": "cookie", "name": "beta", "value": {"matches": "y.*"}}"#
+        )
+        .is_err());
+        assert!(condition(r#"{"type": "header", "name": " "}"#)
+            .validate(true)
+            .is_err());
+    }
+}
@@ -0,0 +1,323 @@
+//! Classification of `User-Agent` headers into browser, version, operating system, device
+//! type and known bots. User agents are full of tokens copied from other browsers (every
+//! Chromium-based browser claims to be Chrome and Safari), so the most specific tokens are
+//! checked first.
+
+/// Browser families, checked in order. The version follows the token.
+const BROWSERS: &[(&str, &str)] = &[
+    ("Edg/", "Edge"),
+    ("EdgA/", "Edge"),
+    ("EdgiOS/", "Edge"),
+    ("OPR/", "Opera"),
+    ("OPT/", "Opera"),
+    ("OPiOS/", "Opera"),
+    ("SamsungBrowser/", "Samsung Internet"),
+    ("YaBrowser/", "Yandex Browser"),
+    ("Vivaldi/", "Vivaldi"),
+    ("FxiOS/", "Firefox"),
+    ("Firefox/", "Firefox"),
+    ("CriOS/", "Chrome"),
+    ("Chrome/", "Chrome"),
+    ("MSIE ", "Internet Explorer"),
+];
+
+/// Known crawlers and HTTP clients, matched ignoring case. The first matching token names the
+/// bot.
+const BOTS: &[(&str, &str)] = &[
+    ("googlebot", "Googlebot"),
+    ("google-inspectiontool", "Googlebot"),
+    ("adsbot-google", "Googlebot"),
+    ("bingbot", "Bingbot"),
+    ("duckduckbot", "DuckDuckBot"),
+    ("baiduspider", "Baiduspider"),
+    ("yandexbot", "YandexBot"),
+    ("applebot", "Applebot"),
+    ("facebookexternalhit", "Facebook"),
+    ("twitterbot", "Twitterbot"),
+    ("linkedinbot", "LinkedInBot"),
+    ("slackbot", "Slackbot"),
+    ("discordbot", "Discordbot"),
+    ("gptbot", "GPTBot"),
+    ("ahrefsbot", "AhrefsBot"),
+    ("semrushbot", "SemrushBot"),
+    ("curl/", "curl"),
+    ("wget/", "Wget"),
+    ("python-requests/", "python-requests"),
+    ("go-http-client/", "Go HTTP client"),
+    ("headlesschrome/", "Headless Chrome"),
+];
+
+/// Words marking other crawlers, which are reported as `Other`. Matched as whole words or as
+/// the end of the product following `compatible;`, e.g. `(compatible; ExampleBot/1.0)`, since
+/// browsers contain them as well, e.g. the `Cubot` phone brand.
+const GENERIC_BOTS: &[&str] = &["bot", "crawler", "spider"];
+
+/// What a `User-Agent` header tells about the client
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserAgent {
+    /// e.g. `Chrome`, `Safari` or `Other`
+    pub browser: &'static str,
+    /// Major version of the browser
+    pub version: Option<u32>,
+    /// e.g. `Windows`, `iOS` or `Other`
+    pub os: &'static str,
+    pub device: Device,
+    /// Name of the bot, if the client is a known crawler or HTTP client
+    pub bot: Option<&'static str>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Device {
+    Desktop,
+    Mobile,
+    Tablet,
+    Other,
+}
+
+impl Device {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Device::Desktop => "desktop",
+            Device::Mobile => "mobile",
+            Device::Tablet => "tablet",
+            Device::Other => "other",
+        }
+    }
+}
+
+impl UserAgent {
+    pub fn parse(user_agent: &str) -> Self {
+        let (browser, version) = browser(user_agent);
+        let os = os(user_agent);
+        UserAgent {
+            browser,
+            version,
+            os,
+            device: device(user_agent, os),
+            bot: bot(user_agent),
+        }
+    }
+}
+
+fn browser(ua: &str) -> (&'static str, Option<u32>) {
+    for (token, browser) in BROWSERS {
+        if let Some(version) = version_after(ua, token) {
+            return (browser, version);
+        }
+    }
+    if let Some(version) = version_after(ua, "Trident/") {
+        // Internet Explorer 11 only reports its version as `rv:11.0`
+        return (
+            "Internet Explorer",
+            version_after(ua, "rv:").flatten().or(version),
+        );
+    }
+    if ua.contains("Safari/") && (ua.contains("Version/") || ua.contains("Mobile/")) {
+        // in-app browsers on iOS omit `Version/`
+        return ("Safari", version_after(ua, "Version/").flatten());
+    }
+    ("Other", None)
+}
+
+/// `Some` if `ua` contains `token`, with the major version following it
+fn version_after(ua: &str, token: &str) -> Option<Option<u32>> {
+    let start = ua.find(token)? + token.len();
+    let digits = ua[start..]
+        .find(|c: char| !c.is_ascii_digit())
+        .map_or(&ua[start..], |end| &ua[start..start + end]);
+    Some(digits.parse().ok())
+}
+
+fn os(ua: &str) -> &'static str {
+    if ua.contains("Windows") {
+        "Windows"
+    } else if ua.contains("CrOS") {
+        "ChromeOS"
+    } else if ua.contains("Android") {
+        "Android"
+    } else if ua.contains("iPhone") || ua.contains("iPad") || ua.contains("iPod") {
+        // checked before macOS, iOS user agents contain `like Mac OS X`
+        "iOS"
+    } else if ua.contains("Macintosh") || ua.contains("Mac OS X") {
+        "macOS"
+    } else if ua.contains("Linux") || ua.contains("X11") {
+        "Linux"
+    } else {
+        "Other"
+    }
+}
+
+fn device(ua: &str, os: &str) -> Device {
+    if ua.contains("iPad") || ua.contains("Tablet") || (os == "Android" && !ua.contains("Mobile")) {
+        Device::Tablet
+    } else if ua.contains("Mobi") || ua.contains("iPhone") || ua.contains("iPod") {
+        Device::Mobile
+    } else if matches!(os, "Windows" | "macOS" | "Linux" | "ChromeOS") {
+        // iPads request desktop sites with a macOS user agent, and are reported as desktop
+        Device::Desktop
+    } else {
+        Device::Other
+    }
+}
+
+fn bot(ua: &str) -> Option<&'static str> {
+    let ua = ua.to_ascii_lowercase();
+    BOTS.iter()
+        .find(|(token, _)| ua.contains(token))
+        .map(|(_, bot)| *bot)
+        .or_else(|| is_generic_bot(&ua).then_some("Other"))
+}
+
+/// Whether the lowercase `ua` contains one of `GENERIC_BOTS` as a word or at the end of a
+/// `compatible;` product
+fn is_generic_bot(ua: &str) -> bool {
+    let as_word = ua
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .any(|word| GENERIC_BOTS.contains(&word));
+    let as_product = ua.split("compatible;").skip(1).any(|rest| {
+        let product = rest
+            .trim_start()
+            .split(|c: char| matches!(c, '/' | ';' | ')') || c.is_whitespace())
+            .next()
+            .unwrap_or_default();
+        GENERIC_BOTS.iter().any(|bot| product.ends_with(bot))
+    });
+    as_word || as_product
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// User agent, browser, version, os, device and bot
+    type Case = (
+        &'static str,
+        &'static str,
+        Option<u32>,
+        &'static str,
+        Device,
+        Option<&'static str>,
+    );
+
+    #[test]
+    fn test_parse() {
+        use Device::*;
+        #[rustfmt::skip]
+        let cases: &[Case] = &[
+            (
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36",
+                "Chrome", Some(124), "Windows", Desktop, None,
+            ),
+            (
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36 Edg/124.0.2478.51",
+                "Edge", Some(124), "Windows", Desktop, None,
+            ),
+            (
+                "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36 OPR/109.0.0.0",
+                "Opera", Some(109), "macOS", Desktop, None,
+            ),
+            (
+                "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4 Safari/605.1.15",
+                "Safari", Some(17), "macOS", Desktop, None,
+            ),
+            (
+                "Mozilla/5.0 (X11; Linux x86_64; rv:125.0) Gecko/20100101 Firefox/125.0",
+                "Firefox", Some(125), "Linux", Desktop, None,
+            ),
+            (
+                "Mozilla/5.0 (X11; CrOS x86_64 14541.0.0) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36",
+                "Chrome", Some(124), "ChromeOS", Desktop, None,
+            ),
+            (
+                "Mozilla/5.0 (iPhone; CPU iPhone OS 17_4_1 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4.1 Mobile/15E148 Safari/604.1",
+                "Safari", Some(17), "iOS", Mobile, None,
+            ),
+            (
+                "Mozilla/5.0 (iPhone; CPU iPhone OS 17_4 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) CriOS/124.0.6367.88 Mobile/15E148 Safari/604.1",
+                "Chrome", Some(124), "iOS", Mobile, None,
+            ),
+            (
+                "Mozilla/5.0 (iPhone; CPU iPhone OS 17_4 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) FxiOS/125.0 Mobile/15E148 Safari/605.1.15",
+                "Firefox", Some(125), "iOS", Mobile, None,
+            ),
+            (
+                "Mozilla/5.0 (iPad; CPU OS 17_4 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4 Mobile/15E148 Safari/604.1",
+                "Safari", Some(17), "iOS", Tablet, None,
+            ),
+            (
+                "Mozilla/5.0 (Linux; Android 10; K) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Mobile Safari/537.36",
+                "Chrome", Some(124), "Android", Mobile, None,
+            ),
+            (
+                "Mozilla/5.0 (Linux; Android 14; SAMSUNG SM-S921B) AppleWebKit/537.36 (KHTML, like Gecko) SamsungBrowser/24.0 Chrome/117.0.0.0 Mobile Safari/537.36",
+                "Samsung Internet", Some(24), "Android", Mobile, None,
+            ),
+            (
+                "Mozilla/5.0 (Linux; Android 13; SM-X700) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36",
+                "Chrome", Some(124), "Android", Tablet, None,
+            ),
+            (
+                "Mozilla/5.0 (Windows NT 10.0; WOW64; Trident/7.0; rv:11.0) like Gecko",
+                "Internet Explorer", Some(11), "Windows", Desktop, None,
+            ),
+            (
+                "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+                "Other", None, "Other", Other, Some("Googlebot"),
+            ),
+            (
+                "Mozilla/5.0 (Linux; Android 6.0.1; Nexus 5X Build/MMB29P) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.6367.201 Mobile Safari/537.36 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+                "Chrome", Some(124), "Android", Mobile, Some("Googlebot"),
+            ),
+            (
+                "Mozilla/5.0 AppleWebKit/537.36 (KHTML, like Gecko; compatible; bingbot/2.0; +http://www.bing.com/bingbot.htm) Chrome/116.0.1938.76 Safari/537.36",
+                "Chrome", Some(116), "Other", Other, Some("Bingbot"),
+            ),
+            (
+                "facebookexternalhit/1.1 (+http://www.facebook.com/externalhit_uatext.php)",
+                "Other", None, "Other", Other, Some("Facebook"),
+            ),
+            (
+                "Mozilla/5.0 (compatible; SomeCrawler/1.0)",
+                "Other", None, "Other", Other, Some("Other"),
+            ),
+            (
+                "Mozilla/5.0 (compatible; MJ12bot/v1.4.8; http://mj12bot.com/)",
+                "Other", None, "Other", Other, Some("Other"),
+            ),
+            (
+                "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko; Spider/1.0) Chrome/120.0.0.0 Safari/537.36",
+                "Chrome", Some(120), "Linux", Desktop, Some("Other"),
+            ),
+            // browsers merely containing `bot`, `crawler` or `spider`
+            (
+                "Mozilla/5.0 (Linux; Android 10; CUBOT_X30) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Mobile Safari/537.36",
+                "Chrome", Some(124), "Android", Mobile, None,
+            ),
+            (
+                "Mozilla/5.0 (Linux; Android 12; Cubot KingKong 9) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Mobile Safari/537.36",
+                "Chrome", Some(124), "Android", Mobile, None,
+            ),
+            (
+                "Mozilla/5.0 (Linux; Android 13; SpiderPhone S1) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Mobile Safari/537.36",
+                "Chrome", Some(124), "Android", Mobile, None,
+            ),
+            ("curl/8.7.1", "Other", None, "Other", Other, Some("curl")),
+            ("", "Other", None, "Other", Other, None),
+        ];
+
+        for &(ua, browser, version, os, device, bot) in cases {
+            assert_eq!(
+                UserAgent::parse(ua),
+                UserAgent {
+                    browser,
+                    version,
+                    os,
+                    device,
+                    bot,
+                },
+                "{}",
+                ua
+            );
+        }
+    }
+}
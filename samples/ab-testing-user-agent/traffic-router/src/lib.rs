@@ -1,19 +1,39 @@
 use anyhow::Result;
 use spin_sdk::http::{
-    send, IntoResponse, Params, Request, RequestBuilder, Response, ResponseBuilder, Router,
+    IntoResponse, Params, Request, Response, ResponseBuilder, ResponseOutparam, Router,
 };
 use spin_sdk::http_component;
 
-const ORIGIN_A: &str = "origin-a";
-const ORIGIN_B: &str = "origin-b";
+use experiment_config::proxy::{Forward, ForwardedResponse};
+use experiment_config::{Decision, ExperimentConfig};
+
+/// Experiments used if the `experiments` variable is empty
+const DEFAULT_EXPERIMENTS: &str = include_str!("../experiments.json");
 
 #[http_component]
-fn handle_ab_testing(req: Request) -> anyhow::Result<impl IntoResponse> {
+async fn handle_ab_testing(req: Request, response_out: ResponseOutparam) {
+    let response = match req.path() {
+        "/" | "/index.html" => app_router().handle(req).into(),
+        // the router keeps a single value per header, the responses of experiments may set
+        // several cookies
+        _ => match route_by_user_agent(req).await {
+            Ok(response) => response,
+            Err(e) => {
+                println!("routing request failed: {:#}", e);
+                Response::new(500, e.to_string()).into()
+            }
+        },
+    };
+    if let Err(e) = response.write(response_out).await {
+        println!("sending response failed: {:#}", e);
+    }
+}
+
+fn app_router() -> Router {
     let mut router = Router::default();
     router.get("/", redirect_to_index);
     router.get("/index.html", route_index);
-    router.get_async("/by-user-agent", route_by_user_agent);
-    Ok(router.handle(req))
+    router
 }
 
 fn redirect_to_index(_req: Request, _: Params) -> Result<impl IntoResponse> {
@@ -35,7 +55,7 @@ fn route_index(_req: Request, _: Params) -> Result<impl IntoResponse> {
 <body>
     <h1>A/B Testing Sample</h1>
     <ul>
-        <li><a href="/by-user-agent">Route Requests by User Agent</a> - Chrome and Safari user agents will be routed to Variant B instead of A</li>
+        <li><a href="/by-user-agent.html">Route Requests by User Agent</a> - Chrome and Safari will be routed to Variant B instead of A, other browsers and bots to Variant A</li>
     </ul>
 </body>
 
@@ -47,25 +67,75 @@ fn route_index(_req: Request, _: Params) -> Result<impl IntoResponse> {
         .build())
 }
 
-async fn route_by_user_agent(req: Request, _: Params) -> Result<impl IntoResponse> {
-    const ORIGIN_REQUEST_PATH: &str = "by-user-agent.html";
+async fn route_by_user_agent(req: Request) -> Result<ForwardedResponse> {
     let Some(user_agent_header_value) = req.header("user-agent") else {
-        return Ok(Response::new(400, "user-agent header not present"));
+        return Ok(Response::new(400, "user-agent header not present").into());
+    };
+    if user_agent_header_value.as_str().is_none() {
+        return Ok(Response::new(400, "user-agent header is empty").into());
+    }
+
+    let config = ExperimentConfig::load(DEFAULT_EXPERIMENTS)?;
+    let Some(experiment) = config.find(req.path()) else {
+        return Ok(Response::new(404, ()).into());
     };
-    let Some(user_agent) = user_agent_header_value.as_str() else {
-        return Ok(Response::new(400, "user-agent header is empty"));
+    let variant = match experiment.decide(&req) {
+        Decision::Excluded(variant) | Decision::Forced(variant) => variant,
+        Decision::Assign => experiment.pick(experiment.bucket(&req)?),
     };
 
-    let mut origin_route = build_request_url(ORIGIN_A, ORIGIN_REQUEST_PATH);
-    println!("{}", user_agent);
-    if user_agent.contains("Chrome") || user_agent.contains("Safari") {
-        origin_route = build_request_url(ORIGIN_B, ORIGIN_REQUEST_PATH);
+    Forward {
+        experiment,
+        variant,
+        cookies: Vec::new(),
+        vary: Vec::new(),
     }
-    let origin_req = RequestBuilder::new(spin_sdk::http::Method::Get, origin_route).build();
-    let response: Response = send(origin_req).await?;
-    Ok(response)
+    .send(&req)
+    .await
 }
 
-fn build_request_url(origin: &str, path: &str) -> String {
-    format!("/{}/{}", origin, path)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spin_sdk::http::Method;
+
+    fn variant(user_agent: &str) -> String {
+        let config = ExperimentConfig::parse(DEFAULT_EXPERIMENTS).unwrap();
+        let experiment = config.find("/by-user-agent.html").unwrap();
+        let mut req = Request::new(Method::Get, "/by-user-agent.html");
+        req.set_header("user-agent", user_agent);
+        match experiment.decide(&req) {
+            Decision::Excluded(variant) | Decision::Forced(variant) => variant.name.clone(),
+            Decision::Assign => experiment
+                .pick(experiment.bucket(&req).unwrap())
+                .name
+                .clone(),
+        }
+    }
+
+    #[test]
+    fn test_default_experiments() {
+        let cases = [
+            // Chrome
+            ("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36", "b"),
+            ("Mozilla/5.0 (Linux; Android 10; K) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Mobile Safari/537.36", "b"),
+            ("Mozilla/5.0 (iPhone; CPU iPhone OS 17_4 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) CriOS/124.0.6367.88 Mobile/15E148 Safari/604.1", "b"),
+            // Safari
+            ("Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4 Safari/605.1.15", "b"),
+            ("Mozilla/5.0 (iPhone; CPU iPhone OS 17_4_1 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4.1 Mobile/15E148 Safari/604.1", "b"),
+            // Chromium-based browsers claim to be Chrome and Safari
+            ("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36 Edg/124.0.2478.51", "a"),
+            ("Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36 OPR/109.0.0.0", "a"),
+            ("Mozilla/5.0 (Linux; Android 14; SAMSUNG SM-S921B) AppleWebKit/537.36 (KHTML, like Gecko) SamsungBrowser/24.0 Chrome/117.0.0.0 Mobile Safari/537.36", "a"),
+            // other browsers
+            ("Mozilla/5.0 (X11; Linux x86_64; rv:125.0) Gecko/20100101 Firefox/125.0", "a"),
+            ("Mozilla/5.0 (iPhone; CPU iPhone OS 17_4 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) FxiOS/125.0 Mobile/15E148 Safari/605.1.15", "a"),
+            // bots don't take part, even when rendering with Chrome
+            ("Mozilla/5.0 (Linux; Android 6.0.1; Nexus 5X Build/MMB29P) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.6367.201 Mobile Safari/537.36 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)", "a"),
+            ("curl/8.7.1", "a"),
+        ];
+        for (user_agent, expected) in cases {
+            assert_eq!(variant(user_agent), expected, "{}", user_agent);
+        }
+    }
 }
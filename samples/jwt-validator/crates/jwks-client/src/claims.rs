@@ -0,0 +1,440 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error::Error;
+use crate::jwt::{Jwt, Payload};
+
+/// A declarative check applied to a single claim of a token.
+///
+/// `claim` is either the name of a top level claim or a dot separated path into nested
+/// objects (e.g. `realm_access.roles`). Every rule requires the claim to be present, unless
+/// it is marked `optional`. Without `equals`, `anyOf`, `allOf` or `matches`, the rule only
+/// checks the presence of the claim.
+///
+/// Array claims are checked element by element. String claims are checked as a single value,
+/// or as a list of values if the rule is `spaceDelimited` (e.g. the `scope` claim).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClaimRule {
+    pub claim: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub equals: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub any_of: Option<Vec<Value>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub all_of: Option<Vec<Value>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub matches: Option<String>,
+    #[serde(default)]
+    pub space_delimited: bool,
+    #[serde(default)]
+    pub ignore_case: bool,
+    #[serde(default)]
+    pub optional: bool,
+}
+
+impl ClaimRule {
+    /// Require `claim` to be present
+    pub fn new(claim: &str) -> Self {
+        ClaimRule {
+            claim: claim.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Require the claim to be equal to `value`
+    pub fn equals(mut self, value: impl Into<Value>) -> Self {
+        self.equals = Some(value.into());
+        self
+    }
+
+    /// Require at least one value of the claim to be one of `values`
+    pub fn any_of<V: Into<Value>>(mut self, values: impl IntoIterator<Item = V>) -> Self {
+        self.any_of = Some(values.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Require the claim to contain all of `values`
+    pub fn all_of<V: Into<Value>>(mut self, values: impl IntoIterator<Item = V>) -> Self {
+        self.all_of = Some(values.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Require at least one value of the claim to match the regular expression `pattern`
+    pub fn matches(mut self, pattern: &str) -> Self {
+        self.matches = Some(pattern.to_string());
+        self
+    }
+
+    /// Treat string values as a space delimited list, as used by the `scope` claim
+    pub fn space_delimited(mut self) -> Self {
+        self.space_delimited = true;
+        self
+    }
+
+    /// Compare string values case insensitive
+    pub fn ignore_case(mut self) -> Self {
+        self.ignore_case = true;
+        self
+    }
+
+    /// Only apply the rule if the claim is present
+    pub fn optional(mut self) -> Self {
+        self.optional = true;
+        self
+    }
+}
+
+/// A rule that was not satisfied by a token
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Violation {
+    pub claim: String,
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl Violation {
+    fn new(claim: &str, code: &'static str, message: String) -> Self {
+        Violation {
+            claim: claim.to_string(),
+            code,
+            message,
+        }
+    }
+}
+
+/// Validates the claims of a token against a set of `ClaimRule`s
+#[derive(Debug, Clone)]
+pub struct ClaimsValidator {
+    rules: Vec<(ClaimRule, Option<Regex>)>,
+}
+
+impl ClaimsValidator {
+    /// Create a validator, compiling the regular expressions of all rules
+    pub fn new(rules: Vec<ClaimRule>) -> Result<Self, Error> {
+        let rules = rules
+            .into_iter()
+            .map(|rule| {
+                let regex = match &rule.matches {
+                    Some(pattern) => Some(Regex::new(pattern).map_err(|e| Error::InvalidRule {
+                        claim: rule.claim.clone(),
+                        source: Box::new(e),
+                    })?),
+                    None => None,
+                };
+                Ok((rule, regex))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        Ok(ClaimsValidator { rules })
+    }
+
+    pub fn rules(&self) -> impl Iterator<Item = &ClaimRule> {
+        self.rules.iter().map(|(rule, _)| rule)
+    }
+
+    /// Validate the payload of `jwt`, see `validate`
+    pub fn validate_jwt(&self, jwt: &Jwt) -> Vec<Violation> {
+        self.validate(jwt.payload())
+    }
+
+    /// Check all rules against `payload` and return every violation found
+    pub fn validate(&self, payload: &Payload) -> Vec<Violation> {
+        let mut violations = vec![];
+
+        for (rule, regex) in self.rules.iter() {
+            let Some(value) = lookup(&payload.json, &rule.claim) else {
+                if !rule.optional {
+                    violations.push(Violation::new(
+                        &rule.claim,
+                        "missing_claim",
+                        format!("JWT does not have '{}' claim", rule.claim),
+                    ));
+                }
+                continue;
+            };
+            check_rule(rule, regex.as_ref(), value, &mut violations);
+        }
+
+        violations
+    }
+}
+
+fn check_rule(
+    rule: &ClaimRule,
+    regex: Option<&Regex>,
+    value: &Value,
+    violations: &mut Vec<Violation>,
+) {
+    let claim = rule.claim.as_str();
+
+    if let Some(want) = &rule.equals {
+        if !values_equal(value, want, rule.ignore_case) {
+            violations.push(Violation::new(
+                claim,
+                "claim_mismatch",
+                format!("JWT does not have expected value for '{}' claim", claim),
+            ));
+        }
+    }
+
+    let got = claim_values(value, rule.space_delimited);
+
+    if let Some(want) = &rule.any_of {
+        let found = got
+            .iter()
+            .any(|g| want.iter().any(|w| values_equal(g, w, rule.ignore_case)));
+        if !found {
+            violations.push(Violation::new(
+                claim,
+                "claim_not_allowed",
+                format!(
+                    "JWT '{}' claim does not contain any of {}",
+                    claim,
+                    display_values(want)
+                ),
+            ));
+        }
+    }
+
+    if let Some(want) = &rule.all_of {
+        for w in want.iter() {
+            if !got.iter().any(|g| values_equal(g, w, rule.ignore_case)) {
+                violations.push(Violation::new(
+                    claim,
+                    "claim_value_missing",
+                    format!(
+                        "JWT is missing {} as part of the '{}' claim",
+                        display_value(w),
+                        claim
+                    ),
+                ));
+            }
+        }
+    }
+
+    if let Some(regex) = regex {
+        let matched = got
+            .iter()
+            .filter_map(|g| g.as_str())
+            .any(|g| regex.is_match(g));
+        if !matched {
+            violations.push(Violation::new(
+                claim,
+                "claim_pattern_mismatch",
+                format!("JWT '{}' claim does not match {}", claim, regex.as_str()),
+            ));
+        }
+    }
+}
+
+/// Find a claim by name, or by a dot separated path into nested objects
+fn lookup<'a>(json: &'a Value, path: &str) -> Option<&'a Value> {
+    if let Some(value) = json.get(path) {
+        return Some(value);
+    }
+    path.split('.')
+        .try_fold(json, |current, segment| current.get(segment))
+}
+
+fn claim_values(value: &Value, space_delimited: bool) -> Vec<Value> {
+    match value {
+        Value::Array(values) => values.clone(),
+        Value::String(s) if space_delimited => s
+            .split_whitespace()
+            .map(|v| Value::String(v.to_string()))
+            .collect(),
+        other => vec![other.clone()],
+    }
+}
+
+fn values_equal(got: &Value, want: &Value, ignore_case: bool) -> bool {
+    match (got, want) {
+        (Value::String(g), Value::String(w)) if ignore_case => g.eq_ignore_ascii_case(w),
+        _ => got == want,
+    }
+}
+
+fn display_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn display_values(values: &[Value]) -> String {
+    values
+        .iter()
+        .map(display_value)
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn payload() -> Payload {
+        Payload::new(json!({
+            "iss": "https://idp.example.com",
+            "aud": ["invoice", "customer"],
+            "scope": "invoice.read Invoice.Write",
+            "email": "alice@example.com",
+            "realm_access": { "roles": ["admin", "user"] },
+            "https://example.com/tenant": "acme"
+        }))
+    }
+
+    fn validate(rules: Vec<ClaimRule>) -> Vec<Violation> {
+        ClaimsValidator::new(rules).unwrap().validate(&payload())
+    }
+
+    fn codes(violations: &[Violation]) -> Vec<&'static str> {
+        violations.iter().map(|v| v.code).collect()
+    }
+
+    #[test]
+    fn test_presence() {
+        assert!(validate(vec![ClaimRule::new("iss")]).is_empty());
+        assert_eq!(
+            codes(&validate(vec![ClaimRule::new("client_app_type")])),
+            vec!["missing_claim"]
+        );
+        assert!(validate(vec![ClaimRule::new("client_app_type").optional()]).is_empty());
+    }
+
+    #[test]
+    fn test_equals() {
+        assert!(validate(vec![ClaimRule::new("iss").equals("https://idp.example.com")]).is_empty());
+        assert_eq!(
+            codes(&validate(vec![
+                ClaimRule::new("iss").equals("https://other.example.com")
+            ])),
+            vec!["claim_mismatch"]
+        );
+    }
+
+    #[test]
+    fn test_all_of_array() {
+        assert!(validate(vec![ClaimRule::new("aud").all_of(["invoice", "customer"])]).is_empty());
+
+        let violations = validate(vec![
+            ClaimRule::new("aud").all_of(["invoice", "billing", "x"])
+        ]);
+        assert_eq!(
+            codes(&violations),
+            vec!["claim_value_missing", "claim_value_missing"]
+        );
+        assert_eq!(
+            violations[0].message,
+            "JWT is missing billing as part of the 'aud' claim"
+        );
+    }
+
+    #[test]
+    fn test_any_of() {
+        assert!(validate(vec![ClaimRule::new("aud").any_of(["billing", "customer"])]).is_empty());
+        assert_eq!(
+            codes(&validate(vec![ClaimRule::new("aud").any_of(["billing"])])),
+            vec!["claim_not_allowed"]
+        );
+    }
+
+    #[test]
+    fn test_space_delimited_scope() {
+        let rule = ClaimRule::new("scope")
+            .all_of(["invoice.read", "invoice.write"])
+            .space_delimited()
+            .ignore_case();
+        assert!(validate(vec![rule]).is_empty());
+
+        let case_sensitive = ClaimRule::new("scope")
+            .all_of(["invoice.write"])
+            .space_delimited();
+        assert_eq!(
+            codes(&validate(vec![case_sensitive])),
+            vec!["claim_value_missing"]
+        );
+    }
+
+    #[test]
+    fn test_nested_path() {
+        assert!(validate(vec![ClaimRule::new("realm_access.roles").any_of(["admin"])]).is_empty());
+        assert_eq!(
+            codes(&validate(vec![ClaimRule::new("realm_access.groups")])),
+            vec!["missing_claim"]
+        );
+    }
+
+    #[test]
+    fn test_claim_name_containing_dots() {
+        assert!(validate(vec![
+            ClaimRule::new("https://example.com/tenant").equals("acme")
+        ])
+        .is_empty());
+    }
+
+    #[test]
+    fn test_regex() {
+        assert!(validate(vec![ClaimRule::new("email").matches(r"@example\.com$")]).is_empty());
+        assert_eq!(
+            codes(&validate(vec![
+                ClaimRule::new("email").matches(r"@fermyon\.com$")
+            ])),
+            vec!["claim_pattern_mismatch"]
+        );
+        assert_eq!(
+            codes(&validate(vec![
+                ClaimRule::new("realm_access").matches("admin")
+            ])),
+            vec!["claim_pattern_mismatch"]
+        );
+    }
+
+    #[test]
+    fn test_invalid_regex() {
+        let result = ClaimsValidator::new(vec![ClaimRule::new("email").matches("(")]);
+
+        assert_eq!(result.err().map(|e| e.code()), Some("invalid_rule"));
+    }
+
+    #[test]
+    fn test_reports_every_violation() {
+        let violations = validate(vec![
+            ClaimRule::new("aud").all_of(["billing"]),
+            ClaimRule::new("iss").equals("https://other.example.com"),
+            ClaimRule::new("sub"),
+            ClaimRule::new("scope").all_of(["manage"]).space_delimited(),
+        ]);
+
+        assert_eq!(
+            codes(&violations),
+            vec![
+                "claim_value_missing",
+                "claim_mismatch",
+                "missing_claim",
+                "claim_value_missing"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_deserialize_rules() {
+        let rules: Vec<ClaimRule> = serde_json::from_value(json!([
+            { "claim": "scope", "allOf": ["invoice.read"], "spaceDelimited": true },
+            { "claim": "email", "matches": "@example\\.com$", "optional": true }
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            rules[0],
+            ClaimRule::new("scope")
+                .all_of(["invoice.read"])
+                .space_delimited()
+        );
+        assert!(validate(rules).is_empty());
+    }
+}
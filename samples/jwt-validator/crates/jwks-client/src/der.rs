@@ -0,0 +1,313 @@
+//! Minimal PEM and DER parsing, just enough to read keys and X.509 certificates.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::signature::{
+    RsaPublicKeyComponents, RSA_PKCS1_2048_8192_SHA256, RSA_PKCS1_2048_8192_SHA384,
+    RSA_PKCS1_2048_8192_SHA512,
+};
+
+use crate::error::*;
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_BIT_STRING: u8 = 0x03;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OID: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_VERSION: u8 = 0xa0;
+
+/// 1.2.840.113549.1.1.1
+const OID_RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+/// 1.2.840.10045.2.1
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+/// 1.2.840.10045.3.1.7
+const OID_PRIME256V1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+/// 1.3.101.112
+const OID_ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+/// 1.2.840.113549.1.1.11
+const OID_SHA256_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b];
+/// 1.2.840.113549.1.1.12
+const OID_SHA384_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0c];
+/// 1.2.840.113549.1.1.13
+const OID_SHA512_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0d];
+
+/// A decoded PEM block, e.g. `PUBLIC KEY` or `CERTIFICATE`
+pub(crate) struct PemBlock {
+    pub label: String,
+    pub der: Vec<u8>,
+}
+
+/// Decode all PEM blocks found in `pem`, in order
+pub(crate) fn pem_decode(pem: &str) -> Result<Vec<PemBlock>, Error> {
+    let mut blocks = vec![];
+    let mut current: Option<(String, String)> = None;
+
+    for line in pem.lines().map(str::trim) {
+        if let Some(label) = line
+            .strip_prefix("-----BEGIN ")
+            .and_then(|l| l.strip_suffix("-----"))
+        {
+            current = Some((label.to_string(), String::new()));
+        } else if let Some(label) = line
+            .strip_prefix("-----END ")
+            .and_then(|l| l.strip_suffix("-----"))
+        {
+            let (begin_label, body) = current.take().ok_or(err_key("Unexpected PEM footer"))?;
+            if begin_label != label {
+                return Err(err_key("PEM header and footer do not match"));
+            }
+            let der = STANDARD
+                .decode(body)
+                .or(Err(err_key("Failed to decode PEM body")))?;
+            blocks.push(PemBlock {
+                label: begin_label,
+                der,
+            });
+        } else if let Some((_, body)) = current.as_mut() {
+            body.push_str(line);
+        }
+    }
+
+    if current.is_some() {
+        return Err(err_key("PEM block is not terminated"));
+    }
+    if blocks.is_empty() {
+        return Err(err_key("No PEM block found"));
+    }
+
+    Ok(blocks)
+}
+
+/// An RSA public key as big-endian modulus and exponent
+pub(crate) struct RsaComponents {
+    pub n: Vec<u8>,
+    pub e: Vec<u8>,
+}
+
+/// Key types that can be read from `SubjectPublicKeyInfo` and PKCS#8 structures
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum KeyType {
+    Rsa,
+    P256,
+    Ed25519,
+}
+
+/// A public key read from a `SubjectPublicKeyInfo`
+pub(crate) enum PublicKey {
+    Rsa(RsaComponents),
+    /// Uncompressed point (0x04 || x || y)
+    P256(Vec<u8>),
+    Ed25519(Vec<u8>),
+}
+
+/// Read a public key from a DER encoded `SubjectPublicKeyInfo`
+pub(crate) fn public_key_from_spki(der: &[u8]) -> Result<PublicKey, Error> {
+    let mut spki = DerReader::new(der).expect(TAG_SEQUENCE)?;
+    let key_type = algorithm_identifier(&mut spki)?;
+    let key = bit_string(spki.expect(TAG_BIT_STRING)?.data)?;
+
+    match key_type {
+        KeyType::Rsa => Ok(PublicKey::Rsa(rsa_from_pkcs1(key)?)),
+        KeyType::P256 if key.len() == 65 && key[0] == 0x04 => Ok(PublicKey::P256(key.to_vec())),
+        KeyType::Ed25519 if key.len() == 32 => Ok(PublicKey::Ed25519(key.to_vec())),
+        _ => Err(err_key("Invalid public key")),
+    }
+}
+
+/// Read an RSA public key from a DER encoded `SubjectPublicKeyInfo`
+pub(crate) fn rsa_from_spki(der: &[u8]) -> Result<RsaComponents, Error> {
+    match public_key_from_spki(der)? {
+        PublicKey::Rsa(components) => Ok(components),
+        _ => Err(err_key("Unsupported public key type")),
+    }
+}
+
+/// Determine the key type of a DER encoded PKCS#8 `PrivateKeyInfo`
+pub(crate) fn pkcs8_key_type(der: &[u8]) -> Result<KeyType, Error> {
+    let mut info = DerReader::new(der).expect(TAG_SEQUENCE)?;
+    info.expect(TAG_INTEGER)?;
+    let key_type = algorithm_identifier(&mut info)?;
+    info.expect(TAG_OCTET_STRING)?;
+
+    Ok(key_type)
+}
+
+fn algorithm_identifier(parent: &mut Tlv) -> Result<KeyType, Error> {
+    let mut algorithm = parent.expect(TAG_SEQUENCE)?;
+    let oid = algorithm.expect(TAG_OID)?.data;
+
+    if oid == OID_RSA_ENCRYPTION {
+        Ok(KeyType::Rsa)
+    } else if oid == OID_ED25519 {
+        Ok(KeyType::Ed25519)
+    } else if oid == OID_EC_PUBLIC_KEY {
+        match algorithm.expect(TAG_OID)?.data {
+            OID_PRIME256V1 => Ok(KeyType::P256),
+            _ => Err(err_key("Unsupported elliptic curve")),
+        }
+    } else {
+        Err(err_key("Unsupported public key type"))
+    }
+}
+
+/// Read an RSA public key from a DER encoded PKCS#1 `RSAPublicKey`
+pub(crate) fn rsa_from_pkcs1(der: &[u8]) -> Result<RsaComponents, Error> {
+    let mut key = DerReader::new(der).expect(TAG_SEQUENCE)?;
+    let n = unsigned_integer(key.expect(TAG_INTEGER)?.data);
+    let e = unsigned_integer(key.expect(TAG_INTEGER)?.data);
+    if n.is_empty() || e.is_empty() {
+        return Err(err_key("Invalid RSA public key"));
+    }
+    Ok(RsaComponents { n, e })
+}
+
+/// The parts of an X.509 certificate needed to extract its key and check its signature
+pub(crate) struct Certificate<'a> {
+    tbs_certificate: &'a [u8],
+    signature_algorithm: &'a [u8],
+    signature: &'a [u8],
+    pub spki: &'a [u8],
+}
+
+impl<'a> Certificate<'a> {
+    pub fn parse(der: &'a [u8]) -> Result<Self, Error> {
+        let mut certificate = DerReader::new(der)
+            .expect(TAG_SEQUENCE)
+            .or(Err(err_cer("Invalid certificate")))?;
+        let tbs = certificate
+            .expect(TAG_SEQUENCE)
+            .or(Err(err_cer("Invalid certificate")))?;
+        let mut algorithm = certificate
+            .expect(TAG_SEQUENCE)
+            .or(Err(err_cer("Invalid certificate signature algorithm")))?;
+        let signature_algorithm = algorithm
+            .expect(TAG_OID)
+            .or(Err(err_cer("Invalid certificate signature algorithm")))?
+            .data;
+        let signature = certificate
+            .expect(TAG_BIT_STRING)
+            .or(Err(err_cer("Invalid certificate signature")))
+            .and_then(|s| bit_string(s.data).or(Err(err_cer("Invalid certificate signature"))))?;
+
+        let mut fields = DerReader::new(tbs.data);
+        if fields.peek_tag() == Some(TAG_VERSION) {
+            fields.next()?;
+        }
+        // serialNumber, signature, issuer, validity, subject
+        for _ in 0..5 {
+            fields.next().or(Err(err_cer("Invalid certificate")))?;
+        }
+        let spki = fields
+            .expect(TAG_SEQUENCE)
+            .or(Err(err_cer("Certificate has no public key")))?
+            .raw;
+
+        Ok(Certificate {
+            tbs_certificate: tbs.raw,
+            signature_algorithm,
+            signature,
+            spki,
+        })
+    }
+
+    /// Check that this certificate was signed by the RSA key of `issuer`
+    pub fn verify_signed_by(&self, issuer: &Certificate) -> Result<(), Error> {
+        let algorithm = match self.signature_algorithm {
+            OID_SHA256_WITH_RSA => &RSA_PKCS1_2048_8192_SHA256,
+            OID_SHA384_WITH_RSA => &RSA_PKCS1_2048_8192_SHA384,
+            OID_SHA512_WITH_RSA => &RSA_PKCS1_2048_8192_SHA512,
+            _ => return Err(err_cer("Unsupported certificate signature algorithm")),
+        };
+        let key = rsa_from_spki(issuer.spki)?;
+        let pkc = RsaPublicKeyComponents {
+            n: &key.n,
+            e: &key.e,
+        };
+        pkc.verify(algorithm, self.tbs_certificate, self.signature)
+            .or(Err(err_cer(
+                "Certificate is not signed by the next certificate in chain",
+            )))
+    }
+}
+
+struct Tlv<'a> {
+    tag: u8,
+    data: &'a [u8],
+    raw: &'a [u8],
+}
+
+struct DerReader<'a> {
+    input: &'a [u8],
+}
+
+impl<'a> DerReader<'a> {
+    fn new(input: &'a [u8]) -> Self {
+        DerReader { input }
+    }
+
+    fn peek_tag(&self) -> Option<u8> {
+        self.input.first().copied()
+    }
+
+    fn next(&mut self) -> Result<Tlv<'a>, Error> {
+        let input = self.input;
+        let tag = *input
+            .first()
+            .ok_or(err_key("Unexpected end of DER input"))?;
+        let first = *input.get(1).ok_or(err_key("Unexpected end of DER input"))?;
+
+        let (len, header) = if first < 0x80 {
+            (first as usize, 2)
+        } else {
+            let count = (first & 0x7f) as usize;
+            if count == 0 || count > 4 {
+                return Err(err_key("Unsupported DER length"));
+            }
+            let bytes = input
+                .get(2..2 + count)
+                .ok_or(err_key("Unexpected end of DER input"))?;
+            let len = bytes.iter().fold(0usize, |acc, b| (acc << 8) | *b as usize);
+            (len, 2 + count)
+        };
+
+        let end = header
+            .checked_add(len)
+            .filter(|end| *end <= input.len())
+            .ok_or(err_key("Unexpected end of DER input"))?;
+        self.input = &input[end..];
+
+        Ok(Tlv {
+            tag,
+            data: &input[header..end],
+            raw: &input[..end],
+        })
+    }
+
+    fn expect(&mut self, tag: u8) -> Result<Tlv<'a>, Error> {
+        let tlv = self.next()?;
+        if tlv.tag != tag {
+            return Err(err_key("Unexpected DER tag"));
+        }
+        Ok(tlv)
+    }
+}
+
+impl<'a> Tlv<'a> {
+    fn expect(&mut self, tag: u8) -> Result<Tlv<'a>, Error> {
+        let mut reader = DerReader::new(self.data);
+        let tlv = reader.expect(tag)?;
+        self.data = reader.input;
+        Ok(tlv)
+    }
+}
+
+fn bit_string(data: &[u8]) -> Result<&[u8], Error> {
+    match data.split_first() {
+        Some((0, bits)) => Ok(bits),
+        _ => Err(err_key("Unsupported DER bit string")),
+    }
+}
+
+fn unsigned_integer(data: &[u8]) -> Vec<u8> {
+    let start = data.iter().position(|b| *b != 0).unwrap_or(data.len());
+    data[start..].to_vec()
+}
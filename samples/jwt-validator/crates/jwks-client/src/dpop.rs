@@ -0,0 +1,366 @@
+//! DPoP proofs (RFC 9449), which bind an access token to a key held by the client.
+//!
+//! A proof is a JWT signed with the private key of the client. The public key is sent in the
+//! `jwk` header of the proof, and its thumbprint is bound to the access token via `cnf.jkt`.
+
+use std::time::{Duration, SystemTime};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::digest::{digest, SHA256};
+use serde_json::Value;
+
+use crate::error::*;
+use crate::jwt::{Header, Jwt, Payload};
+use crate::keyset::{decode_segment, verify_signature, JwtKey};
+
+/// Signature algorithms supported for proofs
+pub const ALGORITHMS: &[&str] = &["RS256", "ES256", "EdDSA"];
+
+/// Rules applied when validating proofs
+#[derive(Debug, Clone, PartialEq)]
+pub struct DpopOptions {
+    /// Reject proofs issued (`iat`) longer than this ago.
+    ///
+    /// The default is 300 seconds.
+    pub max_age: Duration,
+    /// Tolerated clock skew between the client and this host.
+    ///
+    /// The default is 60 seconds.
+    pub leeway: Duration,
+    /// Accepted signature algorithms, e.g. the `dpop_signing_alg_values_supported` of the IdP.
+    ///
+    /// The default is all of `ALGORITHMS`.
+    pub algorithms: Vec<String>,
+}
+
+impl Default for DpopOptions {
+    fn default() -> Self {
+        DpopOptions {
+            max_age: Duration::from_secs(300),
+            leeway: Duration::from_secs(60),
+            algorithms: ALGORITHMS.iter().map(|alg| alg.to_string()).collect(),
+        }
+    }
+}
+
+/// A DPoP proof whose signature was verified with the public key in its `jwk` header
+#[derive(Debug)]
+pub struct DpopProof {
+    jwt: Jwt,
+    thumbprint: String,
+}
+
+impl DpopProof {
+    /// Decode `proof` and verify its signature with the public key in its `jwk` header.
+    ///
+    /// This only shows that the client holds the private key. Use `validate_request_time` and
+    /// `validate_binding` to check what the proof was created for.
+    pub fn verify(proof: &str, options: &DpopOptions) -> Result<DpopProof, Error> {
+        let segments: Vec<&str> = proof.split('.').collect();
+        if segments.len() != 3 {
+            return Err(err_dpop("Proof does not have 3 segments"));
+        }
+
+        let header = Header::new(decode_segment::<Value>(segments[0])?);
+        let payload = Payload::new(decode_segment::<Value>(segments[1])?);
+
+        if header.typ() != Some("dpop+jwt") {
+            return Err(err_dpop("Proof does not have typ dpop+jwt"));
+        }
+        let alg = match header.alg() {
+            Some(alg) if options.algorithms.iter().any(|allowed| allowed == alg) => alg,
+            alg => {
+                return Err(Error::UnsupportedAlgorithm {
+                    alg: alg.map(String::from),
+                })
+            }
+        };
+
+        let mut jwk = header
+            .get_object("jwk")
+            .cloned()
+            .ok_or(err_dpop("Proof has no jwk header"))?;
+        if jwk.contains_key("d") {
+            return Err(err_dpop("Proof jwk header contains a private key"));
+        }
+        // the key is identified by its thumbprint, a kid is optional
+        jwk.entry("kid")
+            .or_insert_with(|| Value::String(String::new()));
+        let key = serde_json::from_value::<JwtKey>(Value::Object(jwk)).map_err(|e| Error::Key {
+            reason: "Failed to parse proof jwk header",
+            source: Some(Box::new(e)),
+        })?;
+
+        let body = format!("{}.{}", segments[0], segments[1]);
+        verify_signature(&key, alg, &body, segments[2])?;
+
+        Ok(DpopProof {
+            thumbprint: key.thumbprint()?,
+            jwt: Jwt::new(header, payload, segments[2].to_string()),
+        })
+    }
+
+    pub fn jwt(&self) -> &Jwt {
+        &self.jwt
+    }
+
+    pub fn jti(&self) -> Option<&str> {
+        self.jwt.payload().jti()
+    }
+
+    /// JWK SHA-256 Thumbprint of the key that signed the proof
+    pub fn thumbprint(&self) -> &str {
+        &self.thumbprint
+    }
+
+    /// Time after which the proof is too old, so a record of its `jti` is no longer needed to
+    /// detect replays
+    pub fn expire_time(&self, options: &DpopOptions) -> Option<SystemTime> {
+        let iat = self.jwt.payload().iat()?;
+        Some(SystemTime::UNIX_EPOCH + Duration::from_secs(iat) + options.max_age + options.leeway)
+    }
+
+    /// Check that the proof was created for a request with `method` to `url` at about `time`.
+    ///
+    /// Query and fragment of `url` and of the `htu` claim are ignored. Replays of the same
+    /// proof (same `jti`) must be detected by the caller.
+    pub fn validate_request_time(
+        &self,
+        method: &str,
+        url: &str,
+        options: &DpopOptions,
+        time: SystemTime,
+    ) -> Result<(), Error> {
+        let payload = self.jwt.payload();
+
+        if self.jti().unwrap_or_default().is_empty() {
+            return Err(err_dpop("Proof has no jti claim"));
+        }
+        if payload.get_str("htm") != Some(method) {
+            return Err(err_dpop("Proof was created for another HTTP method"));
+        }
+        let htu = payload
+            .get_str("htu")
+            .ok_or(err_dpop("Proof has no htu claim"))?;
+        if without_query(htu) != without_query(url) {
+            return Err(err_dpop("Proof was created for another URL"));
+        }
+
+        let iat = payload.iat().ok_or(err_dpop("Proof has no iat claim"))?;
+        let now = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let leeway = options.leeway.as_secs();
+        if iat > now.saturating_add(leeway) {
+            return Err(err_dpop("Proof was issued in the future"));
+        }
+        if now
+            > iat
+                .saturating_add(options.max_age.as_secs())
+                .saturating_add(leeway)
+        {
+            return Err(err_dpop("Proof is too old"));
+        }
+
+        Ok(())
+    }
+
+    /// Check that the proof was created for `access_token` (`ath` claim) and that `token`,
+    /// the verified access token, is bound to the key of the proof (`cnf.jkt` claim).
+    pub fn validate_binding(&self, access_token: &str, token: &Jwt) -> Result<(), Error> {
+        let ath = URL_SAFE_NO_PAD.encode(digest(&SHA256, access_token.as_bytes()));
+        if self.jwt.payload().get_str("ath") != Some(ath.as_str()) {
+            return Err(err_dpop("Proof was created for another access token"));
+        }
+
+        match confirmation_thumbprint(token) {
+            Some(jkt) if jkt == self.thumbprint => Ok(()),
+            Some(_) => Err(err_dpop("Access token is bound to another key")),
+            None => Err(err_dpop("Access token is not bound to a key")),
+        }
+    }
+}
+
+/// The `cnf.jkt` claim of an access token bound to a DPoP key
+pub fn confirmation_thumbprint(token: &Jwt) -> Option<&str> {
+    token.payload().get_object("cnf")?.get("jkt")?.as_str()
+}
+
+fn without_query(url: &str) -> &str {
+    url.split(['?', '#']).next().unwrap_or(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::signing::{JwtBuilder, SigningKey};
+
+    const EC_PRIVATE_KEY: &str = include_str!("../testdata/ec_private.pem");
+    const ED25519_PRIVATE_KEY: &str = include_str!("../testdata/ed25519_private.pem");
+    const URL: &str = "https://api.example.com/invoices";
+    const ACCESS_TOKEN: &str = "eyJhbGciOiJFUzI1NiJ9.eyJzdWIiOiJhbGljZSJ9.c2ln";
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn key() -> SigningKey {
+        SigningKey::from_pem("client-key", EC_PRIVATE_KEY).unwrap()
+    }
+
+    fn proof_builder(key: &SigningKey) -> JwtBuilder {
+        let jwk = serde_json::to_value(key.public_key().unwrap()).unwrap();
+        let ath = URL_SAFE_NO_PAD.encode(digest(&SHA256, ACCESS_TOKEN.as_bytes()));
+        JwtBuilder::new()
+            .token_type("dpop+jwt")
+            .header("jwk", jwk)
+            .jwt_id("proof-1")
+            .claim("htm", "POST")
+            .claim("htu", URL)
+            .claim("ath", ath)
+    }
+
+    fn access_token(jkt: &str) -> Jwt {
+        Jwt::new(
+            Header::new(json!({ "alg": "ES256" })),
+            Payload::new(json!({ "sub": "alice", "cnf": { "jkt": jkt } })),
+            String::new(),
+        )
+    }
+
+    fn verify(proof: &str) -> Result<DpopProof, Error> {
+        DpopProof::verify(proof, &DpopOptions::default())
+    }
+
+    #[test]
+    fn test_valid_proof() {
+        let key = key();
+        let proof = proof_builder(&key).sign_time(&key, at(1000)).unwrap();
+        let options = DpopOptions::default();
+
+        let proof = verify(&proof).unwrap();
+        assert_eq!(proof.jti(), Some("proof-1"));
+        assert_eq!(
+            proof.thumbprint(),
+            key.public_key().unwrap().thumbprint().unwrap()
+        );
+        assert_eq!(proof.expire_time(&options), Some(at(1360)));
+
+        let url = format!("{}?page=2", URL);
+        assert!(proof
+            .validate_request_time("POST", &url, &options, at(1100))
+            .is_ok());
+        let token = access_token(proof.thumbprint());
+        assert!(proof.validate_binding(ACCESS_TOKEN, &token).is_ok());
+    }
+
+    #[test]
+    fn test_proof_must_match_request() {
+        let key = key();
+        let proof = verify(&proof_builder(&key).sign_time(&key, at(1000)).unwrap()).unwrap();
+        let options = DpopOptions::default();
+        let error = |method, url, time| {
+            proof
+                .validate_request_time(method, url, &options, time)
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert!(error("GET", URL, at(1000)).contains("another HTTP method"));
+        assert!(
+            error("POST", "https://api.example.com/customers", at(1000)).contains("another URL")
+        );
+        assert!(error("POST", URL, at(900)).contains("issued in the future"));
+        assert!(error("POST", URL, at(1361)).contains("too old"));
+
+        let without_jti = proof_builder(&key)
+            .jwt_id("")
+            .sign_time(&key, at(1000))
+            .unwrap();
+        let err = verify(&without_jti)
+            .unwrap()
+            .validate_request_time("POST", URL, &options, at(1000))
+            .unwrap_err();
+        assert_eq!(err.code(), "invalid_dpop_proof");
+    }
+
+    #[test]
+    fn test_proof_must_be_bound_to_access_token() {
+        let key = key();
+        let proof = verify(&proof_builder(&key).sign_time(&key, at(1000)).unwrap()).unwrap();
+        let token = access_token(proof.thumbprint());
+
+        let err = proof.validate_binding("other-token", &token).unwrap_err();
+        assert!(err.to_string().contains("another access token"));
+
+        let other = SigningKey::from_pem("other-key", ED25519_PRIVATE_KEY).unwrap();
+        let token = access_token(&other.public_key().unwrap().thumbprint().unwrap());
+        let err = proof.validate_binding(ACCESS_TOKEN, &token).unwrap_err();
+        assert!(err.to_string().contains("bound to another key"));
+
+        let unbound = Jwt::new(
+            Header::new(json!({})),
+            Payload::new(json!({ "sub": "alice" })),
+            String::new(),
+        );
+        assert!(proof.validate_binding(ACCESS_TOKEN, &unbound).is_err());
+    }
+
+    #[test]
+    fn test_proof_signature_must_match_embedded_key() {
+        let key = key();
+        let other = SigningKey::from_pem("other-key", ED25519_PRIVATE_KEY).unwrap();
+
+        // signed with another key than the one in the header
+        let proof = proof_builder(&key).sign_time(&other, at(1000)).unwrap();
+        assert!(verify(&proof).is_err());
+
+        let proof = proof_builder(&key).sign_time(&key, at(1000)).unwrap();
+        let mut segments: Vec<&str> = proof.split('.').collect();
+        let tampered = URL_SAFE_NO_PAD.encode(r#"{"htm":"DELETE"}"#);
+        segments[1] = &tampered;
+        assert_eq!(
+            verify(&segments.join(".")).unwrap_err().code(),
+            "invalid_signature"
+        );
+    }
+
+    #[test]
+    fn test_rejects_invalid_proofs() {
+        let key = key();
+
+        let wrong_type = proof_builder(&key)
+            .token_type("JWT")
+            .sign_time(&key, at(1000))
+            .unwrap();
+        assert!(verify(&wrong_type)
+            .unwrap_err()
+            .to_string()
+            .contains("typ dpop+jwt"));
+
+        let mut private_jwk = serde_json::to_value(key.public_key().unwrap()).unwrap();
+        private_jwk["d"] = json!("AAAA");
+        let private = proof_builder(&key)
+            .header("jwk", private_jwk)
+            .sign_time(&key, at(1000))
+            .unwrap();
+        assert!(verify(&private)
+            .unwrap_err()
+            .to_string()
+            .contains("private key"));
+
+        let proof = proof_builder(&key).sign_time(&key, at(1000)).unwrap();
+        let options = DpopOptions {
+            algorithms: vec!["RS256".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            DpopProof::verify(&proof, &options).unwrap_err().code(),
+            "unsupported_algorithm"
+        );
+        assert!(verify("not-a-proof").is_err());
+    }
+}
@@ -1,24 +1,178 @@
-use std::fmt::{Display, Formatter};
 use std::fmt;
+use std::fmt::{Display, Formatter};
+
+/// Underlying cause of an error (serde, base64, ring, HTTP, ...)
+pub type Source = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Token is not of the form "HEADER.PAYLOAD.SIGNATURE"
+    Malformed {
+        reason: &'static str,
+        source: Option<Source>,
+    },
+    /// JWT header could not be decoded
+    Header { source: Source },
+    /// JWT payload could not be decoded
+    Payload { source: Source },
+    /// Payload could not be deserialized into the requested type
+    Claims { source: Source },
+    /// The `alg` header is missing or not supported
+    UnsupportedAlgorithm { alg: Option<String> },
+    /// The token has no `kid` header
+    MissingKid,
+    /// No key with the `kid` of the token is known
+    UnknownKid { kid: String },
+    /// Problem with a key, e.g. it could not be decoded or parsed
+    Key {
+        reason: &'static str,
+        source: Option<Source>,
+    },
+    /// Problem with a certificate
+    Certificate {
+        reason: &'static str,
+        source: Option<Source>,
+    },
+    /// Signature could not be decoded or does not match the key
+    Signature {
+        reason: &'static str,
+        source: Option<Source>,
+    },
+    /// Encrypted token (JWE) could not be decrypted
+    Decryption {
+        reason: &'static str,
+        source: Option<Source>,
+    },
+    /// DPoP proof is invalid or not bound to the request or access token
+    Dpop { reason: &'static str },
+    /// Token has expired
+    Expired { exp: u64, now: u64 },
+    /// Not Before (nbf) is set and it's too early to use the token
+    Early { nbf: u64, now: u64 },
+    /// Token was issued (iat) longer than the maximum age ago
+    TooOld { iat: u64, max_age: u64, now: u64 },
+    /// A claim required for validation is missing
+    MissingClaim { claim: &'static str },
+    /// Could not download key set
+    Connection { url: String, source: Option<Source> },
+    /// Key set document could not be parsed
+    KeySet { source: Source },
+    /// A claim validation rule could not be compiled
+    InvalidRule { claim: String, source: Source },
+}
+
+impl Error {
+    /// Stable, machine-readable identifier of the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Malformed { .. } => "malformed_token",
+            Error::Header { .. } => "invalid_header",
+            Error::Payload { .. } => "invalid_payload",
+            Error::Claims { .. } => "invalid_claims",
+            Error::UnsupportedAlgorithm { .. } => "unsupported_algorithm",
+            Error::MissingKid => "missing_kid",
+            Error::UnknownKid { .. } => "unknown_kid",
+            Error::Key { .. } => "invalid_key",
+            Error::Certificate { .. } => "invalid_certificate",
+            Error::Signature { .. } => "invalid_signature",
+            Error::Decryption { .. } => "decryption_failed",
+            Error::Dpop { .. } => "invalid_dpop_proof",
+            Error::Expired { .. } => "token_expired",
+            Error::Early { .. } => "token_not_yet_valid",
+            Error::TooOld { .. } => "token_too_old",
+            Error::MissingClaim { .. } => "missing_claim",
+            Error::Connection { .. } => "jwks_unavailable",
+            Error::KeySet { .. } => "invalid_jwks",
+            Error::InvalidRule { .. } => "invalid_rule",
+        }
+    }
 
-#[derive(Debug, PartialEq)]
-pub struct Error {
-    /// Debug message associated with error
-    pub msg: &'static str,
-    pub typ: Type,
+    /// Coarse category of the error
+    pub fn typ(&self) -> Type {
+        match self {
+            Error::Malformed { .. }
+            | Error::UnsupportedAlgorithm { .. }
+            | Error::Decryption { .. }
+            | Error::Dpop { .. } => Type::Invalid,
+            Error::Header { .. } => Type::Header,
+            Error::Payload { .. } | Error::Claims { .. } | Error::MissingClaim { .. } => {
+                Type::Payload
+            }
+            Error::MissingKid | Error::UnknownKid { .. } | Error::Key { .. } => Type::Key,
+            Error::Certificate { .. } => Type::Certificate,
+            Error::Signature { .. } => Type::Signature,
+            Error::Expired { .. } | Error::TooOld { .. } => Type::Expired,
+            Error::Early { .. } => Type::Early,
+            Error::Connection { .. } => Type::Connection,
+            Error::KeySet { .. } | Error::InvalidRule { .. } => Type::Internal,
+        }
+    }
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}: {}", self.typ, self.msg)
+        match self {
+            Error::Malformed { reason, .. } => write!(f, "Malformed token: {}", reason),
+            Error::Header { .. } => write!(f, "Failed to decode header"),
+            Error::Payload { .. } => write!(f, "Failed to decode payload"),
+            Error::Claims { .. } => write!(f, "Failed to deserialize claims"),
+            Error::UnsupportedAlgorithm { alg: Some(alg) } => {
+                write!(f, "Unsupported algorithm {}", alg)
+            }
+            Error::UnsupportedAlgorithm { alg: None } => write!(f, "No algorithm specified"),
+            Error::MissingKid => write!(f, "No key id"),
+            Error::UnknownKid { kid } => write!(f, "JWT key {} does not exist", kid),
+            Error::Key { reason, .. } => write!(f, "Invalid key: {}", reason),
+            Error::Certificate { reason, .. } => write!(f, "Invalid certificate: {}", reason),
+            Error::Signature { reason, .. } => write!(f, "Invalid signature: {}", reason),
+            Error::Decryption { reason, .. } => write!(f, "Failed to decrypt token: {}", reason),
+            Error::Dpop { reason } => write!(f, "Invalid DPoP proof: {}", reason),
+            Error::Expired { exp, now } => write!(f, "Token expired at {} (now {})", exp, now),
+            Error::Early { nbf, now } => {
+                write!(
+                    f,
+                    "Too early to use token, valid from {} (now {})",
+                    nbf, now
+                )
+            }
+            Error::TooOld { iat, max_age, now } => write!(
+                f,
+                "Token issued at {} is older than {} seconds (now {})",
+                iat, max_age, now
+            ),
+            Error::MissingClaim { claim } => write!(f, "Token does not have '{}' claim", claim),
+            Error::Connection { url, .. } => write!(f, "Could not download JWKS from {}", url),
+            Error::KeySet { .. } => write!(f, "Failed to parse keys"),
+            Error::InvalidRule { claim, .. } => {
+                write!(f, "Invalid validation rule for '{}' claim", claim)
+            }
+        }
     }
 }
 
 impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        let source = match self {
+            Error::Header { source }
+            | Error::Payload { source }
+            | Error::Claims { source }
+            | Error::KeySet { source }
+            | Error::InvalidRule { source, .. } => Some(source),
+            Error::Malformed { source, .. }
+            | Error::Key { source, .. }
+            | Error::Certificate { source, .. }
+            | Error::Signature { source, .. }
+            | Error::Decryption { source, .. }
+            | Error::Connection { source, .. } => source.as_ref(),
+            _ => None,
+        };
+        source.map(|s| s.as_ref() as &(dyn std::error::Error + 'static))
+    }
 }
 
 /// Type of error encountered
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Type {
     /// Token is invalid
     /// For example, the format of the token is not "HEADER.PAYLOAD.SIGNATURE"
@@ -43,46 +197,41 @@ pub enum Type {
     Internal,
 }
 
-pub(crate) fn err(msg: &'static str, typ: Type) -> Error {
-    Error { msg, typ }
-}
-
-pub(crate) fn err_inv(msg: &'static str) -> Error {
-    err(msg, Type::Invalid)
-}
-
-pub(crate) fn err_exp(msg: &'static str) -> Error {
-    err(msg, Type::Expired)
-}
-
-pub(crate) fn err_nbf(msg: &'static str) -> Error {
-    err(msg, Type::Early)
-}
-
-pub(crate) fn err_cer(msg: &'static str) -> Error {
-    err(msg, Type::Certificate)
-}
-
-pub(crate) fn err_key(msg: &'static str) -> Error {
-    err(msg, Type::Key)
+pub(crate) fn err_inv(reason: &'static str) -> Error {
+    Error::Malformed {
+        reason,
+        source: None,
+    }
 }
 
-pub(crate) fn err_con(msg: &'static str) -> Error {
-    err(msg, Type::Connection)
+pub(crate) fn err_cer(reason: &'static str) -> Error {
+    Error::Certificate {
+        reason,
+        source: None,
+    }
 }
 
-pub(crate) fn err_hea(msg: &'static str) -> Error {
-    err(msg, Type::Header)
+pub(crate) fn err_key(reason: &'static str) -> Error {
+    Error::Key {
+        reason,
+        source: None,
+    }
 }
 
-pub(crate) fn err_pay(msg: &'static str) -> Error {
-    err(msg, Type::Payload)
+pub(crate) fn err_sig(reason: &'static str) -> Error {
+    Error::Signature {
+        reason,
+        source: None,
+    }
 }
 
-pub(crate) fn err_sig(msg: &'static str) -> Error {
-    err(msg, Type::Signature)
+pub(crate) fn err_dec(reason: &'static str) -> Error {
+    Error::Decryption {
+        reason,
+        source: None,
+    }
 }
 
-pub(crate) fn err_int(msg: &'static str) -> Error {
-    err(msg, Type::Internal)
+pub(crate) fn err_dpop(reason: &'static str) -> Error {
+    Error::Dpop { reason }
 }
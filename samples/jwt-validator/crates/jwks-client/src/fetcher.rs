@@ -0,0 +1,121 @@
+//! Downloading of key sets.
+//!
+//! `KeyStore` downloads key sets with a `JwksFetcher`. `SpinFetcher` uses Spin's outbound HTTP
+//! and is the default. Other hosts can provide their own fetcher, and tests can use
+//! `MockFetcher` to simulate key rotation, cache headers and failures without any network.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use spin_sdk::http::{send, RequestBuilder, Response};
+
+use crate::error::Error;
+
+/// A downloaded key set document
+#[derive(Debug, Clone, PartialEq)]
+pub struct JwksResponse {
+    pub status: u16,
+    pub body: Vec<u8>,
+    /// Value of the `cache-control` header, if any
+    pub cache_control: Option<String>,
+}
+
+/// Downloads key set documents for a `KeyStore`.
+///
+/// Spin components are single threaded, so the returned futures don't need to be `Send`.
+#[allow(async_fn_in_trait)]
+pub trait JwksFetcher {
+    /// Download the document at `url`. Failures to reach the server are reported as
+    /// `Error::Connection`, responses of any status are returned as is.
+    async fn fetch(&self, url: &str) -> Result<JwksResponse, Error>;
+}
+
+/// Downloads key sets using Spin's outbound HTTP
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SpinFetcher;
+
+impl JwksFetcher for SpinFetcher {
+    async fn fetch(&self, url: &str) -> Result<JwksResponse, Error> {
+        let request = RequestBuilder::new(spin_sdk::http::Method::Get, url)
+            .header("Accept", "application/json")
+            .build();
+        let response: Response = send(request).await.map_err(|e| Error::Connection {
+            url: url.to_string(),
+            source: Some(e.to_string().into()),
+        })?;
+
+        Ok(JwksResponse {
+            status: *response.status(),
+            cache_control: response
+                .header("cache-control")
+                .and_then(|value| value.as_str())
+                .map(String::from),
+            body: response.into_body(),
+        })
+    }
+}
+
+/// Serves key sets from memory, e.g. in tests.
+///
+/// Responses can be changed at any time through `KeyStore::fetcher`, e.g. to simulate a key
+/// rotation. Requests to URLs without a response fail like an unreachable server.
+#[derive(Debug, Default)]
+pub struct MockFetcher {
+    responses: RefCell<HashMap<String, JwksResponse>>,
+    requests: RefCell<Vec<String>>,
+}
+
+impl MockFetcher {
+    pub fn new() -> MockFetcher {
+        MockFetcher::default()
+    }
+
+    /// Serve `body` at `url` with status 200 and the given `cache-control` header
+    pub fn respond(&self, url: &str, body: &str, cache_control: Option<&str>) {
+        self.respond_with(
+            url,
+            JwksResponse {
+                status: 200,
+                body: body.as_bytes().to_vec(),
+                cache_control: cache_control.map(String::from),
+            },
+        );
+    }
+
+    pub fn respond_with(&self, url: &str, response: JwksResponse) {
+        self.responses
+            .borrow_mut()
+            .insert(url.to_string(), response);
+    }
+
+    /// Make requests to `url` fail like an unreachable server
+    pub fn fail(&self, url: &str) {
+        self.responses.borrow_mut().remove(url);
+    }
+
+    /// URLs requested so far, oldest first
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.borrow().clone()
+    }
+}
+
+impl JwksFetcher for MockFetcher {
+    async fn fetch(&self, url: &str) -> Result<JwksResponse, Error> {
+        self.requests.borrow_mut().push(url.to_string());
+
+        self.responses
+            .borrow()
+            .get(url)
+            .cloned()
+            .ok_or_else(|| Error::Connection {
+                url: url.to_string(),
+                source: Some("no response configured".into()),
+            })
+    }
+}
+
+impl<F: JwksFetcher> JwksFetcher for &F {
+    async fn fetch(&self, url: &str) -> Result<JwksResponse, Error> {
+        (**self).fetch(url).await
+    }
+}
@@ -0,0 +1,596 @@
+//! Decryption of JWE tokens in compact serialization (RFC 7516).
+//!
+//! Supported key management algorithms are `RSA-OAEP`, `RSA-OAEP-256` and `ECDH-ES` (P-256)
+//! with a private key, and `dir`, `A128GCMKW` and `A256GCMKW` with a secret shared with the
+//! issuer. Content encryption algorithms are `A128GCM` and `A256GCM`.
+//!
+//! RSA decryption uses the `rsa` crate, which is affected by RUSTSEC-2023-0071: its timing may
+//! leak the private key to an attacker who can time many decryptions. Prefer `ECDH-ES` or the
+//! shared-secret algorithms where the issuer supports them. ring supports neither RSA
+//! decryption nor key agreement with a static private key.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use p256::pkcs8::DecodePrivateKey as _;
+use ring::aead::{Aad, Algorithm, LessSafeKey, Nonce, UnboundKey, AES_128_GCM, AES_256_GCM};
+use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::{BigUint, Oaep, RsaPrivateKey};
+use serde_json::Value;
+
+use crate::der::{self, KeyType};
+use crate::error::*;
+use crate::jwk::{rejected, PrivateJwk};
+use crate::jwt::Header;
+use crate::keyset::decode_segment;
+
+#[derive(Clone)]
+enum PrivateKey {
+    Rsa(Box<RsaPrivateKey>),
+    P256(p256::SecretKey),
+    Secret(Vec<u8>),
+}
+
+/// A key used to decrypt JWE tokens: an RSA key (`RSA-OAEP`, `RSA-OAEP-256`), a P-256 key
+/// (`ECDH-ES`), or a secret shared with the issuer used directly (`dir`) or to unwrap the
+/// content key (`A128GCMKW`, `A256GCMKW`)
+#[derive(Clone)]
+pub struct DecryptionKey {
+    kid: String,
+    key: PrivateKey,
+}
+
+impl DecryptionKey {
+    /// Load a private key from a PEM document, either a `PRIVATE KEY` (PKCS#8) holding an RSA
+    /// or P-256 key or an `RSA PRIVATE KEY` (PKCS#1).
+    pub fn from_pem(kid: &str, pem: &str) -> Result<DecryptionKey, Error> {
+        let blocks = der::pem_decode(pem)?;
+        let der = blocks[0].der.as_slice();
+
+        let key = match blocks[0].label.as_str() {
+            "PRIVATE KEY" => match der::pkcs8_key_type(der)? {
+                KeyType::Rsa => PrivateKey::Rsa(Box::new(
+                    rsa::pkcs8::DecodePrivateKey::from_pkcs8_der(der).map_err(rejected)?,
+                )),
+                KeyType::P256 => {
+                    PrivateKey::P256(p256::SecretKey::from_pkcs8_der(der).map_err(rejected)?)
+                }
+                KeyType::Ed25519 => {
+                    return Err(err_key("Ed25519 keys can not be used for decryption"))
+                }
+            },
+            "RSA PRIVATE KEY" => PrivateKey::Rsa(Box::new(
+                RsaPrivateKey::from_pkcs1_der(der).map_err(rejected)?,
+            )),
+            _ => {
+                return Err(err_key(
+                    "Unsupported PEM block, expected a PKCS#8 private key",
+                ))
+            }
+        };
+
+        Ok(DecryptionKey {
+            kid: kid.to_string(),
+            key,
+        })
+    }
+
+    /// Use a 128 or 256 bit secret shared with the token issuer.
+    pub fn from_secret(kid: &str, secret: &[u8]) -> Result<DecryptionKey, Error> {
+        if ![16, 32].contains(&secret.len()) {
+            return Err(err_key("Secret key must have 128 or 256 bits"));
+        }
+
+        Ok(DecryptionKey {
+            kid: kid.to_string(),
+            key: PrivateKey::Secret(secret.to_vec()),
+        })
+    }
+
+    /// Load a key from a JSON Web Key with `kty` of `RSA`, `EC` (P-256) or `oct`.
+    ///
+    /// The key id is taken from the `kid` parameter.
+    pub fn from_jwk(jwk: &str) -> Result<DecryptionKey, Error> {
+        let jwk = PrivateJwk::parse(jwk)?;
+        let kid = jwk.kid()?;
+
+        let key = match (jwk.kty.as_str(), jwk.crv.as_deref()) {
+            ("RSA", _) => {
+                let uint = |value: &Option<String>| -> Result<BigUint, Error> {
+                    Ok(BigUint::from_bytes_be(&jwk.param(value)?))
+                };
+                let key = RsaPrivateKey::from_components(
+                    uint(&jwk.n)?,
+                    uint(&jwk.e)?,
+                    uint(&jwk.d)?,
+                    vec![uint(&jwk.p)?, uint(&jwk.q)?],
+                )
+                .map_err(rejected)?;
+                PrivateKey::Rsa(Box::new(key))
+            }
+            ("EC", Some("P-256")) => PrivateKey::P256(
+                p256::SecretKey::from_slice(&jwk.param(&jwk.d)?).map_err(rejected)?,
+            ),
+            ("oct", _) => return DecryptionKey::from_secret(&kid, &jwk.param(&jwk.k)?),
+            _ => return Err(err_key("Unsupported JWK key type")),
+        };
+
+        Ok(DecryptionKey { kid, key })
+    }
+
+    pub fn kid(&self) -> &str {
+        &self.kid
+    }
+
+    /// Whether the key can be used with `alg`, for content keys of `key_len` bytes
+    fn supports(&self, alg: &str, key_len: usize) -> bool {
+        match (&self.key, alg) {
+            (PrivateKey::Rsa(_), "RSA-OAEP" | "RSA-OAEP-256") => true,
+            (PrivateKey::P256(_), "ECDH-ES") => true,
+            (PrivateKey::Secret(secret), "dir") => secret.len() == key_len,
+            (PrivateKey::Secret(secret), "A128GCMKW") => secret.len() == 16,
+            (PrivateKey::Secret(secret), "A256GCMKW") => secret.len() == 32,
+            _ => false,
+        }
+    }
+}
+
+/// A decrypted JWE token
+#[derive(Debug)]
+pub struct Jwe {
+    header: Header,
+    plaintext: Vec<u8>,
+}
+
+impl Jwe {
+    /// The JOSE header, with `alg`, `enc` and optionally `kid` and `cty`
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn plaintext(&self) -> &[u8] {
+        &self.plaintext
+    }
+
+    pub fn into_plaintext(self) -> Vec<u8> {
+        self.plaintext
+    }
+
+    /// Whether the plaintext is a nested JWT according to the `cty` header
+    pub fn is_nested(&self) -> bool {
+        self.header
+            .cty()
+            .is_some_and(|cty| cty.eq_ignore_ascii_case("JWT"))
+    }
+}
+
+/// Decrypt a JWE token with one of `keys`.
+///
+/// The key is selected by the `kid` header. Without `kid`, the first key usable with the
+/// `alg` of the token is used.
+pub(crate) fn decrypt(keys: &[DecryptionKey], token: &str) -> Result<Jwe, Error> {
+    let segments: Vec<&str> = token.split('.').collect();
+    if segments.len() != 5 {
+        return Err(err_inv("JWE does not have 5 segments"));
+    }
+
+    let header = Header::new(
+        decode_segment::<Value>(segments[0]).map_err(|e| Error::Header {
+            source: Box::new(e),
+        })?,
+    );
+    if header.zip().is_some() {
+        return Err(err_dec("Compressed content is not supported"));
+    }
+
+    let alg = match header.alg() {
+        Some(
+            alg @ ("RSA-OAEP" | "RSA-OAEP-256" | "ECDH-ES" | "dir" | "A128GCMKW" | "A256GCMKW"),
+        ) => alg,
+        alg => {
+            return Err(Error::UnsupportedAlgorithm {
+                alg: alg.map(String::from),
+            })
+        }
+    };
+    let (algorithm, enc): (&'static Algorithm, &str) = match header.enc() {
+        Some(enc @ "A128GCM") => (&AES_128_GCM, enc),
+        Some(enc @ "A256GCM") => (&AES_256_GCM, enc),
+        enc => {
+            return Err(Error::UnsupportedAlgorithm {
+                alg: enc.map(String::from),
+            })
+        }
+    };
+
+    let key = match header.kid() {
+        Some(kid) => keys
+            .iter()
+            .find(|key| key.kid == kid)
+            .ok_or_else(|| Error::UnknownKid {
+                kid: kid.to_string(),
+            })?,
+        None => keys
+            .iter()
+            .find(|key| key.supports(alg, algorithm.key_len()))
+            .ok_or(Error::MissingKid)?,
+    };
+    if !key.supports(alg, algorithm.key_len()) {
+        return Err(err_key("Key does not match the token algorithm"));
+    }
+
+    let encrypted_key = decode_part(segments[1])?;
+    let cek = match &key.key {
+        PrivateKey::Rsa(key) => rsa_unwrap(key, alg, &encrypted_key, algorithm.key_len())?,
+        PrivateKey::P256(key) => {
+            if !encrypted_key.is_empty() {
+                return Err(err_dec("ECDH-ES tokens must not have an encrypted key"));
+            }
+            ecdh_es(key, &header, enc, algorithm.key_len())?
+        }
+        PrivateKey::Secret(secret) if alg == "dir" => {
+            if !encrypted_key.is_empty() {
+                return Err(err_dec(
+                    "Direct encryption tokens must not have an encrypted key",
+                ));
+            }
+            secret.clone()
+        }
+        PrivateKey::Secret(secret) => unwrap_key(secret, &header, encrypted_key)?,
+    };
+
+    let iv = decode_part(segments[2])?;
+    let mut in_out = decode_part(segments[3])?;
+    in_out.extend(decode_part(segments[4])?);
+
+    let nonce = Nonce::try_assume_unique_for_key(&iv).or(Err(err_dec("Invalid IV")))?;
+    let cek = UnboundKey::new(algorithm, &cek).or(Err(err_dec("Invalid content key")))?;
+    let plaintext = LessSafeKey::new(cek)
+        .open_in_place(nonce, Aad::from(segments[0].as_bytes()), &mut in_out)
+        .or(Err(err_dec("Content could not be decrypted")))?;
+    let plaintext = plaintext.to_vec();
+
+    Ok(Jwe { header, plaintext })
+}
+
+/// Decrypt the content encryption key.
+///
+/// If the key can not be decrypted, a random key is used instead so the failure only shows when
+/// decrypting the content and does not reveal anything about the padding (RFC 7516, 11.5).
+fn rsa_unwrap(
+    key: &RsaPrivateKey,
+    alg: &str,
+    encrypted_key: &[u8],
+    key_len: usize,
+) -> Result<Vec<u8>, Error> {
+    let padding = match alg {
+        "RSA-OAEP" => Oaep::new::<sha1::Sha1>(),
+        _ => Oaep::new::<sha2::Sha256>(),
+    };
+
+    match key.decrypt(padding, encrypted_key) {
+        Ok(cek) if cek.len() == key_len => Ok(cek),
+        _ => {
+            let mut cek = vec![0; key_len];
+            SystemRandom::new()
+                .fill(&mut cek)
+                .or(Err(err_dec("Failed to generate random key")))?;
+            Ok(cek)
+        }
+    }
+}
+
+/// Derive the content encryption key for direct key agreement with ECDH-ES (RFC 7518, 4.6)
+fn ecdh_es(
+    key: &p256::SecretKey,
+    header: &Header,
+    enc: &str,
+    key_len: usize,
+) -> Result<Vec<u8>, Error> {
+    let epk = header
+        .get_object("epk")
+        .ok_or(err_dec("ECDH-ES token has no ephemeral public key"))?;
+    if epk.get("kty").and_then(Value::as_str) != Some("EC")
+        || epk.get("crv").and_then(Value::as_str) != Some("P-256")
+    {
+        return Err(err_dec("Ephemeral public key is not a P-256 key"));
+    }
+    let coordinate = |name: &str| -> Result<Vec<u8>, Error> {
+        let value = epk.get(name).and_then(Value::as_str).unwrap_or_default();
+        decode_part(value)
+    };
+    let point = [vec![0x04], coordinate("x")?, coordinate("y")?].concat();
+    let epk = p256::PublicKey::from_sec1_bytes(&point).map_err(|e| Error::Decryption {
+        reason: "Invalid ephemeral public key",
+        source: Some(e.to_string().into()),
+    })?;
+
+    let shared_secret = p256::ecdh::diffie_hellman(key.to_nonzero_scalar(), epk.as_affine());
+    let party_info = |name: &str| -> Result<Vec<u8>, Error> {
+        header.get_str(name).map(decode_part).unwrap_or(Ok(vec![]))
+    };
+
+    Ok(concat_kdf(
+        shared_secret.raw_secret_bytes(),
+        enc.as_bytes(),
+        &party_info("apu")?,
+        &party_info("apv")?,
+        key_len,
+    ))
+}
+
+/// Decrypt the content encryption key with AES-GCM key wrapping (RFC 7518, 4.7). The IV and
+/// authentication tag of the wrapped key are in the `iv` and `tag` header parameters.
+fn unwrap_key(secret: &[u8], header: &Header, encrypted_key: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let param = |name: &str| -> Result<Vec<u8>, Error> {
+        let value = header
+            .get_str(name)
+            .ok_or(err_dec("Key wrap parameters iv and tag are missing"))?;
+        decode_part(value)
+    };
+    let iv = param("iv")?;
+    let mut in_out = encrypted_key;
+    in_out.extend(param("tag")?);
+
+    let algorithm = match secret.len() {
+        16 => &AES_128_GCM,
+        _ => &AES_256_GCM,
+    };
+    let nonce = Nonce::try_assume_unique_for_key(&iv).or(Err(err_dec("Invalid key wrap IV")))?;
+    let key = UnboundKey::new(algorithm, secret).or(Err(err_key("Invalid secret key")))?;
+    let cek = LessSafeKey::new(key)
+        .open_in_place(nonce, Aad::empty(), &mut in_out)
+        .or(Err(err_dec("Content key could not be decrypted")))?;
+
+    Ok(cek.to_vec())
+}
+
+/// Single-step KDF from NIST SP 800-56A with SHA-256, as used by ECDH-ES
+fn concat_kdf(z: &[u8], algorithm: &[u8], apu: &[u8], apv: &[u8], key_len: usize) -> Vec<u8> {
+    let mut other_info = vec![];
+    for value in [algorithm, apu, apv] {
+        other_info.extend((value.len() as u32).to_be_bytes());
+        other_info.extend(value);
+    }
+    other_info.extend(((key_len * 8) as u32).to_be_bytes());
+
+    let mut key = vec![];
+    let mut counter = 1u32;
+    while key.len() < key_len {
+        let round = [&counter.to_be_bytes(), z, &other_info].concat();
+        key.extend(digest(&SHA256, &round).as_ref());
+        counter += 1;
+    }
+    key.truncate(key_len);
+    key
+}
+
+fn decode_part(segment: &str) -> Result<Vec<u8>, Error> {
+    URL_SAFE_NO_PAD
+        .decode(segment)
+        .map_err(|e| Error::Malformed {
+            reason: "Failed to decode segment",
+            source: Some(Box::new(e)),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+    use crate::keyset::KeyStore;
+    use crate::signing::SigningKey;
+
+    const RSA_PRIVATE_KEY: &str = include_str!("../testdata/rsa_private.pem");
+    const RSA_PRIVATE_KEY_PKCS1: &str = include_str!("../testdata/rsa_private_pkcs1.pem");
+    const RSA_PRIVATE_JWK: &str = include_str!("../testdata/rsa_private.jwk.json");
+    const EC_PRIVATE_KEY: &str = include_str!("../testdata/ec_private.pem");
+    const EC_PRIVATE_JWK: &str = include_str!("../testdata/ec_private.jwk.json");
+    const ED25519_PRIVATE_KEY: &str = include_str!("../testdata/ed25519_private.pem");
+    const SECRET_A128_JWK: &str = include_str!("../testdata/secret_a128.jwk.json");
+    const SECRET_A256_JWK: &str = include_str!("../testdata/secret_a256.jwk.json");
+    const JWE_RSA_OAEP: &str = include_str!("../testdata/jwe_rsa_oaep.jwe");
+    const JWE_RSA_OAEP_256: &str = include_str!("../testdata/jwe_rsa_oaep_256.jwe");
+    const JWE_ECDH_ES: &str = include_str!("../testdata/jwe_ecdh_es.jwe");
+    const JWE_ECDH_ES_A256GCM: &str = include_str!("../testdata/jwe_ecdh_es_a256gcm.jwe");
+    const JWE_DIR: &str = include_str!("../testdata/jwe_dir.jwe");
+    const JWE_DIR_A256GCM: &str = include_str!("../testdata/jwe_dir_a256gcm.jwe");
+    const JWE_A128GCMKW: &str = include_str!("../testdata/jwe_a128gcmkw.jwe");
+    const JWE_A256GCMKW: &str = include_str!("../testdata/jwe_a256gcmkw.jwe");
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    /// Key store with the decryption keys and the public keys of the nested token signers
+    fn key_store() -> KeyStore {
+        let mut key_store = KeyStore::new();
+        key_store
+            .add_decryption_key(DecryptionKey::from_pem("rsa-signer", RSA_PRIVATE_KEY).unwrap());
+        key_store.add_decryption_key(DecryptionKey::from_pem("ec-signer", EC_PRIVATE_KEY).unwrap());
+        key_store.add_decryption_key(DecryptionKey::from_jwk(SECRET_A128_JWK).unwrap());
+        key_store.add_decryption_key(DecryptionKey::from_jwk(SECRET_A256_JWK).unwrap());
+        for (kid, pem) in [
+            ("rsa-signer", RSA_PRIVATE_KEY),
+            ("ec-signer", EC_PRIVATE_KEY),
+            ("ed-signer", ED25519_PRIVATE_KEY),
+        ] {
+            let signer = SigningKey::from_pem(kid, pem).unwrap();
+            key_store.add_key(&signer.public_key().unwrap());
+        }
+        key_store
+    }
+
+    fn secret(jwk: &str) -> Vec<u8> {
+        let jwk = PrivateJwk::parse(jwk).unwrap();
+        jwk.param(&jwk.k).unwrap()
+    }
+
+    #[test]
+    fn test_decrypt_rsa_oaep_256() {
+        let jwe = key_store().decrypt(JWE_RSA_OAEP_256).unwrap();
+
+        assert_eq!(jwe.header().alg(), Some("RSA-OAEP-256"));
+        assert_eq!(jwe.header().enc(), Some("A128GCM"));
+        assert!(!jwe.is_nested());
+        assert_eq!(jwe.plaintext(), b"Live long and prosper.");
+    }
+
+    #[test]
+    fn test_decrypt_direct() {
+        let jwe = key_store().decrypt(JWE_DIR).unwrap();
+
+        assert_eq!(jwe.header().alg(), Some("dir"));
+        assert_eq!(jwe.header().enc(), Some("A128GCM"));
+        assert!(!jwe.is_nested());
+        assert_eq!(jwe.plaintext(), b"Live long and prosper.");
+    }
+
+    #[test]
+    fn test_decrypt_and_verify_nested_tokens() {
+        let key_store = key_store();
+
+        for (token, alg) in [
+            (JWE_RSA_OAEP, "ES256"),
+            (JWE_ECDH_ES, "RS256"),
+            (JWE_ECDH_ES_A256GCM, "EdDSA"),
+            (JWE_A128GCMKW, "ES256"),
+            (JWE_A256GCMKW, "RS256"),
+            (JWE_DIR_A256GCM, "EdDSA"),
+        ] {
+            let jwt = key_store
+                .decrypt_and_verify_time(token, at(1700001000))
+                .unwrap();
+            assert_eq!(jwt.header().alg(), Some(alg));
+            assert_eq!(jwt.payload().iss(), Some("https://partner.example.com"));
+            assert_eq!(jwt.payload().sub(), Some("bob"));
+        }
+    }
+
+    #[test]
+    fn test_nested_token_is_validated() {
+        let err = key_store()
+            .decrypt_and_verify_time(JWE_ECDH_ES, at(1700009000))
+            .unwrap_err();
+        assert_eq!(err.code(), "token_expired");
+
+        let err = key_store()
+            .decrypt_and_verify_time(JWE_RSA_OAEP_256, at(1700001000))
+            .unwrap_err();
+        assert_eq!(err.code(), "malformed_token");
+    }
+
+    #[test]
+    fn test_decryption_keys_from_pkcs1_and_jwk() {
+        let mut key_store = KeyStore::new();
+        key_store.add_decryption_key(
+            DecryptionKey::from_pem("rsa-signer", RSA_PRIVATE_KEY_PKCS1).unwrap(),
+        );
+        assert!(key_store.decrypt(JWE_RSA_OAEP_256).is_ok());
+
+        let mut key_store = KeyStore::new();
+        key_store.add_decryption_key(DecryptionKey::from_jwk(RSA_PRIVATE_JWK).unwrap());
+        key_store.add_decryption_key(DecryptionKey::from_jwk(EC_PRIVATE_JWK).unwrap());
+        assert!(key_store.decrypt(JWE_RSA_OAEP).is_ok());
+        assert!(key_store.decrypt(JWE_ECDH_ES).is_ok());
+    }
+
+    #[test]
+    fn test_decryption_keys_from_secret() {
+        let mut key_store = KeyStore::new();
+        key_store.add_decryption_key(
+            DecryptionKey::from_secret("partner-a128", &secret(SECRET_A128_JWK)).unwrap(),
+        );
+        assert!(key_store.decrypt(JWE_DIR).is_ok());
+        assert!(key_store.decrypt(JWE_A128GCMKW).is_ok());
+
+        let err = DecryptionKey::from_secret("short", &[0; 20]).err().unwrap();
+        assert_eq!(err.code(), "invalid_key");
+    }
+
+    #[test]
+    fn test_key_selection() {
+        let mut key_store = KeyStore::new();
+        key_store.add_decryption_key(DecryptionKey::from_pem("other", EC_PRIVATE_KEY).unwrap());
+        let err = key_store.decrypt(JWE_ECDH_ES).unwrap_err();
+        assert_eq!(err.code(), "unknown_kid");
+
+        let mut key_store = KeyStore::new();
+        key_store.add_decryption_key(DecryptionKey::from_secret("other", &[0; 16]).unwrap());
+        // without kid, a key of the size of the content key is required
+        let err = key_store.decrypt(JWE_DIR_A256GCM).unwrap_err();
+        assert_eq!(err.code(), "missing_kid");
+
+        let mut key_store = KeyStore::new();
+        key_store.add_decryption_key(
+            DecryptionKey::from_secret("partner-a256", &secret(SECRET_A128_JWK)).unwrap(),
+        );
+        let err = key_store.decrypt(JWE_A256GCMKW).unwrap_err();
+        assert_eq!(err.code(), "invalid_key");
+    }
+
+    #[test]
+    fn test_tampered_token_is_rejected() {
+        for (token, segment) in [
+            (JWE_ECDH_ES, 3),
+            (JWE_A256GCMKW, 3),
+            (JWE_A128GCMKW, 1),
+            (JWE_DIR, 4),
+        ] {
+            let mut segments: Vec<String> = token.split('.').map(String::from).collect();
+            let mut bytes = URL_SAFE_NO_PAD.decode(&segments[segment]).unwrap();
+            bytes[0] ^= 1;
+            segments[segment] = URL_SAFE_NO_PAD.encode(bytes);
+
+            let err = key_store().decrypt(&segments.join(".")).unwrap_err();
+            assert_eq!(err.code(), "decryption_failed");
+        }
+    }
+
+    #[test]
+    fn test_wrong_rsa_key_fails_on_content() {
+        let mut segments: Vec<String> = JWE_RSA_OAEP.split('.').map(String::from).collect();
+        let mut encrypted_key = URL_SAFE_NO_PAD.decode(&segments[1]).unwrap();
+        encrypted_key[10] ^= 1;
+        segments[1] = URL_SAFE_NO_PAD.encode(encrypted_key);
+
+        let err = key_store().decrypt(&segments.join(".")).unwrap_err();
+        assert_eq!(err.code(), "decryption_failed");
+    }
+
+    #[test]
+    fn test_rejects_unsupported_tokens() {
+        let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"RSA1_5","enc":"A128GCM"}"#);
+        let err = key_store()
+            .decrypt(&format!("{}.aaaa.aaaa.aaaa.aaaa", header))
+            .unwrap_err();
+        assert_eq!(err.code(), "unsupported_algorithm");
+
+        let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"RSA-OAEP","enc":"A128CBC-HS256"}"#);
+        let err = key_store()
+            .decrypt(&format!("{}..aaaa.aaaa.aaaa", header))
+            .unwrap_err();
+        assert_eq!(err.code(), "unsupported_algorithm");
+
+        let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"A128GCMKW","enc":"A128GCM"}"#);
+        let err = key_store()
+            .decrypt(&format!("{}.aaaa.aaaa.aaaa.aaaa", header))
+            .unwrap_err();
+        assert_eq!(err.code(), "decryption_failed");
+
+        let err = key_store().decrypt("a.b.c").unwrap_err();
+        assert_eq!(err.code(), "malformed_token");
+    }
+
+    #[test]
+    fn test_concat_kdf() {
+        // RFC 7518, Appendix C
+        let z = [
+            158, 86, 217, 29, 129, 113, 53, 211, 114, 131, 66, 131, 191, 132, 38, 156, 251, 49,
+            110, 163, 218, 128, 106, 72, 246, 218, 167, 121, 140, 254, 144, 196,
+        ];
+        let key = concat_kdf(&z, b"A128GCM", b"Alice", b"Bob", 16);
+        assert_eq!(URL_SAFE_NO_PAD.encode(key), "VqqN6vgjbSBcIijNcacQGg");
+    }
+}
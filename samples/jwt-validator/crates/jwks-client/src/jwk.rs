@@ -0,0 +1,57 @@
+//! Private and secret JSON Web Keys (RFC 7517), as loaded by signing and decryption keys.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::Deserialize;
+
+use crate::error::*;
+
+#[derive(Deserialize)]
+pub(crate) struct PrivateJwk {
+    pub kty: String,
+    pub kid: Option<String>,
+    pub crv: Option<String>,
+    pub n: Option<String>,
+    pub e: Option<String>,
+    pub d: Option<String>,
+    pub p: Option<String>,
+    pub q: Option<String>,
+    pub dp: Option<String>,
+    pub dq: Option<String>,
+    pub qi: Option<String>,
+    pub x: Option<String>,
+    pub y: Option<String>,
+    pub k: Option<String>,
+}
+
+impl PrivateJwk {
+    pub fn parse(jwk: &str) -> Result<PrivateJwk, Error> {
+        serde_json::from_str(jwk).map_err(|e| Error::Key {
+            reason: "Failed to parse JWK",
+            source: Some(Box::new(e)),
+        })
+    }
+
+    /// The key id, required to select the key
+    pub fn kid(&self) -> Result<String, Error> {
+        self.kid.clone().ok_or(err_key("JWK has no kid"))
+    }
+
+    /// Decode a base64url encoded key parameter
+    pub fn param(&self, value: &Option<String>) -> Result<Vec<u8>, Error> {
+        let value = value
+            .as_deref()
+            .ok_or(err_key("JWK is missing a key parameter"))?;
+        URL_SAFE_NO_PAD.decode(value).map_err(|e| Error::Key {
+            reason: "Failed to decode JWK key parameter",
+            source: Some(Box::new(e)),
+        })
+    }
+}
+
+/// Error for a private key that could not be loaded
+pub(crate) fn rejected(e: impl std::fmt::Display) -> Error {
+    Error::Key {
+        reason: "Private key rejected",
+        source: Some(e.to_string().into()),
+    }
+}
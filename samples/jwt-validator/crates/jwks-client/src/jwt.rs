@@ -4,7 +4,7 @@ use std::time::{Duration, SystemTime};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::{Map, Value};
 
-use crate::error::{err_inv, Error};
+use crate::error::Error;
 
 macro_rules! impl_segment {
     () => {
@@ -45,7 +45,9 @@ macro_rules! impl_segment {
         }
 
         pub fn into<T: DeserializeOwned>(&self) -> Result<T, Error> {
-            Ok(serde_json::from_value::<T>(self.json.clone()).or(Err(err_inv("Failed to deserialize segment")))?)
+            serde_json::from_value::<T>(self.json.clone()).map_err(|e| Error::Claims {
+                source: Box::new(e),
+            })
         }
     };
 }
@@ -128,15 +130,27 @@ impl Payload {
     }
 
     pub fn exp(&self) -> Option<u64> {
-        self.get_f64("exp").and_then(|f| Some(f as u64))
+        self.get_numeric_date("exp", f64::floor)
     }
 
     pub fn nbf(&self) -> Option<u64> {
-        self.get_f64("nbf").and_then(|f| Some(f as u64))
+        self.get_numeric_date("nbf", f64::ceil)
     }
 
     pub fn iat(&self) -> Option<u64> {
-        self.get_f64("iat").and_then(|f| Some(f as u64))
+        self.get_numeric_date("iat", f64::floor)
+    }
+
+    /// Read a NumericDate claim as whole seconds since the epoch.
+    ///
+    /// Integers are read as is, fractional values are rounded with `round`, so that
+    /// precision is only ever lost in favour of the stricter check.
+    fn get_numeric_date(&self, key: &str, round: fn(f64) -> f64) -> Option<u64> {
+        self.get_u64(key).or_else(|| {
+            self.get_f64(key)
+                .filter(|f| f.is_finite())
+                .map(|f| round(f).max(0.0) as u64)
+        })
     }
 
     pub fn jti(&self) -> Option<&str> {
@@ -203,6 +217,11 @@ impl Jwt {
         self.expired_time(SystemTime::now())
     }
 
+    /// Whether `time` is past the `exp` claim, `None` without `exp`.
+    ///
+    /// The claim is compared as is, without the leeway of `ValidationOptions`. Tokens returned by
+    /// `KeyStore::verify` have already passed `ValidationOptions::validate_time`, which allows for
+    /// clock skew.
     pub fn expired_time(&self, time: SystemTime) -> Option<bool> {
         match self.payload.expiry() {
             Some(token_time) => Some(time > token_time),
@@ -214,6 +233,8 @@ impl Jwt {
         self.early_time(SystemTime::now())
     }
 
+    /// Whether `time` is before the `nbf` claim, `None` without `nbf`. Like `expired_time`,
+    /// without leeway.
     pub fn early_time(&self, time: SystemTime) -> Option<bool> {
         match self.payload.not_before() {
             Some(token_time) => Some(time < token_time),
@@ -232,7 +253,40 @@ impl Jwt {
         self.valid_time(SystemTime::now())
     }
 
+    /// Whether `time` is within the `nbf` and `exp` claims, `None` if one of them is missing.
+    /// Like `expired_time`, without leeway; use `ValidationOptions::validate_time` to apply it.
     pub fn valid_time(&self, time: SystemTime) -> Option<bool> {
         Some(!self.expired_time(time)? && !self.early_time(time)?)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_numeric_dates_keep_integer_precision() {
+        let payload = Payload::new(json!({ "exp": 9007199254740993u64 }));
+
+        assert_eq!(payload.exp(), Some(9007199254740993));
+    }
+
+    #[test]
+    fn test_fractional_numeric_dates_round_to_stricter_value() {
+        let payload = Payload::new(json!({ "exp": 1000.9, "nbf": 1000.1, "iat": 1000.9 }));
+
+        assert_eq!(payload.exp(), Some(1000));
+        assert_eq!(payload.nbf(), Some(1001));
+        assert_eq!(payload.iat(), Some(1000));
+    }
+
+    #[test]
+    fn test_invalid_numeric_dates() {
+        let payload = Payload::new(json!({ "exp": "tomorrow", "nbf": -5 }));
+
+        assert_eq!(payload.exp(), None);
+        assert_eq!(payload.nbf(), Some(0));
+    }
+}
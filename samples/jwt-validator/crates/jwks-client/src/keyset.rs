@@ -1,41 +1,206 @@
 use std::time::{Duration, SystemTime};
 
-use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
 use regex::Regex;
-use ring::signature::{RsaPublicKeyComponents, RSA_PKCS1_2048_8192_SHA256};
+use ring::digest::{digest, SHA256};
+use ring::signature::{
+    RsaPublicKeyComponents, UnparsedPublicKey, ECDSA_P256_SHA256_FIXED, ED25519,
+    RSA_PKCS1_2048_8192_SHA256,
+};
 use serde::{
     de::DeserializeOwned,
     {Deserialize, Serialize},
 };
-use serde_json::Value;
-use spin_sdk::http::{send, RequestBuilder, Response};
+use serde_json::{json, Value};
+use spin_sdk::http::Response;
 
+use crate::der::{self, Certificate, PublicKey};
 use crate::error::*;
+use crate::fetcher::{JwksFetcher, SpinFetcher};
+use crate::jwe::{self, DecryptionKey, Jwe};
 use crate::jwt::*;
+use crate::validation::ValidationOptions;
 
 type HeaderBody = String;
 pub type Signature = String;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct JwtKey {
-    #[serde(default)] // https://github.com/jfbilodeau/jwks-client/issues/1
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    // https://github.com/jfbilodeau/jwks-client/issues/1
     pub e: String,
     pub kty: String,
     pub alg: Option<String>,
-    #[serde(default)] // https://github.com/jfbilodeau/jwks-client/issues/1
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    // https://github.com/jfbilodeau/jwks-client/issues/1
     pub n: String,
     pub kid: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub x5c: Option<Vec<String>>,
+    /// Curve of `EC` (`P-256`) and `OKP` (`Ed25519`) keys
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crv: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub x: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub y: Option<String>,
 }
 
 impl JwtKey {
     pub fn new(kid: &str, n: &str, e: &str) -> JwtKey {
         JwtKey {
             e: e.to_owned(),
-            kty: "JTW".to_string(),
+            kty: "RSA".to_string(),
             alg: Some("RS256".to_string()),
             n: n.to_owned(),
             kid: kid.to_owned(),
+            x5c: None,
+            crv: None,
+            x: None,
+            y: None,
+        }
+    }
+
+    /// Create a key from a PEM document.
+    ///
+    /// Supported are `PUBLIC KEY` (SubjectPublicKeyInfo of an RSA, P-256 or Ed25519 key),
+    /// `RSA PUBLIC KEY` (PKCS#1) and `CERTIFICATE` blocks. Multiple certificates are treated as a chain, leaf first.
+    pub fn from_pem(kid: &str, pem: &str) -> Result<JwtKey, Error> {
+        let blocks = der::pem_decode(pem)?;
+
+        if blocks.iter().all(|b| b.label == "CERTIFICATE") {
+            let chain: Vec<&[u8]> = blocks.iter().map(|b| b.der.as_slice()).collect();
+            let mut key = JwtKey::from_certificate_chain(kid, &chain)?;
+            key.x5c = Some(blocks.iter().map(|b| STANDARD.encode(&b.der)).collect());
+            return Ok(key);
+        }
+
+        match blocks[0].label.as_str() {
+            "PUBLIC KEY" => {
+                JwtKey::from_public_key(kid, der::public_key_from_spki(&blocks[0].der)?)
+            }
+            "RSA PUBLIC KEY" => {
+                JwtKey::from_public_key(kid, PublicKey::Rsa(der::rsa_from_pkcs1(&blocks[0].der)?))
+            }
+            _ => Err(err_key("Unsupported PEM block")),
+        }
+    }
+
+    /// Create a key from a DER encoded SubjectPublicKeyInfo or PKCS#1 RSA public key.
+    pub fn from_der(kid: &str, der: &[u8]) -> Result<JwtKey, Error> {
+        let public_key = der::public_key_from_spki(der)
+            .or_else(|_| der::rsa_from_pkcs1(der).map(PublicKey::Rsa))?;
+
+        JwtKey::from_public_key(kid, public_key)
+    }
+
+    /// Create a key from an X.509 certificate chain as found in the `x5c` JWK parameter.
+    ///
+    /// Certificates are base64 (not base64url) encoded DER, leaf first. Every certificate
+    /// must be signed by the one following it. The chain is not checked against any trust
+    /// anchor, so only use keys from sources you trust.
+    pub fn from_x5c(kid: &str, x5c: &[String]) -> Result<JwtKey, Error> {
+        let chain = x5c
+            .iter()
+            .map(|c| STANDARD.decode(c))
+            .collect::<Result<Vec<Vec<u8>>, _>>()
+            .map_err(|e| Error::Certificate {
+                reason: "Failed to decode x5c certificate",
+                source: Some(Box::new(e)),
+            })?;
+        let chain: Vec<&[u8]> = chain.iter().map(Vec::as_slice).collect();
+
+        let mut key = JwtKey::from_certificate_chain(kid, &chain)?;
+        key.x5c = Some(x5c.to_vec());
+
+        Ok(key)
+    }
+
+    fn from_certificate_chain(kid: &str, chain: &[&[u8]]) -> Result<JwtKey, Error> {
+        let certificates = chain
+            .iter()
+            .map(|der| Certificate::parse(der))
+            .collect::<Result<Vec<Certificate>, Error>>()?;
+        let leaf = certificates
+            .first()
+            .ok_or(err_cer("Certificate chain is empty"))?;
+
+        for pair in certificates.windows(2) {
+            pair[0].verify_signed_by(&pair[1])?;
+        }
+
+        JwtKey::from_public_key(kid, der::public_key_from_spki(leaf.spki)?)
+    }
+
+    /// JWK SHA-256 Thumbprint (RFC 7638) of the key, base64url encoded
+    pub fn thumbprint(&self) -> Result<String, Error> {
+        let quote = |value: &str| Value::String(value.to_string()).to_string();
+        let param = |value: &Option<String>| {
+            value.as_deref().map(quote).ok_or(err_key(
+                "Key is missing a parameter required for the thumbprint",
+            ))
+        };
+
+        // the required members in lexicographic order, without whitespace
+        let members = match self.kty.as_str() {
+            "RSA" if !self.n.is_empty() && !self.e.is_empty() => format!(
+                r#"{{"e":{},"kty":"RSA","n":{}}}"#,
+                quote(&self.e),
+                quote(&self.n)
+            ),
+            "EC" => format!(
+                r#"{{"crv":{},"kty":"EC","x":{},"y":{}}}"#,
+                param(&self.crv)?,
+                param(&self.x)?,
+                param(&self.y)?
+            ),
+            "OKP" => format!(
+                r#"{{"crv":{},"kty":"OKP","x":{}}}"#,
+                param(&self.crv)?,
+                param(&self.x)?
+            ),
+            _ => return Err(err_key("Unsupported key type for the thumbprint")),
+        };
+
+        Ok(URL_SAFE_NO_PAD.encode(digest(&SHA256, members.as_bytes())))
+    }
+
+    /// Whether the key carries its own public key parameters, rather than only an `x5c` chain
+    fn has_key_material(&self) -> bool {
+        match self.kty.as_str() {
+            "EC" => self.x.is_some() && self.y.is_some(),
+            "OKP" => self.x.is_some(),
+            _ => !self.n.is_empty(),
+        }
+    }
+
+    pub(crate) fn from_public_key(kid: &str, public_key: PublicKey) -> Result<JwtKey, Error> {
+        let mut key = JwtKey::new(kid, "", "");
+
+        match public_key {
+            PublicKey::Rsa(components) => {
+                key.n = URL_SAFE_NO_PAD.encode(components.n);
+                key.e = URL_SAFE_NO_PAD.encode(components.e);
+            }
+            PublicKey::P256(point) => {
+                key.kty = "EC".to_string();
+                key.alg = Some("ES256".to_string());
+                key.crv = Some("P-256".to_string());
+                key.x = Some(URL_SAFE_NO_PAD.encode(&point[1..33]));
+                key.y = Some(URL_SAFE_NO_PAD.encode(&point[33..65]));
+            }
+            PublicKey::Ed25519(x) => {
+                key.kty = "OKP".to_string();
+                key.alg = Some("EdDSA".to_string());
+                key.crv = Some("Ed25519".to_string());
+                key.x = Some(URL_SAFE_NO_PAD.encode(x));
+            }
         }
+
+        Ok(key)
     }
 }
 
@@ -47,35 +212,136 @@ impl Clone for JwtKey {
             alg: self.alg.clone(),
             n: self.n.clone(),
             kid: self.kid.clone(),
+            x5c: self.x5c.clone(),
+            crv: self.crv.clone(),
+            x: self.x.clone(),
+            y: self.y.clone(),
         }
     }
 }
 
-pub struct KeyStore {
+/// Minimum time between two downloads of the key set, unless changed with
+/// `KeyStore::set_min_refresh_interval`
+pub const DEFAULT_MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Serializable state of a `KeyStore`.
+///
+/// Allows persisting downloaded keys and their cache timings between invocations.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyStoreSnapshot {
+    pub key_url: String,
+    pub keys: Vec<JwtKey>,
+    /// Keys added with `add_key`, kept when the key set is downloaded again
+    #[serde(default)]
+    pub manual_keys: Vec<JwtKey>,
+    pub refresh_interval: f64,
+    pub load_time: Option<SystemTime>,
+    pub expire_time: Option<SystemTime>,
+    pub refresh_time: Option<SystemTime>,
+    pub last_fetch_time: Option<SystemTime>,
+}
+
+/// Keys to verify tokens with, optionally downloaded from a JSON Web Key Set URL.
+///
+/// Key sets are downloaded with a `JwksFetcher`, by default using Spin's outbound HTTP.
+pub struct KeyStore<F = SpinFetcher> {
     key_url: String,
     keys: Vec<JwtKey>,
+    manual_keys: Vec<JwtKey>,
     refresh_interval: f64,
     load_time: Option<SystemTime>,
     expire_time: Option<SystemTime>,
     refresh_time: Option<SystemTime>,
+    min_refresh_interval: Duration,
+    last_fetch_time: Option<SystemTime>,
+    validation: ValidationOptions,
+    decryption_keys: Vec<DecryptionKey>,
+    fetcher: F,
 }
 
 impl KeyStore {
     pub fn new() -> KeyStore {
-        let key_store = KeyStore {
+        KeyStore::with_fetcher(SpinFetcher)
+    }
+
+    pub async fn new_from(jkws_url: String) -> Result<KeyStore, Error> {
+        KeyStore::new_from_fetcher(jkws_url, SpinFetcher).await
+    }
+
+    /// Create a key store from a JSON Web Key Set document, without downloading anything.
+    pub fn new_from_jwks(jwks: &str) -> Result<KeyStore, Error> {
+        let mut key_store = KeyStore::new();
+
+        key_store.add_keys_from_jwks(jwks.as_bytes())?;
+
+        Ok(key_store)
+    }
+
+    /// Create a key store holding a single key read from a PEM document.
+    ///
+    /// See `JwtKey::from_pem` for supported formats.
+    pub fn new_from_pem(kid: &str, pem: &str) -> Result<KeyStore, Error> {
+        let mut key_store = KeyStore::new();
+
+        key_store.add_key(&JwtKey::from_pem(kid, pem)?);
+
+        Ok(key_store)
+    }
+
+    /// Create a key store holding a single key read from a DER encoded public key.
+    pub fn new_from_der(kid: &str, der: &[u8]) -> Result<KeyStore, Error> {
+        let mut key_store = KeyStore::new();
+
+        key_store.add_key(&JwtKey::from_der(kid, der)?);
+
+        Ok(key_store)
+    }
+
+    /// Create a key store holding a single key read from an X.509 certificate chain.
+    ///
+    /// See `JwtKey::from_x5c` for details.
+    pub fn new_from_x5c(kid: &str, x5c: &[String]) -> Result<KeyStore, Error> {
+        let mut key_store = KeyStore::new();
+
+        key_store.add_key(&JwtKey::from_x5c(kid, x5c)?);
+
+        Ok(key_store)
+    }
+
+    /// Restore a key store from a snapshot, without downloading the key set.
+    pub fn from_snapshot(snapshot: KeyStoreSnapshot) -> KeyStore {
+        KeyStore::from_snapshot_with(snapshot, SpinFetcher)
+    }
+
+    /// Read the `max-age` directive (in seconds) from the `cache-control` header of a response.
+    pub fn cache_max_age(res: &Response) -> Option<u64> {
+        let header_value = res.header("cache-control")?;
+
+        max_age(header_value.as_str().unwrap_or_default())
+    }
+}
+
+impl<F: JwksFetcher> KeyStore<F> {
+    /// Create an empty key store downloading key sets with `fetcher`
+    pub fn with_fetcher(fetcher: F) -> KeyStore<F> {
+        KeyStore {
             key_url: "".to_owned(),
             keys: vec![],
+            manual_keys: vec![],
             refresh_interval: 0.5,
             load_time: None,
             expire_time: None,
             refresh_time: None,
-        };
-
-        key_store
+            min_refresh_interval: DEFAULT_MIN_REFRESH_INTERVAL,
+            last_fetch_time: None,
+            validation: ValidationOptions::default(),
+            decryption_keys: vec![],
+            fetcher,
+        }
     }
 
-    pub async fn new_from(jkws_url: String) -> Result<KeyStore, Error> {
-        let mut key_store = KeyStore::new();
+    pub async fn new_from_fetcher(jkws_url: String, fetcher: F) -> Result<KeyStore<F>, Error> {
+        let mut key_store = KeyStore::with_fetcher(fetcher);
 
         key_store.key_url = jkws_url;
 
@@ -84,8 +350,44 @@ impl KeyStore {
         Ok(key_store)
     }
 
+    /// Restore a key store from a snapshot, downloading key sets with `fetcher` from then on.
+    pub fn from_snapshot_with(snapshot: KeyStoreSnapshot, fetcher: F) -> KeyStore<F> {
+        let mut key_store = KeyStore::with_fetcher(fetcher);
+
+        key_store.key_url = snapshot.key_url;
+        key_store.keys = snapshot.keys;
+        key_store.manual_keys = snapshot.manual_keys;
+        key_store.refresh_interval = snapshot.refresh_interval;
+        key_store.load_time = snapshot.load_time;
+        key_store.expire_time = snapshot.expire_time;
+        key_store.refresh_time = snapshot.refresh_time;
+        key_store.last_fetch_time = snapshot.last_fetch_time;
+
+        key_store
+    }
+
+    /// The fetcher used to download key sets
+    pub fn fetcher(&self) -> &F {
+        &self.fetcher
+    }
+
+    /// Capture the keys and cache timings of the key store.
+    pub fn snapshot(&self) -> KeyStoreSnapshot {
+        KeyStoreSnapshot {
+            key_url: self.key_url.clone(),
+            keys: self.keys.clone(),
+            manual_keys: self.manual_keys.clone(),
+            refresh_interval: self.refresh_interval,
+            load_time: self.load_time,
+            expire_time: self.expire_time,
+            refresh_time: self.refresh_time,
+            last_fetch_time: self.last_fetch_time,
+        }
+    }
+
     pub fn clear_keys(&mut self) {
         self.keys.clear();
+        self.manual_keys.clear();
     }
 
     pub fn key_set_url(&self) -> &str {
@@ -101,54 +403,67 @@ impl KeyStore {
     }
 
     pub async fn load_keys(&mut self) -> Result<(), Error> {
-        #[derive(Deserialize)]
-        pub struct JwtKeys {
-            pub keys: Vec<JwtKey>,
-        }
-        let load_keys_request = RequestBuilder::new(spin_sdk::http::Method::Get, &self.key_url)
-            .header("Accept", "application/json")
-            .build();
-        let load_keys_response: Response = send(load_keys_request)
-            .await
-            .map_err(|_| err_con("Could not download JWKS"))?;
+        let keys = self.fetch_keys(SystemTime::now()).await?;
 
-        let load_time = SystemTime::now();
-        self.load_time = Some(load_time);
+        self.keys.extend(keys);
 
-        let result = KeyStore::cache_max_age(&load_keys_response);
+        Ok(())
+    }
 
-        if let Ok(value) = result {
-            let expire = load_time + Duration::new(value, 0);
-            self.expire_time = Some(expire);
-            let refresh_time = (value as f64 * self.refresh_interval) as u64;
-            let refresh = load_time + Duration::new(refresh_time, 0);
-            self.refresh_time = Some(refresh);
-        }
+    /// Re-download the key set and replace the downloaded keys held by the key store.
+    ///
+    /// Keys added with `add_key` are kept. The existing keys are kept as well if the key set
+    /// could not be downloaded or parsed.
+    pub async fn refresh_keys(&mut self) -> Result<(), Error> {
+        self.refresh_keys_time(SystemTime::now()).await
+    }
 
-        let jwks = serde_json::from_slice::<JwtKeys>(load_keys_response.body())
-            .map_err(|_| err_int("Failed to parse keys"))?;
+    /// Like `refresh_keys`, recording `time` as the time of the download.
+    pub async fn refresh_keys_time(&mut self, time: SystemTime) -> Result<(), Error> {
+        let keys = self.fetch_keys(time).await?;
 
-        jwks.keys.iter().for_each(|k| self.add_key(k));
+        self.keys = self.manual_keys.iter().cloned().chain(keys).collect();
 
         Ok(())
     }
 
-    fn cache_max_age(res: &Response) -> Result<u64, ()> {
-        let header_value = res.header("cache-control").ok_or(())?;
+    async fn fetch_keys(&mut self, time: SystemTime) -> Result<Vec<JwtKey>, Error> {
+        self.last_fetch_time = Some(time);
+
+        let response = self.fetcher.fetch(&self.key_url).await?;
+        if !(200..300).contains(&response.status) {
+            return Err(Error::Connection {
+                url: self.key_url.clone(),
+                source: Some(format!("unexpected status {}", response.status).into()),
+            });
+        }
+
+        let keys = parse_jwks(&response.body)?;
 
-        let header_text = header_value.as_str().unwrap_or_default();
+        self.load_time = Some(time);
+        self.expire_time = None;
+        self.refresh_time = None;
 
-        let re = Regex::new("max-age\\s*=\\s*(\\d+)").map_err(|_| ())?;
+        let result = max_age(response.cache_control.as_deref().unwrap_or_default());
 
-        let captures = re.captures(header_text).ok_or(())?;
+        if let Some(value) = result {
+            let expire = time + Duration::new(value, 0);
+            self.expire_time = Some(expire);
+            let refresh_time = (value as f64 * self.refresh_interval) as u64;
+            let refresh = time + Duration::new(refresh_time, 0);
+            self.refresh_time = Some(refresh);
+        }
 
-        let capture = captures.get(1).ok_or(())?;
+        Ok(keys)
+    }
 
-        let text = capture.as_str();
+    /// Add all keys of a JSON Web Key Set document to the keystore
+    pub fn add_keys_from_jwks(&mut self, jwks: &[u8]) -> Result<(), Error> {
+        let keys = parse_jwks(jwks)?;
 
-        let value = text.parse::<u64>().map_err(|_| ())?;
+        keys.iter().for_each(|k| self.add_key(k));
 
-        Ok(value)
+        Ok(())
     }
 
     /// Fetch a key by key id (KID)
@@ -161,9 +476,15 @@ impl KeyStore {
         self.keys.len()
     }
 
-    /// Manually add a key to the keystore
+    /// Manually add a key to the keystore, the key is kept when the key set is refreshed
     pub fn add_key(&mut self, key: &JwtKey) {
         self.keys.push(key.clone());
+        self.manual_keys.push(key.clone());
+    }
+
+    /// The keys of the keystore as a JWKS document, e.g. to publish the keys of a token issuer
+    pub fn to_jwks(&self) -> Value {
+        json!({ "keys": self.keys })
     }
 
     fn decode_segments(
@@ -179,13 +500,18 @@ impl KeyStore {
         let payload_segment = raw_segments[1];
         let signature_segment = raw_segments[2].to_string();
 
-        let header = Header::new(
-            decode_segment::<Value>(header_segment).or(Err(err_hea("Failed to decode header")))?,
-        );
-        let payload = Payload::new(
-            decode_segment::<Value>(payload_segment)
-                .or(Err(err_pay("Failed to decode payload")))?,
-        );
+        let header =
+            Header::new(
+                decode_segment::<Value>(header_segment).map_err(|e| Error::Header {
+                    source: Box::new(e),
+                })?,
+            );
+        let payload =
+            Payload::new(
+                decode_segment::<Value>(payload_segment).map_err(|e| Error::Payload {
+                    source: Box::new(e),
+                })?,
+            );
 
         let body = format!("{}.{}", header_segment, payload_segment);
 
@@ -199,37 +525,69 @@ impl KeyStore {
     }
 
     pub fn verify_time(&self, token: &str, time: SystemTime) -> Result<Jwt, Error> {
+        let jwt = self.verify_signature_only(token)?;
+
+        self.validation.validate_time(jwt.payload(), time)?;
+
+        Ok(jwt)
+    }
+
+    /// Verify the algorithm, key id and signature of a token, without checking its time based
+    /// claims. Useful to report on tokens, not to accept them.
+    pub fn verify_signature_only(&self, token: &str) -> Result<Jwt, Error> {
         let (header, payload, signature, body) = self.decode_segments(token)?;
 
-        if header.alg() != Some("RS256") {
-            return Err(err_inv("Unsupported algorithm"));
-        }
+        let alg = match header.alg() {
+            Some(alg @ ("RS256" | "ES256" | "EdDSA")) => alg,
+            alg => {
+                return Err(Error::UnsupportedAlgorithm {
+                    alg: alg.map(String::from),
+                })
+            }
+        };
 
-        let kid = header.kid().ok_or(err_key("No key id"))?;
+        let kid = header.kid().ok_or(Error::MissingKid)?;
 
-        let key = self
-            .key_by_id(kid)
-            .ok_or(err_key("JWT key does not exists"))?;
+        let key = self.key_by_id(kid).ok_or_else(|| Error::UnknownKid {
+            kid: kid.to_string(),
+        })?;
 
-        let e = URL_SAFE_NO_PAD
-            .decode(&key.e)
-            .or(Err(err_cer("Failed to decode exponent")))?;
-        let n = URL_SAFE_NO_PAD
-            .decode(&key.n)
-            .or(Err(err_cer("Failed to decode modulus")))?;
+        verify_signature(key, alg, &body, &signature)?;
 
-        verify_signature(&e, &n, &body, &signature)?;
+        Ok(Jwt::new(header, payload, signature))
+    }
 
-        let jwt = Jwt::new(header, payload, signature);
+    /// Verify a JWT token, downloading the key set again if required.
+    ///
+    /// The key set is refreshed before verification if the token references an unknown `kid`
+    /// or if the keys have reached their refresh time. Refreshes are rate limited by the
+    /// minimum refresh interval, so tokens carrying random key ids can not trigger a download
+    /// on every call. If a scheduled refresh fails, the current keys are used.
+    pub async fn verify_and_refresh(&mut self, token: &str) -> Result<Jwt, Error> {
+        self.verify_and_refresh_time(token, SystemTime::now()).await
+    }
 
-        if jwt.expired_time(time).unwrap_or(false) {
-            return Err(err_exp("Token expired"));
-        }
-        if jwt.early_time(time).unwrap_or(false) {
-            return Err(err_nbf("Too early to use token (nbf)"));
+    pub async fn verify_and_refresh_time(
+        &mut self,
+        token: &str,
+        time: SystemTime,
+    ) -> Result<Jwt, Error> {
+        let (header, _, _, _) = self.decode_segments(token)?;
+
+        let unknown_kid = header
+            .kid()
+            .is_some_and(|kid| self.key_by_id(kid).is_none());
+        let refresh_due = self.should_refresh_time(time).unwrap_or(false);
+
+        if (unknown_kid || refresh_due) && self.can_refresh_time(time) {
+            if let Err(e) = self.refresh_keys_time(time).await {
+                if unknown_kid {
+                    return Err(e);
+                }
+            }
         }
 
-        Ok(jwt)
+        self.verify_time(token, time)
     }
 
     /// Verify a JWT token.
@@ -239,12 +597,48 @@ impl KeyStore {
     /// * Is well formed
     /// * Has a `kid` field that matches a public signature `kid
     /// * Signature matches public key
-    /// * It is not expired
+    /// * It has an `exp` claim and is not expired
     /// * The `nbf` is not set to before now
+    /// * It is not older than the maximum age, if configured
+    ///
+    /// Time based checks allow for the leeway set via `set_validation_options`.
     pub fn verify(&self, token: &str) -> Result<Jwt, Error> {
         self.verify_time(token, SystemTime::now())
     }
 
+    /// Add a private or secret key used to decrypt JWE tokens.
+    /// Decryption keys are not part of snapshots.
+    pub fn add_decryption_key(&mut self, key: DecryptionKey) {
+        self.decryption_keys.push(key);
+    }
+
+    /// Decrypt a JWE token (five segments) with the decryption key matching its `kid`.
+    ///
+    /// The content is returned as is, use `decrypt_and_verify` for JWE tokens carrying a
+    /// signed JWT.
+    pub fn decrypt(&self, token: &str) -> Result<Jwe, Error> {
+        jwe::decrypt(&self.decryption_keys, token)
+    }
+
+    /// Decrypt a JWE token and verify the nested JWT like `verify` does.
+    pub fn decrypt_and_verify(&self, token: &str) -> Result<Jwt, Error> {
+        self.decrypt_and_verify_time(token, SystemTime::now())
+    }
+
+    pub fn decrypt_and_verify_time(&self, token: &str, time: SystemTime) -> Result<Jwt, Error> {
+        let jwe = self.decrypt(token)?;
+        if jwe.header().cty().is_some() && !jwe.is_nested() {
+            return Err(err_dec("Encrypted content is not a JWT"));
+        }
+
+        let nested = std::str::from_utf8(jwe.plaintext()).map_err(|e| Error::Malformed {
+            reason: "Encrypted content is not a JWT",
+            source: Some(Box::new(e)),
+        })?;
+
+        self.verify_time(nested, time)
+    }
+
     /// Time at which the keys were last refreshed
     pub fn last_load_time(&self) -> Option<SystemTime> {
         self.load_time
@@ -302,6 +696,58 @@ impl KeyStore {
         None
     }
 
+    /// Specifies the rules applied to `exp`, `nbf` and `iat` when verifying tokens.
+    pub fn set_validation_options(&mut self, options: ValidationOptions) {
+        self.validation = options;
+    }
+
+    /// Get the rules applied to `exp`, `nbf` and `iat` when verifying tokens.
+    pub fn validation_options(&self) -> &ValidationOptions {
+        &self.validation
+    }
+
+    /// Specifies the minimum time between two downloads of the key set.
+    ///
+    /// The default is `DEFAULT_MIN_REFRESH_INTERVAL`, 60 seconds. `verify_and_refresh` will not
+    /// download the key set again before this interval has passed since the last download
+    /// attempt.
+    pub fn set_min_refresh_interval(&mut self, interval: Duration) {
+        self.min_refresh_interval = interval;
+    }
+
+    /// Get the minimum time between two downloads of the key set.
+    pub fn min_refresh_interval(&self) -> Duration {
+        self.min_refresh_interval
+    }
+
+    /// Time of the last attempt to download the key set, whether it succeeded or not.
+    pub fn last_fetch_time(&self) -> Option<SystemTime> {
+        self.last_fetch_time
+    }
+
+    /// Record a download attempt without downloading the key set.
+    ///
+    /// Useful to claim a refresh when the key store is shared between several callers.
+    pub fn set_last_fetch_time(&mut self, time: SystemTime) {
+        self.last_fetch_time = Some(time);
+    }
+
+    /// Returns `true` if the minimum refresh interval has passed since the last download
+    /// attempt at the given `current_time`.
+    pub fn can_refresh_time(&self, current_time: SystemTime) -> bool {
+        if self.key_url.is_empty() {
+            return false;
+        }
+
+        match self.last_fetch_time {
+            Some(last_fetch) => match current_time.duration_since(last_fetch) {
+                Ok(elapsed) => elapsed >= self.min_refresh_interval,
+                Err(_) => false,
+            },
+            None => true,
+        }
+    }
+
     /// Returns `Option<true>` if keys should be refreshed based on the system time.
     ///
     /// None is returned if the key store does not have a refresh time available. For example, the
@@ -311,29 +757,526 @@ impl KeyStore {
     }
 }
 
-fn verify_signature(e: &Vec<u8>, n: &Vec<u8>, message: &str, signature: &str) -> Result<(), Error> {
-    let pkc = RsaPublicKeyComponents { e, n };
+/// Read the `max-age` directive (in seconds) of a `cache-control` header value
+fn max_age(cache_control: &str) -> Option<u64> {
+    let re = Regex::new("max-age\\s*=\\s*(\\d+)").ok()?;
+
+    let captures = re.captures(cache_control)?;
+
+    let capture = captures.get(1)?;
+
+    let text = capture.as_str();
+
+    text.parse::<u64>().ok()
+}
+
+fn parse_jwks(jwks: &[u8]) -> Result<Vec<JwtKey>, Error> {
+    #[derive(Deserialize)]
+    pub struct JwtKeys {
+        pub keys: Vec<JwtKey>,
+    }
+    let jwks = serde_json::from_slice::<JwtKeys>(jwks).map_err(|e| Error::KeySet {
+        source: Box::new(e),
+    })?;
+
+    // A key that can't be used must not make the other keys of the set unusable
+    Ok(jwks
+        .keys
+        .into_iter()
+        .filter_map(|key| match &key.x5c {
+            // Keys published only as a certificate chain
+            Some(x5c) if !key.has_key_material() => match JwtKey::from_x5c(&key.kid, x5c) {
+                Ok(mut from_x5c) => {
+                    from_x5c.alg = key.alg.or(from_x5c.alg);
+                    Some(from_x5c)
+                }
+                Err(e) => {
+                    println!("skipping key {} of the key set: {}", key.kid, e);
+                    None
+                }
+            },
+            _ => Some(key),
+        })
+        .collect())
+}
+
+/// Verify `signature` over `message` with `key`, using the algorithm `alg` of the token header
+pub(crate) fn verify_signature(
+    key: &JwtKey,
+    alg: &str,
+    message: &str,
+    signature: &str,
+) -> Result<(), Error> {
+    if key.alg.as_deref().is_some_and(|key_alg| key_alg != alg) {
+        return Err(err_key(
+            "Key is not meant to be used with the token algorithm",
+        ));
+    }
 
-    let message_bytes = &message.as_bytes().to_vec();
+    let message_bytes = message.as_bytes();
     let signature_bytes = URL_SAFE_NO_PAD
-        .decode(&signature)
-        .or(Err(err_sig("Could not base64 decode signature")))?;
+        .decode(signature)
+        .map_err(|e| Error::Signature {
+            reason: "Could not base64 decode signature",
+            source: Some(Box::new(e)),
+        })?;
+
+    let result = match alg {
+        "RS256" => {
+            if key.n.is_empty() {
+                return Err(err_key("Key is not an RSA key"));
+            }
+            let e = decode_key_parameter(&key.e, "Failed to decode exponent")?;
+            let n = decode_key_parameter(&key.n, "Failed to decode modulus")?;
+            RsaPublicKeyComponents { e, n }.verify(
+                &RSA_PKCS1_2048_8192_SHA256,
+                message_bytes,
+                &signature_bytes,
+            )
+        }
+        "ES256" => {
+            if key.crv.as_deref() != Some("P-256") {
+                return Err(err_key("Key is not a P-256 key"));
+            }
+            let x =
+                decode_key_parameter(key.x.as_deref().unwrap_or_default(), "Failed to decode x")?;
+            let y =
+                decode_key_parameter(key.y.as_deref().unwrap_or_default(), "Failed to decode y")?;
+            let point = [&[0x04], x.as_slice(), y.as_slice()].concat();
+            UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, point)
+                .verify(message_bytes, &signature_bytes)
+        }
+        "EdDSA" => {
+            if key.crv.as_deref() != Some("Ed25519") {
+                return Err(err_key("Key is not an Ed25519 key"));
+            }
+            let x =
+                decode_key_parameter(key.x.as_deref().unwrap_or_default(), "Failed to decode x")?;
+            UnparsedPublicKey::new(&ED25519, x).verify(message_bytes, &signature_bytes)
+        }
+        _ => {
+            return Err(Error::UnsupportedAlgorithm {
+                alg: Some(alg.to_string()),
+            })
+        }
+    };
 
-    let result = pkc.verify(
-        &RSA_PKCS1_2048_8192_SHA256,
-        &message_bytes,
-        &signature_bytes,
-    );
+    result.or(Err(err_sig("Signature does not match key")))
+}
 
-    result.or(Err(err_cer("Signature does not match certificate")))
+fn decode_key_parameter(value: &str, reason: &'static str) -> Result<Vec<u8>, Error> {
+    URL_SAFE_NO_PAD.decode(value).map_err(|e| Error::Key {
+        reason,
+        source: Some(Box::new(e)),
+    })
 }
 
-fn decode_segment<T: DeserializeOwned>(segment: &str) -> Result<T, Error> {
+pub(crate) fn decode_segment<T: DeserializeOwned>(segment: &str) -> Result<T, Error> {
     let raw = URL_SAFE_NO_PAD
         .decode(segment)
-        .or(Err(err_inv("Failed to decode segment")))?;
+        .map_err(|e| Error::Malformed {
+            reason: "Failed to decode segment",
+            source: Some(Box::new(e)),
+        })?;
     let slice = String::from_utf8_lossy(&raw);
-    let decoded: T = serde_json::from_str(&slice).or(Err(err_inv("Failed to decode segment")))?;
+    let decoded: T = serde_json::from_str(&slice).map_err(|e| Error::Malformed {
+        reason: "Failed to decode segment",
+        source: Some(Box::new(e)),
+    })?;
 
     Ok(decoded)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetcher::{JwksResponse, MockFetcher};
+    use crate::signing::{JwtBuilder, SigningKey};
+    use pollster::block_on;
+
+    const JWKS: &str = include_str!("../testdata/jwks.json");
+    const JWKS_X5C: &str = include_str!("../testdata/jwks_x5c.json");
+    const PUBLIC_KEY_PEM: &str = include_str!("../testdata/rsa_public.pem");
+    const PUBLIC_KEY_PKCS1_PEM: &str = include_str!("../testdata/rsa_public_pkcs1.pem");
+    const PUBLIC_KEY_DER: &[u8] = include_bytes!("../testdata/rsa_public.der");
+    const SIGNER_CERTIFICATE: &str = include_str!("../testdata/signer.crt");
+    const CA_CERTIFICATE: &str = include_str!("../testdata/ca.crt");
+    const TOKEN: &str = include_str!("../testdata/rs256.jwt");
+    const RSA_PRIVATE_KEY: &str = include_str!("../testdata/rsa_private.pem");
+    const EC_PRIVATE_KEY: &str = include_str!("../testdata/ec_private.pem");
+    const JWKS_URL: &str = "https://idp.example.com/keys";
+
+    fn token_valid_time() -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1700001000)
+    }
+
+    fn x5c_from_pem(pem: &str) -> String {
+        STANDARD.encode(&der::pem_decode(pem).unwrap()[0].der)
+    }
+
+    fn assert_same_key(key_store: &KeyStore) {
+        let expected = KeyStore::new_from_jwks(JWKS).unwrap();
+        let expected = expected.key_by_id("test-key").unwrap();
+        let key = key_store.key_by_id("test-key").unwrap();
+        assert_eq!(key.n, expected.n);
+        assert_eq!(key.e, expected.e);
+    }
+
+    #[test]
+    fn test_new_from_jwks_verifies_token() {
+        let key_store = KeyStore::new_from_jwks(JWKS).unwrap();
+
+        assert_eq!(key_store.keys_len(), 1);
+        let jwt = key_store
+            .verify_time(TOKEN.trim(), token_valid_time())
+            .unwrap();
+        assert_eq!(jwt.payload().sub(), Some("alice"));
+    }
+
+    #[test]
+    fn test_new_from_jwks_rejects_invalid_document() {
+        assert!(KeyStore::new_from_jwks("{\"keys\": 42}").is_err());
+        assert!(KeyStore::new_from_jwks("not json").is_err());
+    }
+
+    #[test]
+    fn test_new_from_jwks_with_x5c_only() {
+        let key_store = KeyStore::new_from_jwks(JWKS_X5C).unwrap();
+
+        assert_same_key(&key_store);
+        assert!(key_store
+            .verify_time(TOKEN.trim(), token_valid_time())
+            .is_ok());
+    }
+
+    #[test]
+    fn test_new_from_jwks_skips_unusable_x5c() {
+        let mut jwks: Value = serde_json::from_str(JWKS).unwrap();
+        let keys = jwks["keys"].as_array_mut().unwrap();
+        // the chain is not used, the key has its own parameters
+        let ec_key = SigningKey::from_pem("ec-key", EC_PRIVATE_KEY).unwrap();
+        let mut ec_jwk = serde_json::to_value(ec_key.public_key().unwrap()).unwrap();
+        ec_jwk["x5c"] = json!(["AAAA"]);
+        keys.push(ec_jwk);
+        // neither parameters nor a valid chain
+        keys.push(json!({ "kty": "RSA", "kid": "broken-key", "x5c": ["AAAA"] }));
+
+        let key_store = KeyStore::new_from_jwks(&jwks.to_string()).unwrap();
+
+        assert_eq!(key_store.keys_len(), 2);
+        assert!(key_store.key_by_id("ec-key").is_some());
+        assert!(key_store.key_by_id("broken-key").is_none());
+        assert!(key_store
+            .verify_time(TOKEN.trim(), token_valid_time())
+            .is_ok());
+    }
+
+    #[test]
+    fn test_new_from_pem_spki() {
+        let key_store = KeyStore::new_from_pem("test-key", PUBLIC_KEY_PEM).unwrap();
+
+        assert_same_key(&key_store);
+        assert!(key_store
+            .verify_time(TOKEN.trim(), token_valid_time())
+            .is_ok());
+    }
+
+    #[test]
+    fn test_new_from_pem_pkcs1() {
+        let key_store = KeyStore::new_from_pem("test-key", PUBLIC_KEY_PKCS1_PEM).unwrap();
+
+        assert_same_key(&key_store);
+    }
+
+    #[test]
+    fn test_new_from_pem_certificate_chain() {
+        let chain = format!("{}{}", SIGNER_CERTIFICATE, CA_CERTIFICATE);
+        let key_store = KeyStore::new_from_pem("test-key", &chain).unwrap();
+
+        assert_same_key(&key_store);
+        let key = key_store.key_by_id("test-key").unwrap();
+        assert_eq!(key.x5c.as_ref().map(Vec::len), Some(2));
+    }
+
+    #[test]
+    fn test_new_from_pem_rejects_garbage() {
+        assert!(KeyStore::new_from_pem("test-key", "no pem here").is_err());
+        assert!(KeyStore::new_from_pem(
+            "test-key",
+            "-----BEGIN PUBLIC KEY-----\nAAAA\n-----END PUBLIC KEY-----\n"
+        )
+        .is_err());
+        assert!(KeyStore::new_from_pem(
+            "test-key",
+            "-----BEGIN PUBLIC KEY-----\nAAAA\n-----END CERTIFICATE-----\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_new_from_der() {
+        let key_store = KeyStore::new_from_der("test-key", PUBLIC_KEY_DER).unwrap();
+
+        assert_same_key(&key_store);
+    }
+
+    #[test]
+    fn test_new_from_x5c() {
+        let x5c = vec![
+            x5c_from_pem(SIGNER_CERTIFICATE),
+            x5c_from_pem(CA_CERTIFICATE),
+        ];
+        let key_store = KeyStore::new_from_x5c("test-key", &x5c).unwrap();
+
+        assert_same_key(&key_store);
+    }
+
+    #[test]
+    fn test_new_from_x5c_rejects_broken_chain() {
+        let x5c = vec![
+            x5c_from_pem(CA_CERTIFICATE),
+            x5c_from_pem(SIGNER_CERTIFICATE),
+        ];
+        let result = KeyStore::new_from_x5c("test-key", &x5c);
+
+        assert_eq!(result.err().map(|e| e.typ()), Some(Type::Certificate));
+    }
+
+    #[test]
+    fn test_verify_reports_unknown_kid() {
+        let key_store = KeyStore::new_from_pem("other-key", PUBLIC_KEY_PEM).unwrap();
+
+        match key_store.verify_time(TOKEN.trim(), token_valid_time()) {
+            Err(Error::UnknownKid { kid }) => assert_eq!(kid, "test-key"),
+            other => panic!("expected UnknownKid, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_verify_signature_only_ignores_expiry() {
+        let key_store = KeyStore::new_from_jwks(JWKS).unwrap();
+
+        assert!(key_store.verify(TOKEN.trim()).is_err());
+        assert!(key_store.verify_signature_only(TOKEN.trim()).is_ok());
+    }
+
+    #[test]
+    fn test_verify_reports_expiry() {
+        let key_store = KeyStore::new_from_jwks(JWKS).unwrap();
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1700003700);
+
+        let err = key_store.verify_time(TOKEN.trim(), time).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Expired {
+                exp: 1700003600,
+                now: 1700003700
+            }
+        ));
+        assert_eq!(err.code(), "token_expired");
+        assert_eq!(err.typ(), Type::Expired);
+    }
+
+    #[test]
+    fn test_verify_chains_error_source() {
+        let key_store = KeyStore::new_from_jwks(JWKS).unwrap();
+
+        let err = key_store.verify("e30.!!!.sig").unwrap_err();
+        assert_eq!(err.code(), "invalid_payload");
+        assert!(std::error::Error::source(&err).is_some());
+    }
+
+    #[test]
+    fn test_thumbprint() {
+        // example of RFC 7638, section 3.1
+        let key = JwtKey::new(
+            "2011-04-29",
+            "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw",
+            "AQAB",
+        );
+        let mut key = JwtKey {
+            kty: "RSA".to_string(),
+            ..key
+        };
+        assert_eq!(
+            key.thumbprint().unwrap(),
+            "NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs"
+        );
+
+        key.kty = "oct".to_string();
+        assert_eq!(key.thumbprint().unwrap_err().code(), "invalid_key");
+    }
+
+    fn jwks_of(keys: &[&SigningKey]) -> String {
+        let mut key_store = KeyStore::new();
+        keys.iter()
+            .for_each(|key| key_store.add_key(&key.public_key().unwrap()));
+        key_store.to_jwks().to_string()
+    }
+
+    fn signed_token(key: &SigningKey) -> String {
+        JwtBuilder::new()
+            .subject("alice")
+            .expires_in(Duration::from_secs(300))
+            .sign(key)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_refresh_picks_up_rotated_key() {
+        let old_key = SigningKey::from_pem("old-key", RSA_PRIVATE_KEY).unwrap();
+        let new_key = SigningKey::from_pem("new-key", EC_PRIVATE_KEY).unwrap();
+        let fetcher = MockFetcher::new();
+        fetcher.respond(JWKS_URL, &jwks_of(&[&old_key]), None);
+
+        let mut key_store = block_on(KeyStore::new_from_fetcher(JWKS_URL.into(), fetcher)).unwrap();
+        key_store.set_min_refresh_interval(Duration::from_secs(0));
+        assert!(block_on(key_store.verify_and_refresh(&signed_token(&old_key))).is_ok());
+        assert_eq!(key_store.fetcher().requests().len(), 1);
+
+        // the issuer rotates its key, tokens with the new kid trigger a refresh
+        key_store
+            .fetcher()
+            .respond(JWKS_URL, &jwks_of(&[&new_key]), None);
+        assert!(block_on(key_store.verify_and_refresh(&signed_token(&new_key))).is_ok());
+        assert_eq!(key_store.fetcher().requests().len(), 2);
+        assert!(key_store.key_by_id("old-key").is_none());
+    }
+
+    #[test]
+    fn test_restored_key_store_refreshes_on_unknown_kid() {
+        let old_key = SigningKey::from_pem("old-key", RSA_PRIVATE_KEY).unwrap();
+        let new_key = SigningKey::from_pem("new-key", EC_PRIVATE_KEY).unwrap();
+        let fetcher = MockFetcher::new();
+        fetcher.respond(JWKS_URL, &jwks_of(&[&old_key]), None);
+        let snapshot = block_on(KeyStore::new_from_fetcher(JWKS_URL.into(), &fetcher))
+            .unwrap()
+            .snapshot();
+        fetcher.respond(JWKS_URL, &jwks_of(&[&new_key]), None);
+        let token = signed_token(&new_key);
+        let now = SystemTime::now();
+
+        // the key store is restored on every request, the download time is kept
+        let mut key_store = KeyStore::from_snapshot_with(snapshot.clone(), &fetcher);
+        let result = block_on(key_store.verify_and_refresh_time(&token, now));
+        assert!(matches!(result, Err(Error::UnknownKid { .. })));
+        assert_eq!(fetcher.requests().len(), 1);
+
+        // once the minimum refresh interval passed, the unknown kid triggers a download
+        let later = now + key_store.min_refresh_interval();
+        let mut key_store = KeyStore::from_snapshot_with(snapshot, &fetcher);
+        assert!(block_on(key_store.verify_and_refresh_time(&token, later)).is_ok());
+        assert_eq!(fetcher.requests().len(), 2);
+        assert!(key_store.snapshot().last_fetch_time.is_some());
+    }
+
+    #[test]
+    fn test_cache_control_sets_refresh_time() {
+        let fetcher = MockFetcher::new();
+        fetcher.respond(JWKS_URL, JWKS, Some("public, max-age=3600"));
+
+        let key_store = block_on(KeyStore::new_from_fetcher(JWKS_URL.into(), fetcher)).unwrap();
+        let load_time = key_store.load_time().unwrap();
+
+        assert_eq!(
+            key_store.expire_time(),
+            Some(load_time + Duration::from_secs(3600))
+        );
+        assert_eq!(
+            key_store.refresh_time(),
+            Some(load_time + Duration::from_secs(1800))
+        );
+    }
+
+    #[test]
+    fn test_refresh_without_cache_control_clears_refresh_time() {
+        let fetcher = MockFetcher::new();
+        fetcher.respond(JWKS_URL, JWKS, Some("max-age=60"));
+        let mut key_store = block_on(KeyStore::new_from_fetcher(JWKS_URL.into(), fetcher)).unwrap();
+        assert!(key_store.refresh_time().is_some());
+
+        key_store.fetcher().respond(JWKS_URL, JWKS, None);
+        block_on(key_store.refresh_keys()).unwrap();
+        assert_eq!(key_store.refresh_time(), None);
+        assert_eq!(key_store.expire_time(), None);
+    }
+
+    #[test]
+    fn test_refresh_records_given_time() {
+        let fetcher = MockFetcher::new();
+        fetcher.respond(JWKS_URL, JWKS, Some("max-age=3600"));
+        let mut key_store = block_on(KeyStore::new_from_fetcher(JWKS_URL.into(), fetcher)).unwrap();
+
+        let later = SystemTime::now() + Duration::from_secs(7200);
+        block_on(key_store.refresh_keys_time(later)).unwrap();
+        assert_eq!(key_store.last_fetch_time(), Some(later));
+        assert_eq!(key_store.load_time(), Some(later));
+        assert_eq!(
+            key_store.refresh_time(),
+            Some(later + Duration::from_secs(1800))
+        );
+        assert!(!key_store.can_refresh_time(later));
+    }
+
+    #[test]
+    fn test_refresh_keeps_manually_added_keys() {
+        let pinned_key = SigningKey::from_pem("pinned-key", RSA_PRIVATE_KEY).unwrap();
+        let old_key = SigningKey::from_pem("old-key", EC_PRIVATE_KEY).unwrap();
+        let new_key = SigningKey::from_pem("new-key", EC_PRIVATE_KEY).unwrap();
+        let fetcher = MockFetcher::new();
+        fetcher.respond(JWKS_URL, &jwks_of(&[&old_key]), None);
+        let mut key_store = block_on(KeyStore::new_from_fetcher(JWKS_URL.into(), fetcher)).unwrap();
+        key_store.add_key(&pinned_key.public_key().unwrap());
+
+        // the manual key survives a refresh and a snapshot round trip
+        let mut key_store = KeyStore::from_snapshot_with(key_store.snapshot(), MockFetcher::new());
+        key_store
+            .fetcher()
+            .respond(JWKS_URL, &jwks_of(&[&new_key]), None);
+        block_on(key_store.refresh_keys()).unwrap();
+        assert!(key_store.key_by_id("old-key").is_none());
+        assert!(key_store.key_by_id("new-key").is_some());
+        assert!(key_store.verify(&signed_token(&pinned_key)).is_ok());
+        assert_eq!(key_store.keys_len(), 2);
+    }
+
+    #[test]
+    fn test_failed_refresh_keeps_keys() {
+        let fetcher = MockFetcher::new();
+        fetcher.respond(JWKS_URL, JWKS, None);
+        let mut key_store = block_on(KeyStore::new_from_fetcher(JWKS_URL.into(), fetcher)).unwrap();
+
+        key_store.fetcher().fail(JWKS_URL);
+        let err = block_on(key_store.refresh_keys()).unwrap_err();
+        assert_eq!(err.typ(), Type::Connection);
+        assert_eq!(key_store.keys_len(), 1);
+
+        key_store.fetcher().respond_with(
+            JWKS_URL,
+            JwksResponse {
+                status: 500,
+                body: b"{}".to_vec(),
+                cache_control: None,
+            },
+        );
+        let err = block_on(key_store.refresh_keys()).unwrap_err();
+        assert_eq!(err.typ(), Type::Connection);
+        assert_eq!(key_store.keys_len(), 1);
+    }
+
+    #[test]
+    fn test_unknown_kid_refreshes_are_rate_limited() {
+        let fetcher = MockFetcher::new();
+        fetcher.respond(JWKS_URL, JWKS, None);
+        let mut key_store =
+            block_on(KeyStore::new_from_fetcher(JWKS_URL.into(), &fetcher)).unwrap();
+
+        let unknown_key = SigningKey::from_pem("unknown-key", EC_PRIVATE_KEY).unwrap();
+        for _ in 0..3 {
+            let result = block_on(key_store.verify_and_refresh(&signed_token(&unknown_key)));
+            assert!(matches!(result, Err(Error::UnknownKid { .. })));
+        }
+        // the initial download only, the minimum refresh interval has not passed
+        assert_eq!(fetcher.requests(), vec![JWKS_URL.to_string()]);
+    }
+}
@@ -1,3 +1,11 @@
+pub mod claims;
+mod der;
+pub mod dpop;
 pub mod error;
+pub mod fetcher;
+mod jwk;
+pub mod jwe;
 pub mod jwt;
 pub mod keyset;
+pub mod signing;
+pub mod validation;
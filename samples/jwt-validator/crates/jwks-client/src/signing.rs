@@ -0,0 +1,471 @@
+use std::time::{Duration, SystemTime};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::rsa::{KeyPairComponents, PublicKeyComponents};
+use ring::signature::{
+    EcdsaKeyPair, Ed25519KeyPair, KeyPair, RsaKeyPair, ECDSA_P256_SHA256_FIXED_SIGNING,
+    RSA_PKCS1_SHA256,
+};
+use serde_json::{Map, Value};
+
+use crate::der::{self, KeyType, PublicKey};
+use crate::error::*;
+use crate::jwk::{rejected, PrivateJwk};
+use crate::keyset::JwtKey;
+
+enum PrivateKey {
+    Rsa(RsaKeyPair),
+    P256(EcdsaKeyPair),
+    Ed25519(Ed25519KeyPair),
+}
+
+/// A private key used to sign tokens with RS256, ES256 or EdDSA (Ed25519)
+pub struct SigningKey {
+    kid: String,
+    key: PrivateKey,
+}
+
+impl SigningKey {
+    /// Load a private key from a PEM document.
+    ///
+    /// Supported are `PRIVATE KEY` (PKCS#8) blocks holding an RSA, P-256 or Ed25519 key and
+    /// `RSA PRIVATE KEY` (PKCS#1) blocks.
+    pub fn from_pem(kid: &str, pem: &str) -> Result<SigningKey, Error> {
+        let blocks = der::pem_decode(pem)?;
+        let der = blocks[0].der.as_slice();
+
+        let key = match blocks[0].label.as_str() {
+            "PRIVATE KEY" => match der::pkcs8_key_type(der)? {
+                KeyType::Rsa => PrivateKey::Rsa(RsaKeyPair::from_pkcs8(der).map_err(rejected)?),
+                KeyType::P256 => PrivateKey::P256(
+                    EcdsaKeyPair::from_pkcs8(
+                        &ECDSA_P256_SHA256_FIXED_SIGNING,
+                        der,
+                        &SystemRandom::new(),
+                    )
+                    .map_err(rejected)?,
+                ),
+                KeyType::Ed25519 => PrivateKey::Ed25519(
+                    Ed25519KeyPair::from_pkcs8_maybe_unchecked(der).map_err(rejected)?,
+                ),
+            },
+            "RSA PRIVATE KEY" => PrivateKey::Rsa(RsaKeyPair::from_der(der).map_err(rejected)?),
+            _ => {
+                return Err(err_key(
+                    "Unsupported PEM block, expected a PKCS#8 private key",
+                ))
+            }
+        };
+
+        Ok(SigningKey {
+            kid: kid.to_string(),
+            key,
+        })
+    }
+
+    /// Load a private key from a JSON Web Key with `kty` of `RSA`, `EC` (P-256) or `OKP` (Ed25519).
+    ///
+    /// The key id is taken from the `kid` parameter.
+    pub fn from_jwk(jwk: &str) -> Result<SigningKey, Error> {
+        let jwk = PrivateJwk::parse(jwk)?;
+        let kid = jwk.kid()?;
+
+        let key = match (jwk.kty.as_str(), jwk.crv.as_deref()) {
+            ("RSA", _) => {
+                let components = KeyPairComponents {
+                    public_key: PublicKeyComponents {
+                        n: jwk.param(&jwk.n)?,
+                        e: jwk.param(&jwk.e)?,
+                    },
+                    d: jwk.param(&jwk.d)?,
+                    p: jwk.param(&jwk.p)?,
+                    q: jwk.param(&jwk.q)?,
+                    dP: jwk.param(&jwk.dp)?,
+                    dQ: jwk.param(&jwk.dq)?,
+                    qInv: jwk.param(&jwk.qi)?,
+                };
+                PrivateKey::Rsa(RsaKeyPair::from_components(&components).map_err(rejected)?)
+            }
+            ("EC", Some("P-256")) => {
+                let point = [
+                    &[0x04],
+                    jwk.param(&jwk.x)?.as_slice(),
+                    jwk.param(&jwk.y)?.as_slice(),
+                ]
+                .concat();
+                PrivateKey::P256(
+                    EcdsaKeyPair::from_private_key_and_public_key(
+                        &ECDSA_P256_SHA256_FIXED_SIGNING,
+                        &jwk.param(&jwk.d)?,
+                        &point,
+                        &SystemRandom::new(),
+                    )
+                    .map_err(rejected)?,
+                )
+            }
+            ("OKP", Some("Ed25519")) => PrivateKey::Ed25519(
+                Ed25519KeyPair::from_seed_and_public_key(&jwk.param(&jwk.d)?, &jwk.param(&jwk.x)?)
+                    .map_err(rejected)?,
+            ),
+            _ => return Err(err_key("Unsupported JWK key type")),
+        };
+
+        Ok(SigningKey { kid, key })
+    }
+
+    pub fn kid(&self) -> &str {
+        &self.kid
+    }
+
+    /// The JWS algorithm (`alg` header) of tokens signed with this key
+    pub fn alg(&self) -> &'static str {
+        match self.key {
+            PrivateKey::Rsa(_) => "RS256",
+            PrivateKey::P256(_) => "ES256",
+            PrivateKey::Ed25519(_) => "EdDSA",
+        }
+    }
+
+    /// The public key, e.g. to publish it as part of a JWKS via `KeyStore::to_jwks`
+    pub fn public_key(&self) -> Result<JwtKey, Error> {
+        let public_key = match &self.key {
+            PrivateKey::Rsa(key) => PublicKey::Rsa(der::rsa_from_pkcs1(key.public().as_ref())?),
+            PrivateKey::P256(key) => PublicKey::P256(key.public_key().as_ref().to_vec()),
+            PrivateKey::Ed25519(key) => PublicKey::Ed25519(key.public_key().as_ref().to_vec()),
+        };
+
+        JwtKey::from_public_key(&self.kid, public_key)
+    }
+
+    /// Sign `message`, returning the raw signature
+    pub fn sign(&self, message: &[u8]) -> Result<Vec<u8>, Error> {
+        let rng = SystemRandom::new();
+
+        match &self.key {
+            PrivateKey::Rsa(key) => {
+                let mut signature = vec![0; key.public().modulus_len()];
+                key.sign(&RSA_PKCS1_SHA256, &rng, message, &mut signature)
+                    .or(Err(err_sig("Failed to sign token")))?;
+                Ok(signature)
+            }
+            PrivateKey::P256(key) => key
+                .sign(&rng, message)
+                .map(|signature| signature.as_ref().to_vec())
+                .or(Err(err_sig("Failed to sign token"))),
+            PrivateKey::Ed25519(key) => Ok(key.sign(message).as_ref().to_vec()),
+        }
+    }
+}
+
+/// Builds and signs JWTs.
+///
+/// `iat` is set to the time of signing unless specified explicitly. The `alg` and `kid`
+/// headers are taken from the `SigningKey`, `typ` defaults to `JWT`.
+#[derive(Debug, Clone, Default)]
+pub struct JwtBuilder {
+    header: Map<String, Value>,
+    claims: Map<String, Value>,
+    lifetime: Option<Duration>,
+    random_jwt_id: bool,
+}
+
+impl JwtBuilder {
+    pub fn new() -> JwtBuilder {
+        JwtBuilder::default()
+    }
+
+    pub fn issuer(self, iss: &str) -> Self {
+        self.claim("iss", iss)
+    }
+
+    pub fn subject(self, sub: &str) -> Self {
+        self.claim("sub", sub)
+    }
+
+    /// Set the `aud` claim, either a single string or a list of audiences
+    pub fn audience(self, aud: impl Into<Value>) -> Self {
+        self.claim("aud", aud)
+    }
+
+    /// Set `exp` relative to the time of signing
+    pub fn expires_in(mut self, lifetime: Duration) -> Self {
+        self.lifetime = Some(lifetime);
+        self
+    }
+
+    pub fn expires_at(self, exp: SystemTime) -> Self {
+        self.claim("exp", numeric_date(exp))
+    }
+
+    pub fn not_before(self, nbf: SystemTime) -> Self {
+        self.claim("nbf", numeric_date(nbf))
+    }
+
+    pub fn issued_at(self, iat: SystemTime) -> Self {
+        self.claim("iat", numeric_date(iat))
+    }
+
+    pub fn jwt_id(self, jti: &str) -> Self {
+        self.claim("jti", jti)
+    }
+
+    /// Set `jti` to a random value when signing
+    pub fn random_jwt_id(mut self) -> Self {
+        self.random_jwt_id = true;
+        self
+    }
+
+    pub fn token_type(self, typ: &str) -> Self {
+        self.header("typ", typ)
+    }
+
+    /// Set a custom claim
+    pub fn claim(mut self, name: &str, value: impl Into<Value>) -> Self {
+        self.claims.insert(name.to_string(), value.into());
+        self
+    }
+
+    /// Set a custom header parameter, `alg` and `kid` are always taken from the signing key
+    pub fn header(mut self, name: &str, value: impl Into<Value>) -> Self {
+        self.header.insert(name.to_string(), value.into());
+        self
+    }
+
+    /// Sign the token with `key`
+    pub fn sign(&self, key: &SigningKey) -> Result<String, Error> {
+        self.sign_time(key, SystemTime::now())
+    }
+
+    pub fn sign_time(&self, key: &SigningKey, time: SystemTime) -> Result<String, Error> {
+        let mut header = self.header.clone();
+        header.insert("alg".to_string(), key.alg().into());
+        header.insert("kid".to_string(), key.kid().into());
+        header
+            .entry("typ")
+            .or_insert_with(|| Value::String("JWT".to_string()));
+
+        let mut claims = self.claims.clone();
+        claims
+            .entry("iat")
+            .or_insert_with(|| numeric_date(time).into());
+        if let Some(lifetime) = self.lifetime {
+            claims.insert("exp".to_string(), numeric_date(time + lifetime).into());
+        }
+        if self.random_jwt_id {
+            claims.insert("jti".to_string(), random_id()?.into());
+        }
+
+        let body = format!("{}.{}", encode_segment(&header), encode_segment(&claims));
+        let signature = key.sign(body.as_bytes())?;
+
+        Ok(format!("{}.{}", body, URL_SAFE_NO_PAD.encode(signature)))
+    }
+}
+
+fn numeric_date(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn encode_segment(json: &Map<String, Value>) -> String {
+    URL_SAFE_NO_PAD.encode(Value::Object(json.clone()).to_string())
+}
+
+fn random_id() -> Result<String, Error> {
+    let mut bytes = [0u8; 16];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .or(Err(err_sig("Failed to generate random token id")))?;
+    Ok(URL_SAFE_NO_PAD.encode(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::keyset::KeyStore;
+
+    const RSA_PRIVATE_KEY: &str = include_str!("../testdata/rsa_private.pem");
+    const RSA_PRIVATE_KEY_PKCS1: &str = include_str!("../testdata/rsa_private_pkcs1.pem");
+    const RSA_PRIVATE_JWK: &str = include_str!("../testdata/rsa_private.jwk.json");
+    const EC_PRIVATE_KEY: &str = include_str!("../testdata/ec_private.pem");
+    const EC_PRIVATE_JWK: &str = include_str!("../testdata/ec_private.jwk.json");
+    const EC_PUBLIC_KEY: &str = include_str!("../testdata/ec_public.pem");
+    const ED25519_PRIVATE_KEY: &str = include_str!("../testdata/ed25519_private.pem");
+    const ED25519_PRIVATE_JWK: &str = include_str!("../testdata/ed25519_private.jwk.json");
+    const ED25519_PUBLIC_KEY: &str = include_str!("../testdata/ed25519_public.pem");
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn key_store_for(key: &SigningKey) -> KeyStore {
+        let mut key_store = KeyStore::new();
+        key_store.add_key(&key.public_key().unwrap());
+        key_store
+    }
+
+    fn round_trip(key: SigningKey, alg: &str) {
+        let token = JwtBuilder::new()
+            .issuer("https://edge.example.com")
+            .subject("alice")
+            .audience(json!(["invoice", "customer"]))
+            .expires_in(Duration::from_secs(300))
+            .jwt_id("token-1")
+            .claim("client_app_type", "web")
+            .sign_time(&key, at(1000))
+            .unwrap();
+
+        let jwt = key_store_for(&key).verify_time(&token, at(1100)).unwrap();
+
+        assert_eq!(jwt.header().alg(), Some(alg));
+        assert_eq!(jwt.header().kid(), Some(key.kid()));
+        assert_eq!(jwt.header().typ(), Some("JWT"));
+        assert_eq!(jwt.payload().iss(), Some("https://edge.example.com"));
+        assert_eq!(jwt.payload().sub(), Some("alice"));
+        assert_eq!(jwt.payload().iat(), Some(1000));
+        assert_eq!(jwt.payload().exp(), Some(1300));
+        assert_eq!(jwt.payload().jti(), Some("token-1"));
+        assert_eq!(jwt.payload().get_str("client_app_type"), Some("web"));
+    }
+
+    #[test]
+    fn test_rs256_round_trip() {
+        round_trip(
+            SigningKey::from_pem("rsa", RSA_PRIVATE_KEY).unwrap(),
+            "RS256",
+        );
+        round_trip(
+            SigningKey::from_pem("rsa", RSA_PRIVATE_KEY_PKCS1).unwrap(),
+            "RS256",
+        );
+        round_trip(SigningKey::from_jwk(RSA_PRIVATE_JWK).unwrap(), "RS256");
+    }
+
+    #[test]
+    fn test_es256_round_trip() {
+        round_trip(SigningKey::from_pem("ec", EC_PRIVATE_KEY).unwrap(), "ES256");
+        round_trip(SigningKey::from_jwk(EC_PRIVATE_JWK).unwrap(), "ES256");
+    }
+
+    #[test]
+    fn test_eddsa_round_trip() {
+        round_trip(
+            SigningKey::from_pem("ed", ED25519_PRIVATE_KEY).unwrap(),
+            "EdDSA",
+        );
+        round_trip(SigningKey::from_jwk(ED25519_PRIVATE_JWK).unwrap(), "EdDSA");
+    }
+
+    #[test]
+    fn test_pem_and_jwk_keys_match() {
+        let from_pem = SigningKey::from_pem("ec-signer", EC_PRIVATE_KEY).unwrap();
+        let from_jwk = SigningKey::from_jwk(EC_PRIVATE_JWK).unwrap();
+
+        let token = JwtBuilder::new()
+            .expires_in(Duration::from_secs(60))
+            .sign_time(&from_pem, at(1000))
+            .unwrap();
+        assert!(key_store_for(&from_jwk)
+            .verify_time(&token, at(1000))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_public_key_matches_public_pem() {
+        let ec = SigningKey::from_pem("ec", EC_PRIVATE_KEY).unwrap();
+        let ec_public = JwtKey::from_pem("ec", EC_PUBLIC_KEY).unwrap();
+        assert_eq!(ec.public_key().unwrap().x, ec_public.x);
+        assert_eq!(ec.public_key().unwrap().y, ec_public.y);
+
+        let ed = SigningKey::from_pem("ed", ED25519_PRIVATE_KEY).unwrap();
+        let ed_public = JwtKey::from_pem("ed", ED25519_PUBLIC_KEY).unwrap();
+        assert_eq!(ed.public_key().unwrap().x, ed_public.x);
+    }
+
+    #[test]
+    fn test_published_jwks_verifies_tokens() {
+        let keys = [
+            SigningKey::from_pem("rsa", RSA_PRIVATE_KEY).unwrap(),
+            SigningKey::from_pem("ec", EC_PRIVATE_KEY).unwrap(),
+            SigningKey::from_pem("ed", ED25519_PRIVATE_KEY).unwrap(),
+        ];
+        let mut publisher = KeyStore::new();
+        for key in keys.iter() {
+            publisher.add_key(&key.public_key().unwrap());
+        }
+
+        let jwks = publisher.to_jwks().to_string();
+        let key_store = KeyStore::new_from_jwks(&jwks).unwrap();
+
+        assert!(!jwks.contains("\"d\""));
+        for key in keys.iter() {
+            let token = JwtBuilder::new()
+                .expires_in(Duration::from_secs(60))
+                .sign(key)
+                .unwrap();
+            assert!(key_store.verify(&token).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_random_jwt_id() {
+        let key = SigningKey::from_pem("ed", ED25519_PRIVATE_KEY).unwrap();
+        let builder = JwtBuilder::new()
+            .expires_in(Duration::from_secs(60))
+            .random_jwt_id();
+
+        let first = key_store_for(&key)
+            .decode(&builder.sign_time(&key, at(1000)).unwrap())
+            .unwrap();
+        let second = key_store_for(&key)
+            .decode(&builder.sign_time(&key, at(1000)).unwrap())
+            .unwrap();
+
+        assert!(first.payload().jti().is_some());
+        assert_ne!(first.payload().jti(), second.payload().jti());
+    }
+
+    #[test]
+    fn test_tampered_token_is_rejected() {
+        let key = SigningKey::from_pem("rsa", RSA_PRIVATE_KEY).unwrap();
+        let token = JwtBuilder::new()
+            .subject("alice")
+            .expires_in(Duration::from_secs(60))
+            .sign_time(&key, at(1000))
+            .unwrap();
+        let forged_payload = URL_SAFE_NO_PAD.encode(r#"{"sub":"mallory","iat":1000,"exp":1060}"#);
+        let parts: Vec<&str> = token.split('.').collect();
+        let forged = format!("{}.{}.{}", parts[0], forged_payload, parts[2]);
+
+        let err = key_store_for(&key)
+            .verify_time(&forged, at(1000))
+            .unwrap_err();
+        assert_eq!(err.code(), "invalid_signature");
+    }
+
+    #[test]
+    fn test_algorithm_must_match_key() {
+        let rsa = SigningKey::from_pem("shared", RSA_PRIVATE_KEY).unwrap();
+        let ec = SigningKey::from_pem("shared", EC_PRIVATE_KEY).unwrap();
+        let token = JwtBuilder::new()
+            .expires_in(Duration::from_secs(60))
+            .sign_time(&ec, at(1000))
+            .unwrap();
+
+        let err = key_store_for(&rsa)
+            .verify_time(&token, at(1000))
+            .unwrap_err();
+        assert_eq!(err.code(), "invalid_key");
+    }
+
+    #[test]
+    fn test_rejects_unsupported_keys() {
+        assert!(SigningKey::from_pem("rsa", include_str!("../testdata/rsa_public.pem")).is_err());
+        assert!(SigningKey::from_jwk(r#"{"kty":"oct","kid":"k","k":"c2VjcmV0"}"#).is_err());
+        assert!(SigningKey::from_jwk(r#"{"kty":"EC","crv":"P-256"}"#).is_err());
+    }
+}
@@ -0,0 +1,179 @@
+use std::time::{Duration, SystemTime};
+
+use crate::error::Error;
+use crate::jwt::Payload;
+
+/// Rules applied to the time based claims (`exp`, `nbf` and `iat`) of a token
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationOptions {
+    /// Tolerated clock skew between the token issuer and this host.
+    ///
+    /// The default is 60 seconds.
+    pub leeway: Duration,
+    /// Reject tokens issued (`iat`) longer than this ago, regardless of their `exp`.
+    ///
+    /// Requires tokens to carry an `iat` claim. The default is `None` (no maximum age).
+    pub max_age: Option<Duration>,
+    /// Reject tokens without an `exp` claim.
+    ///
+    /// The default is `true`.
+    pub require_exp: bool,
+}
+
+impl Default for ValidationOptions {
+    fn default() -> Self {
+        ValidationOptions {
+            leeway: Duration::from_secs(60),
+            max_age: None,
+            require_exp: true,
+        }
+    }
+}
+
+impl ValidationOptions {
+    /// Check the time based claims of `payload` against the given `time`
+    pub fn validate_time(&self, payload: &Payload, time: SystemTime) -> Result<(), Error> {
+        let now = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let leeway = self.leeway.as_secs();
+
+        match payload.exp() {
+            Some(exp) if now > exp.saturating_add(leeway) => {
+                return Err(Error::Expired { exp, now });
+            }
+            None if self.require_exp => {
+                return Err(Error::MissingClaim { claim: "exp" });
+            }
+            _ => {}
+        }
+
+        if let Some(nbf) = payload.nbf() {
+            if now.saturating_add(leeway) < nbf {
+                return Err(Error::Early { nbf, now });
+            }
+        }
+
+        if let Some(max_age) = self.max_age {
+            let iat = payload.iat().ok_or(Error::MissingClaim { claim: "iat" })?;
+            let max_age = max_age.as_secs();
+            if now > iat.saturating_add(max_age).saturating_add(leeway) {
+                return Err(Error::TooOld { iat, max_age, now });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn payload() -> Payload {
+        Payload::new(json!({ "iat": 1000, "nbf": 1000, "exp": 2000 }))
+    }
+
+    #[test]
+    fn test_valid_token() {
+        let options = ValidationOptions::default();
+
+        assert!(options.validate_time(&payload(), at(1500)).is_ok());
+    }
+
+    #[test]
+    fn test_exp_leeway() {
+        let options = ValidationOptions::default();
+
+        assert!(options.validate_time(&payload(), at(2060)).is_ok());
+        assert!(matches!(
+            options.validate_time(&payload(), at(2061)),
+            Err(Error::Expired {
+                exp: 2000,
+                now: 2061
+            })
+        ));
+    }
+
+    #[test]
+    fn test_nbf_leeway() {
+        let options = ValidationOptions::default();
+
+        assert!(options.validate_time(&payload(), at(940)).is_ok());
+        assert!(matches!(
+            options.validate_time(&payload(), at(939)),
+            Err(Error::Early {
+                nbf: 1000,
+                now: 939
+            })
+        ));
+    }
+
+    #[test]
+    fn test_without_leeway() {
+        let options = ValidationOptions {
+            leeway: Duration::ZERO,
+            ..Default::default()
+        };
+
+        assert!(options.validate_time(&payload(), at(2000)).is_ok());
+        assert!(options.validate_time(&payload(), at(2001)).is_err());
+        assert!(options.validate_time(&payload(), at(999)).is_err());
+    }
+
+    #[test]
+    fn test_require_exp() {
+        let without_exp = Payload::new(json!({ "iat": 1000 }));
+        let options = ValidationOptions::default();
+
+        assert!(matches!(
+            options.validate_time(&without_exp, at(1500)),
+            Err(Error::MissingClaim { claim: "exp" })
+        ));
+
+        let options = ValidationOptions {
+            require_exp: false,
+            ..Default::default()
+        };
+        assert!(options.validate_time(&without_exp, at(1500)).is_ok());
+    }
+
+    #[test]
+    fn test_max_age() {
+        let options = ValidationOptions {
+            max_age: Some(Duration::from_secs(300)),
+            ..Default::default()
+        };
+
+        assert!(options.validate_time(&payload(), at(1360)).is_ok());
+        assert!(matches!(
+            options.validate_time(&payload(), at(1361)),
+            Err(Error::TooOld {
+                iat: 1000,
+                max_age: 300,
+                now: 1361
+            })
+        ));
+    }
+
+    #[test]
+    fn test_max_age_requires_iat() {
+        let without_iat = Payload::new(json!({ "exp": 2000 }));
+        let options = ValidationOptions {
+            max_age: Some(Duration::from_secs(300)),
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            options.validate_time(&without_iat, at(1500)),
+            Err(Error::MissingClaim { claim: "iat" })
+        ));
+    }
+}
@@ -0,0 +1,260 @@
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use jwks_client::keyset::{KeyStore, KeyStoreSnapshot, DEFAULT_MIN_REFRESH_INTERVAL};
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use spin_sdk::http::{send, RequestBuilder, Response};
+use spin_sdk::key_value::Store;
+use spin_sdk::variables;
+
+use crate::models::OpenIdConfiguration;
+use crate::replay::sweep;
+
+/// Used when the IdP does not send a `cache-control` header with a `max-age` directive
+const DEFAULT_MAX_AGE_SECONDS: u64 = 300;
+/// How long a cached document may be used after it expired, if the IdP can't be reached
+const DEFAULT_MAX_STALE_SECONDS: u64 = 86400;
+/// Prefix of the keys of cached introspection results
+const INTROSPECTION_PREFIX: &str = "introspection";
+
+/// Caches the OpenID discovery document and the JWKS in a Spin key-value store.
+///
+/// Fresh entries are used as is. Once an entry expired, the first request revalidates it
+/// while requests arriving during the revalidation keep using the stale entry. If the IdP
+/// can't be reached, the last good entry is used for up to `max_stale`.
+pub struct DocumentCache {
+    store: Store,
+    default_max_age: Duration,
+    max_stale: Duration,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedOpenIdConfiguration {
+    configuration: OpenIdConfiguration,
+    expire_time: SystemTime,
+    last_fetch_time: SystemTime,
+}
+
+/// Result of a token introspection, cached until the token expires
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedIntrospection {
+    claims: Map<String, Value>,
+    expire_time: SystemTime,
+}
+
+impl DocumentCache {
+    pub fn open_default() -> Result<Self> {
+        let store = Store::open_default().with_context(|| "Error opening key-value store")?;
+        Ok(Self {
+            store,
+            default_max_age: Duration::from_secs(seconds_from_variable(
+                "cache_default_max_age",
+                DEFAULT_MAX_AGE_SECONDS,
+            )),
+            max_stale: Duration::from_secs(seconds_from_variable(
+                "cache_max_stale",
+                DEFAULT_MAX_STALE_SECONDS,
+            )),
+        })
+    }
+
+    /// The discovery document of `issuer`, retrieved from `authority`
+    pub async fn openid_configuration(
+        &self,
+        authority: &str,
+        issuer: &str,
+    ) -> Result<OpenIdConfiguration> {
+        let cache_key = format!("oidc:{}:{}", issuer, authority);
+        let now = SystemTime::now();
+        let cached = self.read::<CachedOpenIdConfiguration>(&cache_key);
+
+        let Some(mut cached) = cached else {
+            let (configuration, max_age) = fetch_openid_configuration(authority, issuer).await?;
+            self.write(
+                &cache_key,
+                &CachedOpenIdConfiguration {
+                    configuration: configuration.clone(),
+                    expire_time: now + max_age.unwrap_or(self.default_max_age),
+                    last_fetch_time: now,
+                },
+            );
+            return Ok(configuration);
+        };
+
+        if now < cached.expire_time || !self.can_revalidate(cached.last_fetch_time, now) {
+            return self.use_stale(cached.configuration, cached.expire_time, now);
+        }
+
+        cached.last_fetch_time = now;
+        self.write(&cache_key, &cached);
+
+        match fetch_openid_configuration(authority, issuer).await {
+            Ok((configuration, max_age)) => {
+                cached.configuration = configuration;
+                cached.expire_time = now + max_age.unwrap_or(self.default_max_age);
+                self.write(&cache_key, &cached);
+                Ok(cached.configuration)
+            }
+            Err(e) => {
+                println!("revalidating OpenID configuration failed: {:?}", e);
+                self.use_stale(cached.configuration, cached.expire_time, now)
+            }
+        }
+    }
+
+    pub async fn key_store(&self, jwks_uri: &str) -> Result<KeyStore> {
+        let cache_key = format!("jwks:{}", jwks_uri);
+        let now = SystemTime::now();
+
+        let Some(snapshot) = self.read::<KeyStoreSnapshot>(&cache_key) else {
+            let key_store = KeyStore::new_from(jwks_uri.to_string()).await?;
+            self.save_key_store(&key_store);
+            return Ok(key_store);
+        };
+
+        let mut key_store = KeyStore::from_snapshot(snapshot);
+        let expire_time = self.key_store_expire_time(&key_store);
+
+        if now < expire_time || !key_store.can_refresh_time(now) {
+            return self.use_stale(key_store, expire_time, now);
+        }
+
+        key_store.set_last_fetch_time(now);
+        self.save_key_store(&key_store);
+
+        match key_store.refresh_keys_time(now).await {
+            Ok(_) => {
+                self.save_key_store(&key_store);
+                Ok(key_store)
+            }
+            Err(e) => {
+                println!("revalidating JWKS failed: {:?}", e);
+                self.use_stale(key_store, expire_time, now)
+            }
+        }
+    }
+
+    /// Persist the key store, e.g. after `verify_and_refresh` downloaded the key set again
+    pub fn save_key_store(&self, key_store: &KeyStore) {
+        let cache_key = format!("jwks:{}", key_store.key_set_url());
+        self.write(&cache_key, &key_store.snapshot());
+    }
+
+    /// Claims of an active token introspected before, if the token has not expired since
+    pub fn introspection(&self, token: &str) -> Option<Map<String, Value>> {
+        let cache_key = introspection_cache_key(token);
+        let cached = self.read::<CachedIntrospection>(&cache_key)?;
+        if SystemTime::now() < cached.expire_time {
+            return Some(cached.claims);
+        }
+        if let Err(e) = self.store.delete(&cache_key) {
+            println!("error deleting cache entry {}: {}", cache_key, e);
+        }
+        None
+    }
+
+    /// Cache the claims of an active token until its `exp`. Tokens without `exp` are
+    /// introspected on every request. Expired results of other tokens are deleted by a periodic
+    /// sweep, as each token is only looked up again while it is valid.
+    pub fn save_introspection(&self, token: &str, claims: &Map<String, Value>) {
+        let Some(exp) = claims.get("exp").and_then(Value::as_u64) else {
+            return;
+        };
+        let entry = CachedIntrospection {
+            claims: claims.clone(),
+            expire_time: SystemTime::UNIX_EPOCH + Duration::from_secs(exp),
+        };
+        self.write(&introspection_cache_key(token), &entry);
+
+        let now = SystemTime::now();
+        let expired = |value: &[u8]| {
+            serde_json::from_slice::<CachedIntrospection>(value)
+                .map_or(true, |cached| cached.expire_time <= now)
+        };
+        if let Err(e) = sweep(&self.store, INTROSPECTION_PREFIX, now, expired) {
+            println!("error deleting expired introspection results: {}", e);
+        }
+    }
+
+    fn key_store_expire_time(&self, key_store: &KeyStore) -> SystemTime {
+        key_store
+            .expire_time()
+            .or_else(|| key_store.load_time().map(|t| t + self.default_max_age))
+            .unwrap_or(SystemTime::UNIX_EPOCH)
+    }
+
+    fn can_revalidate(&self, last_fetch_time: SystemTime, now: SystemTime) -> bool {
+        now.duration_since(last_fetch_time)
+            .is_ok_and(|elapsed| elapsed >= DEFAULT_MIN_REFRESH_INTERVAL)
+    }
+
+    fn use_stale<T>(&self, value: T, expire_time: SystemTime, now: SystemTime) -> Result<T> {
+        if now < expire_time + self.max_stale {
+            return Ok(value);
+        }
+        anyhow::bail!("Cached document expired and could not be revalidated")
+    }
+
+    fn read<T: for<'de> Deserialize<'de>>(&self, key: &str) -> Option<T> {
+        let value = self.store.get(key).ok()??;
+        match serde_json::from_slice::<T>(&value) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                println!("ignoring unreadable cache entry {}: {}", key, e);
+                None
+            }
+        }
+    }
+
+    fn write<T: Serialize>(&self, key: &str, value: &T) {
+        let result = serde_json::to_vec(value)
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| Ok(self.store.set(key, &bytes)?));
+        if let Err(e) = result {
+            println!("error writing cache entry {}: {}", key, e);
+        }
+    }
+}
+
+async fn fetch_openid_configuration(
+    authority: &str,
+    issuer: &str,
+) -> Result<(OpenIdConfiguration, Option<Duration>)> {
+    let req = RequestBuilder::new(spin_sdk::http::Method::Get, discovery_url(authority)).build();
+    let res: Response = send(req).await?;
+    let max_age = KeyStore::cache_max_age(&res).map(Duration::from_secs);
+    let configuration = serde_json::from_slice::<OpenIdConfiguration>(res.body())
+        .with_context(|| "Error while deserializing into OpenIdConfiguration")?;
+    configuration
+        .validate(issuer)
+        .with_context(|| format!("invalid discovery document of {}", authority))?;
+    Ok((configuration, max_age))
+}
+
+/// URL of the discovery document of `authority`
+pub(crate) fn discovery_url(authority: &str) -> String {
+    format!(
+        "{}/.well-known/openid-configuration",
+        authority.trim_end_matches('/')
+    )
+}
+
+/// Tokens are bearer credentials, so only their hash is used as key
+fn introspection_cache_key(token: &str) -> String {
+    format!("{}:{}", INTROSPECTION_PREFIX, sha256_hex(token))
+}
+
+/// Hex encoded SHA-256 hash, used to derive fixed length keys from untrusted values
+pub(crate) fn sha256_hex(value: &str) -> String {
+    let hash = digest(&SHA256, value.as_bytes());
+    hash.as_ref().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn seconds_from_variable(name: &str, default: u64) -> u64 {
+    variables::get(name)
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .unwrap_or(default)
+}
@@ -0,0 +1,325 @@
+//! DPoP (RFC 9449): access tokens bound to a key of the client. They are presented with the
+//! `Authorization: DPoP <token>` header along with a proof of possession of the key in the
+//! `DPoP` header.
+
+use std::time::SystemTime;
+
+use anyhow::Result;
+use jwks_client::dpop::{confirmation_thumbprint, DpopOptions, DpopProof};
+use jwks_client::jwt::Jwt;
+use spin_sdk::http::{Request, Response, ResponseBuilder};
+
+use crate::cache::DocumentCache;
+use crate::issuers::TrustedIssuers;
+use crate::replay::SeenIds;
+use crate::ValidationError;
+
+/// Authorization scheme the access token was presented with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scheme {
+    Bearer,
+    Dpop,
+}
+
+/// Check that a DPoP-bound access token is presented with a valid proof for a request with
+/// `method` to `url`, and that tokens not bound to a key are not presented as DPoP tokens.
+/// Returns the response to send to the client if the check fails.
+pub async fn check(
+    req: &Request,
+    scheme: Scheme,
+    access_token: &str,
+    jwt: &Jwt,
+    issuers: &TrustedIssuers,
+    method: &str,
+    url: &str,
+) -> Result<Result<(), Response>> {
+    let options = match scheme {
+        Scheme::Bearer => DpopOptions::default(),
+        Scheme::Dpop => dpop_options(jwt, issuers).await,
+    };
+    let request = ProofRequest {
+        scheme,
+        access_token,
+        method,
+        url,
+    };
+    let now = SystemTime::now();
+
+    let proof = match check_proof(req, &request, jwt, &options, now) {
+        Ok(Some(proof)) => proof,
+        Ok(None) => return Ok(Ok(())),
+        Err(error) => return Ok(Err(dpop_response(&error, &options)?)),
+    };
+
+    // a jti only needs to be unique per key, so the key is part of the record
+    let id = format!("{}:{}", proof.thumbprint(), proof.jti().unwrap_or_default());
+    let expire_time = proof.expire_time(&options).unwrap_or(now);
+    if !SeenIds::open_default("dpop")?.insert(&id, expire_time)? {
+        let error = invalid_proof(String::from("DPoP proof was used before"));
+        return Ok(Err(dpop_response(&error, &options)?));
+    }
+
+    Ok(Ok(()))
+}
+
+/// The request an access token was presented with
+struct ProofRequest<'a> {
+    scheme: Scheme,
+    access_token: &'a str,
+    method: &'a str,
+    url: &'a str,
+}
+
+/// Verify the proof of a DPoP token. Returns `None` for bearer tokens, which must not be
+/// bound to a key.
+fn check_proof(
+    req: &Request,
+    request: &ProofRequest,
+    jwt: &Jwt,
+    options: &DpopOptions,
+    now: SystemTime,
+) -> Result<Option<DpopProof>, ValidationError> {
+    if request.scheme == Scheme::Bearer {
+        if confirmation_thumbprint(jwt).is_some() {
+            return Err(ValidationError {
+                code: Some("invalid_token"),
+                message: String::from("DPoP-bound access token presented as bearer token"),
+            });
+        }
+        return Ok(None);
+    }
+
+    // Spin keeps only one value of a repeated header, but proxies may join repeated headers
+    // with `,`, which never occurs in a proof
+    let proof = req
+        .header("dpop")
+        .and_then(|proof| proof.as_str())
+        .unwrap_or_default();
+    if proof.is_empty() || proof.contains(',') {
+        return Err(invalid_proof(String::from(
+            "Exactly one DPoP header is required",
+        )));
+    }
+
+    let verified = DpopProof::verify(proof, options).and_then(|proof| {
+        proof.validate_request_time(request.method, request.url, options, now)?;
+        proof.validate_binding(request.access_token, jwt)?;
+        Ok(proof)
+    });
+    match verified {
+        Ok(proof) => Ok(Some(proof)),
+        Err(e) => {
+            println!("DPoP proof validation failed: {:?}", e);
+            Err(invalid_proof(e.to_string()))
+        }
+    }
+}
+
+fn invalid_proof(message: String) -> ValidationError {
+    ValidationError {
+        code: Some("invalid_dpop_proof"),
+        message,
+    }
+}
+
+/// Proofs must be signed with one of the `dpop_signing_alg_values_supported` of the issuer,
+/// if it advertises them
+async fn dpop_options(jwt: &Jwt, issuers: &TrustedIssuers) -> DpopOptions {
+    let mut options = DpopOptions::default();
+    let Some(issuer) = jwt.payload().iss().and_then(|iss| issuers.get(iss)) else {
+        return options;
+    };
+    if issuer.jwks.is_some() {
+        return options;
+    }
+
+    let supported = match DocumentCache::open_default() {
+        Ok(cache) => match issuer.openid_configuration(&cache).await {
+            Ok(openid_config) => openid_config.dpop_signing_alg_values_supported,
+            Err(e) => {
+                println!("using default DPoP algorithms: {:#}", e);
+                return options;
+            }
+        },
+        Err(e) => {
+            println!("using default DPoP algorithms: {:#}", e);
+            return options;
+        }
+    };
+    if !supported.is_empty() {
+        options.algorithms.retain(|alg| supported.contains(alg));
+    }
+    options
+}
+
+/// Full URL of the request, as sent by the client
+pub fn request_url(req: &Request) -> String {
+    req.header("spin-full-url")
+        .and_then(|value| value.as_str())
+        .unwrap_or(req.uri())
+        .to_string()
+}
+
+/// URL of the request authorized by a reverse proxy using forward authentication, taken from
+/// the `X-Forwarded-Proto`, `X-Forwarded-Host` and `X-Forwarded-Uri` headers
+pub fn forwarded_url(req: &Request) -> String {
+    let header = |name| req.header(name).and_then(|value| value.as_str());
+    match (header("X-Forwarded-Host"), header("X-Forwarded-Uri")) {
+        (Some(host), Some(uri)) => format!(
+            "{}://{}{}",
+            header("X-Forwarded-Proto").unwrap_or("https"),
+            host,
+            uri
+        ),
+        _ => request_url(req),
+    }
+}
+
+/// `401` with a DPoP challenge listing the accepted proof algorithms
+fn dpop_response(error: &ValidationError, options: &DpopOptions) -> Result<Response> {
+    let challenge = format!(
+        r#"DPoP error="{}", algs="{}""#,
+        error.code.unwrap_or("invalid_token"),
+        options.algorithms.join(" ")
+    );
+    Ok(ResponseBuilder::new(401)
+        .header("content-type", "application/json")
+        .header("www-authenticate", challenge)
+        .body(serde_json::to_string(error)?)
+        .build())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use jwks_client::jwt::{Header, Payload};
+    use jwks_client::signing::{JwtBuilder, SigningKey};
+    use ring::digest::{digest, SHA256};
+    use serde_json::json;
+    use spin_sdk::http::Method;
+
+    use super::*;
+
+    const EC_PRIVATE_KEY: &str = include_str!("../crates/jwks-client/testdata/ec_private.pem");
+    const URL: &str = "https://api.example.com/invoices";
+    const ACCESS_TOKEN: &str = "eyJhbGciOiJFUzI1NiJ9.eyJzdWIiOiJhbGljZSJ9.c2ln";
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn key() -> SigningKey {
+        SigningKey::from_pem("client-key", EC_PRIVATE_KEY).unwrap()
+    }
+
+    fn access_token(jkt: Option<&str>) -> Jwt {
+        let mut claims = json!({ "iss": "https://idp.example.com", "sub": "alice" });
+        if let Some(jkt) = jkt {
+            claims["cnf"] = json!({ "jkt": jkt });
+        }
+        Jwt::new(
+            Header::new(json!({ "alg": "ES256" })),
+            Payload::new(claims),
+            String::new(),
+        )
+    }
+
+    fn proof(key: &SigningKey) -> String {
+        let jwk = serde_json::to_value(key.public_key().unwrap()).unwrap();
+        let ath = URL_SAFE_NO_PAD.encode(digest(&SHA256, ACCESS_TOKEN.as_bytes()));
+        JwtBuilder::new()
+            .token_type("dpop+jwt")
+            .header("jwk", jwk)
+            .jwt_id("proof-1")
+            .claim("htm", "GET")
+            .claim("htu", URL)
+            .claim("ath", ath)
+            .sign_time(key, at(1000))
+            .unwrap()
+    }
+
+    fn request(proofs: &[&str]) -> Request {
+        let mut builder = Request::builder();
+        builder.method(Method::Get).uri(URL);
+        for proof in proofs {
+            builder.header("DPoP", *proof);
+        }
+        builder.build()
+    }
+
+    fn check(req: &Request, scheme: Scheme, jwt: &Jwt) -> Result<bool, ValidationError> {
+        let request = ProofRequest {
+            scheme,
+            access_token: ACCESS_TOKEN,
+            method: "GET",
+            url: URL,
+        };
+        check_proof(req, &request, jwt, &DpopOptions::default(), at(1010))
+            .map(|proof| proof.is_some())
+    }
+
+    #[test]
+    fn test_dpop_token_with_valid_proof() {
+        let key = key();
+        let jkt = key.public_key().unwrap().thumbprint().unwrap();
+        let req = request(&[&proof(&key)]);
+
+        assert_eq!(
+            check(&req, Scheme::Dpop, &access_token(Some(&jkt))).ok(),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn test_dpop_token_requires_single_valid_proof() {
+        let key = key();
+        let jkt = key.public_key().unwrap().thumbprint().unwrap();
+        let jwt = access_token(Some(&jkt));
+        let proof = proof(&key);
+
+        let error = check(&request(&[]), Scheme::Dpop, &jwt).unwrap_err();
+        assert_eq!(error.code, Some("invalid_dpop_proof"));
+        let joined = format!("{}, {}", proof, proof);
+        let error = check(&request(&[&joined]), Scheme::Dpop, &jwt).unwrap_err();
+        assert_eq!(error.message, "Exactly one DPoP header is required");
+
+        // the token is not bound to the key of the proof
+        let error = check(&request(&[&proof]), Scheme::Dpop, &access_token(None)).unwrap_err();
+        assert!(error.message.contains("not bound to a key"));
+    }
+
+    #[test]
+    fn test_bound_token_is_not_accepted_as_bearer_token() {
+        let jwt = access_token(Some("thumbprint"));
+        let error = check(&request(&[]), Scheme::Bearer, &jwt).unwrap_err();
+        assert_eq!(error.code, Some("invalid_token"));
+
+        assert_eq!(
+            check(&request(&[]), Scheme::Bearer, &access_token(None)).ok(),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn test_forwarded_url() {
+        let req = Request::builder()
+            .uri("/validate")
+            .header("X-Forwarded-Proto", "http")
+            .header("X-Forwarded-Host", "api.example.com")
+            .header("X-Forwarded-Uri", "/invoices?page=2")
+            .build();
+        assert_eq!(
+            forwarded_url(&req),
+            "http://api.example.com/invoices?page=2"
+        );
+
+        let req = Request::builder()
+            .uri("/validate")
+            .header("spin-full-url", "https://edge.example.com/validate")
+            .build();
+        assert_eq!(forwarded_url(&req), "https://edge.example.com/validate");
+    }
+}
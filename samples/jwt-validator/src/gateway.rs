@@ -0,0 +1,475 @@
+//! Forward-auth gateway: validates the bearer token of a request and forwards the request to
+//! the configured origin, with selected claims injected as headers.
+
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use anyhow::{Context, Result};
+use jwks_client::jwt::{Jwt, Payload};
+use serde::Deserialize;
+use serde_json::Value;
+use spin_sdk::http::{send, Params, Request, RequestBuilder, Response};
+use spin_sdk::variables;
+
+use crate::dpop::{self, request_url};
+use crate::login;
+use crate::policy::{method_name, normalize_path};
+use crate::sources::Credentials;
+use crate::{authorize, Config, JwtValidationRequestModel};
+
+/// Headers that only apply to a single connection and must not be forwarded
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "host",
+    "content-length",
+];
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GatewayConfig {
+    /// What to do with the `Authorization` header of the incoming request
+    #[serde(default)]
+    pub authorization: AuthorizationForwarding,
+    /// Claims to forward, mapped to the name of the upstream header
+    #[serde(default)]
+    pub claim_headers: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AuthorizationForwarding {
+    /// Remove the header
+    #[default]
+    Strip,
+    /// Forward the header as is
+    Forward,
+    /// Forward the validated token in the given header instead
+    Rename(String),
+}
+
+impl GatewayConfig {
+    /// Load the configuration from the `gateway_config` variable.
+    /// Returns `None` if the gateway is not configured.
+    pub fn load() -> Result<Option<Self>> {
+        let config = variables::get("gateway_config").unwrap_or_default();
+        if config.trim().is_empty() {
+            return Ok(None);
+        }
+        let config = serde_json::from_str(&config).context("invalid gateway_config")?;
+        Ok(Some(config))
+    }
+
+    /// Build the request to send to `origin` for `req`, which was authorized with `jwt` for the
+    /// normalized `path`. The `credentials` of the client are removed from the query and the
+    /// cookies.
+    pub fn upstream_request(
+        &self,
+        origin: &str,
+        req: &Request,
+        path: &str,
+        jwt: &Jwt,
+        token: &str,
+        credentials: &Credentials,
+    ) -> Request {
+        let mut url = format!("{}{}", origin.trim_end_matches('/'), path);
+        let query = without_query_params(req.query(), &credentials.query_params);
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&query);
+        }
+
+        let mut builder = RequestBuilder::new(req.method().clone(), url);
+        for (name, value) in req.headers() {
+            if !self.forwards_header(name) {
+                continue;
+            }
+            let value = String::from_utf8_lossy(value.as_bytes());
+            if name.eq_ignore_ascii_case("cookie") {
+                let cookies = without_cookies(&value, &credentials.cookies);
+                if !cookies.is_empty() {
+                    builder.header(name, cookies);
+                }
+            } else {
+                builder.header(name, value.into_owned());
+            }
+        }
+
+        match &self.authorization {
+            AuthorizationForwarding::Strip => {}
+            AuthorizationForwarding::Forward => {
+                if let Some(value) = req.header("authorization") {
+                    builder.header(
+                        "authorization",
+                        String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                    );
+                }
+            }
+            AuthorizationForwarding::Rename(header) => {
+                builder.header(header, token);
+            }
+        }
+
+        for (claim, header) in self.claim_headers.iter() {
+            if let Some(value) = claim_header_value(jwt.payload(), claim) {
+                builder.header(header, value);
+            }
+        }
+
+        builder.body(req.body().to_vec());
+        builder.build()
+    }
+
+    /// Incoming headers that collide with headers set by the gateway are dropped, so clients
+    /// can not spoof claims.
+    fn forwards_header(&self, name: &str) -> bool {
+        let set_by_gateway = |header: &String| header.eq_ignore_ascii_case(name);
+
+        !HOP_BY_HOP_HEADERS.contains(&name.to_ascii_lowercase().as_str())
+            && !name.eq_ignore_ascii_case("authorization")
+            && !self.claim_headers.values().any(set_by_gateway)
+            && !matches!(&self.authorization, AuthorizationForwarding::Rename(header) if set_by_gateway(header))
+    }
+}
+
+/// Forward requests to routes covered by the policy to the origin
+pub async fn handle_forward(req: Request, _: Params, config: Rc<Config>) -> Result<Response> {
+    let Some(gateway) = config.gateway.as_ref() else {
+        return Ok(Response::new(404, ()));
+    };
+    // the path the policy is checked for is the one forwarded to the origin
+    let Some(path) = normalize_path(req.path()) else {
+        return Ok(Response::new(400, "invalid request path"));
+    };
+    let Some(route) = config.policy.route(method_name(req.method()), &path) else {
+        return Ok(Response::new(404, ()));
+    };
+    let Ok(origin) = variables::get("gateway_origin") else {
+        return Ok(Response::new(
+            500,
+            "application not configured correctly, gateway_origin missing",
+        ));
+    };
+    let method = method_name(req.method());
+    // without a token from the configured sources, browsers present the session of the login
+    let presented = match (config.tokens.token(&req, method)?, &config.login) {
+        (Ok(presented), _) => presented,
+        (Err(response), Some(login)) if *response.status() == 401 => {
+            match login::session_token(&req, login, &config, method).await? {
+                Ok(presented) => presented,
+                Err(response) => return Ok(response),
+            }
+        }
+        (Err(response), _) => return Ok(response),
+    };
+    let (scheme, token) = (presented.scheme, presented.token.as_str());
+
+    let model = JwtValidationRequestModel {
+        jwt: String::from(token),
+        options: route.options.clone(),
+    };
+    let jwt = match authorize(&model, &config.issuers).await? {
+        Ok(jwt) => jwt,
+        Err(response) => return Ok(response),
+    };
+    let url = request_url(&req);
+    if let Err(response) =
+        dpop::check(&req, scheme, token, &jwt, &config.issuers, method, &url).await?
+    {
+        return Ok(response);
+    }
+
+    let mut credentials = config.tokens.credentials();
+    if let Some(login) = &config.login {
+        credentials.cookies.extend(login.cookie_names());
+    }
+    let upstream = gateway.upstream_request(&origin, &req, &path, &jwt, token, &credentials);
+    match send::<_, Response>(upstream).await {
+        Ok(response) => Ok(response),
+        Err(e) => {
+            println!("forwarding request to {} failed: {}", origin, e);
+            Ok(Response::new(502, ()))
+        }
+    }
+}
+
+/// `query` without the parameters `names`, the other parameters are kept as they are
+fn without_query_params(query: &str, names: &[String]) -> String {
+    query
+        .split('&')
+        .filter(|param| {
+            let name = form_urlencoded::parse(param.as_bytes()).next();
+            !name.is_some_and(|(name, _)| names.iter().any(|n| *n == name))
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// The `Cookie` header `cookies` without the cookies `names`
+fn without_cookies(cookies: &str, names: &[String]) -> String {
+    cookies
+        .split(';')
+        .map(str::trim)
+        .filter(|cookie| {
+            let name = cookie.split_once('=').map_or(*cookie, |(name, _)| name);
+            !cookie.is_empty() && !names.iter().any(|n| n == name)
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Render a claim as header value. Lists are joined with `,`, objects are not forwarded.
+fn claim_header_value(payload: &Payload, claim: &str) -> Option<String> {
+    let value = match payload.get_array(claim) {
+        Some(values) => values
+            .iter()
+            .map(scalar_value)
+            .collect::<Option<Vec<_>>>()?
+            .join(","),
+        None => payload
+            .get_str(claim)
+            .map(String::from)
+            .or_else(|| payload.get_bool(claim).map(|b| b.to_string()))
+            .or_else(|| payload.get_i64(claim).map(|n| n.to_string()))
+            .or_else(|| payload.get_f64(claim).map(|n| n.to_string()))?,
+    };
+
+    // header values must not be used to inject additional headers
+    if value.chars().any(char::is_control) {
+        return None;
+    }
+    Some(value)
+}
+
+fn scalar_value(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jwks_client::jwt::Header;
+    use serde_json::json;
+    use spin_sdk::http::Method;
+
+    use super::*;
+    use crate::sources::TokenSources;
+
+    fn config(json: Value) -> GatewayConfig {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn jwt() -> Jwt {
+        Jwt::new(
+            Header::new(json!({ "alg": "RS256" })),
+            Payload::new(json!({
+                "sub": "alice",
+                "scope": ["invoice.read", "invoice.write"],
+                "admin": false,
+                "address": { "country": "DE" },
+                "name": "Alice\r\nx-admin: true"
+            })),
+            String::new(),
+        )
+    }
+
+    fn header<'a>(req: &'a Request, name: &str) -> Option<&'a str> {
+        req.header(name).and_then(|value| value.as_str())
+    }
+
+    #[test]
+    fn test_upstream_request_injects_claims() {
+        let config = config(json!({
+            "claimHeaders": { "sub": "x-user-sub", "scope": "x-user-scope", "admin": "x-user-admin" }
+        }));
+        let req = Request::builder()
+            .method(Method::Post)
+            .uri("https://edge.example.com/api/invoices?page=2")
+            .header("authorization", "Bearer token")
+            .header("x-user-sub", "mallory")
+            .header("accept", "application/json")
+            .header("connection", "keep-alive")
+            .body("{}")
+            .build();
+
+        let upstream = config.upstream_request(
+            "https://origin.example.com/",
+            &req,
+            "/api/invoices",
+            &jwt(),
+            "token",
+            &Credentials::default(),
+        );
+
+        assert_eq!(
+            upstream.uri(),
+            "https://origin.example.com/api/invoices?page=2"
+        );
+        assert_eq!(upstream.method(), &Method::Post);
+        assert_eq!(upstream.body(), b"{}");
+        assert_eq!(header(&upstream, "x-user-sub"), Some("alice"));
+        assert_eq!(
+            header(&upstream, "x-user-scope"),
+            Some("invoice.read,invoice.write")
+        );
+        assert_eq!(header(&upstream, "x-user-admin"), Some("false"));
+        assert_eq!(header(&upstream, "accept"), Some("application/json"));
+        assert_eq!(
+            upstream
+                .headers()
+                .filter(|(name, _)| *name == "x-user-sub")
+                .count(),
+            1
+        );
+        assert!(upstream.header("authorization").is_none());
+        assert!(upstream.header("connection").is_none());
+    }
+
+    #[test]
+    fn test_authorization_forwarding() {
+        let req = Request::builder()
+            .uri("/api")
+            .header("authorization", "Bearer token")
+            .header("x-access-token", "forged")
+            .build();
+
+        let forward = config(json!({ "authorization": "forward" }));
+        let upstream = forward.upstream_request(
+            "https://origin.example.com",
+            &req,
+            "/api",
+            &jwt(),
+            "token",
+            &Credentials::default(),
+        );
+        assert_eq!(header(&upstream, "authorization"), Some("Bearer token"));
+
+        let rename = config(json!({ "authorization": { "rename": "x-access-token" } }));
+        let upstream = rename.upstream_request(
+            "https://origin.example.com",
+            &req,
+            "/api",
+            &jwt(),
+            "token",
+            &Credentials::default(),
+        );
+        assert!(upstream.header("authorization").is_none());
+        assert_eq!(header(&upstream, "x-access-token"), Some("token"));
+    }
+
+    #[test]
+    fn test_query_token_is_not_forwarded() {
+        let sources =
+            TokenSources::parse(r#"[{ "type": "query", "name": "access_token" }]"#, "").unwrap();
+        let req = Request::builder()
+            .uri("https://edge.example.com/api?page=2&access_token=token&sort=a%20b")
+            .build();
+
+        let upstream = config(json!({})).upstream_request(
+            "https://origin.example.com",
+            &req,
+            "/api",
+            &jwt(),
+            "token",
+            &sources.credentials(),
+        );
+        assert_eq!(
+            upstream.uri(),
+            "https://origin.example.com/api?page=2&sort=a%20b"
+        );
+
+        let req = Request::builder()
+            .uri("https://edge.example.com/api?access%5Ftoken=token")
+            .build();
+        let upstream = config(json!({})).upstream_request(
+            "https://origin.example.com",
+            &req,
+            "/api",
+            &jwt(),
+            "token",
+            &sources.credentials(),
+        );
+        assert_eq!(upstream.uri(), "https://origin.example.com/api");
+    }
+
+    #[test]
+    fn test_cookie_token_is_not_forwarded() {
+        let sources = TokenSources::parse(
+            r#"[{ "type": "cookie", "name": "access_token" }]"#,
+            r#"{ "mode": "disabled" }"#,
+        )
+        .unwrap();
+        let forwarded = |cookies: &str| {
+            let req = Request::builder()
+                .uri("/api")
+                .header("cookie", cookies)
+                .build();
+            let upstream = config(json!({})).upstream_request(
+                "https://origin.example.com",
+                &req,
+                "/api",
+                &jwt(),
+                "token",
+                &sources.credentials(),
+            );
+            header(&upstream, "cookie").map(String::from)
+        };
+
+        assert_eq!(
+            forwarded("theme=dark; access_token=token;lang=de").as_deref(),
+            Some("theme=dark; lang=de")
+        );
+        assert_eq!(forwarded("access_token=token"), None);
+    }
+
+    #[test]
+    fn test_session_cookies_are_not_forwarded() {
+        let credentials = Credentials {
+            cookies: vec![
+                String::from("__Host-session"),
+                String::from("__Host-session-login"),
+            ],
+            query_params: Vec::new(),
+        };
+        let req = Request::builder()
+            .uri("/api")
+            .header(
+                "cookie",
+                "__Host-session=id; theme=dark; __Host-session-login=state",
+            )
+            .build();
+
+        let upstream = config(json!({})).upstream_request(
+            "https://origin.example.com",
+            &req,
+            "/api",
+            &jwt(),
+            "token",
+            &credentials,
+        );
+        assert_eq!(header(&upstream, "cookie"), Some("theme=dark"));
+    }
+
+    #[test]
+    fn test_claim_header_values() {
+        let jwt = jwt();
+
+        assert_eq!(
+            claim_header_value(jwt.payload(), "sub").as_deref(),
+            Some("alice")
+        );
+        assert_eq!(claim_header_value(jwt.payload(), "address"), None);
+        assert_eq!(claim_header_value(jwt.payload(), "name"), None);
+        assert_eq!(claim_header_value(jwt.payload(), "missing"), None);
+    }
+}
@@ -0,0 +1,565 @@
+//! `POST /inspect`: a report on why a token is accepted or rejected, to troubleshoot tokens
+//! without pasting them into external tools.
+
+use std::rc::Rc;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use jwks_client::claims::{ClaimRule, ClaimsValidator};
+use jwks_client::error::Error;
+use jwks_client::jwt::{Jwt, Payload};
+use jwks_client::keyset::KeyStore;
+use jwks_client::validation::ValidationOptions;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use spin_sdk::http::{IntoResponse, Params, Request, Response, ResponseBuilder};
+
+use crate::cache::DocumentCache;
+use crate::issuers::{TrustedIssuers, UntrustedIssuer};
+use crate::policy::{normalize_path, Policy};
+use crate::revocation::{authorize_admin, unix_time, RevocationList};
+use crate::{claim_rules, Config, JwtValidationOptions, LenientOptions};
+
+/// Claims shown when the report is redacted. They describe the token rather than its
+/// subject.
+const UNREDACTED_CLAIMS: [&str; 16] = [
+    "iss",
+    "aud",
+    "exp",
+    "nbf",
+    "iat",
+    "auth_time",
+    "jti",
+    "scope",
+    "scp",
+    "azp",
+    "client_id",
+    "typ",
+    "token_use",
+    "cnf",
+    "acr",
+    "amr",
+];
+const TIME_CLAIMS: [&str; 4] = ["exp", "nbf", "iat", "auth_time"];
+
+/// Payload of `POST /inspect`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct InspectRequest {
+    pub jwt: String,
+    /// Options to check the token against, instead of those of a policy route
+    pub options: Option<LenientOptions>,
+    /// Method and path of the policy route whose options are checked
+    pub method: Option<String>,
+    pub path: Option<String>,
+    /// Hide the values of claims that may identify the subject of the token
+    #[serde(default)]
+    pub redact: bool,
+}
+
+impl InspectRequest {
+    fn options(&self, policy: &Policy) -> Result<JwtValidationOptions, String> {
+        if let Some(lenient) = self.options.as_ref() {
+            return Ok(lenient.options.clone());
+        }
+        let Some(path) = self.path.as_deref() else {
+            return Ok(JwtValidationOptions::default());
+        };
+        let method = self.method.as_deref().unwrap_or("GET");
+        let normalized = normalize_path(path).ok_or_else(|| format!("invalid path {}", path))?;
+        policy
+            .route(method, &normalized)
+            .map(|route| route.options.clone())
+            .ok_or_else(|| format!("no policy for {} {}", method, path))
+    }
+}
+
+/// Result of a single check
+#[derive(Debug, Serialize)]
+pub struct RuleResult {
+    pub rule: String,
+    pub passed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// The claim rule, for rules of the validation options
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub check: Option<ClaimRule>,
+}
+
+impl RuleResult {
+    fn passed(rule: &str) -> Self {
+        RuleResult {
+            rule: rule.to_string(),
+            passed: true,
+            code: None,
+            message: None,
+            check: None,
+        }
+    }
+
+    fn failed(rule: &str, code: &'static str, message: String) -> Self {
+        RuleResult {
+            rule: rule.to_string(),
+            passed: false,
+            code: Some(code),
+            message: Some(message),
+            check: None,
+        }
+    }
+
+    fn from_result(rule: &str, result: Result<(), Error>) -> Self {
+        match result {
+            Ok(()) => RuleResult::passed(rule),
+            Err(e) => RuleResult::failed(rule, e.code(), e.to_string()),
+        }
+    }
+}
+
+/// The report returned by `/inspect`
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Report {
+    /// Whether the token passes every rule. One-time use is not checked, as that would use
+    /// up the token.
+    pub valid: bool,
+    pub header: Option<Value>,
+    pub payload: Option<Value>,
+    /// Time claims as RFC 3339 timestamps, and relative to the time of the report
+    pub timestamps: Map<String, Value>,
+    /// The trusted issuer selected by the `iss` claim
+    pub issuer: Option<String>,
+    /// The `kid` of the token, if the key set of the issuer has a key with this id
+    pub matched_kid: Option<String>,
+    /// Whether the signature was verified with the matched key
+    pub verified: bool,
+    pub rules: Vec<RuleResult>,
+}
+
+/// `POST /inspect`
+pub async fn handle_inspect(
+    req: Request,
+    _: Params,
+    config: Rc<Config>,
+) -> Result<impl IntoResponse> {
+    if let Err(response) = authorize_admin(&req) {
+        return Ok(response);
+    }
+    let request = match serde_json::from_slice::<InspectRequest>(req.body()) {
+        Ok(request) => request,
+        Err(e) => return Ok(Response::new(400, format!("invalid request: {}", e))),
+    };
+    let options = match request.options(&config.policy) {
+        Ok(options) => options,
+        Err(message) => return Ok(Response::new(400, message)),
+    };
+
+    let report = inspect(&request, options, &config.issuers).await?;
+    Ok(ResponseBuilder::new(200)
+        .header("content-type", "application/json")
+        .body(serde_json::to_string_pretty(&report)?)
+        .build())
+}
+
+async fn inspect(
+    request: &InspectRequest,
+    options: JwtValidationOptions,
+    issuers: &TrustedIssuers,
+) -> Result<Report> {
+    let now = SystemTime::now();
+    let mut report = Report::default();
+
+    let jwt = match KeyStore::new().decode(&request.jwt) {
+        Ok(jwt) => jwt,
+        Err(e) => {
+            report
+                .rules
+                .push(RuleResult::failed("decode", e.code(), e.to_string()));
+            return Ok(report);
+        }
+    };
+    report.rules.push(RuleResult::passed("decode"));
+    describe(&mut report, &jwt, request.redact, unix_time(now));
+
+    let mut options = options;
+    let mut validation = ValidationOptions::default();
+    match issuers.select(&request.jwt) {
+        Ok(issuer) => {
+            report.issuer = Some(issuer.issuer.clone());
+            report.rules.push(RuleResult::passed("issuer"));
+            options = issuer.bind(&options);
+
+            let cache = DocumentCache::open_default()?;
+            match issuer.key_store(&cache).await {
+                Ok(mut key_set) => {
+                    refresh_for_unknown_kid(&mut key_set, &jwt, &cache, now).await;
+                    report.matched_kid = jwt
+                        .header()
+                        .kid()
+                        .filter(|kid| key_set.key_by_id(kid).is_some())
+                        .map(String::from);
+                    let verified = key_set.verify_signature_only(&request.jwt).map(|_| ());
+                    report.verified = verified.is_ok();
+                    report
+                        .rules
+                        .push(RuleResult::from_result("signature", verified));
+                    validation = key_set.validation_options().clone();
+                }
+                Err(e) => report.rules.push(RuleResult::failed(
+                    "signature",
+                    "jwks_unavailable",
+                    format!("{:#}", e),
+                )),
+            }
+        }
+        Err(UntrustedIssuer(iss)) => report.rules.push(RuleResult::failed(
+            "issuer",
+            "untrusted_issuer",
+            format!(
+                "JWT issuer {} is not trusted",
+                iss.as_deref().unwrap_or("none")
+            ),
+        )),
+    }
+
+    report
+        .rules
+        .extend(check_rules(&jwt, &options, &validation, now));
+
+    let revocation = RevocationList::open_default()?.revocation(&jwt, unix_time(now))?;
+    report.rules.push(match revocation {
+        Some(revocation) => RuleResult::failed(
+            "revocation",
+            "token_revoked",
+            format!(
+                "JWT has been revoked{}",
+                revocation
+                    .reason
+                    .map(|reason| format!(": {}", reason))
+                    .unwrap_or_default()
+            ),
+        ),
+        None => RuleResult::passed("revocation"),
+    });
+
+    report.valid = report.rules.iter().all(|rule| rule.passed);
+    Ok(report)
+}
+
+/// Download the key set again if it doesn't have the key of `jwt`, as `/validate` would
+async fn refresh_for_unknown_kid(
+    key_set: &mut KeyStore,
+    jwt: &Jwt,
+    cache: &DocumentCache,
+    now: SystemTime,
+) {
+    let unknown_kid = jwt
+        .header()
+        .kid()
+        .is_some_and(|kid| key_set.key_by_id(kid).is_none());
+    if !unknown_kid || !key_set.can_refresh_time(now) {
+        return;
+    }
+    if let Err(e) = key_set.refresh_keys_time(now).await {
+        println!("refreshing keys failed: {:?}", e);
+    }
+    cache.save_key_store(key_set);
+}
+
+/// Add the header, payload and timestamps of `jwt` to the report
+fn describe(report: &mut Report, jwt: &Jwt, redact: bool, now: u64) {
+    report.header = jwt.header().into::<Value>().ok();
+
+    let mut payload = jwt
+        .payload()
+        .into::<Map<String, Value>>()
+        .unwrap_or_default();
+    if redact {
+        for (claim, value) in payload.iter_mut() {
+            if !UNREDACTED_CLAIMS.contains(&claim.as_str()) {
+                *value = Value::from("[redacted]");
+            }
+        }
+    }
+    report.payload = Some(Value::Object(payload));
+
+    for claim in TIME_CLAIMS {
+        if let Some(time) = jwt.payload().get_u64(claim) {
+            report.timestamps.insert(
+                claim.to_string(),
+                json!({ "utc": format_utc(time), "relative": format_relative(time, now) }),
+            );
+        }
+    }
+}
+
+/// Check the time based claims and the validation options separately, so that every failed
+/// rule is reported
+fn check_rules(
+    jwt: &Jwt,
+    options: &JwtValidationOptions,
+    validation: &ValidationOptions,
+    now: SystemTime,
+) -> Vec<RuleResult> {
+    let mut results = Vec::new();
+    let payload = jwt.payload();
+    // a payload with just one of the time claims of the token
+    let only = |claim: &str| {
+        let mut claims = Map::new();
+        if let Some(value) = payload.get_u64(claim) {
+            claims.insert(claim.to_string(), Value::from(value));
+        }
+        Payload::new(Value::Object(claims))
+    };
+
+    let exp = ValidationOptions {
+        max_age: None,
+        ..validation.clone()
+    };
+    results.push(RuleResult::from_result(
+        "exp",
+        exp.validate_time(&only("exp"), now),
+    ));
+    let others = ValidationOptions {
+        require_exp: false,
+        ..validation.clone()
+    };
+    if payload.nbf().is_some() {
+        let nbf = ValidationOptions {
+            max_age: None,
+            ..others.clone()
+        };
+        results.push(RuleResult::from_result(
+            "nbf",
+            nbf.validate_time(&only("nbf"), now),
+        ));
+    }
+    if others.max_age.is_some() {
+        results.push(RuleResult::from_result(
+            "maxAge",
+            others.validate_time(&only("iat"), now),
+        ));
+    }
+
+    if let Some(want) = options.expected_token_type.as_ref() {
+        results.push(match jwt.header().typ() {
+            Some(got) if got.eq_ignore_ascii_case(want) => RuleResult::passed("typ"),
+            got => RuleResult::failed(
+                "typ",
+                "invalid_token_type",
+                format!(
+                    "JWT has wrong token type. Got: {} Wanted: {}",
+                    got.unwrap_or("none"),
+                    want
+                ),
+            ),
+        });
+    }
+
+    for rule in claim_rules(options) {
+        let name = format!("claim:{}", rule.claim);
+        let mut result = match ClaimsValidator::new(vec![rule.clone()]) {
+            Ok(validator) => match validator.validate_jwt(jwt).into_iter().next() {
+                Some(violation) => RuleResult::failed(&name, violation.code, violation.message),
+                None => RuleResult::passed(&name),
+            },
+            Err(e) => RuleResult::failed(&name, e.code(), e.to_string()),
+        };
+        result.check = Some(rule);
+        results.push(result);
+    }
+
+    results
+}
+
+/// RFC 3339 timestamp in UTC of `secs` since the epoch
+fn format_utc(secs: u64) -> String {
+    let (days, time) = (secs / 86400, secs % 86400);
+
+    // civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// `time` relative to `now`, e.g. "in 4m 10s" or "2d 3h ago"
+fn format_relative(time: u64, now: u64) -> String {
+    if time >= now {
+        format!("in {}", format_duration(time - now))
+    } else {
+        format!("{} ago", format_duration(now - time))
+    }
+}
+
+/// The two largest units of `secs`, e.g. "2d 3h"
+fn format_duration(secs: u64) -> String {
+    let units = [
+        (secs / 86400, "d"),
+        (secs % 86400 / 3600, "h"),
+        (secs % 3600 / 60, "m"),
+        (secs % 60, "s"),
+    ];
+    let first = units
+        .iter()
+        .position(|(value, _)| *value > 0)
+        .unwrap_or(units.len() - 1);
+    units[first..]
+        .iter()
+        .take(2)
+        .filter(|(value, _)| *value > 0 || first == units.len() - 1)
+        .map(|(value, unit)| format!("{}{}", value, unit))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use jwks_client::jwt::Header;
+
+    use super::*;
+
+    fn jwt(claims: Value) -> Jwt {
+        Jwt::new(
+            Header::new(json!({ "alg": "RS256", "kid": "test-key" })),
+            Payload::new(claims),
+            String::new(),
+        )
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn rule<'a>(results: &'a [RuleResult], name: &str) -> &'a RuleResult {
+        results.iter().find(|result| result.rule == name).unwrap()
+    }
+
+    #[test]
+    fn test_format_timestamps() {
+        assert_eq!(format_utc(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_utc(1700003600), "2023-11-14T23:13:20Z");
+        assert_eq!(format_utc(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(format_utc(4107542399), "2100-02-28T23:59:59Z");
+
+        assert_eq!(format_relative(1250, 1000), "in 4m 10s");
+        assert_eq!(
+            format_relative(1000, 1000 + 2 * 86400 + 3 * 3600 + 59),
+            "2d 3h ago"
+        );
+        assert_eq!(format_relative(1000 + 3600, 1000), "in 1h");
+        assert_eq!(format_relative(1000, 1000), "in 0s");
+    }
+
+    #[test]
+    fn test_every_rule_is_reported() {
+        let jwt = jwt(json!({
+            "iss": "https://idp.example.com",
+            "scope": "invoice.read",
+            "exp": 1000,
+            "nbf": 500,
+            "iat": 500,
+        }));
+        let options = JwtValidationOptions {
+            expected_token_type: Some(String::from("at+jwt")),
+            expected_scopes: Some(vec![String::from("invoice.read")]),
+            expected_audiences: Some(vec![String::from("invoice")]),
+            ..Default::default()
+        };
+        let validation = ValidationOptions {
+            max_age: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        };
+
+        let results = check_rules(&jwt, &options, &validation, at(2000));
+        let failed = |name| !rule(&results, name).passed;
+
+        assert!(failed("exp"));
+        assert_eq!(rule(&results, "exp").code, Some("token_expired"));
+        assert!(!failed("nbf"));
+        assert!(!failed("maxAge"));
+        assert!(failed("typ"));
+        assert!(failed("claim:aud"));
+        assert!(!failed("claim:scope"));
+        assert_eq!(results.len(), 6);
+    }
+
+    #[test]
+    fn test_missing_exp_is_reported() {
+        let results = check_rules(
+            &jwt(json!({ "sub": "alice" })),
+            &JwtValidationOptions::default(),
+            &ValidationOptions::default(),
+            at(2000),
+        );
+
+        assert_eq!(rule(&results, "exp").code, Some("missing_claim"));
+        assert_eq!(results.len(), 1);
+    }
+
+    #[test]
+    fn test_redacted_report() {
+        let jwt = jwt(json!({
+            "iss": "https://idp.example.com",
+            "sub": "alice",
+            "email": "alice@example.com",
+            "exp": 1700003600,
+        }));
+
+        let mut report = Report::default();
+        describe(&mut report, &jwt, true, 1700000000);
+        let payload = report.payload.unwrap();
+        assert_eq!(payload["sub"], "[redacted]");
+        assert_eq!(payload["email"], "[redacted]");
+        assert_eq!(payload["iss"], "https://idp.example.com");
+        assert_eq!(report.header.unwrap()["kid"], "test-key");
+        assert_eq!(
+            report.timestamps["exp"],
+            json!({ "utc": "2023-11-14T23:13:20Z", "relative": "in 1h" })
+        );
+
+        let mut report = Report::default();
+        describe(&mut report, &jwt, false, 1700000000);
+        assert_eq!(report.payload.unwrap()["sub"], "alice");
+    }
+
+    #[test]
+    fn test_options_from_policy_route() {
+        let policy = Policy::parse(
+            r#"{ "routes": [{ "path": "/api/...", "options": { "expectedScopes": ["api"] } }] }"#,
+        )
+        .unwrap();
+        let request = |body: Value| serde_json::from_value::<InspectRequest>(body).unwrap();
+
+        let options = request(json!({ "jwt": "a.b.c", "path": "/api/invoices" }))
+            .options(&policy)
+            .unwrap();
+        assert_eq!(options.expected_scopes, Some(vec![String::from("api")]));
+
+        let error = request(json!({ "jwt": "a.b.c", "path": "/other" }))
+            .options(&policy)
+            .unwrap_err();
+        assert_eq!(error, "no policy for GET /other");
+
+        let options = request(json!({ "jwt": "a.b.c" })).options(&policy).unwrap();
+        assert!(options.expected_scopes.is_none());
+    }
+}
@@ -0,0 +1,180 @@
+//! OAuth 2.0 token introspection (RFC 7662) for opaque tokens, which can't be verified with
+//! the keys of the issuer.
+
+use anyhow::{bail, Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use jwks_client::jwt::{Header, Jwt, Payload};
+use serde_json::{json, Map, Value};
+use spin_sdk::http::{send, Method, RequestBuilder, Response};
+use spin_sdk::variables;
+
+use crate::cache::DocumentCache;
+use crate::issuers::TrustedIssuer;
+use crate::{check_claims, json_response, JwtValidationRequestModel, ValidationError};
+
+/// Introspect the opaque token of `model` at the introspection endpoint of `issuer` and
+/// validate the returned claims against its options.
+/// Returns the claims as token if it is valid, otherwise the response to send to the client.
+pub async fn authorize(
+    model: &JwtValidationRequestModel,
+    issuer: &TrustedIssuer,
+    cache: &DocumentCache,
+) -> Result<Result<Jwt, Response>> {
+    let claims = match cache.introspection(&model.jwt) {
+        Some(claims) => claims,
+        None => match introspect(issuer, cache, &model.jwt).await {
+            Ok(Some(claims)) => {
+                cache.save_introspection(&model.jwt, &claims);
+                claims
+            }
+            Ok(None) => {
+                let error = ValidationError {
+                    code: Some("inactive_token"),
+                    message: String::from("Token is not active"),
+                };
+                return Ok(Err(json_response(401, &error)?));
+            }
+            Err(e) => {
+                println!("token introspection failed: {:#}", e);
+                let error = ValidationError {
+                    code: Some("introspection_unavailable"),
+                    message: String::from("Token introspection failed"),
+                };
+                return Ok(Err(json_response(503, &error)?));
+            }
+        },
+    };
+
+    let mut options = issuer.bind(&model.options);
+    // opaque tokens have no header, so there is no token type to check
+    options.expected_token_type = None;
+    check_claims(introspected_jwt(issuer, claims), &options)
+}
+
+/// Claims of the token if the authorization server reports it as active
+async fn introspect(
+    issuer: &TrustedIssuer,
+    cache: &DocumentCache,
+    token: &str,
+) -> Result<Option<Map<String, Value>>> {
+    let openid_config = issuer.openid_configuration(cache).await?;
+    let Some(endpoint) = openid_config.introspection_endpoint else {
+        bail!("issuer {} has no introspection endpoint", issuer.issuer);
+    };
+    let client_id = variables::get("introspection_client_id")
+        .context("application not configured correctly, introspection_client_id missing")?;
+    let client_secret = variables::get("introspection_client_secret").unwrap_or_default();
+
+    let body = form_urlencoded::Serializer::new(String::new())
+        .append_pair("token", token)
+        .append_pair("token_type_hint", "access_token")
+        .finish();
+    let req = RequestBuilder::new(Method::Post, &endpoint)
+        .header("content-type", "application/x-www-form-urlencoded")
+        .header("accept", "application/json")
+        .header(
+            "authorization",
+            basic_credentials(&client_id, &client_secret),
+        )
+        .body(body)
+        .build();
+    let res: Response = send(req).await?;
+    if *res.status() != 200 {
+        bail!(
+            "introspection endpoint {} responded with {}",
+            endpoint,
+            res.status()
+        );
+    }
+    parse_introspection_response(res.body())
+}
+
+/// Client credentials are form-encoded before they are joined, see RFC 6749 section 2.3.1
+pub(crate) fn basic_credentials(client_id: &str, client_secret: &str) -> String {
+    let encode =
+        |value: &str| form_urlencoded::byte_serialize(value.as_bytes()).collect::<String>();
+    let credentials = format!("{}:{}", encode(client_id), encode(client_secret));
+    format!("Basic {}", STANDARD.encode(credentials))
+}
+
+fn parse_introspection_response(body: &[u8]) -> Result<Option<Map<String, Value>>> {
+    let mut claims = serde_json::from_slice::<Map<String, Value>>(body)
+        .context("invalid introspection response")?;
+    match claims.remove("active") {
+        Some(Value::Bool(true)) => Ok(Some(claims)),
+        Some(Value::Bool(false)) => Ok(None),
+        _ => bail!("introspection response has no active member"),
+    }
+}
+
+/// Wrap the introspected claims as a token without header and signature, so they pass the
+/// same claim validation as verified JWTs
+fn introspected_jwt(issuer: &TrustedIssuer, mut claims: Map<String, Value>) -> Jwt {
+    claims
+        .entry("iss")
+        .or_insert_with(|| Value::String(issuer.issuer.clone()));
+    Jwt::new(
+        Header::new(json!({})),
+        Payload::new(Value::Object(claims)),
+        String::new(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn issuer() -> TrustedIssuer {
+        serde_json::from_value(json!({ "issuer": "https://idp.example.com", "introspect": true }))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_parse_introspection_response() {
+        let claims = parse_introspection_response(
+            br#"{ "active": true, "sub": "alice", "scope": "invoice.read", "exp": 1700003600 }"#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(claims["sub"], "alice");
+        assert!(!claims.contains_key("active"));
+
+        assert!(parse_introspection_response(br#"{ "active": false }"#)
+            .unwrap()
+            .is_none());
+        assert!(parse_introspection_response(br#"{ "active": "true" }"#).is_err());
+        assert!(parse_introspection_response(b"<html></html>").is_err());
+    }
+
+    #[test]
+    fn test_basic_credentials_are_form_encoded() {
+        assert_eq!(
+            basic_credentials("validator", "s3cr3t:+"),
+            format!("Basic {}", STANDARD.encode("validator:s3cr3t%3A%2B"))
+        );
+    }
+
+    #[test]
+    fn test_introspected_claims_are_validated() {
+        let issuer = issuer();
+        let claims = parse_introspection_response(
+            br#"{ "active": true, "sub": "alice", "aud": "invoice", "scope": "invoice.read" }"#,
+        )
+        .unwrap()
+        .unwrap();
+        let jwt = introspected_jwt(&issuer, claims);
+        assert_eq!(jwt.payload().iss(), Some("https://idp.example.com"));
+
+        let mut options = issuer.bind(&Default::default());
+        options.expected_scopes = Some(vec![String::from("invoice.read")]);
+        assert!(check_claims(jwt, &options).unwrap().is_ok());
+
+        let jwt = introspected_jwt(&issuer, Map::new());
+        options.expected_scopes = Some(vec![String::from("invoice.write")]);
+        assert_eq!(
+            check_claims(jwt, &options).unwrap().unwrap_err().status(),
+            &401
+        );
+    }
+}
@@ -0,0 +1,303 @@
+//! Trusted token issuers and the keys used to verify their tokens.
+
+use std::collections::HashSet;
+
+use anyhow::{bail, Context, Result};
+use jwks_client::claims::ClaimRule;
+use jwks_client::keyset::KeyStore;
+use serde::Deserialize;
+use serde_json::Value;
+use spin_sdk::variables;
+
+use crate::cache::DocumentCache;
+use crate::models::OpenIdConfiguration;
+use crate::JwtValidationOptions;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct TrustedIssuer {
+    /// Expected `iss` claim of tokens issued by this issuer
+    pub issuer: String,
+    /// URL used for OpenID discovery, defaults to `issuer`. The discovery document must still
+    /// describe `issuer`.
+    pub authority: Option<String>,
+    /// Static JSON Web Key Set, used instead of discovery
+    pub jwks: Option<Value>,
+    /// Tokens must be issued for at least one of these audiences, if any
+    #[serde(default)]
+    pub audiences: Vec<String>,
+    /// Introspect opaque tokens at the introspection endpoint of this issuer
+    #[serde(default)]
+    pub introspect: bool,
+}
+
+/// The issuers whose tokens are accepted. The unverified `iss` claim of a token selects the
+/// issuer, and thereby the only keys that are used to verify it.
+#[derive(Debug)]
+pub struct TrustedIssuers {
+    issuers: Vec<TrustedIssuer>,
+}
+
+/// A token that names an issuer which is not trusted
+#[derive(Debug)]
+pub struct UntrustedIssuer(pub Option<String>);
+
+impl TrustedIssuers {
+    /// Load the issuers from the `trusted_issuers` variable. If it is empty, `oidc_url` is the
+    /// only trusted issuer.
+    pub fn load() -> Result<TrustedIssuers> {
+        let issuers = variables::get("trusted_issuers").unwrap_or_default();
+        if !issuers.trim().is_empty() {
+            return TrustedIssuers::parse(&issuers).context("invalid trusted_issuers variable");
+        }
+
+        let oidc_url = variables::get("oidc_url")
+            .context("application not configured correctly, oidc_url missing")?;
+        let introspect = variables::get("introspection_client_id")
+            .is_ok_and(|client_id| !client_id.trim().is_empty());
+        Ok(TrustedIssuers {
+            issuers: vec![TrustedIssuer {
+                issuer: oidc_url,
+                authority: None,
+                jwks: None,
+                audiences: vec![],
+                introspect,
+            }],
+        })
+    }
+
+    /// Parse and check a JSON list of trusted issuers
+    pub fn parse(issuers: &str) -> Result<TrustedIssuers> {
+        let issuers: Vec<TrustedIssuer> = serde_json::from_str(issuers)?;
+        if issuers.is_empty() {
+            bail!("at least one issuer is required");
+        }
+
+        let mut seen = HashSet::new();
+        for issuer in issuers.iter() {
+            if !seen.insert(issuer.issuer.as_str()) {
+                bail!("issuer {} is listed more than once", issuer.issuer);
+            }
+            if issuer.authority.is_some() && issuer.jwks.is_some() {
+                bail!("issuer {} specifies both authority and jwks", issuer.issuer);
+            }
+            if issuer.introspect && issuer.jwks.is_some() {
+                bail!(
+                    "issuer {} requires discovery to introspect tokens",
+                    issuer.issuer
+                );
+            }
+            if issuer.jwks.is_some() {
+                issuer
+                    .static_key_store()
+                    .with_context(|| format!("invalid jwks of issuer {}", issuer.issuer))?;
+            }
+        }
+
+        if issuers.iter().filter(|issuer| issuer.introspect).count() > 1 {
+            bail!("only one issuer can introspect tokens");
+        }
+
+        Ok(TrustedIssuers { issuers })
+    }
+
+    pub fn get(&self, issuer: &str) -> Option<&TrustedIssuer> {
+        self.issuers.iter().find(|trusted| trusted.issuer == issuer)
+    }
+
+    /// The issuer, if only one is trusted
+    pub fn single(&self) -> Option<&TrustedIssuer> {
+        match self.issuers.as_slice() {
+            [issuer] => Some(issuer),
+            _ => None,
+        }
+    }
+
+    /// The issuer used to introspect opaque tokens, if any
+    pub fn introspecting(&self) -> Option<&TrustedIssuer> {
+        self.issuers.iter().find(|issuer| issuer.introspect)
+    }
+
+    /// Select the issuer of `token` by its unverified `iss` claim
+    pub fn select(&self, token: &str) -> Result<&TrustedIssuer, UntrustedIssuer> {
+        let iss = KeyStore::new()
+            .decode(token)
+            .ok()
+            .and_then(|jwt| jwt.payload().iss().map(String::from));
+
+        match iss.as_deref().and_then(|iss| self.get(iss)) {
+            Some(issuer) => Ok(issuer),
+            None => Err(UntrustedIssuer(iss)),
+        }
+    }
+}
+
+impl TrustedIssuer {
+    /// The keys of this issuer, either the static JWKS or the JWKS found by discovery
+    pub async fn key_store(&self, cache: &DocumentCache) -> Result<KeyStore> {
+        if self.jwks.is_some() {
+            return self.static_key_store();
+        }
+
+        let openid_config = self.openid_configuration(cache).await?;
+        cache.key_store(&openid_config.jwks_uri).await
+    }
+
+    pub async fn openid_configuration(&self, cache: &DocumentCache) -> Result<OpenIdConfiguration> {
+        cache
+            .openid_configuration(self.authority(), &self.issuer)
+            .await
+    }
+
+    /// Where the discovery document is retrieved from
+    pub fn authority(&self) -> &str {
+        self.authority.as_deref().unwrap_or(&self.issuer)
+    }
+
+    fn static_key_store(&self) -> Result<KeyStore> {
+        let jwks = self.jwks.as_ref().map(Value::to_string).unwrap_or_default();
+        Ok(KeyStore::new_from_jwks(&jwks)?)
+    }
+
+    /// Bind `options` to this issuer: the `iss` claim must match it and the audience must be
+    /// one of its allowed audiences, in addition to the checks of `options`
+    pub fn bind(&self, options: &JwtValidationOptions) -> JwtValidationOptions {
+        let mut options = options.clone();
+        options
+            .expected_issuer
+            .get_or_insert_with(|| self.issuer.clone());
+        if !self.audiences.is_empty() {
+            options
+                .claim_rules
+                .get_or_insert_with(Vec::new)
+                .push(ClaimRule::new("aud").any_of(self.audiences.iter().map(String::as_str)));
+        }
+        options
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+    use crate::cache::discovery_url;
+
+    const TOKEN: &str = include_str!("../crates/jwks-client/testdata/rs256.jwt");
+    const JWKS: &str = include_str!("../crates/jwks-client/testdata/jwks.json");
+
+    fn issuers() -> TrustedIssuers {
+        TrustedIssuers::parse(&format!(
+            r#"[
+                {{ "issuer": "https://tenant-a.example.com" }},
+                {{ "issuer": "https://idp.example.com", "jwks": {}, "audiences": ["invoice"] }}
+            ]"#,
+            JWKS
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_select_by_unverified_issuer() {
+        let issuers = issuers();
+
+        let issuer = issuers.select(TOKEN.trim()).unwrap();
+        assert_eq!(issuer.issuer, "https://idp.example.com");
+
+        let unknown = TrustedIssuers::parse(r#"[{ "issuer": "https://tenant-a.example.com" }]"#)
+            .unwrap()
+            .select(TOKEN.trim())
+            .unwrap_err();
+        assert_eq!(unknown.0.as_deref(), Some("https://idp.example.com"));
+
+        assert!(issuers.select("not-a-token").unwrap_err().0.is_none());
+    }
+
+    #[test]
+    fn test_static_jwks_verifies_token() {
+        let issuers = issuers();
+        let issuer = issuers.get("https://idp.example.com").unwrap();
+
+        let key_store = issuer.static_key_store().unwrap();
+        let valid_time = UNIX_EPOCH + Duration::from_secs(1700001000);
+        assert!(key_store.verify_time(TOKEN.trim(), valid_time).is_ok());
+    }
+
+    #[test]
+    fn test_bind_options_to_issuer() {
+        let issuers = issuers();
+        let issuer = issuers.get("https://idp.example.com").unwrap();
+
+        let options = issuer.bind(&JwtValidationOptions::default());
+        assert_eq!(
+            options.expected_issuer.as_deref(),
+            Some("https://idp.example.com")
+        );
+        assert_eq!(options.claim_rules.unwrap()[0].claim, "aud");
+
+        // a route requiring another issuer is not satisfied by this one
+        let options = issuer.bind(&JwtValidationOptions {
+            expected_issuer: Some(String::from("https://tenant-a.example.com")),
+            ..Default::default()
+        });
+        assert_eq!(
+            options.expected_issuer.as_deref(),
+            Some("https://tenant-a.example.com")
+        );
+    }
+
+    #[test]
+    fn test_discovery_at_authority_other_than_issuer() {
+        let issuers = TrustedIssuers::parse(
+            r#"[{
+                "issuer": "https://idsrv.purplesky-721836c2.eastus.azurecontainerapps.io",
+                "authority": "https://idsrv.internal.example.com/"
+            }]"#,
+        )
+        .unwrap();
+        let issuer = issuers.single().unwrap();
+        assert_eq!(
+            discovery_url(issuer.authority()),
+            "https://idsrv.internal.example.com/.well-known/openid-configuration"
+        );
+
+        // the document names the issuer, not the URL it was retrieved from
+        let document: OpenIdConfiguration = serde_json::from_str(include_str!(
+            "../testdata/openid-configuration/identityserver.json"
+        ))
+        .unwrap();
+        assert!(document.validate(&issuer.issuer).is_ok());
+        assert!(document.validate(issuer.authority()).is_err());
+    }
+
+    #[test]
+    fn test_invalid_issuers_are_rejected() {
+        let error = |issuers: &str| format!("{:#}", TrustedIssuers::parse(issuers).unwrap_err());
+
+        assert!(error("[]").contains("at least one issuer"));
+        assert!(error(
+            r#"[{ "issuer": "https://a.example.com" }, { "issuer": "https://a.example.com" }]"#
+        )
+        .contains("listed more than once"));
+        assert!(error(
+            r#"[{ "issuer": "https://a.example.com", "authority": "https://a.example.com", "jwks": { "keys": [] } }]"#
+        )
+        .contains("both authority and jwks"));
+        assert!(
+            error(r#"[{ "issuer": "https://a.example.com", "jwks": { "keys": 1 } }]"#)
+                .contains("invalid jwks of issuer https://a.example.com")
+        );
+        assert!(error(
+            r#"[{ "issuer": "https://a.example.com", "introspect": true, "jwks": { "keys": [] } }]"#
+        )
+        .contains("requires discovery to introspect tokens"));
+        assert!(error(
+            r#"[{ "issuer": "https://a.example.com", "introspect": true }, { "issuer": "https://b.example.com", "introspect": true }]"#
+        )
+        .contains("only one issuer can introspect tokens"));
+        assert!(
+            error(r#"[{ "issuer": "https://a.example.com", "audience": ["a"] }]"#)
+                .contains("unknown field `audience`")
+        );
+    }
+}
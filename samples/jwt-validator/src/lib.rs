@@ -1,261 +1,391 @@
-use anyhow::{Context, Result};
-use jwks_client::error::Error;
+use std::rc::Rc;
+
+use anyhow::{bail, Result};
+use cache::DocumentCache;
+use dpop::{forwarded_url, Scheme};
+use gateway::GatewayConfig;
+use issuers::{TrustedIssuers, UntrustedIssuer};
+use jwks_client::claims::{ClaimRule, ClaimsValidator, Violation};
+use jwks_client::error::{Error, Type};
 use jwks_client::jwt::Jwt;
 use jwks_client::keyset::KeyStore;
-use models::OpenIdConfiguration;
+use login::Login;
+use policy::{method_name, normalize_path, Policy};
 use serde::{Deserialize, Serialize};
-use spin_sdk::http::{
-    send, IntoResponse, Params, Request, RequestBuilder, Response, ResponseBuilder, Router,
-};
-use spin_sdk::{http_component, variables};
+use sources::TokenSources;
+use spin_sdk::http::{IntoResponse, Params, Request, Response, ResponseBuilder, Router};
+use spin_sdk::http_component;
 
+mod cache;
+mod dpop;
+mod gateway;
+mod inspect;
+mod introspection;
+mod issuers;
+mod login;
 mod models;
+mod policy;
+mod replay;
+mod revocation;
+mod session;
+mod sources;
+
+/// Configuration loaded and checked when the component starts
+pub(crate) struct Config {
+    pub policy: Policy,
+    pub issuers: TrustedIssuers,
+    pub gateway: Option<GatewayConfig>,
+    pub tokens: TokenSources,
+    pub login: Option<Login>,
+}
+
+impl Config {
+    fn load() -> Result<Config> {
+        let config = Config {
+            policy: Policy::load()?,
+            issuers: TrustedIssuers::load()?,
+            gateway: GatewayConfig::load()?,
+            tokens: TokenSources::load()?,
+            login: Login::load()?,
+        };
+        if let Some(login) = config.login.as_ref() {
+            login.issuer(&config.issuers)?;
+            if !config.tokens.has_csrf_protection() {
+                bail!("login requires csrf_protection");
+            }
+        }
+        Ok(config)
+    }
+}
 
 #[http_component]
 fn handle_jwt_validator(req: Request) -> Result<impl IntoResponse> {
+    let config = match Config::load() {
+        Ok(config) => Rc::new(config),
+        Err(e) => {
+            println!("failed to load configuration: {:#}", e);
+            return Ok(Response::new(
+                500,
+                format!("failed to load configuration: {:#}", e),
+            ));
+        }
+    };
+
     let mut router = Router::default();
-    router.post_async("/validate", handle_validate_jwt);
-    router.post_async("/validate-with-options", handle_validate_jwt_with_options);
+    router.get_async("/admin/revocations", revocation::handle_list);
+    router.post_async("/admin/revocations", revocation::handle_add);
+    let login_config = config.clone();
+    router.get_async("/auth/login", move |req, params| {
+        login::handle_login(req, params, login_config.clone())
+    });
+    let callback_config = config.clone();
+    router.get_async("/auth/callback", move |req, params| {
+        login::handle_callback(req, params, callback_config.clone())
+    });
+    let logout_config = config.clone();
+    router.get_async("/auth/logout", move |req, params| {
+        login::handle_logout(req, params, logout_config.clone())
+    });
+    let inspect_config = config.clone();
+    router.post_async("/inspect", move |req, params| {
+        inspect::handle_inspect(req, params, inspect_config.clone())
+    });
+    let validate_config = config.clone();
+    router.post_async("/validate", move |req, params| {
+        handle_validate_jwt(req, params, validate_config.clone())
+    });
+    let options_config = config.clone();
+    router.post_async("/validate-with-options", move |req, params| {
+        handle_validate_jwt_with_options(req, params, options_config.clone())
+    });
+    router.any_async("/...", move |req, params| {
+        gateway::handle_forward(req, params, config.clone())
+    });
     Ok(router.handle(req))
 }
 
-async fn handle_validate_jwt(req: Request, _: Params) -> Result<impl IntoResponse> {
-    let Ok(oidc_url) = variables::get("oidc_url") else {
+/// Validates the token against the policy of the protected route. The route is taken from the
+/// `X-Forwarded-Method` and `X-Forwarded-Uri` headers set by reverse proxies using this
+/// endpoint for forward authentication, or from the request itself.
+async fn handle_validate_jwt(
+    req: Request,
+    _: Params,
+    config: Rc<Config>,
+) -> Result<impl IntoResponse> {
+    let method = req
+        .header("X-Forwarded-Method")
+        .and_then(|value| value.as_str())
+        .unwrap_or(method_name(req.method()));
+    let presented = match config.tokens.token(&req, method)? {
+        Ok(presented) => presented,
+        Err(response) => return Ok(response),
+    };
+
+    let path = req
+        .header("X-Forwarded-Uri")
+        .and_then(|value| value.as_str())
+        .and_then(|uri| uri.split('?').next())
+        .unwrap_or(req.path());
+    let Some(path) = normalize_path(path) else {
+        return Ok(Response::new(400, "invalid request path"));
+    };
+    let Some(route) = config.policy.route(method, &path) else {
         return Ok(Response::new(
-            500,
-            "application not configured correctly, oidc_url missing",
+            403,
+            format!("no policy for {} {}", method, path),
         ));
     };
-    let Some(jwt_header) = req.header("Authorization") else {
-        return Ok(Response::new(401, ()));
-    };
-    let jwt = jwt_header
-        .as_str()
-        .and_then(|val| {
-            let mut parts = val.split_whitespace();
-            parts.nth(1)
-        })
-        .unwrap_or("");
 
-    if jwt.is_empty() {
-        return Ok(Response::new(401, ()));
-    }
     let model = JwtValidationRequestModel {
-        jwt: String::from(jwt),
-        authority: oidc_url.clone(),
-
-        options: JwtValidationOptions {
-            expected_audiences: Some(vec![String::from("invoice")]),
-            expected_issuer: Some(oidc_url),
-            expected_token_type: Some(String::from("at+jwt")),
-            expected_scopes: Some(vec![String::from("invoice.read")]),
-            expected_claims: Some(vec![String::from("client_app_type")]),
-        },
+        jwt: presented.token,
+        options: route.options.clone(),
     };
-    return validate(model).await;
+    validate(&req, presented.scheme, model, &config, method).await
 }
 
-async fn handle_validate_jwt_with_options(req: Request, _: Params) -> Result<impl IntoResponse> {
-    let Ok(oidc_url) = variables::get("oidc_url") else {
-        return Ok(Response::new(
-            500,
-            "application not configured correctly, oidc_url missing",
-        ));
-    };
-    let Some(jwt_header) = req.header("Authorization") else {
-        return Ok(Response::new(401, ()));
+async fn handle_validate_jwt_with_options(
+    req: Request,
+    _: Params,
+    config: Rc<Config>,
+) -> Result<impl IntoResponse> {
+    let method = req
+        .header("X-Forwarded-Method")
+        .and_then(|value| value.as_str())
+        .unwrap_or(method_name(req.method()));
+    let presented = match config.tokens.token(&req, method)? {
+        Ok(presented) => presented,
+        Err(response) => return Ok(response),
     };
-    let jwt = jwt_header
-        .as_str()
-        .and_then(|val| {
-            let mut parts = val.split_whitespace();
-            parts.nth(1)
-        })
-        .unwrap_or("");
 
-    if jwt.is_empty() {
-        return Ok(Response::new(401, ()));
-    }
-
-    let Ok(options) = serde_json::from_slice::<JwtValidationOptions>(req.body()) else {
+    let Ok(LenientOptions { options }) = serde_json::from_slice(req.body()) else {
         return Ok(Response::new(400, "Error deserializing payload"));
     };
-    if options
-        .expected_issuer
-        .as_ref()
-        .is_some_and(|iss| iss.as_str() != oidc_url.as_str())
-    {
-        return Ok(Response::new(
-            400,
-            format!(
-                "Expected issuer is specified, but differs from sample issuer {}",
-                oidc_url.as_str()
-            ),
-        ));
+    if let Some(iss) = options.expected_issuer.as_ref() {
+        if config.issuers.get(iss).is_none() {
+            return Ok(Response::new(
+                400,
+                format!("Expected issuer {} is not a trusted issuer", iss),
+            ));
+        }
     }
 
     let model = JwtValidationRequestModel {
-        jwt: String::from(jwt),
-        authority: oidc_url,
+        jwt: presented.token,
         options,
     };
-    return validate(model).await;
+    validate(&req, presented.scheme, model, &config, method).await
+}
+
+/// Validate the token of `model` and, for DPoP tokens, the proof for the request with
+/// `method` to the forwarded URL
+async fn validate(
+    req: &Request,
+    scheme: Scheme,
+    model: JwtValidationRequestModel,
+    config: &Config,
+    method: &str,
+) -> Result<Response> {
+    let jwt = match authorize(&model, &config.issuers).await? {
+        Ok(jwt) => jwt,
+        Err(response) => return Ok(response),
+    };
+    let url = forwarded_url(req);
+    match dpop::check(req, scheme, &model.jwt, &jwt, &config.issuers, method, &url).await? {
+        Ok(()) => Ok(Response::new(200, ())),
+        Err(response) => Ok(response),
+    }
+}
+
+/// Verify the token of `model` with the keys of its issuer and validate it against its options.
+/// Tokens that are not JWTs are introspected, if an issuer is configured to do so. Revoked
+/// tokens are rejected.
+/// Returns the token if it is valid, otherwise the response to send to the client.
+pub(crate) async fn authorize(
+    model: &JwtValidationRequestModel,
+    issuers: &TrustedIssuers,
+) -> Result<Result<Jwt, Response>> {
+    let jwt = match verify(model, issuers).await? {
+        Ok(jwt) => jwt,
+        Err(response) => return Ok(Err(response)),
+    };
+    if let Some(error) = revocation::check(&jwt, &model.options)? {
+        return Ok(Err(json_response(401, &error)?));
+    }
+    Ok(Ok(jwt))
 }
 
-async fn validate(model: JwtValidationRequestModel) -> Result<Response> {
-    let openid_config = get_openid_configuration(model.authority).await?;
-    let key_set = KeyStore::new_from(openid_config.jwks_uri.clone())
-        .await
-        .unwrap();
+async fn verify(
+    model: &JwtValidationRequestModel,
+    issuers: &TrustedIssuers,
+) -> Result<Result<Jwt, Response>> {
+    if let Some(issuer) = issuers.introspecting() {
+        if KeyStore::new().decode(&model.jwt).is_err() {
+            let cache = DocumentCache::open_default()?;
+            return introspection::authorize(model, issuer, &cache).await;
+        }
+    }
+
+    let issuer = match issuers.select(&model.jwt) {
+        Ok(issuer) => issuer,
+        Err(UntrustedIssuer(iss)) => {
+            println!("token issuer {:?} is not trusted", iss);
+            let error = ValidationError {
+                code: Some("untrusted_issuer"),
+                message: format!(
+                    "JWT issuer {} is not trusted",
+                    iss.as_deref().unwrap_or("none")
+                ),
+            };
+            return Ok(Err(json_response(401, &error)?));
+        }
+    };
+
+    let cache = DocumentCache::open_default()?;
+    let mut key_set = issuer.key_store(&cache).await?;
+
+    let last_fetch_time = key_set.last_fetch_time();
+    let result = key_set.verify_and_refresh(&model.jwt).await;
+    if key_set.last_fetch_time() != last_fetch_time {
+        cache.save_key_store(&key_set);
+    }
 
-    match key_set.verify(&model.jwt) {
+    match result {
         Ok(jwt) => {
             println!("keyset validation succeeded. Starting JWT validation");
-            let errors = validate_jwt_and_track_errors(&jwt, &model.options);
-            if errors.len() == 0 {
-                return Ok(Response::new(200, ()));
-            }
-            let payload = serde_json::to_string_pretty(&errors)?;
-            return Ok(ResponseBuilder::new(401)
-                .header("content-type", "application/json")
-                .body(payload)
-                .build());
+            check_claims(jwt, &issuer.bind(&model.options))
         }
-        Err(Error { msg, typ }) => {
-            println!("keyset validation failed. Skipping JWT validation");
-            let e = ValidationError::new(format!(
-                "JWT Keyset validation failed (type: {:?}) {}",
-                typ, msg
-            ));
-            let payload = serde_json::to_string(&e)?;
-            Ok(Response::new(401, payload))
+        Err(e) => {
+            println!("keyset validation failed. Skipping JWT validation: {:?}", e);
+            let status = match e.typ() {
+                Type::Connection | Type::Internal => 503,
+                _ => 401,
+            };
+            Ok(Err(json_response(status, &ValidationError::from(&e))?))
         }
     }
 }
 
-async fn get_openid_configuration(authority: String) -> Result<OpenIdConfiguration> {
-    let openid_configuration_url = format!("{}/.well-known/openid-configuration", authority);
-    let req = RequestBuilder::new(spin_sdk::http::Method::Get, openid_configuration_url).build();
-    let res: Response = send(req).await?;
-    serde_json::from_slice::<OpenIdConfiguration>(res.body())
-        .with_context(|| "Error while deserializing into OpenIdConfiguration")
+/// Validate the claims of a verified token against `options`
+fn check_claims(jwt: Jwt, options: &JwtValidationOptions) -> Result<Result<Jwt, Response>> {
+    let errors = match validate_jwt_and_track_errors(&jwt, options) {
+        Ok(errors) => errors,
+        Err(e) => return Ok(Err(Response::new(400, e.to_string()))),
+    };
+    if errors.is_empty() {
+        return Ok(Ok(jwt));
+    }
+    let payload = serde_json::to_string_pretty(&errors)?;
+    Ok(Err(ResponseBuilder::new(401)
+        .header("content-type", "application/json")
+        .body(payload)
+        .build()))
+}
+
+fn json_response(status: u16, error: &ValidationError) -> Result<Response> {
+    let payload = serde_json::to_string(error)?;
+    Ok(ResponseBuilder::new(status)
+        .header("content-type", "application/json")
+        .body(payload)
+        .build())
 }
 
 fn validate_jwt_and_track_errors(
     jwt: &Jwt,
     options: &JwtValidationOptions,
-) -> Vec<ValidationError> {
+) -> Result<Vec<ValidationError>, Error> {
     let mut errors = Vec::new();
-    if options.expected_token_type.is_some() {
-        let want = options.expected_token_type.as_ref().unwrap();
-        let got = jwt.header().typ();
-        if got.is_none() || got.is_some_and(|a| a.to_ascii_lowercase() != want.to_ascii_lowercase())
-        {
-            errors.push(ValidationError::new(format!(
+    if let Some(want) = options.expected_token_type.as_ref() {
+        match jwt.header().typ() {
+            Some(got) if got.eq_ignore_ascii_case(want) => {}
+            got => errors.push(ValidationError::new(format!(
                 "JWT has wrong token type. Got: {} Wanted: {}",
-                got.unwrap(),
+                got.unwrap_or("none"),
                 want
-            )));
+            ))),
         }
     }
-    if options.expected_audiences.is_some() {
-        let want = options.expected_audiences.as_ref().unwrap();
-        let got = match jwt.payload().get_str("aud") {
-            Some(aud) => Some(vec![aud.to_string()]),
-            None => match jwt.payload().get_array("aud") {
-                Some(auds) => Some(
-                    auds.iter()
-                        .map(|v| v.as_str().unwrap().to_string())
-                        .collect::<Vec<String>>(),
-                ),
-                None => None,
-            },
-        };
-        if got.is_none() {
-            errors.push(ValidationError::new(format!(
-                "JWT does not have 'aud' claim"
-            )));
-        }
-        let got = got.unwrap();
 
-        for aud in want.iter() {
-            if !got.contains(aud) {
-                errors.push(ValidationError::new(format!(
-                    "JWT is missing {} as part of the 'aud' claim",
-                    aud
-                )));
-            }
-        }
+    let validator = ClaimsValidator::new(claim_rules(options))?;
+    errors.extend(
+        validator
+            .validate_jwt(jwt)
+            .into_iter()
+            .map(ValidationError::from),
+    );
+    Ok(errors)
+}
+
+pub(crate) fn claim_rules(options: &JwtValidationOptions) -> Vec<ClaimRule> {
+    let mut rules = Vec::new();
+    if let Some(want) = options.expected_audiences.as_ref() {
+        rules.push(ClaimRule::new("aud").all_of(want.iter().map(String::as_str)));
     }
-    if options.expected_issuer.is_some() {
-        let want = options.expected_issuer.as_ref().unwrap();
-        let got = jwt.payload().get_str("iss");
-        if got.is_none() {
-            errors.push(ValidationError::new(format!(
-                "JWT does not have 'iss' claim"
-            )));
-        }
-        if want != got.unwrap() {
-            errors.push(ValidationError::new(format!(
-                "JWT does not have expected value for 'iss' claim"
-            )));
-        }
+    if let Some(want) = options.expected_issuer.as_ref() {
+        rules.push(ClaimRule::new("iss").equals(want.as_str()));
     }
-    if options.expected_scopes.is_some() {
-        let want = options.expected_scopes.as_ref().unwrap();
-        let got = jwt.payload().get_array("scope");
-        if got.is_none() {
-            errors.push(ValidationError::new(format!(
-                "JWT does not have required scopes"
-            )));
-        }
-        let got: Vec<String> = got
-            .unwrap()
-            .iter()
-            .map(|v| v.as_str().unwrap().to_ascii_lowercase())
-            .collect();
-        for scope in want.iter() {
-            if !got.contains(&scope.to_ascii_lowercase()) {
-                errors.push(ValidationError::new(format!(
-                    "JWT does not have the required {} scope.",
-                    scope
-                )));
-            }
-        }
+    if let Some(want) = options.expected_scopes.as_ref() {
+        rules.push(
+            ClaimRule::new("scope")
+                .all_of(want.iter().map(String::as_str))
+                .space_delimited()
+                .ignore_case(),
+        );
     }
-    if options.expected_claims.is_some() {
-        let want = options.expected_claims.as_ref().unwrap();
-        for claim in want.iter() {
-            if !jwt.payload().has_claim(claim.to_string()) {
-                errors.push(ValidationError::new(format!(
-                    "JWT does not have the required {} claim",
-                    claim
-                )));
-            }
-        }
+    if let Some(want) = options.expected_claims.as_ref() {
+        rules.extend(want.iter().map(|claim| ClaimRule::new(claim)));
     }
-    errors
+    if let Some(claim_rules) = options.claim_rules.as_ref() {
+        rules.extend(claim_rules.iter().cloned());
+    }
+    rules
 }
 
 #[derive(Debug, Serialize)]
 pub struct ValidationError {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<&'static str>,
     pub message: String,
 }
 
 impl ValidationError {
     fn new(message: String) -> Self {
-        Self { message }
+        Self {
+            code: None,
+            message,
+        }
+    }
+}
+
+impl From<Violation> for ValidationError {
+    fn from(violation: Violation) -> Self {
+        Self {
+            code: Some(violation.code),
+            message: violation.message,
+        }
+    }
+}
+
+impl From<&Error> for ValidationError {
+    fn from(e: &Error) -> Self {
+        Self {
+            code: Some(e.code()),
+            message: format!("JWT Keyset validation failed: {}", e),
+        }
     }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct JwtValidationRequestModel {
     pub jwt: String,
-    pub authority: String,
     pub options: JwtValidationOptions,
 }
 
-#[derive(Debug, Deserialize)]
+/// Checks applied to a token on top of its signature and lifetime.
+///
+/// Unknown fields are rejected, so a typo in a policy can't silently drop a check. Request
+/// payloads are read with `LenientOptions` instead.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct JwtValidationOptions {
     #[serde(rename = "expectedAudience")]
     pub expected_audiences: Option<Vec<String>>,
@@ -268,4 +398,17 @@ pub struct JwtValidationOptions {
     pub expected_scopes: Option<Vec<String>>,
     #[serde(rename = "expectedClaims")]
     pub expected_claims: Option<Vec<String>>,
+    /// Declarative claim checks, see `jwks_client::claims::ClaimRule`
+    #[serde(rename = "claimRules")]
+    pub claim_rules: Option<Vec<ClaimRule>>,
+    /// Accept every token (identified by its `jti`) only once
+    #[serde(rename = "oneTimeUse")]
+    pub one_time_use: Option<bool>,
+}
+
+/// `JwtValidationOptions` ignoring unknown fields, as `/validate-with-options` always did
+#[derive(Debug, Deserialize)]
+pub struct LenientOptions {
+    #[serde(flatten)]
+    pub options: JwtValidationOptions,
 }
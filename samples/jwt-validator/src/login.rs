@@ -0,0 +1,690 @@
+//! Login at the edge: the OpenID Connect authorization code flow with PKCE. The tokens of a
+//! logged in user are kept in a server-side session, refreshed when they expire, and presented
+//! to the origin by the gateway.
+
+use std::rc::Rc;
+use std::time::SystemTime;
+
+use anyhow::{bail, Context, Result};
+use jwks_client::claims::{ClaimRule, ClaimsValidator};
+use serde::Deserialize;
+use spin_sdk::http::{send, Method, Params, Request, RequestBuilder, Response, ResponseBuilder};
+use spin_sdk::variables;
+
+use crate::cache::DocumentCache;
+use crate::dpop::Scheme;
+use crate::introspection::basic_credentials;
+use crate::issuers::{TrustedIssuer, TrustedIssuers};
+use crate::revocation::unix_time;
+use crate::session::{
+    clear_cookie, set_cookie, LoginState, Session, SessionKey, SessionStore, TokenResponse,
+};
+use crate::sources::{cookie_value, PresentedToken};
+use crate::Config;
+
+const LOGIN_PATH: &str = "/auth/login";
+const CALLBACK_PATH: &str = "/auth/callback";
+/// Time a user has to complete the login at the IdP
+const LOGIN_TIMEOUT_SECONDS: u64 = 600;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct LoginConfig {
+    /// Issuer to log in with, may be omitted if there is only one trusted issuer
+    pub issuer: Option<String>,
+    pub client_id: String,
+    /// URL of the `/auth/callback` endpoint, as registered at the IdP
+    pub redirect_uri: String,
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+    /// Where the IdP sends users after logging out
+    pub post_logout_redirect_uri: Option<String>,
+    #[serde(default = "default_cookie_name")]
+    pub cookie_name: String,
+    /// Seconds a session lasts at most, as long as its tokens can be refreshed
+    #[serde(default = "default_session_max_age")]
+    pub session_max_age: u64,
+}
+
+fn default_scopes() -> Vec<String> {
+    vec![String::from("openid"), String::from("offline_access")]
+}
+
+fn default_cookie_name() -> String {
+    String::from("__Host-session")
+}
+
+fn default_session_max_age() -> u64 {
+    8 * 3600
+}
+
+/// The login configuration with the secrets used by the flow
+pub struct Login {
+    pub config: LoginConfig,
+    key: SessionKey,
+    client_secret: String,
+}
+
+impl Login {
+    /// Load the configuration from the `login_config` variable, the key of the session cookies
+    /// from `session_secret` and the client secret from `login_client_secret`.
+    /// Returns `None` if login is not configured.
+    pub fn load() -> Result<Option<Login>> {
+        let config = variables::get("login_config").unwrap_or_default();
+        if config.trim().is_empty() {
+            return Ok(None);
+        }
+        let login = Login::parse(
+            &config,
+            &variables::get("session_secret").unwrap_or_default(),
+            &variables::get("login_client_secret").unwrap_or_default(),
+        )?;
+        Ok(Some(login))
+    }
+
+    /// Parse and check the login configuration. Without client secret, the app is a public
+    /// client and relies on PKCE alone.
+    pub fn parse(config: &str, session_secret: &str, client_secret: &str) -> Result<Login> {
+        let config: LoginConfig =
+            serde_json::from_str(config).context("invalid login_config variable")?;
+        if config.client_id.trim().is_empty() {
+            bail!("login_config requires a clientId");
+        }
+        let callback = config
+            .redirect_uri
+            .strip_prefix("https://")
+            .and_then(|rest| rest.find('/').map(|path| &rest[path..]));
+        if callback != Some(CALLBACK_PATH) {
+            bail!(
+                "redirectUri must be an https URL with the path {}",
+                CALLBACK_PATH
+            );
+        }
+        if !config.scopes.iter().any(|scope| scope == "openid") {
+            bail!("scopes must include openid");
+        }
+        if config.cookie_name.is_empty()
+            || !config
+                .cookie_name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_".contains(c))
+        {
+            bail!("invalid cookieName {}", config.cookie_name);
+        }
+        let key = SessionKey::new(session_secret).context("invalid session_secret variable")?;
+
+        Ok(Login {
+            config,
+            key,
+            client_secret: String::from(client_secret.trim()),
+        })
+    }
+
+    /// The issuer users log in with
+    pub fn issuer<'a>(&self, issuers: &'a TrustedIssuers) -> Result<&'a TrustedIssuer> {
+        let issuer = match self.config.issuer.as_deref() {
+            Some(iss) => issuers.get(iss),
+            None => issuers.single(),
+        };
+        issuer.context("the issuer of login_config must be one of the trusted issuers")
+    }
+
+    fn login_cookie_name(&self) -> String {
+        format!("{}-login", self.config.cookie_name)
+    }
+
+    /// The session and login cookies, which must not reach the origin
+    pub fn cookie_names(&self) -> [String; 2] {
+        [self.config.cookie_name.clone(), self.login_cookie_name()]
+    }
+
+    fn session_cookie(&self, session_id: &str) -> String {
+        set_cookie(
+            &self.config.cookie_name,
+            session_id,
+            self.config.session_max_age,
+        )
+    }
+
+    fn session_id<'a>(&self, req: &'a Request) -> Option<&'a str> {
+        cookie_value(req, &self.config.cookie_name).filter(|id| !id.is_empty())
+    }
+
+    /// Redirect to `return_to` with the cookie of the session `session_id`. Spin responses hold
+    /// a single value per header, so the login cookie is not cleared but left to expire.
+    fn logged_in(&self, session_id: &str, return_to: &str) -> Response {
+        ResponseBuilder::new(302)
+            .header("location", return_to)
+            .header("set-cookie", self.session_cookie(session_id))
+            .header("cache-control", "no-store")
+            .build()
+    }
+
+    /// URL of the authorization endpoint starting the login for `state`
+    fn authorization_url(&self, endpoint: &str, state: &LoginState) -> String {
+        let query = form_urlencoded::Serializer::new(String::new())
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", &self.config.redirect_uri)
+            .append_pair("scope", &self.config.scopes.join(" "))
+            .append_pair("state", &state.state)
+            .append_pair("nonce", &state.nonce)
+            .append_pair("code_challenge", &state.code_challenge())
+            .append_pair("code_challenge_method", "S256")
+            .finish();
+        let separator = if endpoint.contains('?') { '&' } else { '?' };
+        format!("{}{}{}", endpoint, separator, query)
+    }
+
+    /// Request tokens at the token endpoint of `issuer` with the grant described by `params`
+    async fn request_tokens(
+        &self,
+        issuer: &TrustedIssuer,
+        cache: &DocumentCache,
+        params: &[(&str, &str)],
+    ) -> Result<TokenResponse> {
+        let openid_config = issuer.openid_configuration(cache).await?;
+        let Some(endpoint) = openid_config.token_endpoint else {
+            bail!("issuer {} has no token endpoint", issuer.issuer);
+        };
+
+        let mut body = form_urlencoded::Serializer::new(String::new());
+        body.extend_pairs(params);
+        let mut builder = RequestBuilder::new(Method::Post, &endpoint);
+        builder
+            .header("content-type", "application/x-www-form-urlencoded")
+            .header("accept", "application/json");
+        if self.client_secret.is_empty() {
+            body.append_pair("client_id", &self.config.client_id);
+        } else {
+            builder.header(
+                "authorization",
+                basic_credentials(&self.config.client_id, &self.client_secret),
+            );
+        }
+        let req = builder.body(body.finish()).build();
+
+        let res: Response = send(req).await?;
+        if *res.status() != 200 {
+            bail!(
+                "token endpoint {} responded with {}: {}",
+                endpoint,
+                res.status(),
+                String::from_utf8_lossy(res.body())
+            );
+        }
+        serde_json::from_slice(res.body()).context("invalid token response")
+    }
+
+    /// Verify the ID token of a login with the keys of `issuer`. It must be issued to this
+    /// client for the login with `nonce`.
+    async fn verify_id_token(
+        &self,
+        issuer: &TrustedIssuer,
+        cache: &DocumentCache,
+        id_token: &str,
+        nonce: &str,
+    ) -> Result<()> {
+        let mut key_set = issuer.key_store(cache).await?;
+        let last_fetch_time = key_set.last_fetch_time();
+        let result = key_set.verify_and_refresh(id_token).await;
+        if key_set.last_fetch_time() != last_fetch_time {
+            cache.save_key_store(&key_set);
+        }
+        let jwt = result.context("invalid ID token")?;
+
+        let validator = ClaimsValidator::new(vec![
+            ClaimRule::new("iss").equals(issuer.issuer.as_str()),
+            ClaimRule::new("aud").all_of([self.config.client_id.as_str()]),
+            ClaimRule::new("nonce").equals(nonce),
+        ])?;
+        if let Some(violation) = validator.validate_jwt(&jwt).into_iter().next() {
+            bail!("invalid ID token: {}", violation.message);
+        }
+        Ok(())
+    }
+}
+
+/// `GET /auth/login?returnTo=<path>`: redirect to the authorization endpoint of the IdP
+pub async fn handle_login(req: Request, _: Params, config: Rc<Config>) -> Result<Response> {
+    let Some(login) = config.login.as_ref() else {
+        return Ok(Response::new(404, ()));
+    };
+    let return_to = query_param(&req, "returnTo");
+    let return_to = local_path(return_to.as_deref().unwrap_or("/"));
+
+    let issuer = login.issuer(&config.issuers)?;
+    let cache = DocumentCache::open_default()?;
+    let openid_config = issuer.openid_configuration(&cache).await?;
+    let Some(endpoint) = openid_config.authorization_endpoint else {
+        bail!("issuer {} has no authorization endpoint", issuer.issuer);
+    };
+
+    let now = unix_time(SystemTime::now());
+    let state = LoginState::new(return_to, now + LOGIN_TIMEOUT_SECONDS)?;
+    let login_cookie = login.login_cookie_name();
+    Ok(ResponseBuilder::new(302)
+        .header("location", login.authorization_url(&endpoint, &state))
+        .header(
+            "set-cookie",
+            set_cookie(
+                &login_cookie,
+                &login.key.seal(&login_cookie, &state)?,
+                LOGIN_TIMEOUT_SECONDS,
+            ),
+        )
+        .header("cache-control", "no-store")
+        .build())
+}
+
+/// `GET /auth/callback`: exchange the authorization code for tokens and start the session
+pub async fn handle_callback(req: Request, _: Params, config: Rc<Config>) -> Result<Response> {
+    let Some(login) = config.login.as_ref() else {
+        return Ok(Response::new(404, ()));
+    };
+    let now = unix_time(SystemTime::now());
+    let login_cookie = login.login_cookie_name();
+    let state = cookie_value(&req, &login_cookie)
+        .and_then(|cookie| login.key.open::<LoginState>(&login_cookie, cookie))
+        .filter(|state| now < state.expires_at);
+    let Some(state) = state else {
+        return Ok(Response::new(400, "login expired or not started here"));
+    };
+    if query_param(&req, "state").as_deref() != Some(state.state.as_str()) {
+        return Ok(Response::new(400, "login state does not match"));
+    }
+    if let Some(error) = query_param(&req, "error") {
+        println!(
+            "login failed: {} {}",
+            error,
+            query_param(&req, "error_description").unwrap_or_default()
+        );
+        return Ok(Response::new(401, "login failed"));
+    }
+    let Some(code) = query_param(&req, "code") else {
+        return Ok(Response::new(400, "authorization code missing"));
+    };
+
+    let issuer = login.issuer(&config.issuers)?;
+    let cache = DocumentCache::open_default()?;
+    let params = [
+        ("grant_type", "authorization_code"),
+        ("code", code.as_str()),
+        ("redirect_uri", login.config.redirect_uri.as_str()),
+        ("code_verifier", state.code_verifier.as_str()),
+    ];
+    let tokens = match login.request_tokens(issuer, &cache, &params).await {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            println!("exchanging the authorization code failed: {:#}", e);
+            return Ok(Response::new(502, "login failed"));
+        }
+    };
+    let Some(id_token) = tokens.id_token.as_deref() else {
+        println!("token response has no ID token");
+        return Ok(Response::new(502, "login failed"));
+    };
+    if let Err(e) = login
+        .verify_id_token(issuer, &cache, id_token, &state.nonce)
+        .await
+    {
+        println!("login failed: {:#}", e);
+        return Ok(Response::new(401, "login failed"));
+    }
+
+    let session = Session::from_response(tokens, now, login.config.session_max_age);
+    let session_id = SessionStore::open_default()?.create(&session)?;
+    Ok(login.logged_in(&session_id, &state.return_to))
+}
+
+/// `GET /auth/logout`: end the session and log out at the IdP, if it supports that
+pub async fn handle_logout(req: Request, _: Params, config: Rc<Config>) -> Result<Response> {
+    let Some(login) = config.login.as_ref() else {
+        return Ok(Response::new(404, ()));
+    };
+    let now = unix_time(SystemTime::now());
+    let mut session = None;
+    if let Some(session_id) = login.session_id(&req) {
+        let sessions = SessionStore::open_default()?;
+        session = sessions.get(session_id, now)?;
+        sessions.delete(session_id)?;
+    }
+    let fallback = login
+        .config
+        .post_logout_redirect_uri
+        .as_deref()
+        .unwrap_or("/");
+
+    let issuer = login.issuer(&config.issuers)?;
+    let end_session_endpoint = match DocumentCache::open_default() {
+        Ok(cache) => match issuer.openid_configuration(&cache).await {
+            Ok(openid_config) => openid_config.end_session_endpoint,
+            Err(e) => {
+                println!("logging out locally only: {:#}", e);
+                None
+            }
+        },
+        Err(e) => {
+            println!("logging out locally only: {:#}", e);
+            None
+        }
+    };
+    let location = match end_session_endpoint {
+        Some(endpoint) => end_session_url(
+            &endpoint,
+            login,
+            session.as_ref().and_then(|s| s.id_token.as_deref()),
+        ),
+        None => String::from(fallback),
+    };
+
+    Ok(ResponseBuilder::new(302)
+        .header("location", location)
+        .header("set-cookie", clear_cookie(&login.config.cookie_name))
+        .header("cache-control", "no-store")
+        .build())
+}
+
+/// URL of the end session endpoint logging out the user of the session with `id_token`, see
+/// OpenID Connect RP-Initiated Logout 1.0
+fn end_session_url(endpoint: &str, login: &Login, id_token: Option<&str>) -> String {
+    let mut query = form_urlencoded::Serializer::new(String::new());
+    query.append_pair("client_id", &login.config.client_id);
+    if let Some(id_token) = id_token {
+        query.append_pair("id_token_hint", id_token);
+    }
+    if let Some(uri) = login.config.post_logout_redirect_uri.as_deref() {
+        query.append_pair("post_logout_redirect_uri", uri);
+    }
+    let separator = if endpoint.contains('?') { '&' } else { '?' };
+    format!("{}{}{}", endpoint, separator, query.finish())
+}
+
+/// The access token of the session of `req` for a request with `method`, refreshed if it is
+/// about to expire. Otherwise returns the response to send: a redirect to the login for page
+/// loads, `401` for other requests.
+pub async fn session_token(
+    req: &Request,
+    login: &Login,
+    config: &Config,
+    method: &str,
+) -> Result<Result<PresentedToken, Response>> {
+    let now = unix_time(SystemTime::now());
+    let Some(session_id) = login.session_id(req) else {
+        return Ok(Err(unauthenticated(req, method)));
+    };
+    if let Err(response) = config.tokens.check_cookie_request(req, method)? {
+        return Ok(Err(response));
+    }
+
+    let sessions = SessionStore::open_default()?;
+    let Some(mut session) = sessions.get(session_id, now)? else {
+        return Ok(Err(session_ended(req, login, method)));
+    };
+    if session.expires_soon(now) {
+        match refresh(login, config, &session, now).await {
+            Ok(refreshed) => {
+                sessions.update(session_id, &refreshed)?;
+                session = refreshed;
+            }
+            Err(e) => println!("refreshing the session failed: {:#}", e),
+        }
+    }
+    if session.expired(now) {
+        sessions.delete(session_id)?;
+        return Ok(Err(session_ended(req, login, method)));
+    }
+
+    Ok(Ok(PresentedToken {
+        scheme: Scheme::Bearer,
+        token: session.access_token,
+        from_cookie: true,
+    }))
+}
+
+async fn refresh(login: &Login, config: &Config, session: &Session, now: u64) -> Result<Session> {
+    let Some(refresh_token) = session.refresh_token.as_deref() else {
+        bail!("session has no refresh token");
+    };
+    let issuer = login.issuer(&config.issuers)?;
+    let cache = DocumentCache::open_default()?;
+    let params = [
+        ("grant_type", "refresh_token"),
+        ("refresh_token", refresh_token),
+    ];
+    let tokens = login.request_tokens(issuer, &cache, &params).await?;
+    Ok(session.refreshed(tokens, now))
+}
+
+/// `unauthenticated`, removing the cookie of a session that is over
+fn session_ended(req: &Request, login: &Login, method: &str) -> Response {
+    let mut response = unauthenticated(req, method);
+    response.set_header("set-cookie", clear_cookie(&login.config.cookie_name));
+    response
+}
+
+/// Page loads are redirected to the login, returning to the page afterwards
+fn unauthenticated(req: &Request, method: &str) -> Response {
+    let accepts_html = req
+        .header("accept")
+        .and_then(|value| value.as_str())
+        .is_some_and(|accept| accept.contains("text/html"));
+    if !method.eq_ignore_ascii_case("GET") || !accepts_html {
+        return Response::new(401, ());
+    }
+
+    let mut return_to = String::from(req.path());
+    if !req.query().is_empty() {
+        return_to.push('?');
+        return_to.push_str(req.query());
+    }
+    let query = form_urlencoded::Serializer::new(String::new())
+        .append_pair("returnTo", &return_to)
+        .finish();
+    ResponseBuilder::new(302)
+        .header("location", format!("{}?{}", LOGIN_PATH, query))
+        .header("cache-control", "no-store")
+        .build()
+}
+
+fn query_param(req: &Request, name: &str) -> Option<String> {
+    form_urlencoded::parse(req.query().as_bytes())
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+/// `path` if it is a path on this host, `/` otherwise, so the login can't redirect elsewhere
+fn local_path(path: &str) -> &str {
+    let is_local = path.starts_with('/')
+        && !path.starts_with("//")
+        && !path.starts_with("/\\")
+        && !path.chars().any(char::is_control);
+    if is_local {
+        path
+    } else {
+        "/"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "a secret of at least thirty-two characters";
+
+    fn login(config: &str) -> Result<Login> {
+        Login::parse(config, SECRET, "")
+    }
+
+    fn default_login() -> Login {
+        login(
+            r#"{
+                "clientId": "edge",
+                "redirectUri": "https://app.example.com/auth/callback",
+                "postLogoutRedirectUri": "https://app.example.com/"
+            }"#,
+        )
+        .unwrap()
+    }
+
+    fn params(url: &str) -> Vec<(String, String)> {
+        let query = url.split_once('?').unwrap().1;
+        form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect()
+    }
+
+    #[test]
+    fn test_authorization_url() {
+        let login = default_login();
+        let state = LoginState::new("/invoices", 1600).unwrap();
+
+        let url = login.authorization_url("https://idp.example.com/authorize", &state);
+        assert!(url.starts_with("https://idp.example.com/authorize?response_type=code&"));
+        let params = params(&url);
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        };
+        assert_eq!(param("client_id"), Some("edge"));
+        assert_eq!(
+            param("redirect_uri"),
+            Some("https://app.example.com/auth/callback")
+        );
+        assert_eq!(param("scope"), Some("openid offline_access"));
+        assert_eq!(param("state"), Some(state.state.as_str()));
+        assert_eq!(param("nonce"), Some(state.nonce.as_str()));
+        assert_eq!(
+            param("code_challenge"),
+            Some(state.code_challenge().as_str())
+        );
+        assert_eq!(param("code_challenge_method"), Some("S256"));
+        assert_eq!(param("code_verifier"), None);
+
+        let url = login.authorization_url("https://idp.example.com/authorize?p=signin", &state);
+        assert!(url.starts_with("https://idp.example.com/authorize?p=signin&response_type"));
+    }
+
+    #[test]
+    fn test_end_session_url() {
+        let login = default_login();
+
+        let url = end_session_url("https://idp.example.com/logout", &login, Some("id.token"));
+        assert_eq!(
+            params(&url),
+            vec![
+                (String::from("client_id"), String::from("edge")),
+                (String::from("id_token_hint"), String::from("id.token")),
+                (
+                    String::from("post_logout_redirect_uri"),
+                    String::from("https://app.example.com/")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_login_config() {
+        let error = |config| format!("{:#}", login(config).err().unwrap());
+
+        assert!(error(
+            r#"{ "clientId": "edge", "redirectUri": "http://app.example.com/auth/callback" }"#
+        )
+        .contains("must be an https URL"));
+        assert!(error(
+            r#"{ "clientId": "edge", "redirectUri": "https://app.example.com/callback" }"#
+        )
+        .contains("/auth/callback"));
+        assert!(error(
+            r#"{ "clientId": "", "redirectUri": "https://app.example.com/auth/callback" }"#
+        )
+        .contains("requires a clientId"));
+        assert!(error(
+            r#"{ "clientId": "edge", "redirectUri": "https://app.example.com/auth/callback", "scopes": ["profile"] }"#
+        )
+        .contains("must include openid"));
+        assert!(error(
+            r#"{ "clientId": "edge", "redirectUri": "https://app.example.com/auth/callback", "cookieName": "a;b" }"#
+        )
+        .contains("invalid cookieName"));
+
+        let config =
+            r#"{ "clientId": "edge", "redirectUri": "https://app.example.com/auth/callback" }"#;
+        assert!(
+            format!("{:#}", Login::parse(config, "short", "").err().unwrap())
+                .contains("invalid session_secret")
+        );
+    }
+
+    #[test]
+    fn test_unauthenticated_page_loads_are_redirected() {
+        let req = Request::builder()
+            .uri("/invoices?page=2")
+            .header("accept", "text/html,application/xhtml+xml")
+            .build();
+        let response = unauthenticated(&req, "GET");
+        assert_eq!(*response.status(), 302);
+        assert_eq!(
+            response.header("location").and_then(|value| value.as_str()),
+            Some("/auth/login?returnTo=%2Finvoices%3Fpage%3D2")
+        );
+
+        assert_eq!(*unauthenticated(&req, "POST").status(), 401);
+        let req = Request::builder()
+            .uri("/api/invoices")
+            .header("accept", "application/json")
+            .build();
+        assert_eq!(*unauthenticated(&req, "GET").status(), 401);
+    }
+
+    #[test]
+    fn test_return_to_stays_on_this_host() {
+        assert_eq!(local_path("/invoices?page=2"), "/invoices?page=2");
+        assert_eq!(local_path("https://evil.example.com"), "/");
+        assert_eq!(local_path("//evil.example.com"), "/");
+        assert_eq!(local_path("/\\evil.example.com"), "/");
+        assert_eq!(local_path("/\r\nset-cookie: a=b"), "/");
+    }
+
+    #[test]
+    fn test_session_cookie() {
+        let login = default_login();
+
+        let cookie = login.session_cookie("session-id");
+        assert_eq!(
+            cookie,
+            "__Host-session=session-id; Path=/; Max-Age=28800; HttpOnly; Secure; SameSite=Lax"
+        );
+        let value = cookie.split(';').next().unwrap();
+        let req = Request::builder()
+            .header("cookie", format!("theme=dark; {}", value))
+            .build();
+        assert_eq!(login.session_id(&req), Some("session-id"));
+
+        let req = Request::builder()
+            .header("cookie", "__Host-session=")
+            .build();
+        assert_eq!(login.session_id(&req), None);
+        assert_eq!(
+            login.cookie_names(),
+            ["__Host-session", "__Host-session-login"]
+        );
+    }
+
+    #[test]
+    fn test_callback_response_sets_session_cookie() {
+        let login = default_login();
+
+        let response = login.logged_in("session-id", "/invoices?page=2");
+        let header = |name| response.header(name).and_then(|value| value.as_str());
+        assert_eq!(*response.status(), 302);
+        assert_eq!(header("location"), Some("/invoices?page=2"));
+        assert_eq!(
+            header("set-cookie"),
+            Some(login.session_cookie("session-id").as_str())
+        );
+        assert_eq!(header("cache-control"), Some("no-store"));
+    }
+}
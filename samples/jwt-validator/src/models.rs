@@ -1,71 +1,194 @@
-use serde::Deserialize;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 
-#[derive(Default, Debug, Clone, PartialEq, Deserialize)]
-#[serde(rename_all = "camelCase")]
+/// OpenID Provider Metadata, see OpenID Connect Discovery 1.0, section 3.
+///
+/// Only `issuer` and `jwks_uri` are required. IdPs differ widely in the metadata they publish,
+/// so all other fields are optional and fields not listed here are kept in `additional`.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct OpenIdConfiguration {
     pub issuer: String,
-    #[serde(rename = "jwks_uri")]
     pub jwks_uri: String,
-    #[serde(rename = "authorization_endpoint")]
-    pub authorization_endpoint: String,
-    #[serde(rename = "token_endpoint")]
-    pub token_endpoint: String,
-    #[serde(rename = "userinfo_endpoint")]
-    pub userinfo_endpoint: String,
-    #[serde(rename = "end_session_endpoint")]
-    pub end_session_endpoint: String,
-    #[serde(rename = "check_session_iframe")]
-    pub check_session_iframe: String,
-    #[serde(rename = "revocation_endpoint")]
-    pub revocation_endpoint: String,
-    #[serde(rename = "introspection_endpoint")]
-    pub introspection_endpoint: String,
-    #[serde(rename = "device_authorization_endpoint")]
-    pub device_authorization_endpoint: String,
-    #[serde(rename = "backchannel_authentication_endpoint")]
-    pub backchannel_authentication_endpoint: String,
-    #[serde(rename = "pushed_authorization_request_endpoint")]
-    pub pushed_authorization_request_endpoint: String,
-    #[serde(rename = "require_pushed_authorization_requests")]
-    pub require_pushed_authorization_requests: bool,
-    #[serde(rename = "frontchannel_logout_supported")]
-    pub frontchannel_logout_supported: bool,
-    #[serde(rename = "frontchannel_logout_session_supported")]
-    pub frontchannel_logout_session_supported: bool,
-    #[serde(rename = "backchannel_logout_supported")]
-    pub backchannel_logout_supported: bool,
-    #[serde(rename = "backchannel_logout_session_supported")]
-    pub backchannel_logout_session_supported: bool,
-    #[serde(rename = "scopes_supported")]
+    pub authorization_endpoint: Option<String>,
+    pub token_endpoint: Option<String>,
+    pub userinfo_endpoint: Option<String>,
+    pub end_session_endpoint: Option<String>,
+    pub check_session_iframe: Option<String>,
+    pub revocation_endpoint: Option<String>,
+    pub introspection_endpoint: Option<String>,
+    pub device_authorization_endpoint: Option<String>,
+    pub backchannel_authentication_endpoint: Option<String>,
+    pub pushed_authorization_request_endpoint: Option<String>,
+    pub require_pushed_authorization_requests: Option<bool>,
+    pub frontchannel_logout_supported: Option<bool>,
+    pub frontchannel_logout_session_supported: Option<bool>,
+    pub backchannel_logout_supported: Option<bool>,
+    pub backchannel_logout_session_supported: Option<bool>,
+    #[serde(default)]
     pub scopes_supported: Vec<String>,
-    #[serde(rename = "claims_supported")]
+    #[serde(default)]
     pub claims_supported: Vec<String>,
-    #[serde(rename = "grant_types_supported")]
+    #[serde(default)]
     pub grant_types_supported: Vec<String>,
-    #[serde(rename = "response_types_supported")]
+    #[serde(default)]
     pub response_types_supported: Vec<String>,
-    #[serde(rename = "response_modes_supported")]
+    #[serde(default)]
     pub response_modes_supported: Vec<String>,
-    #[serde(rename = "token_endpoint_auth_methods_supported")]
+    #[serde(default)]
     pub token_endpoint_auth_methods_supported: Vec<String>,
-    #[serde(rename = "id_token_signing_alg_values_supported")]
+    #[serde(default)]
     pub id_token_signing_alg_values_supported: Vec<String>,
-    #[serde(rename = "subject_types_supported")]
+    #[serde(default)]
     pub subject_types_supported: Vec<String>,
-    #[serde(rename = "code_challenge_methods_supported")]
+    #[serde(default)]
     pub code_challenge_methods_supported: Vec<String>,
-    #[serde(rename = "request_parameter_supported")]
-    pub request_parameter_supported: bool,
-    #[serde(rename = "request_object_signing_alg_values_supported")]
+    pub request_parameter_supported: Option<bool>,
+    #[serde(default)]
     pub request_object_signing_alg_values_supported: Vec<String>,
-    #[serde(rename = "prompt_values_supported")]
+    #[serde(default)]
     pub prompt_values_supported: Vec<String>,
-    #[serde(rename = "authorization_response_iss_parameter_supported")]
-    pub authorization_response_iss_parameter_supported: bool,
-    #[serde(rename = "backchannel_token_delivery_modes_supported")]
+    pub authorization_response_iss_parameter_supported: Option<bool>,
+    #[serde(default)]
     pub backchannel_token_delivery_modes_supported: Vec<String>,
-    #[serde(rename = "backchannel_user_code_parameter_supported")]
-    pub backchannel_user_code_parameter_supported: bool,
-    #[serde(rename = "dpop_signing_alg_values_supported")]
+    pub backchannel_user_code_parameter_supported: Option<bool>,
+    #[serde(default)]
     pub dpop_signing_alg_values_supported: Vec<String>,
+    /// Metadata not modeled above, e.g. vendor specific extensions
+    #[serde(flatten)]
+    pub additional: Map<String, Value>,
+}
+
+impl OpenIdConfiguration {
+    /// Whether the document describes `issuer`. A trailing `/` is ignored, as some providers
+    /// add one to the issuer in their discovery document only.
+    pub fn is_for_issuer(&self, issuer: &str) -> bool {
+        self.issuer.trim_end_matches('/') == issuer.trim_end_matches('/')
+    }
+
+    /// Check the discovery document of the trusted `issuer`: it must describe `issuer`, so one
+    /// IdP can't provide keys for another, and keys must be fetched over https
+    pub fn validate(&self, issuer: &str) -> Result<()> {
+        if !self.is_for_issuer(issuer) {
+            bail!(
+                "discovery document is for issuer {}, expected {}",
+                self.issuer,
+                issuer
+            );
+        }
+        let is_https = self
+            .jwks_uri
+            .get(..8)
+            .is_some_and(|scheme| scheme.eq_ignore_ascii_case("https://"));
+        if !is_https {
+            bail!("jwks_uri {} does not use https", self.jwks_uri);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(document: &str) -> OpenIdConfiguration {
+        serde_json::from_str(document).unwrap()
+    }
+
+    #[test]
+    fn test_identity_server_discovery() {
+        let config = parse(include_str!(
+            "../testdata/openid-configuration/identityserver.json"
+        ));
+
+        assert!(config
+            .validate("https://idsrv.purplesky-721836c2.eastus.azurecontainerapps.io")
+            .is_ok());
+        assert_eq!(
+            config.introspection_endpoint.as_deref(),
+            Some(
+                "https://idsrv.purplesky-721836c2.eastus.azurecontainerapps.io/connect/introspect"
+            )
+        );
+        assert_eq!(config.backchannel_user_code_parameter_supported, Some(true));
+        assert_eq!(config.dpop_signing_alg_values_supported[0], "RS256");
+    }
+
+    #[test]
+    fn test_auth0_discovery() {
+        let config = parse(include_str!("../testdata/openid-configuration/auth0.json"));
+
+        // Auth0 issuers end with a slash
+        assert!(config.validate("https://example.eu.auth0.com").is_ok());
+        assert!(config.is_for_issuer("https://example.eu.auth0.com"));
+        assert!(config.is_for_issuer("https://example.eu.auth0.com/"));
+        assert!(!config.is_for_issuer("https://example.eu.auth0.com/tenant"));
+        assert_eq!(config.introspection_endpoint, None);
+        assert_eq!(config.backchannel_logout_supported, None);
+        assert!(config.dpop_signing_alg_values_supported.is_empty());
+        assert_eq!(
+            config.additional["mfa_challenge_endpoint"],
+            "https://example.eu.auth0.com/mfa/challenge"
+        );
+    }
+
+    #[test]
+    fn test_keycloak_discovery() {
+        let config = parse(include_str!(
+            "../testdata/openid-configuration/keycloak.json"
+        ));
+
+        assert!(config
+            .validate("https://keycloak.example.com/realms/invoices")
+            .is_ok());
+        assert_eq!(
+            config.jwks_uri,
+            "https://keycloak.example.com/realms/invoices/protocol/openid-connect/certs"
+        );
+        assert!(config.additional["mtls_endpoint_aliases"].is_object());
+    }
+
+    #[test]
+    fn test_entra_discovery() {
+        let config = parse(include_str!("../testdata/openid-configuration/entra.json"));
+        let authority =
+            "https://login.microsoftonline.com/9188040d-6c67-4c5b-b112-36a304b66dad/v2.0";
+
+        assert!(config.validate(authority).is_ok());
+        assert_eq!(
+            config.additional["cloud_instance_name"],
+            "microsoftonline.com"
+        );
+        assert!(config.code_challenge_methods_supported.is_empty());
+    }
+
+    #[test]
+    fn test_unknown_fields_are_preserved() {
+        let config = parse(include_str!("../testdata/openid-configuration/auth0.json"));
+
+        let cached = serde_json::to_string(&config).unwrap();
+        assert_eq!(parse(&cached), config);
+    }
+
+    #[test]
+    fn test_invalid_discovery_documents() {
+        let config = parse(include_str!(
+            "../testdata/openid-configuration/keycloak.json"
+        ));
+        let error = config
+            .validate("https://keycloak.example.com/realms/other")
+            .unwrap_err();
+        assert!(error.to_string().contains("is for issuer"));
+
+        let config = parse(
+            r#"{ "issuer": "https://idp.example.com", "jwks_uri": "http://idp.example.com/keys" }"#,
+        );
+        let error = config.validate("https://idp.example.com").unwrap_err();
+        assert!(error.to_string().contains("does not use https"));
+
+        assert!(serde_json::from_str::<OpenIdConfiguration>(
+            r#"{ "issuer": "https://idp.example.com" }"#
+        )
+        .is_err());
+    }
 }
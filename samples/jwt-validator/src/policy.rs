@@ -0,0 +1,328 @@
+//! Authorization policy, mapping routes and HTTP methods to the validation options that apply.
+
+use anyhow::{bail, Context, Result};
+use jwks_client::claims::ClaimsValidator;
+use serde::Deserialize;
+use spin_sdk::http::Method;
+use spin_sdk::variables;
+
+use crate::{claim_rules, JwtValidationOptions};
+
+/// Location of the policy file bundled with the app, see `files` in `spin.toml`
+const POLICY_FILE: &str = "/policy.json";
+
+const METHODS: &[&str] = &[
+    "GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH",
+];
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    /// The first route matching the method and path of a request applies
+    pub routes: Vec<RoutePolicy>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoutePolicy {
+    /// Path of the route, a trailing `/...` matches all paths below it
+    pub path: String,
+    /// HTTP methods the route applies to, all methods if empty
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Unknown fields are rejected, see `JwtValidationOptions`
+    #[serde(default)]
+    pub options: JwtValidationOptions,
+}
+
+impl Policy {
+    /// Load the policy from the `policy` variable or, if that is empty, from the bundled
+    /// policy file. Without either, no route is authorized.
+    pub fn load() -> Result<Policy> {
+        let policy = variables::get("policy").unwrap_or_default();
+        if !policy.trim().is_empty() {
+            return Policy::parse(&policy).context("invalid policy variable");
+        }
+
+        match std::fs::read_to_string(POLICY_FILE) {
+            Ok(policy) => {
+                Policy::parse(&policy).with_context(|| format!("invalid {}", POLICY_FILE))
+            }
+            Err(e) => {
+                println!(
+                    "no policy configured, {} could not be read: {}",
+                    POLICY_FILE, e
+                );
+                Ok(Policy::default())
+            }
+        }
+    }
+
+    /// Parse and check a policy document
+    pub fn parse(policy: &str) -> Result<Policy> {
+        let policy: Policy = serde_json::from_str(policy)?;
+        for (index, route) in policy.routes.iter().enumerate() {
+            route
+                .check()
+                .with_context(|| format!("route {} ({})", index, route.path))?;
+        }
+        Ok(policy)
+    }
+
+    pub fn route(&self, method: &str, path: &str) -> Option<&RoutePolicy> {
+        self.routes.iter().find(|route| route.matches(method, path))
+    }
+}
+
+impl RoutePolicy {
+    fn check(&self) -> Result<()> {
+        if !self.path.starts_with('/') {
+            bail!("path must start with '/'");
+        }
+        if let Some(method) = self
+            .methods
+            .iter()
+            .find(|method| !METHODS.contains(&method.to_ascii_uppercase().as_str()))
+        {
+            bail!("unknown HTTP method {}", method);
+        }
+        ClaimsValidator::new(claim_rules(&self.options))?;
+        Ok(())
+    }
+
+    pub fn matches(&self, method: &str, path: &str) -> bool {
+        let method_matches = self.methods.is_empty()
+            || self
+                .methods
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(method));
+
+        method_matches && path_matches(&self.path, path)
+    }
+}
+
+pub fn method_name(method: &Method) -> &str {
+    match method {
+        Method::Get => "GET",
+        Method::Head => "HEAD",
+        Method::Post => "POST",
+        Method::Put => "PUT",
+        Method::Delete => "DELETE",
+        Method::Connect => "CONNECT",
+        Method::Options => "OPTIONS",
+        Method::Trace => "TRACE",
+        Method::Patch => "PATCH",
+        Method::Other(method) => method,
+    }
+}
+
+/// Normalize a request path (RFC 3986, 6.2.2) before it is matched against the policy and
+/// forwarded, so `/api/public/../admin` or `/api/public/%2e%2e/admin` can't reach a route under
+/// the policy of another one. Percent-encoded unreserved characters are decoded, dot segments
+/// are resolved and empty segments are removed.
+///
+/// Returns `None` for paths that don't start with `/`, have invalid percent-encoding or contain
+/// an encoded `/` or a `\`, which origins don't treat alike.
+pub fn normalize_path(path: &str) -> Option<String> {
+    if !path.starts_with('/') || path.contains('\\') {
+        return None;
+    }
+
+    let mut segments: Vec<String> = vec![];
+    let mut trailing_slash = false;
+    for segment in path.split('/').skip(1) {
+        let segment = decode_unreserved(segment)?;
+        trailing_slash = matches!(segment.as_str(), "" | "." | "..");
+        match segment.as_str() {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+
+    let mut normalized = format!("/{}", segments.join("/"));
+    if trailing_slash && !segments.is_empty() {
+        normalized.push('/');
+    }
+    Some(normalized)
+}
+
+/// Decode percent-encoded unreserved characters, other escapes are kept with uppercase digits
+fn decode_unreserved(segment: &str) -> Option<String> {
+    let mut decoded = String::with_capacity(segment.len());
+    let mut rest = segment;
+    while let Some(index) = rest.find('%') {
+        decoded.push_str(&rest[..index]);
+        let hex = rest
+            .get(index + 1..index + 3)
+            .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))?;
+        match u8::from_str_radix(hex, 16).ok()? {
+            b'/' | b'\\' => return None,
+            b if b.is_ascii_alphanumeric() || b"-._~".contains(&b) => decoded.push(b as char),
+            _ => {
+                decoded.push('%');
+                decoded.push_str(&hex.to_ascii_uppercase());
+            }
+        }
+        rest = &rest[index + 3..];
+    }
+    decoded.push_str(rest);
+    Some(decoded)
+}
+
+fn path_matches(pattern: &str, path: &str) -> bool {
+    match pattern.strip_suffix("/...") {
+        Some(prefix) => {
+            path == prefix
+                || path
+                    .strip_prefix(prefix)
+                    .is_some_and(|rest| rest.starts_with('/'))
+        }
+        None => pattern == path,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LenientOptions;
+
+    #[test]
+    fn test_bundled_policy_is_valid() {
+        let policy = Policy::parse(include_str!("../policy.json")).unwrap();
+
+        let route = policy.route("POST", "/validate").unwrap();
+        assert_eq!(
+            route.options.expected_audiences,
+            Some(vec![String::from("invoice")])
+        );
+        assert_eq!(route.options.expected_token_type.as_deref(), Some("at+jwt"));
+    }
+
+    #[test]
+    fn test_route_matching() {
+        let policy = Policy::parse(
+            r#"{
+                "routes": [
+                    { "path": "/health", "methods": ["GET"] },
+                    {
+                        "path": "/api/invoices/...",
+                        "methods": ["post", "PUT"],
+                        "options": { "expectedScopes": ["invoice.write"] }
+                    },
+                    {
+                        "path": "/api/invoices/...",
+                        "options": { "expectedScopes": ["invoice.read"] }
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let scopes = |method, path| {
+            policy
+                .route(method, path)
+                .and_then(|route| route.options.expected_scopes.clone())
+        };
+
+        assert_eq!(policy.route("GET", "/health").unwrap().path, "/health");
+        assert!(policy.route("POST", "/health").is_none());
+        assert!(policy.route("GET", "/health/live").is_none());
+        assert_eq!(
+            scopes("POST", "/api/invoices"),
+            Some(vec![String::from("invoice.write")])
+        );
+        assert_eq!(
+            scopes("GET", "/api/invoices/42"),
+            Some(vec![String::from("invoice.read")])
+        );
+        assert!(policy.route("GET", "/api/invoicesx").is_none());
+        assert!(path_matches("/...", "/"));
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        for (path, normalized) in [
+            ("/", Some("/")),
+            ("/api/invoices/42", Some("/api/invoices/42")),
+            ("/api/public/../admin", Some("/api/admin")),
+            ("/api/public/%2e%2e/admin", Some("/api/admin")),
+            ("/api/public/%2E%2E/admin", Some("/api/admin")),
+            ("/api/public/.%2e/admin", Some("/api/admin")),
+            ("/../../admin", Some("/admin")),
+            ("//api//invoices/", Some("/api/invoices/")),
+            ("/api/./invoices/.", Some("/api/invoices/")),
+            ("/api/invoices/42/..", Some("/api/invoices/")),
+            ("/%61pi/invoices", Some("/api/invoices")),
+            ("/api/a%20b%c3%a4", Some("/api/a%20b%C3%A4")),
+            ("/api/public%2f..%2fadmin", None),
+            ("/api/public%5C..%5Cadmin", None),
+            ("/api/public\\..\\admin", None),
+            ("/api/%zz", None),
+            ("/api/%2", None),
+            ("api", None),
+        ] {
+            assert_eq!(normalize_path(path).as_deref(), normalized, "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_normalized_paths_match_their_route() {
+        let policy = Policy::parse(
+            r#"{
+                "routes": [
+                    {
+                        "path": "/api/admin/...",
+                        "options": { "expectedScopes": ["admin"] }
+                    },
+                    { "path": "/api/public/..." }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        for path in [
+            "/api/public/../admin/users",
+            "/api/public/%2e%2e/admin/users",
+            "/api/public//..//admin/users",
+            "//api/admin/users",
+        ] {
+            let route = policy.route("GET", &normalize_path(path).unwrap()).unwrap();
+            assert_eq!(route.path, "/api/admin/...", "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_invalid_policies_are_rejected() {
+        let error = |policy: &str| format!("{:#}", Policy::parse(policy).unwrap_err());
+
+        assert!(
+            error(r#"{ "routes": [{ "path": "/", "method": ["GET"] }] }"#)
+                .contains("unknown field `method`")
+        );
+        assert!(error(
+            r#"{ "routes": [{ "path": "/", "options": { "expectedAudiences": ["a"] } }] }"#
+        )
+        .contains("unknown field `expectedAudiences`"));
+        assert!(error(r#"{ "routes": [{ "path": "api" }] }"#).contains("route 0 (api)"));
+        assert!(
+            error(r#"{ "routes": [{ "path": "/", "methods": ["FETCH"] }] }"#)
+                .contains("unknown HTTP method FETCH")
+        );
+        assert!(error(
+            r#"{ "routes": [{ "path": "/", "options": { "claimRules": [{ "claim": "sub", "matches": "(" }] } }] }"#
+        )
+        .contains("Invalid validation rule for 'sub' claim"));
+    }
+
+    #[test]
+    fn test_options_outside_policies_ignore_unknown_fields() {
+        let LenientOptions { options } =
+            serde_json::from_str(r#"{ "expectedScopes": ["a"], "expectedAudiences": ["b"] }"#)
+                .unwrap();
+        assert_eq!(options.expected_scopes, Some(vec![String::from("a")]));
+        assert_eq!(options.expected_audiences, None);
+    }
+}
@@ -0,0 +1,101 @@
+//! Records of identifiers that may be used only once, e.g. the `jti` of DPoP proofs.
+
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use spin_sdk::key_value::Store;
+
+use crate::cache::sha256_hex;
+
+/// How often expired records are deleted
+const SWEEP_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Identifiers seen before, kept in a Spin key-value store until they expire.
+///
+/// The store grows with the number of identifiers seen within their lifetime: expired records
+/// are deleted by a sweep over the keys of the store, run by the first insert after
+/// `SWEEP_INTERVAL` has passed. Records outlive their expiry by at most that interval.
+///
+/// The store has no atomic compare-and-set, so concurrent requests presenting the same
+/// identifier may both be accepted. Sequential replays are always detected.
+pub struct SeenIds {
+    store: Store,
+    prefix: &'static str,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SeenId {
+    expire_time: SystemTime,
+}
+
+impl SeenIds {
+    pub fn open_default(prefix: &'static str) -> Result<Self> {
+        let store = Store::open_default().with_context(|| "Error opening key-value store")?;
+        Ok(Self { store, prefix })
+    }
+
+    /// Record `id` until `expire_time`. Returns `false` if `id` was recorded before and the
+    /// record has not expired, i.e. `id` is replayed.
+    pub fn insert(&self, id: &str, expire_time: SystemTime) -> Result<bool> {
+        let key = format!("{}:{}", self.prefix, sha256_hex(id));
+        let now = SystemTime::now();
+
+        if let Some(seen) = self.store.get(&key)? {
+            match serde_json::from_slice::<SeenId>(&seen) {
+                Ok(seen) if now < seen.expire_time => return Ok(false),
+                Ok(_) => {}
+                Err(e) => println!("ignoring unreadable entry {}: {}", key, e),
+            }
+        }
+
+        self.store
+            .set(&key, &serde_json::to_vec(&SeenId { expire_time })?)?;
+
+        // a failed sweep is repeated later and must not reject the request
+        let expired = |value: &[u8]| {
+            serde_json::from_slice::<SeenId>(value).map_or(true, |seen| seen.expire_time <= now)
+        };
+        if let Err(e) = sweep(&self.store, self.prefix, now, expired) {
+            println!("error deleting expired {} records: {}", self.prefix, e);
+        }
+        Ok(true)
+    }
+}
+
+/// Delete the records of `store` under `prefix` for which `expired` is true, if the last sweep
+/// of `prefix` is at least `SWEEP_INTERVAL` ago. Spin key-value stores don't expire entries on
+/// their own.
+pub(crate) fn sweep(
+    store: &Store,
+    prefix: &str,
+    now: SystemTime,
+    expired: impl Fn(&[u8]) -> bool,
+) -> Result<()> {
+    let next_sweep_key = format!("{}:next-sweep", prefix);
+    let next_sweep = store
+        .get(&next_sweep_key)?
+        .and_then(|value| serde_json::from_slice::<SystemTime>(&value).ok());
+    if next_sweep.is_some_and(|next_sweep| now < next_sweep) {
+        return Ok(());
+    }
+    // claimed before sweeping, so concurrent requests don't sweep as well
+    store.set(
+        &next_sweep_key,
+        &serde_json::to_vec(&(now + SWEEP_INTERVAL))?,
+    )?;
+
+    let record_prefix = format!("{}:", prefix);
+    let mut deleted = 0;
+    for key in store.get_keys()? {
+        if !key.starts_with(&record_prefix) || key == next_sweep_key {
+            continue;
+        }
+        if store.get(&key)?.is_some_and(|value| expired(&value)) {
+            store.delete(&key)?;
+            deleted += 1;
+        }
+    }
+    println!("deleted {} expired {} records", deleted, prefix);
+    Ok(())
+}
@@ -0,0 +1,360 @@
+//! Revocation list of tokens, by token id (`jti`) or by subject (`sub`) of an issuer, and the
+//! admin endpoints to manage it.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use jwks_client::jwt::Jwt;
+use serde::{Deserialize, Serialize};
+use spin_sdk::http::{IntoResponse, Params, Request, Response, ResponseBuilder};
+use spin_sdk::key_value::Store;
+use spin_sdk::variables;
+
+use crate::cache::sha256_hex;
+use crate::replay::SeenIds;
+use crate::{JwtValidationOptions, ValidationError};
+
+const KEY_PREFIX: &str = "revoked:";
+/// Revocations can't outlive the longest lived tokens by much, so a year is plenty
+const MAX_TTL_SECONDS: u64 = 365 * 86400;
+/// Tolerated clock skew when recording one-time tokens until they expire
+const LEEWAY_SECONDS: u64 = 60;
+
+/// An entry of the revocation list
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Revocation {
+    /// Issuer of the revoked tokens, token ids and subjects are only unique per issuer
+    pub iss: String,
+    /// The token with this id is revoked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    /// All tokens of this subject issued before `revoked_at` are revoked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Seconds since the epoch
+    pub revoked_at: u64,
+    /// Seconds since the epoch, the entry is removed afterwards
+    pub expires_at: u64,
+}
+
+/// Payload of `POST /admin/revocations`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct RevocationRequest {
+    pub iss: String,
+    pub jti: Option<String>,
+    pub sub: Option<String>,
+    pub reason: Option<String>,
+    /// Seconds the entry is kept, should cover the remaining lifetime of the revoked tokens
+    pub ttl: u64,
+}
+
+impl RevocationRequest {
+    fn into_revocation(self, now: u64) -> Result<Revocation> {
+        let present = |value: Option<String>| value.filter(|value| !value.trim().is_empty());
+        let (jti, sub) = (present(self.jti), present(self.sub));
+        if self.iss.trim().is_empty() {
+            bail!("iss is required");
+        }
+        if jti.is_some() == sub.is_some() {
+            bail!("exactly one of jti and sub is required");
+        }
+        if self.ttl == 0 || self.ttl > MAX_TTL_SECONDS {
+            bail!("ttl must be between 1 and {} seconds", MAX_TTL_SECONDS);
+        }
+        Ok(Revocation {
+            iss: self.iss,
+            jti,
+            sub,
+            reason: self.reason,
+            revoked_at: now,
+            expires_at: now + self.ttl,
+        })
+    }
+}
+
+impl Revocation {
+    fn cache_key(&self) -> String {
+        match (&self.jti, &self.sub) {
+            (Some(jti), _) => jti_key(&self.iss, jti),
+            (None, sub) => sub_key(&self.iss, sub.as_deref().unwrap_or_default()),
+        }
+    }
+
+    /// Whether the entry revokes `jwt` at `now`
+    pub fn applies_to(&self, jwt: &Jwt, now: u64) -> bool {
+        let payload = jwt.payload();
+        if now >= self.expires_at || payload.iss() != Some(self.iss.as_str()) {
+            return false;
+        }
+        match (&self.jti, &self.sub) {
+            (Some(jti), _) => payload.jti() == Some(jti.as_str()),
+            // tokens issued after the revocation, e.g. after a new login, are not affected
+            (None, Some(sub)) => {
+                payload.sub() == Some(sub.as_str())
+                    && payload.iat().is_none_or(|iat| iat <= self.revoked_at)
+            }
+            (None, None) => false,
+        }
+    }
+}
+
+/// The revocation list, kept in a Spin key-value store
+pub struct RevocationList {
+    store: Store,
+}
+
+impl RevocationList {
+    pub fn open_default() -> Result<Self> {
+        let store = Store::open_default().with_context(|| "Error opening key-value store")?;
+        Ok(Self { store })
+    }
+
+    pub fn add(&self, revocation: &Revocation) -> Result<()> {
+        self.store
+            .set(&revocation.cache_key(), &serde_json::to_vec(revocation)?)?;
+        Ok(())
+    }
+
+    /// Entries that have not expired yet. Expired entries are removed.
+    pub fn list(&self, now: u64) -> Result<Vec<Revocation>> {
+        let mut revocations = Vec::new();
+        for key in self.store.get_keys()? {
+            if !key.starts_with(KEY_PREFIX) {
+                continue;
+            }
+            if let Some(revocation) = self.get(&key, now)? {
+                revocations.push(revocation);
+            }
+        }
+        revocations.sort_by_key(|revocation| revocation.revoked_at);
+        Ok(revocations)
+    }
+
+    /// The entry revoking `jwt`, if any
+    pub fn revocation(&self, jwt: &Jwt, now: u64) -> Result<Option<Revocation>> {
+        let payload = jwt.payload();
+        let Some(iss) = payload.iss() else {
+            return Ok(None);
+        };
+        let keys = [
+            payload.jti().map(|jti| jti_key(iss, jti)),
+            payload.sub().map(|sub| sub_key(iss, sub)),
+        ];
+        for key in keys.iter().flatten() {
+            if let Some(revocation) = self.get(key, now)? {
+                if revocation.applies_to(jwt, now) {
+                    return Ok(Some(revocation));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn get(&self, key: &str, now: u64) -> Result<Option<Revocation>> {
+        let Some(value) = self.store.get(key)? else {
+            return Ok(None);
+        };
+        match serde_json::from_slice::<Revocation>(&value) {
+            Ok(revocation) if now < revocation.expires_at => return Ok(Some(revocation)),
+            Ok(_) => {}
+            Err(e) => println!("removing unreadable entry {}: {}", key, e),
+        }
+        self.store.delete(key)?;
+        Ok(None)
+    }
+}
+
+fn jti_key(iss: &str, jti: &str) -> String {
+    format!("{}jti:{}:{}", KEY_PREFIX, sha256_hex(iss), sha256_hex(jti))
+}
+
+fn sub_key(iss: &str, sub: &str) -> String {
+    format!("{}sub:{}:{}", KEY_PREFIX, sha256_hex(iss), sha256_hex(sub))
+}
+
+/// Reject revoked tokens and, if the options demand one-time use, tokens seen before.
+/// Returns the error to report to the client.
+pub fn check(jwt: &Jwt, options: &JwtValidationOptions) -> Result<Option<ValidationError>> {
+    let now = unix_time(SystemTime::now());
+
+    if let Some(revocation) = RevocationList::open_default()?.revocation(jwt, now)? {
+        println!("token is revoked: {:?}", revocation);
+        return Ok(Some(ValidationError {
+            code: Some("token_revoked"),
+            message: String::from("JWT has been revoked"),
+        }));
+    }
+
+    if options.one_time_use == Some(true) {
+        let payload = jwt.payload();
+        let Some(jti) = payload.jti().filter(|jti| !jti.is_empty()) else {
+            return Ok(Some(ValidationError {
+                code: Some("missing_claim"),
+                message: String::from("One-time use requires the 'jti' claim"),
+            }));
+        };
+        // a jti only needs to be unique per issuer
+        let id = format!("{}:{}", payload.iss().unwrap_or_default(), jti);
+        let exp = payload.exp().unwrap_or(now) + LEEWAY_SECONDS;
+        let expire_time = UNIX_EPOCH + Duration::from_secs(exp);
+        if !SeenIds::open_default("jti")?.insert(&id, expire_time)? {
+            return Ok(Some(ValidationError {
+                code: Some("token_replayed"),
+                message: String::from("JWT has been used before"),
+            }));
+        }
+    }
+
+    Ok(None)
+}
+
+/// `GET /admin/revocations`
+pub async fn handle_list(req: Request, _: Params) -> Result<impl IntoResponse> {
+    if let Err(response) = authorize_admin(&req) {
+        return Ok(response);
+    }
+    let revocations = RevocationList::open_default()?.list(unix_time(SystemTime::now()))?;
+    Ok(json(200, serde_json::to_string_pretty(&revocations)?))
+}
+
+/// `POST /admin/revocations`
+pub async fn handle_add(req: Request, _: Params) -> Result<impl IntoResponse> {
+    if let Err(response) = authorize_admin(&req) {
+        return Ok(response);
+    }
+    let revocation = serde_json::from_slice::<RevocationRequest>(req.body())
+        .map_err(anyhow::Error::from)
+        .and_then(|request| request.into_revocation(unix_time(SystemTime::now())));
+    let revocation = match revocation {
+        Ok(revocation) => revocation,
+        Err(e) => return Ok(Response::new(400, format!("invalid revocation: {:#}", e))),
+    };
+
+    RevocationList::open_default()?.add(&revocation)?;
+    Ok(json(201, serde_json::to_string_pretty(&revocation)?))
+}
+
+/// The admin endpoints require the `admin_token` variable as bearer token, and are disabled
+/// if it is empty
+pub(crate) fn authorize_admin(req: &Request) -> Result<(), Response> {
+    let admin_token = variables::get("admin_token").unwrap_or_default();
+    if admin_token.trim().is_empty() {
+        return Err(Response::new(404, ()));
+    }
+
+    let presented = req
+        .header("Authorization")
+        .and_then(|value| value.as_str())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    // comparing hashes doesn't reveal how much of the token matches
+    if sha256_hex(presented) != sha256_hex(admin_token.trim()) {
+        return Err(Response::new(401, ()));
+    }
+    Ok(())
+}
+
+fn json(status: u16, body: String) -> Response {
+    ResponseBuilder::new(status)
+        .header("content-type", "application/json")
+        .body(body)
+        .build()
+}
+
+pub(crate) fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use jwks_client::jwt::{Header, Payload};
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn jwt(claims: Value) -> Jwt {
+        Jwt::new(Header::new(json!({})), Payload::new(claims), String::new())
+    }
+
+    fn revocation(request: Value) -> Result<Revocation> {
+        serde_json::from_value::<RevocationRequest>(request)?.into_revocation(1000)
+    }
+
+    const ISS: &str = "https://idp.example.com";
+
+    #[test]
+    fn test_revocation_by_jti() {
+        let revocation = revocation(json!({ "iss": ISS, "jti": "token-1", "ttl": 3600 })).unwrap();
+        assert_eq!(revocation.expires_at, 4600);
+
+        assert!(revocation.applies_to(&jwt(json!({ "iss": ISS, "jti": "token-1" })), 2000));
+        assert!(!revocation.applies_to(&jwt(json!({ "iss": ISS, "jti": "token-2" })), 2000));
+        assert!(!revocation.applies_to(&jwt(json!({ "iss": ISS, "jti": "token-1" })), 4600));
+    }
+
+    #[test]
+    fn test_revocation_by_subject() {
+        let revocation =
+            revocation(json!({ "iss": ISS, "sub": "alice", "ttl": 3600, "reason": "device lost" }))
+                .unwrap();
+
+        let alice = |iat: Option<u64>| jwt(json!({ "iss": ISS, "sub": "alice", "iat": iat }));
+        assert!(revocation.applies_to(&alice(Some(900)), 2000));
+        assert!(revocation.applies_to(&jwt(json!({ "iss": ISS, "sub": "alice" })), 2000));
+        assert!(!revocation.applies_to(&alice(Some(1500)), 2000));
+        assert!(!revocation.applies_to(&jwt(json!({ "iss": ISS, "sub": "bob", "iat": 900 })), 2000));
+    }
+
+    #[test]
+    fn test_revocation_is_scoped_to_issuer() {
+        let revocation = revocation(json!({ "iss": ISS, "sub": "alice", "ttl": 3600 })).unwrap();
+        let other = Revocation {
+            iss: String::from("https://other.example.com"),
+            ..revocation.clone()
+        };
+
+        assert!(!revocation.applies_to(&jwt(json!({ "iss": other.iss, "sub": "alice" })), 2000));
+        assert!(!revocation.applies_to(&jwt(json!({ "sub": "alice" })), 2000));
+        assert_ne!(revocation.cache_key(), other.cache_key());
+        assert_ne!(jti_key(ISS, "alice"), sub_key(ISS, "alice"));
+    }
+
+    #[test]
+    fn test_blank_values_are_ignored() {
+        let by_sub =
+            revocation(json!({ "iss": ISS, "jti": "", "sub": "alice", "ttl": 60 })).unwrap();
+        assert_eq!(by_sub.jti, None);
+        assert_eq!(by_sub.cache_key(), sub_key(ISS, "alice"));
+
+        let by_jti =
+            revocation(json!({ "iss": ISS, "jti": "token-1", "sub": " ", "ttl": 60 })).unwrap();
+        assert_eq!(by_jti.sub, None);
+    }
+
+    #[test]
+    fn test_invalid_revocations_are_rejected() {
+        let error = |request| format!("{:#}", revocation(request).unwrap_err());
+
+        assert!(error(json!({ "iss": ISS, "ttl": 60 })).contains("exactly one of jti and sub"));
+        assert!(
+            error(json!({ "iss": ISS, "jti": "", "sub": "", "ttl": 60 }))
+                .contains("exactly one of jti and sub")
+        );
+        assert!(
+            error(json!({ "iss": ISS, "jti": "a", "sub": "b", "ttl": 60 })).contains("exactly one")
+        );
+        assert!(error(json!({ "iss": ISS, "jti": "a", "ttl": 0 })).contains("ttl must be between"));
+        assert!(error(json!({ "iss": ISS, "jti": "a" })).contains("missing field `ttl`"));
+        assert!(error(json!({ "jti": "a", "ttl": 60 })).contains("missing field `iss`"));
+        assert!(error(json!({ "iss": " ", "jti": "a", "ttl": 60 })).contains("iss is required"));
+        assert!(
+            error(json!({ "iss": ISS, "jti": "a", "ttl": 60, "exp": 1 })).contains("unknown field")
+        );
+    }
+}
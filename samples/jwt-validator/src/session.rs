@@ -0,0 +1,390 @@
+//! Browser sessions and the state of a login in progress. The tokens of a session are kept in
+//! the key-value store under a random id, which is all the session cookie holds. The login
+//! state is kept in a cookie sealed with AES-256-GCM, so browsers can neither read nor change it.
+
+use std::time::SystemTime;
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use spin_sdk::key_value::Store;
+
+use crate::cache::sha256_hex;
+use crate::replay::sweep;
+use crate::revocation::unix_time;
+
+/// Prefix of the keys of sessions in the key-value store
+const KEY_PREFIX: &str = "session";
+/// Refresh access tokens this long before they expire
+const REFRESH_MARGIN_SECONDS: u64 = 30;
+/// Used if the token endpoint doesn't tell when the access token expires
+const DEFAULT_TOKEN_LIFETIME_SECONDS: u64 = 300;
+/// Shorter secrets are too easy to guess
+const MIN_SECRET_LEN: usize = 32;
+
+/// Seals and opens cookies with a key derived from the `session_secret` variable
+pub struct SessionKey {
+    key: LessSafeKey,
+}
+
+impl SessionKey {
+    pub fn new(secret: &str) -> Result<SessionKey> {
+        if secret.trim().len() < MIN_SECRET_LEN {
+            bail!(
+                "session_secret must have at least {} characters",
+                MIN_SECRET_LEN
+            );
+        }
+        let hash = digest(&SHA256, secret.trim().as_bytes());
+        let key = UnboundKey::new(&AES_256_GCM, hash.as_ref())
+            .map_err(|_| anyhow!("invalid session key"))?;
+        Ok(SessionKey {
+            key: LessSafeKey::new(key),
+        })
+    }
+
+    /// Encrypt `value` as the value of the cookie `name`. The name is authenticated as well,
+    /// so the value of one cookie can't be used as another.
+    pub fn seal<T: Serialize>(&self, name: &str, value: &T) -> Result<String> {
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| anyhow!("failed to generate nonce"))?;
+
+        let mut sealed = serde_json::to_vec(value)?;
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(name.as_bytes()),
+                &mut sealed,
+            )
+            .map_err(|_| anyhow!("failed to encrypt cookie"))?;
+
+        let mut cookie = nonce.to_vec();
+        cookie.extend(sealed);
+        Ok(URL_SAFE_NO_PAD.encode(cookie))
+    }
+
+    /// Decrypt the value of the cookie `name`. Returns `None` if it was not sealed with this
+    /// key for this cookie.
+    pub fn open<T: DeserializeOwned>(&self, name: &str, cookie: &str) -> Option<T> {
+        let cookie = URL_SAFE_NO_PAD.decode(cookie).ok()?;
+        if cookie.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, sealed) = cookie.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+
+        let mut sealed = sealed.to_vec();
+        let plaintext = self
+            .key
+            .open_in_place(nonce, Aad::from(name.as_bytes()), &mut sealed)
+            .ok()?;
+        serde_json::from_slice(plaintext).ok()
+    }
+}
+
+/// Tokens of a logged in user
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    /// Sent as hint when logging out
+    pub id_token: Option<String>,
+    /// Seconds since the epoch at which the access token expires
+    pub expires_at: u64,
+    /// Seconds since the epoch at which the session ends, even if its tokens can be refreshed
+    pub session_expires_at: u64,
+}
+
+/// Response of the token endpoint, see RFC 6749 section 5.1
+#[derive(Debug, Deserialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub expires_in: Option<u64>,
+    pub refresh_token: Option<String>,
+    pub id_token: Option<String>,
+}
+
+impl Session {
+    /// The session started at `now` with the tokens of `response`, lasting `max_age` seconds
+    pub fn from_response(response: TokenResponse, now: u64, max_age: u64) -> Self {
+        Session {
+            access_token: response.access_token,
+            refresh_token: response.refresh_token,
+            id_token: response.id_token,
+            expires_at: now
+                + response
+                    .expires_in
+                    .unwrap_or(DEFAULT_TOKEN_LIFETIME_SECONDS),
+            session_expires_at: now + max_age,
+        }
+    }
+
+    /// The session with the tokens of the refresh `response`. Tokens missing from the response
+    /// are kept, the session ends when it would have before.
+    pub fn refreshed(&self, response: TokenResponse, now: u64) -> Self {
+        Session {
+            access_token: response.access_token,
+            refresh_token: response
+                .refresh_token
+                .or_else(|| self.refresh_token.clone()),
+            id_token: response.id_token.or_else(|| self.id_token.clone()),
+            expires_at: now
+                + response
+                    .expires_in
+                    .unwrap_or(DEFAULT_TOKEN_LIFETIME_SECONDS),
+            session_expires_at: self.session_expires_at,
+        }
+    }
+
+    /// Whether the access token should be refreshed at `now`
+    pub fn expires_soon(&self, now: u64) -> bool {
+        now + REFRESH_MARGIN_SECONDS >= self.expires_at
+    }
+
+    pub fn expired(&self, now: u64) -> bool {
+        now >= self.expires_at
+    }
+
+    /// Whether the session is over at `now`, its tokens must not be used or refreshed anymore
+    pub fn ended(&self, now: u64) -> bool {
+        now >= self.session_expires_at
+    }
+}
+
+/// Sessions kept in a Spin key-value store, by the hash of their id.
+///
+/// Sessions are deleted on logout, when they are used after they ended, and by a periodic
+/// sweep, so abandoned sessions don't pile up.
+pub struct SessionStore {
+    store: Store,
+}
+
+impl SessionStore {
+    pub fn open_default() -> Result<Self> {
+        let store = Store::open_default().with_context(|| "Error opening key-value store")?;
+        Ok(Self { store })
+    }
+
+    /// Store a new session, returning its id
+    pub fn create(&self, session: &Session) -> Result<String> {
+        let id = random_string()?;
+        self.update(&id, session)?;
+
+        let now = SystemTime::now();
+        let ended = |value: &[u8]| {
+            serde_json::from_slice::<Session>(value)
+                .map_or(true, |session| session.ended(unix_time(now)))
+        };
+        if let Err(e) = sweep(&self.store, KEY_PREFIX, now, ended) {
+            println!("error deleting ended sessions: {}", e);
+        }
+        Ok(id)
+    }
+
+    /// The session with `id`, unless it has ended at `now`. Ended sessions are deleted.
+    pub fn get(&self, id: &str, now: u64) -> Result<Option<Session>> {
+        let key = session_key(id);
+        let Some(value) = self.store.get(&key)? else {
+            return Ok(None);
+        };
+        match serde_json::from_slice::<Session>(&value) {
+            Ok(session) if !session.ended(now) => return Ok(Some(session)),
+            Ok(_) => {}
+            Err(e) => println!("removing unreadable session: {}", e),
+        }
+        self.store.delete(&key)?;
+        Ok(None)
+    }
+
+    pub fn update(&self, id: &str, session: &Session) -> Result<()> {
+        self.store
+            .set(&session_key(id), &serde_json::to_vec(session)?)?;
+        Ok(())
+    }
+
+    pub fn delete(&self, id: &str) -> Result<()> {
+        self.store.delete(&session_key(id))?;
+        Ok(())
+    }
+}
+
+fn session_key(id: &str) -> String {
+    format!("{}:{}", KEY_PREFIX, sha256_hex(id))
+}
+
+/// State of a login in progress, kept in a cookie until the callback
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoginState {
+    /// Sent as `state` parameter, binds the callback to this browser
+    pub state: String,
+    /// Sent as `nonce` parameter, binds the ID token to this login
+    pub nonce: String,
+    /// PKCE code verifier (RFC 7636)
+    pub code_verifier: String,
+    /// Path to return to after the login
+    pub return_to: String,
+    /// Seconds since the epoch
+    pub expires_at: u64,
+}
+
+impl LoginState {
+    pub fn new(return_to: &str, expires_at: u64) -> Result<LoginState> {
+        Ok(LoginState {
+            state: random_string()?,
+            nonce: random_string()?,
+            code_verifier: random_string()?,
+            return_to: String::from(return_to),
+            expires_at,
+        })
+    }
+
+    /// The `S256` code challenge of the code verifier
+    pub fn code_challenge(&self) -> String {
+        URL_SAFE_NO_PAD.encode(digest(&SHA256, self.code_verifier.as_bytes()))
+    }
+}
+
+/// 256 random bits, base64url encoded
+fn random_string() -> Result<String> {
+    let mut bytes = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| anyhow!("failed to generate random value"))?;
+    Ok(URL_SAFE_NO_PAD.encode(bytes))
+}
+
+/// `Set-Cookie` value of a session cookie, only sent over https and not readable by scripts.
+/// `SameSite=Lax` keeps the cookie on the redirect back from the IdP.
+pub fn set_cookie(name: &str, value: &str, max_age: u64) -> String {
+    format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; Secure; SameSite=Lax",
+        name, value, max_age
+    )
+}
+
+/// `Set-Cookie` value removing a cookie
+pub fn clear_cookie(name: &str) -> String {
+    set_cookie(name, "", 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "a secret of at least thirty-two characters";
+
+    fn session() -> Session {
+        Session {
+            access_token: String::from("access"),
+            refresh_token: Some(String::from("refresh")),
+            id_token: None,
+            expires_at: 1000,
+            session_expires_at: 3000,
+        }
+    }
+
+    #[test]
+    fn test_sealed_cookie_round_trip() {
+        let key = SessionKey::new(SECRET).unwrap();
+        let state = LoginState::new("/invoices", 1600).unwrap();
+        let cookie = key.seal("__Host-session-login", &state).unwrap();
+
+        assert!(!cookie.contains("invoices"));
+        assert_eq!(
+            key.open("__Host-session-login", &cookie),
+            Some(state.clone())
+        );
+        // sealing twice gives different cookies
+        assert_ne!(key.seal("__Host-session-login", &state).unwrap(), cookie);
+    }
+
+    #[test]
+    fn test_sealed_cookie_is_authenticated() {
+        let key = SessionKey::new(SECRET).unwrap();
+        let cookie = key
+            .seal("__Host-session-login", &LoginState::new("/", 1600).unwrap())
+            .unwrap();
+
+        assert_eq!(key.open::<LoginState>("__Host-other", &cookie), None);
+        let other = SessionKey::new(&format!("{} but other", SECRET)).unwrap();
+        assert_eq!(
+            other.open::<LoginState>("__Host-session-login", &cookie),
+            None
+        );
+
+        let mut tampered = URL_SAFE_NO_PAD.decode(&cookie).unwrap();
+        tampered[NONCE_LEN] ^= 1;
+        let tampered = URL_SAFE_NO_PAD.encode(tampered);
+        assert_eq!(
+            key.open::<LoginState>("__Host-session-login", &tampered),
+            None
+        );
+        assert_eq!(key.open::<LoginState>("__Host-session-login", "AAAA"), None);
+        assert_eq!(
+            key.open::<LoginState>("__Host-session-login", "not base64!"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_short_secrets_are_rejected() {
+        assert!(SessionKey::new("secret").is_err());
+    }
+
+    #[test]
+    fn test_session_from_response() {
+        let response = TokenResponse {
+            access_token: String::from("access"),
+            expires_in: None,
+            refresh_token: None,
+            id_token: Some(String::from("id")),
+        };
+        let session = Session::from_response(response, 1000, 28800);
+
+        assert_eq!(session.expires_at, 1300);
+        assert_eq!(session.session_expires_at, 29800);
+        assert!(!session.ended(29799));
+        assert!(session.ended(29800));
+    }
+
+    #[test]
+    fn test_session_from_refresh_response() {
+        let response = TokenResponse {
+            access_token: String::from("new-access"),
+            expires_in: Some(600),
+            refresh_token: None,
+            id_token: None,
+        };
+        let refreshed = session().refreshed(response, 2000);
+
+        assert_eq!(refreshed.access_token, "new-access");
+        assert_eq!(refreshed.refresh_token.as_deref(), Some("refresh"));
+        assert_eq!(refreshed.expires_at, 2600);
+        assert!(!refreshed.expires_soon(2500));
+        assert!(refreshed.expires_soon(2580));
+        assert!(!refreshed.expired(2580));
+        // refreshing does not extend the session
+        assert_eq!(refreshed.session_expires_at, 3000);
+    }
+
+    #[test]
+    fn test_code_challenge() {
+        // BASE64URL(SHA256(code_verifier)) without padding
+        let state = LoginState {
+            code_verifier: String::from("dBjftJeZ4CVP-mJ92K9qQvQxXS3iMoOtYsYU7GQ3eUI"),
+            ..LoginState::new("/", 0).unwrap()
+        };
+        assert_eq!(
+            state.code_challenge(),
+            "p6J9L1zF2w4Z7lthOlzJYFaC42Xdlp8J20H7fY1DNFc"
+        );
+        assert_eq!(state.state.len(), 43);
+    }
+}
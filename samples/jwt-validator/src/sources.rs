@@ -0,0 +1,468 @@
+//! Where tokens are taken from: headers, cookies or query parameters, and the CSRF protection
+//! of tokens sent automatically by browsers in cookies.
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use spin_sdk::http::{Request, Response};
+use spin_sdk::variables;
+
+use crate::cache::sha256_hex;
+use crate::dpop::Scheme;
+use crate::{json_response, ValidationError};
+
+/// Methods that must not change state, and therefore need no CSRF protection
+const SAFE_METHODS: &[&str] = &["GET", "HEAD", "OPTIONS", "TRACE"];
+
+/// A place to look for the token of a request
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase",
+    deny_unknown_fields
+)]
+pub enum TokenSource {
+    /// A header of the form `<scheme> <token>`, or just `<token>` if `schemes` is empty.
+    /// The `DPoP` scheme presents DPoP-bound tokens, any other scheme bearer tokens.
+    Header {
+        name: String,
+        #[serde(default)]
+        schemes: Vec<String>,
+    },
+    /// A cookie, requires CSRF protection
+    Cookie { name: String },
+    /// A query parameter
+    Query { name: String },
+}
+
+/// Protection against cross-site request forgery for tokens taken from cookies. It applies to
+/// requests with unsafe methods, e.g. `POST`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(
+    tag = "mode",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase",
+    deny_unknown_fields
+)]
+pub enum CsrfProtection {
+    /// The value of the `cookie` must be sent in the `header` as well, which only scripts of
+    /// the app itself can do
+    DoubleSubmit {
+        #[serde(default = "default_csrf_cookie")]
+        cookie: String,
+        #[serde(default = "default_csrf_header")]
+        header: String,
+    },
+    /// The `Origin` (or `Referer`) header must be one of `allowed_origins`
+    Origin { allowed_origins: Vec<String> },
+    /// No protection, e.g. if cookies are `SameSite=Strict`
+    Disabled,
+}
+
+fn default_csrf_cookie() -> String {
+    String::from("csrf_token")
+}
+
+fn default_csrf_header() -> String {
+    String::from("X-CSRF-Token")
+}
+
+/// A token found in a request
+#[derive(Debug, Clone, PartialEq)]
+pub struct PresentedToken {
+    pub scheme: Scheme,
+    pub token: String,
+    /// Whether the token was taken from a cookie
+    pub from_cookie: bool,
+}
+
+/// Cookies and query parameters holding credentials of the client, which the gateway doesn't
+/// forward to the origin
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Credentials {
+    pub cookies: Vec<String>,
+    pub query_params: Vec<String>,
+}
+
+/// The configured token sources, tried in order
+#[derive(Debug)]
+pub struct TokenSources {
+    sources: Vec<TokenSource>,
+    csrf: Option<CsrfProtection>,
+}
+
+impl Default for TokenSources {
+    /// The `Authorization` header with the `Bearer` and `DPoP` schemes
+    fn default() -> Self {
+        TokenSources {
+            sources: vec![TokenSource::Header {
+                name: String::from("Authorization"),
+                schemes: vec![String::from("Bearer"), String::from("DPoP")],
+            }],
+            csrf: None,
+        }
+    }
+}
+
+impl TokenSources {
+    /// Load the sources from the `token_sources` variable and the CSRF protection from the
+    /// `csrf_protection` variable. Without sources, tokens are taken from the `Authorization`
+    /// header.
+    pub fn load() -> Result<TokenSources> {
+        let sources = variables::get("token_sources").unwrap_or_default();
+        let csrf = variables::get("csrf_protection").unwrap_or_default();
+        TokenSources::parse(&sources, &csrf)
+    }
+
+    /// Parse and check the `token_sources` and `csrf_protection` documents. Empty documents
+    /// select the defaults.
+    pub fn parse(sources: &str, csrf: &str) -> Result<TokenSources> {
+        let sources = match sources.trim() {
+            "" => TokenSources::default().sources,
+            sources => serde_json::from_str::<Vec<TokenSource>>(sources)
+                .context("invalid token_sources variable")?,
+        };
+        let csrf = match csrf.trim() {
+            "" => None,
+            csrf => Some(
+                serde_json::from_str::<CsrfProtection>(csrf)
+                    .context("invalid csrf_protection variable")?,
+            ),
+        };
+
+        if sources.is_empty() {
+            bail!("at least one token source is required");
+        }
+        for source in sources.iter() {
+            let (TokenSource::Header { name, .. }
+            | TokenSource::Cookie { name }
+            | TokenSource::Query { name }) = source;
+            if name.trim().is_empty() {
+                bail!("token sources require a name");
+            }
+            if let TokenSource::Header { schemes, .. } = source {
+                if schemes
+                    .iter()
+                    .any(|scheme| scheme.is_empty() || scheme.contains(char::is_whitespace))
+                {
+                    bail!("invalid scheme of header {}", name);
+                }
+            }
+        }
+        let has_cookie = sources
+            .iter()
+            .any(|source| matches!(source, TokenSource::Cookie { .. }));
+        match &csrf {
+            // opting out requires `{ "mode": "disabled" }`
+            None if has_cookie => bail!("cookie sources require csrf_protection"),
+            Some(CsrfProtection::Origin { allowed_origins }) if allowed_origins.is_empty() => {
+                bail!("origin CSRF protection requires allowedOrigins")
+            }
+            _ => {}
+        }
+
+        Ok(TokenSources { sources, csrf })
+    }
+
+    /// The cookies and query parameters tokens are taken from
+    pub fn credentials(&self) -> Credentials {
+        let mut credentials = Credentials::default();
+        for source in self.sources.iter() {
+            match source {
+                TokenSource::Header { .. } => {}
+                TokenSource::Cookie { name } => credentials.cookies.push(name.clone()),
+                TokenSource::Query { name } => credentials.query_params.push(name.clone()),
+            }
+        }
+        credentials
+    }
+
+    /// Whether `csrf_protection` is configured, which cookie sessions require as well
+    pub fn has_csrf_protection(&self) -> bool {
+        self.csrf.is_some()
+    }
+
+    /// The token of `req`, from the first source that has one, checked against CSRF for a
+    /// request with `method`. Returns the response to send to the client if there is no
+    /// token or the CSRF check fails.
+    pub fn token(&self, req: &Request, method: &str) -> Result<Result<PresentedToken, Response>> {
+        let Some(token) = self.find(req) else {
+            return Ok(Err(Response::new(401, ())));
+        };
+        if token.from_cookie {
+            if let Err(response) = self.check_cookie_request(req, method)? {
+                return Ok(Err(response));
+            }
+        }
+        Ok(Ok(token))
+    }
+
+    /// Check a request with `method` authorized by a cookie against CSRF. Returns the
+    /// response to send to the client if the check fails.
+    pub fn check_cookie_request(
+        &self,
+        req: &Request,
+        method: &str,
+    ) -> Result<Result<(), Response>> {
+        if let Err(error) = self.check_csrf(req, method) {
+            println!("CSRF check failed: {}", error.message);
+            return Ok(Err(json_response(403, &error)?));
+        }
+        Ok(Ok(()))
+    }
+
+    fn find(&self, req: &Request) -> Option<PresentedToken> {
+        self.sources
+            .iter()
+            .find_map(|source| source.token(req).filter(|token| !token.token.is_empty()))
+    }
+
+    fn check_csrf(&self, req: &Request, method: &str) -> Result<(), ValidationError> {
+        if SAFE_METHODS.contains(&method.to_ascii_uppercase().as_str()) {
+            return Ok(());
+        }
+        let failed = |message: &str| ValidationError {
+            code: Some("csrf_check_failed"),
+            message: String::from(message),
+        };
+
+        match &self.csrf {
+            None | Some(CsrfProtection::Disabled) => Ok(()),
+            Some(CsrfProtection::DoubleSubmit { cookie, header }) => {
+                let expected = cookie_value(req, cookie).unwrap_or_default();
+                let presented = header_value(req, header).unwrap_or_default();
+                // comparing hashes doesn't reveal how much of the value matches
+                if expected.is_empty() || sha256_hex(expected) != sha256_hex(presented) {
+                    return Err(failed("CSRF token missing or invalid"));
+                }
+                Ok(())
+            }
+            Some(CsrfProtection::Origin { allowed_origins }) => {
+                let origin = header_value(req, "Origin")
+                    .filter(|origin| *origin != "null")
+                    .map(String::from)
+                    .or_else(|| header_value(req, "Referer").and_then(origin_of));
+                let Some(origin) = origin else {
+                    return Err(failed("Origin of the request is unknown"));
+                };
+                let allowed = allowed_origins.iter().any(|allowed| {
+                    allowed
+                        .trim_end_matches('/')
+                        .eq_ignore_ascii_case(origin.trim_end_matches('/'))
+                });
+                if !allowed {
+                    return Err(failed("Origin of the request is not allowed"));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl TokenSource {
+    fn token(&self, req: &Request) -> Option<PresentedToken> {
+        match self {
+            TokenSource::Header { name, schemes } => {
+                let value = header_value(req, name)?.trim();
+                if schemes.is_empty() {
+                    return Some(PresentedToken::bearer(value, false));
+                }
+                let (scheme, token) = value.split_once(char::is_whitespace)?;
+                let scheme = schemes
+                    .iter()
+                    .find(|allowed| allowed.eq_ignore_ascii_case(scheme))?;
+                let scheme = match scheme.eq_ignore_ascii_case("dpop") {
+                    true => Scheme::Dpop,
+                    false => Scheme::Bearer,
+                };
+                Some(PresentedToken {
+                    scheme,
+                    token: String::from(token.trim()),
+                    from_cookie: false,
+                })
+            }
+            TokenSource::Cookie { name } => {
+                cookie_value(req, name).map(|token| PresentedToken::bearer(token, true))
+            }
+            TokenSource::Query { name } => form_urlencoded::parse(req.query().as_bytes())
+                .find(|(key, _)| key == name)
+                .map(|(_, token)| PresentedToken::bearer(&token, false)),
+        }
+    }
+}
+
+impl PresentedToken {
+    fn bearer(token: &str, from_cookie: bool) -> Self {
+        PresentedToken {
+            scheme: Scheme::Bearer,
+            token: String::from(token),
+            from_cookie,
+        }
+    }
+}
+
+fn header_value<'a>(req: &'a Request, name: &str) -> Option<&'a str> {
+    req.header(name).and_then(|value| value.as_str())
+}
+
+/// Value of the cookie `name`, from any of the `Cookie` headers of `req`
+pub(crate) fn cookie_value<'a>(req: &'a Request, name: &str) -> Option<&'a str> {
+    req.headers()
+        .filter(|(header, _)| header.eq_ignore_ascii_case("cookie"))
+        .filter_map(|(_, value)| value.as_str())
+        .flat_map(|cookies| cookies.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(cookie, _)| *cookie == name)
+        .map(|(_, value)| value.trim_matches('"'))
+}
+
+/// `scheme://host[:port]` of `url`
+fn origin_of(url: &str) -> Option<String> {
+    let (scheme, rest) = url.split_once("://")?;
+    let host = rest.split(['/', '?', '#']).next()?;
+    if host.is_empty() {
+        return None;
+    }
+    Some(format!("{}://{}", scheme, host))
+}
+
+#[cfg(test)]
+mod tests {
+    use spin_sdk::http::Method;
+
+    use super::*;
+
+    fn sources(sources: &str, csrf: &str) -> TokenSources {
+        TokenSources::parse(sources, csrf).unwrap()
+    }
+
+    fn request(headers: &[(&str, &str)], uri: &str) -> Request {
+        let mut builder = Request::builder();
+        builder.method(Method::Post).uri(uri);
+        for (name, value) in headers {
+            builder.header(*name, *value);
+        }
+        builder.build()
+    }
+
+    fn token(sources: &TokenSources, req: &Request) -> Option<(Scheme, String)> {
+        sources
+            .find(req)
+            .map(|presented| (presented.scheme, presented.token))
+    }
+
+    #[test]
+    fn test_default_authorization_header() {
+        let sources = TokenSources::default();
+
+        let req = request(&[("Authorization", "bearer abc")], "/validate");
+        assert_eq!(
+            token(&sources, &req),
+            Some((Scheme::Bearer, String::from("abc")))
+        );
+        let req = request(&[("Authorization", "DPoP abc")], "/validate");
+        assert_eq!(
+            token(&sources, &req),
+            Some((Scheme::Dpop, String::from("abc")))
+        );
+        let req = request(&[("Authorization", "Basic abc")], "/validate");
+        assert_eq!(token(&sources, &req), None);
+        let req = request(&[("Authorization", "Bearer ")], "/validate");
+        assert_eq!(token(&sources, &req), None);
+    }
+
+    #[test]
+    fn test_sources_are_tried_in_order() {
+        let sources = sources(
+            r#"[
+                { "type": "header", "name": "X-Api-Token" },
+                { "type": "cookie", "name": "session" },
+                { "type": "query", "name": "access_token" }
+            ]"#,
+            r#"{ "mode": "disabled" }"#,
+        );
+
+        let req = request(
+            &[
+                ("X-Api-Token", "from-header"),
+                ("Cookie", "session=from-cookie"),
+            ],
+            "/api?access_token=from-query",
+        );
+        assert_eq!(token(&sources, &req).unwrap().1, "from-header");
+
+        let req = request(
+            &[("Cookie", "theme=dark; session=\"from-cookie\"")],
+            "/api?access_token=from-query",
+        );
+        let presented = sources.find(&req).unwrap();
+        assert_eq!(presented.token, "from-cookie");
+        assert!(presented.from_cookie);
+
+        let req = request(&[], "/api?page=2&access_token=from%2Bquery");
+        assert_eq!(token(&sources, &req).unwrap().1, "from+query");
+
+        assert_eq!(token(&sources, &request(&[], "/api")), None);
+    }
+
+    #[test]
+    fn test_invalid_sources_are_rejected() {
+        let error =
+            |sources, csrf| format!("{:#}", TokenSources::parse(sources, csrf).unwrap_err());
+
+        assert!(error(r#"[{ "type": "cookie", "name": "session" }]"#, "")
+            .contains("require csrf_protection"));
+        assert!(error("[]", "").contains("at least one token source"));
+        assert!(error(r#"[{ "type": "query", "name": " " }]"#, "").contains("require a name"));
+        assert!(error(
+            r#"[{ "type": "header", "name": "X", "schemes": ["a b"] }]"#,
+            ""
+        )
+        .contains("invalid scheme"));
+        assert!(error("", r#"{ "mode": "origin", "allowedOrigins": [] }"#)
+            .contains("requires allowedOrigins"));
+        assert!(error(r#"[{ "type": "form", "name": "x" }]"#, "").contains("unknown variant"));
+    }
+
+    #[test]
+    fn test_double_submit_csrf_protection() {
+        let sources = sources(
+            r#"[{ "type": "cookie", "name": "session" }]"#,
+            r#"{ "mode": "doubleSubmit" }"#,
+        );
+        let check = |headers: &[(&str, &str)], method| {
+            sources
+                .check_csrf(&request(headers, "/api"), method)
+                .map_err(|error| error.code)
+        };
+
+        let cookies = "session=abc; csrf_token=1234";
+        assert!(check(&[("Cookie", cookies), ("X-CSRF-Token", "1234")], "POST").is_ok());
+        assert_eq!(
+            check(&[("Cookie", cookies), ("X-CSRF-Token", "4321")], "POST"),
+            Err(Some("csrf_check_failed"))
+        );
+        assert!(check(&[("Cookie", cookies)], "DELETE").is_err());
+        assert!(check(&[("Cookie", "session=abc"), ("X-CSRF-Token", "")], "POST").is_err());
+        assert!(check(&[("Cookie", cookies)], "GET").is_ok());
+    }
+
+    #[test]
+    fn test_origin_csrf_protection() {
+        let sources = sources(
+            r#"[{ "type": "cookie", "name": "session" }]"#,
+            r#"{ "mode": "origin", "allowedOrigins": ["https://app.example.com/"] }"#,
+        );
+        let check =
+            |headers: &[(&str, &str)]| sources.check_csrf(&request(headers, "/api"), "PUT").is_ok();
+
+        assert!(check(&[("Origin", "https://app.example.com")]));
+        assert!(check(&[(
+            "Referer",
+            "https://app.example.com/invoices?page=2"
+        )]));
+        assert!(!check(&[("Origin", "https://evil.example.com")]));
+        assert!(!check(&[("Origin", "null")]));
+        assert!(!check(&[]));
+    }
+}